@@ -0,0 +1,218 @@
+use std::{
+    io,
+    ops::{DerefMut},
+    ptr::{NonNull},
+    sync::{Arc},
+};
+use tokio::{
+    io::{AsyncRead,AsyncWrite,AsyncReadExt,AsyncWriteExt},
+    sync::{Mutex},
+};
+use mozjs::{rooted};
+use mozjs::{
+    context::{JSContext,RawJSContext},
+    gc::{Handle,MutableHandle},
+    jsapi::{JSObject,Value,CallArgs},
+    jsval::{UndefinedValue,ObjectValue,NullValue},
+    realm::{AutoRealm},
+    rust::wrappers2::JS_DefineFunction,
+    panic::wrap_panic,
+};
+#[allow(unused_imports)]
+use tracing::{debug,trace,instrument,warn,error,info};
+
+use crate::{
+    streams::{attach_stream_views},
+    runtime::{
+        conversions::{arg_bytes,new_uint8_array,new_io_error,define_value},
+        host_object::{new_host_object,host_object_data,take_host_object_data},
+        resolvable_promise::{ResolutionMarshalling,promise_entry_point},
+    },
+};
+
+/*
+ * Byte streams
+ *
+ * A host object wrapping any `AsyncRead` and/or `AsyncWrite`. Used
+ * for files, sockets & child process pipes.
+ *
+ *  - `read(size)` resolves to a `Uint8Array`, or `null` at EOF
+ *  - `write(data)` resolves to the number of bytes written
+ *  - `shutdown()` closes the write side
+ *  - `close()` drops both sides
+ *
+ * It also gets WHATWG `readable`/`writable` views over the same
+ * sides, see `streams`.
+ *
+ */
+
+/// Default chunk size for `read()`
+const DEFAULT_READ_SIZE: usize = 64 * 1024;
+/// Larger reads are cut down to this, the buffer is allocated up front
+pub const MAX_READ_SIZE: usize = 16 * 1024 * 1024;
+
+pub type BoxedReader = Box<dyn AsyncRead + Send + Unpin + 'static>;
+pub type BoxedWriter = Box<dyn AsyncWrite + Send + Unpin + 'static>;
+
+/// Everything needed to build a byte stream object, `Send` so it can come out of a `Bridge`
+pub struct ByteStreamParts {
+    pub reader: Option<BoxedReader>,
+    pub writer: Option<BoxedWriter>,
+    /// exposed as `localAddress`
+    pub local_address: Option<String>,
+    /// exposed as `remoteAddress`
+    pub remote_address: Option<String>,
+}
+impl ByteStreamParts {
+    pub fn new(reader: Option<BoxedReader>, writer: Option<BoxedWriter>) -> Self {
+        ByteStreamParts { reader, writer, local_address: None, remote_address: None }
+    }
+}
+
+/// Owned by a byte stream object
+///
+/// In flight operations hold their own reference to each side, so
+/// `close()` takes effect once they complete.
+pub struct ByteStream {
+    pub reader: Option<Arc<Mutex<BoxedReader>>>,
+    pub writer: Option<Arc<Mutex<Option<BoxedWriter>>>>,
+}
+
+/// Creates a byte stream object in the current realm, null on failure
+pub fn new_byte_stream(cx: *mut RawJSContext, parts: ByteStreamParts) -> *mut JSObject {
+    let ByteStreamParts { reader, writer, local_address, remote_address } = parts;
+    let readable = reader.is_some();
+    let writable = writer.is_some();
+    let stream = ByteStream {
+        reader: reader.map(|r| Arc::new(Mutex::new(r))),
+        writer: writer.map(|w| Arc::new(Mutex::new(Some(w)))),
+    };
+    rooted!(in(cx) let obj = new_host_object(cx, stream));
+    if obj.get().is_null() {
+        return obj.get();
+    }
+    let mut safe_ctx = unsafe { JSContext::from_ptr(NonNull::new(cx).unwrap()) };
+    unsafe {
+        if readable {
+            JS_DefineFunction(&mut safe_ctx, obj.handle(), c"read".as_ptr(), Some(stream_read), 1, 0);
+        }
+        if writable {
+            JS_DefineFunction(&mut safe_ctx, obj.handle(), c"write".as_ptr(), Some(stream_write), 1, 0);
+            JS_DefineFunction(&mut safe_ctx, obj.handle(), c"shutdown".as_ptr(), Some(stream_shutdown), 0, 0);
+        }
+        JS_DefineFunction(&mut safe_ctx, obj.handle(), c"close".as_ptr(), Some(stream_close), 0, 0);
+    }
+    if let Some(addr) = local_address {
+        define_value(cx, obj.handle(), c"localAddress", &addr);
+    }
+    if let Some(addr) = remote_address {
+        define_value(cx, obj.handle(), c"remoteAddress", &addr);
+    }
+    attach_stream_views(cx, obj.handle());
+    obj.get()
+}
+
+/// Borrows the `ByteStream` behind a JS object
+pub fn byte_stream_data<'a>(obj: *mut JSObject) -> Option<&'a mut ByteStream> {
+    unsafe { host_object_data::<ByteStream>(obj) }
+}
+
+fn this_stream<'a>(args: &CallArgs) -> Option<&'a mut ByteStream> {
+    let this = args.thisv();
+    if !this.is_object() {
+        return None;
+    }
+    byte_stream_data(this.to_object())
+}
+
+/// What a stream operation resolves to
+pub enum StreamValue {
+    Unit,
+    Eof,
+    Bytes(Vec<u8>),
+    Written(usize),
+}
+
+/// Reads at most `size` (up to `MAX_READ_SIZE`) bytes, `Eof` once the other side is done
+pub async fn read_chunk(reader: Arc<Mutex<BoxedReader>>, size: usize) -> io::Result<StreamValue> {
+    let mut buffer = vec![0u8; size.min(MAX_READ_SIZE)];
+    let read = reader.lock().await.read(&mut buffer).await?;
+    if read == 0 {
+        return Ok(StreamValue::Eof);
+    }
+    buffer.truncate(read);
+    Ok(StreamValue::Bytes(buffer))
+}
+
+/// Writes all of `data`
+pub async fn write_all(writer: Arc<Mutex<Option<BoxedWriter>>>, data: Vec<u8>) -> io::Result<StreamValue> {
+    let mut guard = writer.lock().await;
+    let writer = guard.as_mut().ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))?;
+    writer.write_all(&data).await?;
+    writer.flush().await?;
+    Ok(StreamValue::Written(data.len()))
+}
+
+/// `stream.read(size)`, resolves to at most `size` bytes
+unsafe extern "C" fn stream_read(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    promise_entry_point(ctx, argc, vp, "read", bridge_stream, |_ctx, args| {
+        let reader = this_stream(args).ok_or("stream is closed")?
+            .reader.clone().ok_or("stream is not readable")?;
+        let size = match args.argc_ {
+            0 => DEFAULT_READ_SIZE,
+            _ if args.get(0).is_undefined() => DEFAULT_READ_SIZE,
+            _ if args.get(0).is_number() && args.get(0).to_number() >= 1.0 => args.get(0).to_number() as usize,
+            _ => return Err("size must be a positive number"),
+        };
+        Ok(read_chunk(reader, size))
+    })
+}
+
+/// `stream.write(data)`
+unsafe extern "C" fn stream_write(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    promise_entry_point(ctx, argc, vp, "write", bridge_stream, |ctx, args| {
+        let writer = this_stream(args).ok_or("stream is closed")?
+            .writer.clone().ok_or("stream is not writable")?;
+        let data = arg_bytes(ctx, args, 0).ok_or("data must be a string, Uint8Array or ArrayBuffer")?;
+        Ok(write_all(writer, data))
+    })
+}
+
+/// `stream.shutdown()`, flushes & closes the write side
+unsafe extern "C" fn stream_shutdown(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    promise_entry_point(ctx, argc, vp, "shutdown", bridge_stream, |_ctx, args| {
+        let writer = this_stream(args).ok_or("stream is closed")?
+            .writer.clone().ok_or("stream is not writable")?;
+        Ok(async move {
+            if let Some(mut w) = writer.lock().await.take() {
+                w.shutdown().await?;
+            }
+            Ok(StreamValue::Unit)
+        })
+    })
+}
+
+/// `stream.close()`
+unsafe extern "C" fn stream_close(_ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    wrap_panic(&mut || {
+        let args = unsafe { CallArgs::from_vp(vp, argc) };
+        if args.thisv().is_object() {
+            unsafe { take_host_object_data(args.thisv().to_object()) };
+        }
+        args.rval().set(UndefinedValue());
+    });
+    true
+}
+
+pub fn bridge_stream(result: io::Result<StreamValue>) -> ResolutionMarshalling {
+    Box::new(move |realm: &mut AutoRealm, _promise: Handle<'_, *mut JSObject>, _global: Handle<'_, *mut JSObject>, mut ok: MutableHandle<'_,Value>, err: MutableHandle<'_,Value>| {
+        let cx = unsafe { realm.deref_mut().raw_cx() };
+        match result {
+            Ok(StreamValue::Unit) => ok.set(UndefinedValue()),
+            Ok(StreamValue::Eof) => ok.set(NullValue()),
+            Ok(StreamValue::Bytes(bytes)) => { new_uint8_array(cx, &bytes, ok); },
+            Ok(StreamValue::Written(n)) => ok.set(mozjs::jsval::DoubleValue(n as f64)),
+            Err(e) => { new_io_error(cx, &e, err); },
+        };
+    }) as ResolutionMarshalling
+}
@@ -0,0 +1,48 @@
+use mozjs::{rooted};
+use mozjs::{
+    gc::{Handle},
+    jsapi::{Value,CallArgs},
+    jsval::{UndefinedValue},
+    panic::wrap_panic,
+};
+#[allow(unused_imports)]
+use tracing::{debug,trace,instrument,warn,error,info};
+
+use crate::runtime::structured_clone::{write_structured_clone,CloneScope,read_structured_clone,transfer_list};
+
+/// `structuredClone(value, { transfer })`
+///
+/// Round trips `value` through SpiderMonkey's structured clone
+/// serialization. Transferred `ArrayBuffer`s are detached.
+#[instrument(skip_all,name="structured_clone_entry_point")]
+pub unsafe extern "C" fn structured_clone(
+    ctx: *mut mozjs::context::RawJSContext,
+    argc: u32,
+    vp: *mut Value,
+) -> bool {
+    let mut is_okay = true;
+    wrap_panic(&mut || {
+        let args = unsafe { CallArgs::from_vp(vp, argc) };
+        rooted!(in(ctx) let value = args.get(0).get());
+        rooted!(in(ctx) let mut transfer = UndefinedValue());
+        if !transfer_list(ctx, unsafe { Handle::from_raw(args.get(1)) }, transfer.handle_mut()) {
+            is_okay = false;
+            return;
+        }
+        let data = match write_structured_clone(ctx, value.handle(), transfer.handle(), CloneScope::SameThread) {
+            Ok(data) => data,
+            Err(()) => {
+                // DataCloneError is already pending
+                is_okay = false;
+                return;
+            }
+        };
+        rooted!(in(ctx) let mut out = UndefinedValue());
+        if read_structured_clone(ctx, &data, out.handle_mut()).is_err() {
+            is_okay = false;
+            return;
+        }
+        args.rval().set(out.get());
+    });
+    is_okay
+}
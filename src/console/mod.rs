@@ -0,0 +1,202 @@
+use std::{
+    ops::{DerefMut},
+};
+use mozjs::{rooted};
+use mozjs::{
+    context::{RawJSContext},
+    conversions::{ToJSValConvertible},
+    error::{throw_type_error},
+    gc::{Handle},
+    jsapi::{JSObject,Value,CallArgs},
+    jsval::{UndefinedValue,ObjectValue,BooleanValue},
+    realm::{AutoRealm},
+    rust::wrappers2::JS_DefineFunction,
+    panic::wrap_panic,
+};
+#[allow(unused_imports)]
+use tracing::{debug,trace,instrument,warn,error,info};
+use tracing::{Level,enabled,event};
+
+use crate::{
+    realm::{evaluate,realm_id},
+    runtime::{
+        conversions::{arg_string,new_object,define_property},
+        source_map::{map_stack},
+    },
+};
+
+/*
+ * `console`
+ *
+ * Formatting is plain JS (`prelude.js`) & follows what browsers (and
+ * node.js) print. Every message becomes a `tracing` event with the
+ * `console` target at the level matching the method:
+ *
+ *  - `error`, `assert` are `ERROR`
+ *  - `warn` is `WARN`
+ *  - `log`, `info`, `dir`, `table`, `count`, `time*` & `group` are `INFO`
+ *  - `debug` is `DEBUG`, `trace` is `TRACE`
+ *
+ * Each event carries the id of the realm it was logged from & the
+ * `file:line:column` of the caller, both it & printed stacks are
+ * source mapped. Nothing is formatted for a level
+ * the subscriber would drop.
+ *
+ */
+
+/// The target of every `console` event
+pub const TARGET: &str = "console";
+
+const PRELUDE: &str = include_str!("prelude.js");
+
+/// Defines `console` on `global_obj`, must come before the other preludes
+pub fn define_console(realm: &mut AutoRealm, global_obj: Handle<'_,*mut JSObject>) {
+    let cx = unsafe { realm.deref_mut().raw_cx() };
+    rooted!(in(cx) let native = new_object(cx));
+    if native.get().is_null() {
+        error!("could not allocate console natives");
+        return;
+    }
+    unsafe {
+        JS_DefineFunction(realm,native.handle(),c"emit".as_ptr(),Some(console_emit),3,0,);
+        JS_DefineFunction(realm,native.handle(),c"enabled".as_ptr(),Some(console_enabled),1,0,);
+        JS_DefineFunction(realm,native.handle(),c"mapStack".as_ptr(),Some(console_map_stack),1,0,);
+    }
+    rooted!(in(cx) let native_val = ObjectValue(native.get()));
+    define_property(cx, global_obj, c"__consoleNative", native_val.handle());
+    if evaluate(realm, global_obj, "console.js", PRELUDE).is_err() {
+        error!("could not evaluate the console prelude");
+    }
+}
+
+fn parse_level(level: &str) -> Option<Level> {
+    match level {
+        "error" => Some(Level::ERROR),
+        "warn" => Some(Level::WARN),
+        "info" => Some(Level::INFO),
+        "debug" => Some(Level::DEBUG),
+        "trace" => Some(Level::TRACE),
+        _ => None,
+    }
+}
+
+/// `native.enabled(level)`, whether an event at `level` would be recorded
+unsafe extern "C" fn console_enabled(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    let mut is_okay = true;
+    wrap_panic(&mut || {
+        let args = unsafe { CallArgs::from_vp(vp, argc) };
+        let Some(level) = arg_string(ctx, &args, 0).as_deref().and_then(parse_level) else {
+            unsafe { throw_type_error(ctx, "console: unknown level") };
+            is_okay = false;
+            return;
+        };
+        // `enabled!` needs a constant level
+        let enabled = match level {
+            Level::ERROR => enabled!(target: TARGET, Level::ERROR),
+            Level::WARN => enabled!(target: TARGET, Level::WARN),
+            Level::INFO => enabled!(target: TARGET, Level::INFO),
+            Level::DEBUG => enabled!(target: TARGET, Level::DEBUG),
+            _ => enabled!(target: TARGET, Level::TRACE),
+        };
+        args.rval().set(BooleanValue(enabled));
+    });
+    is_okay
+}
+
+/// `native.mapStack(stack)`, `stack` with source mapped frames rewritten
+unsafe extern "C" fn console_map_stack(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    wrap_panic(&mut || {
+        let args = unsafe { CallArgs::from_vp(vp, argc) };
+        let stack = arg_string(ctx, &args, 0).unwrap_or_default();
+        rooted!(in(ctx) let mut mapped = UndefinedValue());
+        unsafe { map_stack(&stack).to_jsval(ctx, mapped.handle_mut()) };
+        args.rval().set(mapped.get());
+    });
+    true
+}
+
+/// `native.emit(level, message, location)`
+unsafe extern "C" fn console_emit(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    let mut is_okay = true;
+    wrap_panic(&mut || {
+        let args = unsafe { CallArgs::from_vp(vp, argc) };
+        let Some(level) = arg_string(ctx, &args, 0).as_deref().and_then(parse_level) else {
+            unsafe { throw_type_error(ctx, "console: unknown level") };
+            is_okay = false;
+            return;
+        };
+        let message = arg_string(ctx, &args, 1).unwrap_or_default();
+        let location = arg_string(ctx, &args, 2).unwrap_or_default();
+        let realm = realm_id(ctx).unwrap_or(0);
+        // `event!` needs a constant level too
+        match level {
+            Level::ERROR => event!(target: TARGET, Level::ERROR, realm, location = %location, "{}", message),
+            Level::WARN => event!(target: TARGET, Level::WARN, realm, location = %location, "{}", message),
+            Level::INFO => event!(target: TARGET, Level::INFO, realm, location = %location, "{}", message),
+            Level::DEBUG => event!(target: TARGET, Level::DEBUG, realm, location = %location, "{}", message),
+            _ => event!(target: TARGET, Level::TRACE, realm, location = %location, "{}", message),
+        }
+        args.rval().set(UndefinedValue());
+    });
+    is_okay
+}
+
+#[cfg(test)]
+mod tests {
+    use mozjs::{rooted};
+    use super::*;
+    use crate::{
+        realm::{realm_id_of},
+        runtime::testing::{CapturedEvent,with_runtime,new_test_global,eval,capture},
+    };
+
+    /// The `console` events `source` logs, with the id of the realm it ran in
+    fn log(source: &str) -> (Vec<CapturedEvent>, Option<u64>) {
+        let (realm, captured) = capture(|| with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            eval(ctx, global.handle(), source).unwrap();
+            realm_id_of(global.get())
+        }));
+        let events = captured.events.into_iter().filter(|e| e.target == TARGET).collect();
+        (events, realm)
+    }
+
+    fn messages(events: &[CapturedEvent]) -> Vec<(Level,&str)> {
+        events.iter().map(|e| (e.level, e.fields["message"].as_str())).collect()
+    }
+
+    #[test]
+    fn methods_log_at_their_level() {
+        let (events, _) = log("console.log('log'); console.info('info'); console.warn('warn'); console.error('error'); console.debug('debug'); console.trace('trace');");
+        let levels: Vec<Level> = events.iter().map(|e| e.level).collect();
+        assert_eq!(levels, [Level::INFO, Level::INFO, Level::WARN, Level::ERROR, Level::DEBUG, Level::TRACE]);
+    }
+
+    #[test]
+    fn arguments_are_formatted() {
+        let (events, _) = log("console.log('%s is %d', 'answer', 42, true); console.count(); console.count();");
+        assert_eq!(messages(&events), [
+            (Level::INFO, "answer is 42 true"),
+            (Level::INFO, "default: 1"),
+            (Level::INFO, "default: 2"),
+        ]);
+    }
+
+    #[test]
+    fn groups_indent_their_messages() {
+        let (events, _) = log("console.group('outer'); console.log('inner'); console.groupEnd(); console.log('after');");
+        assert_eq!(messages(&events), [
+            (Level::INFO, "outer"),
+            (Level::INFO, "  inner"),
+            (Level::INFO, "after"),
+        ]);
+    }
+
+    #[test]
+    fn events_carry_the_realm_and_caller() {
+        let (events, realm) = log("\nconsole.log('located');");
+        let event = &events[0];
+        assert_eq!(event.fields["realm"], realm.unwrap().to_string());
+        assert!(event.fields["location"].starts_with("test.js:2:"), "{}", event.fields["location"]);
+    }
+}
@@ -0,0 +1,582 @@
+// `console`
+//
+// Evaluated once per realm by `console::define_console`, before the
+// other preludes. Formatting happens here, `__consoleNative.emit` turns
+// each message into a `tracing` event. `__consoleNative` is removed
+// from the global before any user script runs.
+(function (native) {
+    'use strict';
+
+    const MAX_DEPTH = 2;
+    const MAX_ITEMS = 100;
+    const MAX_STRING = 10000;
+    const IDENTIFIER = /^[A-Za-z_$][\w$]*$/;
+
+    const { getPrototypeOf, getOwnPropertyNames, getOwnPropertySymbols, getOwnPropertyDescriptor } = Object;
+    const objectToString = Object.prototype.toString;
+    const functionToString = Function.prototype.toString;
+    const errorToString = Error.prototype.toString;
+    const dateToISOString = Date.prototype.toISOString;
+    const regExpToString = RegExp.prototype.toString;
+
+    function quote(s) {
+        if (s.length > MAX_STRING) {
+            return `'${escape(s.slice(0, MAX_STRING))}'... ${s.length - MAX_STRING} more characters`;
+        }
+        return `'${escape(s)}'`;
+    }
+
+    function escape(s) {
+        return s.replace(/[\\'\n\r\t\b\f\v\0]/g, (c) => ({
+            '\\': '\\\\', '\'': '\\\'', '\n': '\\n', '\r': '\\r', '\t': '\\t', '\b': '\\b', '\f': '\\f', '\v': '\\v', '\0': '\\0',
+        })[c]);
+    }
+
+    function formatKey(key) {
+        if (typeof key === 'symbol') {
+            return `[${key.toString()}]`;
+        }
+        return IDENTIFIER.test(key) ? key : quote(key);
+    }
+
+    function className(value) {
+        try {
+            const proto = getPrototypeOf(value);
+            if (proto === null) {
+                return null;
+            }
+            const ctor = getOwnPropertyDescriptor(proto, 'constructor')?.value;
+            if (typeof ctor === 'function' && typeof ctor.name === 'string' && ctor.name !== '') {
+                return ctor.name;
+            }
+        } catch {
+            // a proxy or an exotic prototype chain
+        }
+        return 'Object';
+    }
+
+    function tag(value) {
+        return objectToString.call(value).slice(8, -1);
+    }
+
+    function formatPrimitive(value, nested) {
+        switch (typeof value) {
+        case 'string':
+            return nested ? quote(value) : value;
+        case 'number':
+            return Object.is(value, -0) ? '-0' : String(value);
+        case 'bigint':
+            return `${value}n`;
+        case 'symbol':
+            return value.toString();
+        case 'undefined':
+            return 'undefined';
+        default:
+            return String(value);
+        }
+    }
+
+    function formatFunction(fn) {
+        let source = '';
+        try {
+            source = functionToString.call(fn);
+        } catch {
+            // revoked proxies
+        }
+        const name = fn.name ? `${fn.name}` : '';
+        if (source.startsWith('class')) {
+            return name ? `[class ${name}]` : '[class (anonymous)]';
+        }
+        const kind = tag(fn) === 'AsyncFunction' ? 'AsyncFunction'
+            : tag(fn) === 'GeneratorFunction' ? 'GeneratorFunction'
+            : tag(fn) === 'AsyncGeneratorFunction' ? 'AsyncGeneratorFunction'
+            : 'Function';
+        return name ? `[${kind}: ${name}]` : `[${kind} (anonymous)]`;
+    }
+
+    function formatError(error) {
+        let text;
+        try {
+            text = errorToString.call(error);
+        } catch {
+            text = '[Error]';
+        }
+        const stack = typeof error.stack === 'string' ? native.mapStack(error.stack).trimEnd() : '';
+        if (stack === '') {
+            return `[${text}]`;
+        }
+        // SpiderMonkey stacks are frames only, browsers show the message first
+        const frames = stack.split('\n').map((frame) => `    at ${frame}`).join('\n');
+        let out = `${text}\n${frames}`;
+        if (error.cause !== undefined) {
+            out += `\n  [cause]: ${inspect(error.cause)}`;
+        }
+        return out;
+    }
+
+    // a plain list of entries, broken over lines when it gets long
+    function wrap(open, items, close, indent) {
+        if (items.length === 0) {
+            return `${open}${close}`;
+        }
+        const single = `${open} ${items.join(', ')} ${close}`;
+        if (single.length <= 72 && !single.includes('\n')) {
+            return single;
+        }
+        const pad = '  '.repeat(indent + 1);
+        const body = items.map((item) => pad + item.split('\n').join(`\n${pad}`)).join(',\n');
+        return `${open}\n${body}\n${'  '.repeat(indent)}${close}`;
+    }
+
+    function ownEntries(obj, seen, depth, skipIndices) {
+        const items = [];
+        const keys = [...getOwnPropertyNames(obj), ...getOwnPropertySymbols(obj)];
+        for (const key of keys) {
+            if (skipIndices && typeof key === 'string' && String(key >>> 0) === key) {
+                continue;
+            }
+            const desc = getOwnPropertyDescriptor(obj, key);
+            if (desc === undefined || !desc.enumerable) {
+                continue;
+            }
+            if (items.length >= MAX_ITEMS) {
+                items.push(`... ${keys.length - MAX_ITEMS} more items`);
+                break;
+            }
+            let value;
+            if ('value' in desc) {
+                value = formatValue(desc.value, seen, depth + 1);
+            } else {
+                value = desc.get && desc.set ? '[Getter/Setter]' : desc.get ? '[Getter]' : '[Setter]';
+            }
+            items.push(`${formatKey(key)}: ${value}`);
+        }
+        return items;
+    }
+
+    function formatObject(value, seen, depth) {
+        const name = className(value);
+        const kind = tag(value);
+
+        if (typeof value === 'function') {
+            const base = formatFunction(value);
+            const props = ownEntries(value, seen, depth, false);
+            return props.length === 0 ? base : wrap(`${base} {`, props, '}', depth);
+        }
+        if (value instanceof Error || kind === 'Error') {
+            return formatError(value);
+        }
+        if (kind === 'Date') {
+            const time = Date.prototype.getTime.call(value);
+            return Number.isNaN(time) ? 'Invalid Date' : dateToISOString.call(value);
+        }
+        if (kind === 'RegExp') {
+            return regExpToString.call(value);
+        }
+        if (kind === 'Promise') {
+            return 'Promise { <unknown> }';
+        }
+        if (kind === 'WeakMap' || kind === 'WeakSet' || kind === 'WeakRef') {
+            return `${kind} { <items unknown> }`;
+        }
+        if (kind === 'Symbol' || kind === 'Number' || kind === 'String' || kind === 'Boolean' || kind === 'BigInt') {
+            const inner = formatPrimitive(value.valueOf(), true);
+            return `[${kind}: ${inner}]`;
+        }
+
+        if (depth > MAX_DEPTH) {
+            return Array.isArray(value) ? '[Array]' : `[${name ?? 'Object: null prototype'}]`;
+        }
+        seen.push(value);
+        try {
+            if (Array.isArray(value)) {
+                const items = [];
+                let holes = 0;
+                const flushHoles = () => {
+                    if (holes > 0) {
+                        items.push(`<${holes} empty item${holes > 1 ? 's' : ''}>`);
+                        holes = 0;
+                    }
+                };
+                const shown = Math.min(value.length, MAX_ITEMS);
+                for (let i = 0; i < shown; i++) {
+                    if (!Object.prototype.hasOwnProperty.call(value, i)) {
+                        holes++;
+                        continue;
+                    }
+                    flushHoles();
+                    items.push(formatValue(value[i], seen, depth + 1));
+                }
+                flushHoles();
+                if (value.length > MAX_ITEMS) {
+                    items.push(`... ${value.length - MAX_ITEMS} more items`);
+                }
+                items.push(...ownEntries(value, seen, depth, true).filter((item) => !item.startsWith('length:')));
+                const prefix = name === 'Array' ? '' : `${name ?? '[Array: null prototype]'}(${value.length}) `;
+                return prefix + wrap('[', items, ']', depth);
+            }
+            if (ArrayBuffer.isView(value) && !(value instanceof DataView)) {
+                const items = Array.from(value.subarray(0, MAX_ITEMS), (n) => formatPrimitive(n, true));
+                if (value.length > MAX_ITEMS) {
+                    items.push(`... ${value.length - MAX_ITEMS} more items`);
+                }
+                return `${name}(${value.length}) ${wrap('[', items, ']', depth)}`;
+            }
+            if (kind === 'ArrayBuffer') {
+                return `ArrayBuffer { byteLength: ${value.byteLength} }`;
+            }
+            if (kind === 'Map') {
+                const items = [];
+                for (const [k, v] of Map.prototype.entries.call(value)) {
+                    if (items.length >= MAX_ITEMS) {
+                        items.push(`... ${value.size - MAX_ITEMS} more items`);
+                        break;
+                    }
+                    items.push(`${formatValue(k, seen, depth + 1)} => ${formatValue(v, seen, depth + 1)}`);
+                }
+                return `${name}(${value.size}) ${wrap('{', items, '}', depth)}`;
+            }
+            if (kind === 'Set') {
+                const items = [];
+                for (const v of Set.prototype.values.call(value)) {
+                    if (items.length >= MAX_ITEMS) {
+                        items.push(`... ${value.size - MAX_ITEMS} more items`);
+                        break;
+                    }
+                    items.push(formatValue(v, seen, depth + 1));
+                }
+                return `${name}(${value.size}) ${wrap('{', items, '}', depth)}`;
+            }
+            const items = ownEntries(value, seen, depth, false);
+            let prefix;
+            if (name === null) {
+                prefix = '[Object: null prototype] ';
+            } else if (name === 'Object') {
+                const toStringTag = value[Symbol.toStringTag];
+                prefix = typeof toStringTag === 'string' && toStringTag !== '' ? `Object [${toStringTag}] ` : '';
+            } else {
+                prefix = `${name} `;
+            }
+            return prefix + wrap('{', items, '}', depth);
+        } finally {
+            seen.pop();
+        }
+    }
+
+    function formatValue(value, seen, depth) {
+        if ((typeof value !== 'object' || value === null) && typeof value !== 'function') {
+            return formatPrimitive(value, depth > 0);
+        }
+        if (seen.includes(value)) {
+            return '[Circular]';
+        }
+        try {
+            return formatObject(value, seen, depth);
+        } catch (e) {
+            return `[${tag(value)}: <inspection threw ${e}>]`;
+        }
+    }
+
+    function inspect(value) {
+        return formatValue(value, [], 0);
+    }
+
+    // `%s %d %i %f %o %O %j %c %%` in the first argument, the rest are appended
+    function format(args) {
+        if (args.length === 0) {
+            return '';
+        }
+        let rest = 0;
+        let out = '';
+        if (typeof args[0] === 'string') {
+            const template = args[0];
+            rest = 1;
+            let last = 0;
+            for (let i = 0; i < template.length - 1; i++) {
+                if (template[i] !== '%') {
+                    continue;
+                }
+                const spec = template[i + 1];
+                let replacement;
+                if (spec === '%') {
+                    replacement = '%';
+                } else if (rest >= args.length || !'sdifoOjc'.includes(spec)) {
+                    continue;
+                } else {
+                    const arg = args[rest++];
+                    switch (spec) {
+                    case 's':
+                        replacement = typeof arg === 'string' ? arg
+                            : typeof arg === 'bigint' ? `${arg}n`
+                            : typeof arg === 'object' && arg !== null ? inspect(arg)
+                            : formatPrimitive(arg, false);
+                        break;
+                    case 'd':
+                    case 'i': {
+                        if (typeof arg === 'bigint') {
+                            replacement = `${arg}n`;
+                            break;
+                        }
+                        const n = typeof arg === 'symbol' ? NaN : Number(arg);
+                        replacement = formatPrimitive(spec === 'i' ? Math.trunc(n) : n, false);
+                        break;
+                    }
+                    case 'f':
+                        replacement = formatPrimitive(typeof arg === 'symbol' ? NaN : parseFloat(arg), false);
+                        break;
+                    case 'j':
+                        try {
+                            replacement = JSON.stringify(arg);
+                        } catch {
+                            replacement = '[Circular]';
+                        }
+                        break;
+                    case 'c':
+                        // CSS has nowhere to go
+                        replacement = '';
+                        break;
+                    default:
+                        replacement = inspect(arg);
+                    }
+                }
+                out += template.slice(last, i) + replacement;
+                last = i + 2;
+                i++;
+            }
+            out += template.slice(last);
+        }
+        for (let i = rest; i < args.length; i++) {
+            const arg = args[i];
+            const text = typeof arg === 'string' ? arg : inspect(arg);
+            out += out === '' && i === 0 ? text : ` ${text}`;
+        }
+        return out;
+    }
+
+    // `file:line:column` of the first frame outside this file
+    function callerLocation() {
+        const stack = native.mapStack(new Error().stack ?? '');
+        for (const frame of stack.split('\n')) {
+            const at = frame.lastIndexOf('@');
+            const location = frame.slice(at + 1);
+            if (location === '' || location.startsWith('console.js:')) {
+                continue;
+            }
+            return location;
+        }
+        return '';
+    }
+
+    function callerStack() {
+        const stack = native.mapStack(new Error().stack ?? '');
+        return stack.split('\n')
+            .filter((frame) => frame !== '' && !frame.slice(frame.lastIndexOf('@') + 1).startsWith('console.js:'))
+            .map((frame) => `    at ${frame}`)
+            .join('\n');
+    }
+
+    let groupIndent = '';
+    const counts = new Map();
+    const timers = new Map();
+
+    function emit(level, text) {
+        if (groupIndent !== '') {
+            text = groupIndent + text.split('\n').join(`\n${groupIndent}`);
+        }
+        native.emit(level, text, callerLocation());
+    }
+
+    // `formatter` only runs when the level is enabled
+    function log(level, formatter) {
+        if (native.enabled(level)) {
+            emit(level, formatter());
+        }
+    }
+
+    function label(value) {
+        return value === undefined ? 'default' : `${value}`;
+    }
+
+    function elapsed(start) {
+        const ms = performanceNow() - start;
+        if (ms >= 1000) {
+            return `${(ms / 1000).toFixed(3)}s`;
+        }
+        return `${ms.toFixed(3)}ms`;
+    }
+
+    function performanceNow() {
+        return globalThis.performance?.now?.() ?? Date.now();
+    }
+
+    function renderTable(head, rows) {
+        const widths = head.map((h, i) => Math.max(h.length, ...rows.map((row) => row[i].length)) + 2);
+        const line = (left, mid, right) => left + widths.map((w) => '─'.repeat(w)).join(mid) + right;
+        const cells = (row) => '│' + row.map((cell, i) => ` ${cell}${' '.repeat(widths[i] - cell.length - 1)}`).join('│') + '│';
+        return [
+            line('┌', '┬', '┐'),
+            cells(head),
+            line('├', '┼', '┤'),
+            ...rows.map(cells),
+            line('└', '┴', '┘'),
+        ].join('\n');
+    }
+
+    function table(data, properties) {
+        if (typeof data !== 'object' || data === null) {
+            return format([data]);
+        }
+        const cell = (value) => value === undefined ? '' : formatValue(value, [], MAX_DEPTH);
+        const indexKeys = [];
+        const rowValues = [];
+        if (data instanceof Map) {
+            for (const [k, v] of data) {
+                indexKeys.push(cell(k));
+                rowValues.push(v);
+            }
+        } else if (data instanceof Set) {
+            let i = 0;
+            for (const v of data) {
+                indexKeys.push(String(i++));
+                rowValues.push(v);
+            }
+        } else {
+            for (const key of Object.keys(data)) {
+                indexKeys.push(key);
+                rowValues.push(data[key]);
+            }
+        }
+
+        const columns = [];
+        let hasValues = false;
+        for (const row of rowValues) {
+            if ((typeof row === 'object' && row !== null) || typeof row === 'function') {
+                for (const key of Object.keys(row)) {
+                    if (!columns.includes(key)) {
+                        columns.push(key);
+                    }
+                }
+            } else {
+                hasValues = true;
+            }
+        }
+        const shown = Array.isArray(properties) ? properties.map(String) : columns;
+        const head = [data instanceof Map ? '(iteration index)' : '(index)', ...shown];
+        if (data instanceof Map) {
+            head.splice(1, 0, 'Key');
+        }
+        if (hasValues) {
+            head.push('Values');
+        }
+        const rows = rowValues.map((row, i) => {
+            const isObject = (typeof row === 'object' && row !== null) || typeof row === 'function';
+            const out = data instanceof Map ? [String(i), indexKeys[i]] : [indexKeys[i]];
+            for (const key of shown) {
+                out.push(isObject && Object.prototype.hasOwnProperty.call(row, key) ? cell(row[key]) : '');
+            }
+            if (hasValues) {
+                out.push(isObject ? '' : cell(row));
+            }
+            return out;
+        });
+        return renderTable(head, rows);
+    }
+
+    const console = {
+        log(...args) {
+            log('info', () => format(args));
+        },
+        info(...args) {
+            log('info', () => format(args));
+        },
+        warn(...args) {
+            log('warn', () => format(args));
+        },
+        error(...args) {
+            log('error', () => format(args));
+        },
+        debug(...args) {
+            log('debug', () => format(args));
+        },
+        trace(...args) {
+            log('trace', () => {
+                const message = format(args);
+                return `Trace${message === '' ? '' : `: ${message}`}\n${callerStack()}`;
+            });
+        },
+        dir(value) {
+            log('info', () => inspect(value));
+        },
+        table(data, properties) {
+            log('info', () => table(data, properties));
+        },
+        assert(condition, ...args) {
+            if (condition) {
+                return;
+            }
+            log('error', () => {
+                const message = format(args);
+                return `Assertion failed${message === '' ? '' : `: ${message}`}`;
+            });
+        },
+        count(name) {
+            const key = label(name);
+            const count = (counts.get(key) ?? 0) + 1;
+            counts.set(key, count);
+            log('info', () => `${key}: ${count}`);
+        },
+        countReset(name) {
+            const key = label(name);
+            if (!counts.has(key)) {
+                log('warn', () => `Count for '${key}' does not exist`);
+                return;
+            }
+            counts.set(key, 0);
+        },
+        time(name) {
+            const key = label(name);
+            if (timers.has(key)) {
+                log('warn', () => `Timer '${key}' already exists`);
+                return;
+            }
+            timers.set(key, performanceNow());
+        },
+        timeLog(name, ...args) {
+            const key = label(name);
+            if (!timers.has(key)) {
+                log('warn', () => `Timer '${key}' does not exist`);
+                return;
+            }
+            const start = timers.get(key);
+            log('info', () => {
+                const extra = format(args);
+                return `${key}: ${elapsed(start)}${extra === '' ? '' : ` ${extra}`}`;
+            });
+        },
+        timeEnd(name) {
+            const key = label(name);
+            if (!timers.has(key)) {
+                log('warn', () => `Timer '${key}' does not exist`);
+                return;
+            }
+            const start = timers.get(key);
+            timers.delete(key);
+            log('info', () => `${key}: ${elapsed(start)}`);
+        },
+        group(...args) {
+            if (args.length > 0) {
+                log('info', () => format(args));
+            }
+            groupIndent += '  ';
+        },
+        groupCollapsed(...args) {
+            console.group(...args);
+        },
+        groupEnd() {
+            groupIndent = groupIndent.slice(0, -2);
+        },
+    };
+
+    Object.defineProperty(globalThis, 'console', { value: console, writable: true, configurable: true, enumerable: false });
+})(globalThis.__consoleNative);
+delete globalThis.__consoleNative;
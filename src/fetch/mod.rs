@@ -0,0 +1,212 @@
+use std::{
+    io,
+    ops::{DerefMut},
+    ptr::{NonNull},
+    time::{Duration},
+};
+use tokio::{
+    sync::{watch},
+};
+use mozjs::{rooted};
+use mozjs::{
+    context::{JSContext,RawJSContext},
+    conversions::{ToJSValConvertible},
+    error::{throw_type_error},
+    gc::{Handle,MutableHandle},
+    jsapi::{JSObject,Value,CallArgs},
+    jsval::{UndefinedValue,ObjectValue},
+    realm::{AutoRealm},
+    rust::wrappers2::JS_DefineFunction,
+    panic::wrap_panic,
+};
+#[allow(unused_imports)]
+use tracing::{debug,trace,instrument,warn,error,info};
+
+use crate::{
+    byte_stream::{ByteStreamParts,new_byte_stream},
+    http::client::{self,ClientRequest,ClientResponse,RedirectMode,Url},
+    realm::{evaluate},
+    runtime::{
+        conversions::{arg_string,arg_bytes,value_to_strings,new_io_error,new_uint8_array,new_object,define_value,define_property},
+        host_object::{new_host_object,host_object_data},
+        resolvable_promise::{ResolutionMarshalling,new_pending_promise,spawn_bridged},
+    },
+};
+
+/*
+ * `fetch`, `Request`, `Response`, `Headers` & `AbortController`
+ *
+ * The WHATWG classes are plain JS (`prelude.js`), built on a few
+ * natives the prelude captures from `__fetchNative` & then deletes:
+ *
+ *  - `fetch(method, url, headers, body, redirect, timeout)` starts a
+ *    request with `http::client`, returning `{ response, abort() }`.
+ *    `response` resolves to the head & a byte stream of the body.
+ *  - `encodeUtf8(string)` / `decodeUtf8(bytes)`
+ *
+ */
+
+const PRELUDE: &str = include_str!("prelude.js");
+
+/// Defines `fetch` & friends on `global_obj`
+pub fn define_fetch(realm: &mut AutoRealm, global_obj: Handle<'_,*mut JSObject>) {
+    let cx = unsafe { realm.deref_mut().raw_cx() };
+    rooted!(in(cx) let native = new_object(cx));
+    if native.get().is_null() {
+        error!("could not allocate fetch natives");
+        return;
+    }
+    unsafe {
+        JS_DefineFunction(realm,native.handle(),c"fetch".as_ptr(),Some(native_fetch),6,0,);
+        JS_DefineFunction(realm,native.handle(),c"encodeUtf8".as_ptr(),Some(encode_utf8),1,0,);
+        JS_DefineFunction(realm,native.handle(),c"decodeUtf8".as_ptr(),Some(decode_utf8),1,0,);
+    }
+    rooted!(in(cx) let native_val = ObjectValue(native.get()));
+    define_property(cx, global_obj, c"__fetchNative", native_val.handle());
+    if evaluate(realm, global_obj, "fetch.js", PRELUDE).is_err() {
+        error!("could not evaluate the fetch prelude");
+    }
+}
+
+/// Owned by the object `native.fetch` returns
+struct FetchTask {
+    abort: watch::Sender<bool>,
+}
+
+/// `native.fetch(method, url, headers, body, redirect, timeout)`
+///
+/// `headers` is a flat `[name, value, ...]` array, `body` is `null`
+/// or a `Uint8Array`, `timeout` is in milliseconds (or `undefined`).
+unsafe extern "C" fn native_fetch(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    let mut is_okay = true;
+    wrap_panic(&mut || {
+        let args = unsafe { CallArgs::from_vp(vp, argc) };
+        let request = match fetch_request(ctx, &args) {
+            Ok(request) => request,
+            Err(msg) => {
+                unsafe { throw_type_error(ctx, &format!("fetch: {}", msg)) };
+                is_okay = false;
+                return;
+            }
+        };
+        let (abort, aborted) = watch::channel(false);
+        rooted!(in(ctx) let task = new_host_object(ctx, FetchTask { abort }));
+        if task.get().is_null() {
+            is_okay = false;
+            return;
+        }
+        let Some((promise_id, promise)) = new_pending_promise(ctx, "fetch") else {
+            is_okay = false;
+            return;
+        };
+        rooted!(in(ctx) let promise_val = ObjectValue(promise));
+        define_property(ctx, task.handle(), c"response", promise_val.handle());
+        let mut safe_ctx = unsafe { JSContext::from_ptr(NonNull::new(ctx).unwrap()) };
+        unsafe { JS_DefineFunction(&mut safe_ctx, task.handle(), c"abort".as_ptr(), Some(fetch_abort), 0, 0) };
+        args.rval().set(ObjectValue(task.get()));
+        spawn_bridged(promise_id, client::send(request, aborted), bridge_fetch);
+    });
+    is_okay
+}
+
+fn fetch_request(ctx: *mut RawJSContext, args: &CallArgs) -> Result<ClientRequest,&'static str> {
+    let method = arg_string(ctx, args, 0).ok_or("method must be a string")?;
+    let url = Url::parse(&arg_string(ctx, args, 1).ok_or("url must be a string")?)?;
+    let flat = match args.argc_ {
+        0..=2 => Vec::new(),
+        _ => value_to_strings(ctx, unsafe { Handle::from_raw(args.get(2)) }).ok_or("headers must be an array")?,
+    };
+    let headers = flat.chunks_exact(2).map(|pair| (pair[0].clone(), pair[1].clone())).collect();
+    let body = match args.argc_ {
+        0..=3 => None,
+        _ if args.get(3).is_null_or_undefined() => None,
+        _ => Some(arg_bytes(ctx, args, 3).ok_or("body must be a Uint8Array")?),
+    };
+    let redirect = match arg_string(ctx, args, 4) {
+        None => RedirectMode::Follow,
+        Some(mode) => RedirectMode::parse(&mode).ok_or("redirect must be 'follow', 'manual' or 'error'")?,
+    };
+    let timeout = match args.argc_ {
+        0..=5 => None,
+        _ if args.get(5).is_number() && args.get(5).to_number() >= 0.0 => Some(Duration::from_secs_f64(args.get(5).to_number() / 1000.0)),
+        _ if args.get(5).is_undefined() => None,
+        _ => return Err("timeout must be a non-negative number"),
+    };
+    Ok(ClientRequest { method, url, headers, body, redirect, timeout })
+}
+
+/// `task.abort()`, fails the request, or its body once the head arrived
+unsafe extern "C" fn fetch_abort(_ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    wrap_panic(&mut || {
+        let args = unsafe { CallArgs::from_vp(vp, argc) };
+        let this = args.thisv();
+        if this.is_object() {
+            if let Some(task) = unsafe { host_object_data::<FetchTask>(this.to_object()) } {
+                task.abort.send_replace(true);
+            }
+        }
+        args.rval().set(UndefinedValue());
+    });
+    true
+}
+
+/// Resolves to `{ status, statusText, url, redirected, headers, body }`
+fn bridge_fetch(result: io::Result<ClientResponse>) -> ResolutionMarshalling {
+    Box::new(move |realm: &mut AutoRealm, _promise: Handle<'_, *mut JSObject>, _global: Handle<'_, *mut JSObject>, mut ok: MutableHandle<'_,Value>, err: MutableHandle<'_,Value>| {
+        let cx = unsafe { realm.deref_mut().raw_cx() };
+        let response = match result {
+            Ok(response) => response,
+            Err(e) => {
+                new_io_error(cx, &e, err);
+                return;
+            }
+        };
+        rooted!(in(cx) let obj = new_object(cx));
+        if obj.get().is_null() {
+            return;
+        }
+        rooted!(in(cx) let body = new_byte_stream(cx, ByteStreamParts::new(Some(response.body), None)));
+        if body.get().is_null() {
+            return;
+        }
+        let flat: Vec<String> = response.headers.into_iter().flat_map(|(k, v)| [k, v]).collect();
+        define_value(cx, obj.handle(), c"status", &response.status);
+        define_value(cx, obj.handle(), c"statusText", &response.status_text);
+        define_value(cx, obj.handle(), c"url", &response.url);
+        define_value(cx, obj.handle(), c"redirected", &response.redirected);
+        define_value(cx, obj.handle(), c"headers", &flat);
+        rooted!(in(cx) let body_val = ObjectValue(body.get()));
+        define_property(cx, obj.handle(), c"body", body_val.handle());
+        ok.set(ObjectValue(obj.get()));
+    }) as ResolutionMarshalling
+}
+
+/// `native.encodeUtf8(string)`, returns a `Uint8Array`
+unsafe extern "C" fn encode_utf8(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    let mut is_okay = true;
+    wrap_panic(&mut || {
+        let args = unsafe { CallArgs::from_vp(vp, argc) };
+        let text = arg_string(ctx, &args, 0).unwrap_or_default();
+        rooted!(in(ctx) let mut bytes = UndefinedValue());
+        is_okay = new_uint8_array(ctx, text.as_bytes(), bytes.handle_mut());
+        args.rval().set(bytes.get());
+    });
+    is_okay
+}
+
+/// `native.decodeUtf8(bytes)`, invalid sequences become U+FFFD
+unsafe extern "C" fn decode_utf8(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    let mut is_okay = true;
+    wrap_panic(&mut || {
+        let args = unsafe { CallArgs::from_vp(vp, argc) };
+        let Some(bytes) = arg_bytes(ctx, &args, 0) else {
+            unsafe { throw_type_error(ctx, "decodeUtf8: expected a Uint8Array or ArrayBuffer") };
+            is_okay = false;
+            return;
+        };
+        rooted!(in(ctx) let mut text = UndefinedValue());
+        unsafe { String::from_utf8_lossy(&bytes).to_jsval(ctx, text.handle_mut()) };
+        args.rval().set(text.get());
+    });
+    is_okay
+}
@@ -0,0 +1,626 @@
+// `fetch`, `Request`, `Response`, `Headers`, `AbortController` & `AbortSignal`
+//
+// Evaluated once per realm by `fetch::define_fetch`, after the
+// streams prelude. Everything touching the network goes through
+// `__fetchNative`, which is removed from the global before any user
+// script runs.
+(function (native) {
+    'use strict';
+
+    const sleep = globalThis.sleep_ms;
+    const { isDisturbed } = globalThis.__streamsInternal;
+    const kBody = Symbol('body');
+    const kCreate = Symbol('create');
+    const kTimeout = Symbol('timeout');
+    const kSignal = Symbol('signal');
+
+    // --- errors ---------------------------------------------------------
+
+    const DOMException = globalThis.DOMException ?? class DOMException extends Error {
+        constructor(message = '', name = 'Error') {
+            super(message);
+            this.name = name;
+        }
+    };
+
+    // --- AbortController / AbortSignal -----------------------------------
+
+    class AbortSignal {
+        #aborted = false;
+        #reason = undefined;
+        #listeners = [];
+        onabort = null;
+
+        constructor(token) {
+            if (token !== kCreate) {
+                throw new TypeError('Illegal constructor');
+            }
+        }
+
+        get aborted() { return this.#aborted; }
+        get reason() { return this.#reason; }
+
+        throwIfAborted() {
+            if (this.#aborted) {
+                throw this.#reason;
+            }
+        }
+
+        addEventListener(type, listener, options) {
+            if (type !== 'abort' || listener == null) {
+                return;
+            }
+            const once = typeof options === 'object' && options !== null && !!options.once;
+            if (!this.#listeners.some((l) => l.listener === listener)) {
+                this.#listeners.push({ listener, once });
+            }
+        }
+
+        removeEventListener(type, listener) {
+            if (type === 'abort') {
+                this.#listeners = this.#listeners.filter((l) => l.listener !== listener);
+            }
+        }
+
+        // not part of the public API, used by `AbortController` & friends
+        [kSignal](reason) {
+            if (this.#aborted) {
+                return;
+            }
+            this.#aborted = true;
+            this.#reason = reason === undefined ? new DOMException('This operation was aborted', 'AbortError') : reason;
+            const event = { type: 'abort', target: this };
+            const listeners = this.#listeners;
+            this.#listeners = listeners.filter((l) => !l.once);
+            for (const handler of [this.onabort, ...listeners.map((l) => l.listener)]) {
+                try {
+                    if (typeof handler === 'function') {
+                        handler.call(this, event);
+                    } else if (handler && typeof handler.handleEvent === 'function') {
+                        handler.handleEvent(event);
+                    }
+                } catch (e) {
+                    queueMicrotask(() => { throw e; });
+                }
+            }
+        }
+
+        static abort(reason) {
+            const signal = new AbortSignal(kCreate);
+            signal[kSignal](reason);
+            return signal;
+        }
+
+        // keeps the event loop alive until it fires
+        static timeout(ms) {
+            const signal = new AbortSignal(kCreate);
+            sleep(ms).then(() => signal[kSignal](new DOMException('The operation timed out', 'TimeoutError')));
+            return signal;
+        }
+
+        static any(signals) {
+            const signal = new AbortSignal(kCreate);
+            for (const source of signals) {
+                if (source.aborted) {
+                    signal[kSignal](source.reason);
+                    return signal;
+                }
+            }
+            for (const source of signals) {
+                source.addEventListener('abort', () => signal[kSignal](source.reason), { once: true });
+            }
+            return signal;
+        }
+    }
+
+    class AbortController {
+        #signal = new AbortSignal(kCreate);
+        get signal() { return this.#signal; }
+        abort(reason) { this.#signal[kSignal](reason); }
+    }
+
+    // --- Headers ----------------------------------------------------------
+
+    const TOKEN = /^[!#$%&'*+\-.^_`|~0-9A-Za-z]+$/;
+
+    function normalizeName(name) {
+        name = String(name);
+        if (!TOKEN.test(name)) {
+            throw new TypeError(`Invalid header name '${name}'`);
+        }
+        return name.toLowerCase();
+    }
+
+    function normalizeValue(value) {
+        value = String(value).replace(/^[\t\n\r ]+|[\t\n\r ]+$/g, '');
+        if (/[\0\r\n]/.test(value)) {
+            throw new TypeError('Invalid header value');
+        }
+        return value;
+    }
+
+    class Headers {
+        // [lowercased name, value] in insertion order
+        #list = [];
+
+        constructor(init) {
+            if (init == null) {
+                return;
+            }
+            if (init instanceof Headers) {
+                for (const [name, value] of init.#list) {
+                    this.#list.push([name, value]);
+                }
+            } else if (typeof init[Symbol.iterator] === 'function') {
+                for (const pair of init) {
+                    const [name, value, ...rest] = pair;
+                    if (rest.length !== 0 || value === undefined) {
+                        throw new TypeError('Headers pairs must be [name, value]');
+                    }
+                    this.append(name, value);
+                }
+            } else if (typeof init === 'object') {
+                for (const name of Object.keys(init)) {
+                    this.append(name, init[name]);
+                }
+            } else {
+                throw new TypeError('Headers init must be an object or iterable');
+            }
+        }
+
+        append(name, value) {
+            this.#list.push([normalizeName(name), normalizeValue(value)]);
+        }
+
+        delete(name) {
+            name = normalizeName(name);
+            this.#list = this.#list.filter(([n]) => n !== name);
+        }
+
+        get(name) {
+            name = normalizeName(name);
+            const values = this.#list.filter(([n]) => n === name).map(([, v]) => v);
+            return values.length === 0 ? null : values.join(', ');
+        }
+
+        getSetCookie() {
+            return this.#list.filter(([n]) => n === 'set-cookie').map(([, v]) => v);
+        }
+
+        has(name) {
+            name = normalizeName(name);
+            return this.#list.some(([n]) => n === name);
+        }
+
+        set(name, value) {
+            name = normalizeName(name);
+            value = normalizeValue(value);
+            const index = this.#list.findIndex(([n]) => n === name);
+            if (index === -1) {
+                this.#list.push([name, value]);
+            } else {
+                this.#list[index][1] = value;
+                this.#list = this.#list.filter(([n], i) => n !== name || i === index);
+            }
+        }
+
+        forEach(callback, thisArg) {
+            for (const [name, value] of this) {
+                callback.call(thisArg, value, name, this);
+            }
+        }
+
+        *entries() {
+            const names = [...new Set(this.#list.map(([n]) => n))].sort();
+            for (const name of names) {
+                if (name === 'set-cookie') {
+                    for (const value of this.getSetCookie()) {
+                        yield [name, value];
+                    }
+                } else {
+                    yield [name, this.get(name)];
+                }
+            }
+        }
+
+        *keys() {
+            for (const [name] of this.entries()) {
+                yield name;
+            }
+        }
+
+        *values() {
+            for (const [, value] of this.entries()) {
+                yield value;
+            }
+        }
+
+        [Symbol.iterator]() {
+            return this.entries();
+        }
+
+        // `[name, value, ...]` for the native side, duplicates kept
+        static [kCreate](headers) {
+            return headers.#list.flat();
+        }
+    }
+
+    // --- bodies -----------------------------------------------------------
+
+    // a stream of the one chunk `bytes`
+    function streamOf(bytes) {
+        return new ReadableStream({
+            start(controller) {
+                controller.enqueue(bytes);
+                controller.close();
+            },
+        });
+    }
+
+    function toBytes(data) {
+        if (data instanceof Uint8Array) {
+            return data;
+        }
+        if (data instanceof ArrayBuffer) {
+            return new Uint8Array(data);
+        }
+        if (ArrayBuffer.isView(data)) {
+            return new Uint8Array(data.buffer, data.byteOffset, data.byteLength);
+        }
+        return null;
+    }
+
+    function concat(chunks) {
+        const length = chunks.reduce((n, c) => n + c.byteLength, 0);
+        const out = new Uint8Array(length);
+        let offset = 0;
+        for (const chunk of chunks) {
+            out.set(chunk, offset);
+            offset += chunk.byteLength;
+        }
+        return out;
+    }
+
+    // `[body, content type]` for a `BodyInit`
+    function extractBody(init) {
+        if (init == null) {
+            return [null, null];
+        }
+        if (init instanceof ReadableStream) {
+            return [init, null];
+        }
+        if (typeof init === 'string') {
+            return [native.encodeUtf8(init), 'text/plain;charset=UTF-8'];
+        }
+        const bytes = toBytes(init);
+        if (bytes !== null) {
+            return [bytes.slice(), null];
+        }
+        if (typeof init[Symbol.asyncIterator] === 'function') {
+            const iterator = init[Symbol.asyncIterator]();
+            return [new ReadableStream({
+                async pull(controller) {
+                    const { value, done } = await iterator.next();
+                    if (done) {
+                        controller.close();
+                    } else {
+                        controller.enqueue(toBytes(value) ?? native.encodeUtf8(String(value)));
+                    }
+                },
+                async cancel(reason) {
+                    await iterator.return?.(reason);
+                },
+            }, { highWaterMark: 0 }), null];
+        }
+        return [native.encodeUtf8(String(init)), 'text/plain;charset=UTF-8'];
+    }
+
+    // shared by `Request` & `Response`
+    class Body {
+        // null, a Uint8Array or a ReadableStream
+        [kBody] = null;
+        #used = false;
+
+        get body() {
+            if (this[kBody] instanceof Uint8Array) {
+                this[kBody] = streamOf(this[kBody]);
+            }
+            return this[kBody];
+        }
+
+        get bodyUsed() {
+            return this.#used || (this[kBody] instanceof ReadableStream && isDisturbed(this[kBody]));
+        }
+
+        async #consume() {
+            if (this.bodyUsed) {
+                throw new TypeError('Body has already been consumed');
+            }
+            this.#used = true;
+            const body = this[kBody];
+            if (body === null) {
+                return new Uint8Array(0);
+            }
+            if (body instanceof Uint8Array) {
+                return body;
+            }
+            if (body.locked) {
+                throw new TypeError('Body is locked');
+            }
+            const chunks = [];
+            for await (const chunk of body) {
+                chunks.push(chunk);
+            }
+            return concat(chunks);
+        }
+
+        async arrayBuffer() {
+            const bytes = await this.#consume();
+            return bytes.buffer.slice(bytes.byteOffset, bytes.byteOffset + bytes.byteLength);
+        }
+
+        async bytes() {
+            return (await this.#consume()).slice();
+        }
+
+        async text() {
+            return native.decodeUtf8(await this.#consume());
+        }
+
+        async json() {
+            return JSON.parse(await this.text());
+        }
+
+        // the body for a clone, splitting a stream in two
+        [kCreate]() {
+            if (this.bodyUsed) {
+                throw new TypeError('Body has already been consumed');
+            }
+            if (this[kBody] instanceof ReadableStream) {
+                const [a, b] = this[kBody].tee();
+                this[kBody] = a;
+                return b;
+            }
+            return this[kBody];
+        }
+    }
+
+    // --- Request ----------------------------------------------------------
+
+    const METHODS = ['DELETE', 'GET', 'HEAD', 'OPTIONS', 'POST', 'PUT', 'PATCH'];
+
+    function normalizeMethod(method) {
+        method = String(method);
+        if (!TOKEN.test(method)) {
+            throw new TypeError(`Invalid method '${method}'`);
+        }
+        const upper = method.toUpperCase();
+        if (['CONNECT', 'TRACE', 'TRACK'].includes(upper)) {
+            throw new TypeError(`Forbidden method '${method}'`);
+        }
+        return METHODS.includes(upper) ? upper : method;
+    }
+
+    class Request extends Body {
+        #url;
+        #method;
+        #headers;
+        #redirect;
+        #signal;
+
+        constructor(input, init = {}) {
+            super();
+            init ??= {};
+            const base = input instanceof Request ? input : null;
+            this.#url = base ? base.url : String(input);
+            if (!/^[a-z][a-z0-9+.-]*:/i.test(this.#url)) {
+                throw new TypeError(`Invalid URL '${this.#url}'`);
+            }
+            this.#method = init.method !== undefined ? normalizeMethod(init.method) : (base ? base.method : 'GET');
+            this.#headers = new Headers(init.headers !== undefined ? init.headers : base?.headers);
+            this.#redirect = init.redirect ?? base?.redirect ?? 'follow';
+            if (!['follow', 'manual', 'error'].includes(this.#redirect)) {
+                throw new TypeError(`Invalid redirect mode '${this.#redirect}'`);
+            }
+            this.#signal = init.signal ?? base?.signal ?? new AbortSignal(kCreate);
+            this[kTimeout] = init.timeout ?? base?.[kTimeout];
+
+            if (init.body != null) {
+                if (this.#method === 'GET' || this.#method === 'HEAD') {
+                    throw new TypeError('Request with GET/HEAD method cannot have body');
+                }
+                const [body, type] = extractBody(init.body);
+                this[kBody] = body;
+                if (type !== null && !this.#headers.has('content-type')) {
+                    this.#headers.set('content-type', type);
+                }
+            } else if (base !== null) {
+                this[kBody] = base[kCreate]();
+            }
+        }
+
+        get url() { return this.#url; }
+        get method() { return this.#method; }
+        get headers() { return this.#headers; }
+        get redirect() { return this.#redirect; }
+        get signal() { return this.#signal; }
+
+        clone() {
+            return new Request(this);
+        }
+    }
+
+    // --- Response ---------------------------------------------------------
+
+    class Response extends Body {
+        #status = 200;
+        #statusText = '';
+        #headers;
+        #type = 'default';
+        #url = '';
+        #redirected = false;
+
+        constructor(body = null, init = {}) {
+            super();
+            init ??= {};
+            const status = init.status ?? 200;
+            if (!Number.isInteger(status) || status < 200 || status > 599) {
+                throw new RangeError(`Invalid status ${status}`);
+            }
+            this.#status = status;
+            this.#statusText = String(init.statusText ?? '');
+            this.#headers = new Headers(init.headers);
+            if (body != null) {
+                if ([101, 204, 205, 304].includes(status)) {
+                    throw new TypeError(`Response with status ${status} cannot have body`);
+                }
+                const [bytes, type] = extractBody(body);
+                this[kBody] = bytes;
+                if (type !== null && !this.#headers.has('content-type')) {
+                    this.#headers.set('content-type', type);
+                }
+            }
+        }
+
+        get status() { return this.#status; }
+        get ok() { return this.#status >= 200 && this.#status <= 299; }
+        get statusText() { return this.#statusText; }
+        get headers() { return this.#headers; }
+        get type() { return this.#type; }
+        get url() { return this.#url; }
+        get redirected() { return this.#redirected; }
+
+        clone() {
+            return Response[kCreate]({
+                status: this.#status,
+                statusText: this.#statusText,
+                headers: new Headers(this.#headers),
+                type: this.#type,
+                url: this.#url,
+                redirected: this.#redirected,
+                body: this[kCreate](),
+            });
+        }
+
+        static error() {
+            return Response[kCreate]({ status: 0, type: 'error' });
+        }
+
+        static redirect(url, status = 302) {
+            if (![301, 302, 303, 307, 308].includes(status)) {
+                throw new RangeError(`Invalid redirect status ${status}`);
+            }
+            return Response[kCreate]({ status, headers: new Headers({ location: String(url) }) });
+        }
+
+        static json(data, init = {}) {
+            const response = new Response(JSON.stringify(data), init);
+            response.#headers.set('content-type', 'application/json');
+            return response;
+        }
+
+        // skips the validation done by the constructor
+        static [kCreate](parts) {
+            const response = new Response();
+            response.#status = parts.status;
+            response.#statusText = parts.statusText ?? '';
+            response.#headers = parts.headers ?? new Headers();
+            response.#type = parts.type ?? 'default';
+            response.#url = parts.url ?? '';
+            response.#redirected = parts.redirected ?? false;
+            response[kBody] = parts.body ?? null;
+            return response;
+        }
+    }
+
+    // --- fetch ------------------------------------------------------------
+
+    async function fetch(input, init) {
+        const request = new Request(input, init);
+        const signal = request.signal;
+        signal.throwIfAborted();
+
+        let body = null;
+        if (request[kBody] !== null) {
+            // request bodies are buffered before sending
+            body = await request.bytes();
+        }
+        signal.throwIfAborted();
+
+        const task = native.fetch(
+            request.method,
+            request.url,
+            Headers[kCreate](request.headers),
+            body,
+            request.redirect,
+            request[kTimeout],
+        );
+        const onAbort = () => task.abort();
+        signal.addEventListener('abort', onAbort);
+
+        let head;
+        try {
+            head = await task.response;
+        } catch (e) {
+            signal.removeEventListener('abort', onAbort);
+            if (signal.aborted) {
+                throw signal.reason;
+            }
+            throw new TypeError(`fetch failed: ${e.message}`, { cause: e });
+        }
+
+        const headers = new Headers();
+        for (let i = 0; i + 1 < head.headers.length; i += 2) {
+            headers.append(head.headers[i], head.headers[i + 1]);
+        }
+        const stream = head.body;
+        const finish = () => {
+            signal.removeEventListener('abort', onAbort);
+            stream.close();
+        };
+        // reads only when pulled, so an unread body applies backpressure
+        const responseBody = new ReadableStream({
+            async pull(controller) {
+                let chunk;
+                try {
+                    chunk = await stream.read();
+                } catch (e) {
+                    finish();
+                    if (signal.aborted) {
+                        throw signal.reason;
+                    }
+                    throw new TypeError(`fetch body failed: ${e.message}`, { cause: e });
+                }
+                if (chunk === null) {
+                    finish();
+                    controller.close();
+                } else {
+                    controller.enqueue(chunk);
+                }
+            },
+            cancel() {
+                task.abort();
+                finish();
+            },
+        }, { highWaterMark: 0 });
+
+        if (request.method === 'HEAD') {
+            finish();
+        }
+        return Response[kCreate]({
+            status: head.status,
+            statusText: head.statusText,
+            headers,
+            type: 'basic',
+            url: head.url,
+            redirected: head.redirected,
+            body: request.method === 'HEAD' ? null : responseBody,
+        });
+    }
+
+    for (const [name, value] of Object.entries({ fetch, Request, Response, Headers, AbortController, AbortSignal, DOMException })) {
+        Object.defineProperty(globalThis, name, { value, writable: true, configurable: true, enumerable: false });
+    }
+})(globalThis.__fetchNative);
+delete globalThis.__fetchNative;
+delete globalThis.__streamsInternal;
@@ -0,0 +1,441 @@
+use std::{
+    io,
+    ops::{DerefMut},
+    time::{SystemTime,UNIX_EPOCH},
+};
+use mozjs::{rooted};
+use futures_util::{
+    stream::{self},
+};
+use mozjs::{
+    context::{RawJSContext},
+    conversions::{ToJSValConvertible},
+    error::{throw_type_error},
+    gc::{Handle,MutableHandle},
+    jsapi::{JSObject,Value,CallArgs},
+    jsval::{UndefinedValue,ObjectValue},
+    realm::{AutoRealm},
+    rust::wrappers2::JS_DefineFunction,
+    panic::wrap_panic,
+};
+#[allow(unused_imports)]
+use tracing::{debug,trace,instrument,warn,error,info};
+
+use crate::{
+    byte_stream::{ByteStreamParts,new_byte_stream},
+    runtime::{
+        conversions::{arg_string,arg_exact_string,arg_bytes,new_uint8_array,new_io_error,new_object,define_value,define_property,option_bool},
+        resolvable_promise::{ResolutionMarshalling,promise_entry_point},
+        stream_iterator::{new_stream_iterator},
+    },
+};
+
+/*
+ * `fs` global, backed by `tokio::fs`
+ *
+ * Every function returns a promise, bar `entries` which returns an
+ * async iterator. Errors reject with an `Error` whose `code` is the
+ * errno name (`ENOENT`, `EACCES`, ...).
+ *
+ */
+
+/// What an fs operation resolves to, converted to JS by `bridge_fs`
+enum FsValue {
+    Unit,
+    Bytes(Vec<u8>),
+    Text(String),
+    Entries(Vec<String>),
+    Stat(FsStat),
+    File(ByteStreamParts),
+}
+
+struct FsStat {
+    size: u64,
+    is_file: bool,
+    is_directory: bool,
+    is_symbolic_link: bool,
+    mode: u32,
+    mtime_ms: Option<f64>,
+    atime_ms: Option<f64>,
+    birthtime_ms: Option<f64>,
+}
+impl From<std::fs::Metadata> for FsStat {
+    fn from(meta: std::fs::Metadata) -> Self {
+        use std::os::unix::fs::PermissionsExt;
+        fn millis(t: io::Result<SystemTime>) -> Option<f64> {
+            t.ok()?.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs_f64() * 1000.0)
+        }
+        FsStat {
+            size: meta.len(),
+            is_file: meta.is_file(),
+            is_directory: meta.is_dir(),
+            is_symbolic_link: meta.file_type().is_symlink(),
+            mode: meta.permissions().mode(),
+            mtime_ms: millis(meta.modified()),
+            atime_ms: millis(meta.accessed()),
+            birthtime_ms: millis(meta.created()),
+        }
+    }
+}
+
+/// Defines the `fs` object on `global_obj`
+pub fn define_fs(realm: &mut AutoRealm, global_obj: Handle<'_,*mut JSObject>) {
+    let cx = unsafe { realm.deref_mut().raw_cx() };
+    rooted!(in(cx) let fs = new_object(cx));
+    if fs.get().is_null() {
+        error!("could not allocate fs object");
+        return;
+    }
+    unsafe {
+        JS_DefineFunction(realm,fs.handle(),c"readFile".as_ptr(),Some(fs_read_file),2,0,);
+        JS_DefineFunction(realm,fs.handle(),c"writeFile".as_ptr(),Some(fs_write_file),2,0,);
+        JS_DefineFunction(realm,fs.handle(),c"readdir".as_ptr(),Some(fs_readdir),1,0,);
+        JS_DefineFunction(realm,fs.handle(),c"entries".as_ptr(),Some(fs_entries),1,0,);
+        JS_DefineFunction(realm,fs.handle(),c"stat".as_ptr(),Some(fs_stat),1,0,);
+        JS_DefineFunction(realm,fs.handle(),c"lstat".as_ptr(),Some(fs_lstat),1,0,);
+        JS_DefineFunction(realm,fs.handle(),c"mkdir".as_ptr(),Some(fs_mkdir),2,0,);
+        JS_DefineFunction(realm,fs.handle(),c"rename".as_ptr(),Some(fs_rename),2,0,);
+        JS_DefineFunction(realm,fs.handle(),c"unlink".as_ptr(),Some(fs_unlink),1,0,);
+        JS_DefineFunction(realm,fs.handle(),c"open".as_ptr(),Some(fs_open),2,0,);
+    }
+    rooted!(in(cx) let fs_val = ObjectValue(fs.get()));
+    define_property(cx, global_obj, c"fs", fs_val.handle());
+}
+
+/// A missing (or non string) path is a `TypeError`, never `"undefined"`
+fn path_arg(ctx: *mut RawJSContext, args: &CallArgs, index: u32) -> Result<String,&'static str> {
+    arg_exact_string(ctx, args, index).ok_or("path must be a string")
+}
+
+/// Whether `readFile`'s encoding asks for text, UTF-8 is the only one
+///
+/// Like `TextDecoder`, labels ignore case & surrounding whitespace. An
+/// encoding that isn't understood is a `TypeError` rather than bytes.
+fn text_encoding_arg(ctx: *mut RawJSContext, args: &CallArgs, index: u32) -> Result<bool,&'static str> {
+    if args.get(index).is_null_or_undefined() {
+        return Ok(false);
+    }
+    match arg_exact_string(ctx, args, index).map(|e| e.trim().to_ascii_lowercase()).as_deref() {
+        Some("utf8" | "utf-8") => Ok(true),
+        _ => Err("encoding must be \"utf8\" or left out"),
+    }
+}
+
+/// `fs.readFile(path, encoding)`, resolves to a string for `"utf8"`, a `Uint8Array` without an encoding
+unsafe extern "C" fn fs_read_file(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    promise_entry_point(ctx, argc, vp, "fs.readFile", bridge_fs, |ctx, args| {
+        let path = path_arg(ctx, args, 0)?;
+        let as_text = text_encoding_arg(ctx, args, 1)?;
+        Ok(async move {
+            let bytes = tokio::fs::read(path).await?;
+            if as_text {
+                String::from_utf8(bytes)
+                    .map(FsValue::Text)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            } else {
+                Ok(FsValue::Bytes(bytes))
+            }
+        })
+    })
+}
+
+/// `fs.writeFile(path, data)`, `data` is a string, `Uint8Array` or `ArrayBuffer`
+unsafe extern "C" fn fs_write_file(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    promise_entry_point(ctx, argc, vp, "fs.writeFile", bridge_fs, |ctx, args| {
+        let path = path_arg(ctx, args, 0)?;
+        let data = arg_bytes(ctx, args, 1).ok_or("data must be a string, Uint8Array or ArrayBuffer")?;
+        Ok(async move {
+            tokio::fs::write(path, data).await?;
+            Ok(FsValue::Unit)
+        })
+    })
+}
+
+/// `fs.readdir(path)`, resolves to an array of names
+unsafe extern "C" fn fs_readdir(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    promise_entry_point(ctx, argc, vp, "fs.readdir", bridge_fs, |ctx, args| {
+        let path = path_arg(ctx, args, 0)?;
+        Ok(async move {
+            let mut dir = tokio::fs::read_dir(path).await?;
+            let mut entries = Vec::new();
+            while let Some(entry) = dir.next_entry().await? {
+                entries.push(entry.file_name().to_string_lossy().into_owned());
+            }
+            entries.sort();
+            Ok(FsValue::Entries(entries))
+        })
+    })
+}
+
+/// Where `fs.entries` is up to
+enum DirState {
+    Unopened(String),
+    Open(tokio::fs::ReadDir),
+    Done,
+}
+
+/// `fs.entries(path)`, an async iterator of names in directory order
+///
+/// Nothing is buffered, each `next()` reads one entry. Failing to
+/// open or read the directory rejects that `next()` & ends it.
+unsafe extern "C" fn fs_entries(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    let mut is_okay = true;
+    wrap_panic(&mut || {
+        let args = unsafe { CallArgs::from_vp(vp, argc) };
+        let path = match path_arg(ctx, &args, 0) {
+            Ok(path) => path,
+            Err(msg) => {
+                unsafe { throw_type_error(ctx, &format!("fs.entries: {}", msg)) };
+                is_okay = false;
+                return;
+            }
+        };
+        let entries = stream::unfold(DirState::Unopened(path), |state| async move {
+            let mut dir = match state {
+                DirState::Unopened(path) => match tokio::fs::read_dir(path).await {
+                    Ok(dir) => dir,
+                    Err(e) => return Some((Err(e), DirState::Done)),
+                },
+                DirState::Open(dir) => dir,
+                DirState::Done => return None,
+            };
+            match dir.next_entry().await {
+                Ok(Some(entry)) => {
+                    let name = entry.file_name().to_string_lossy().into_owned();
+                    Some((Ok(FsValue::Text(name)), DirState::Open(dir)))
+                }
+                Ok(None) => None,
+                Err(e) => Some((Err(e), DirState::Done)),
+            }
+        });
+        let iterator = new_stream_iterator(ctx, entries, bridge_fs);
+        if iterator.is_null() {
+            is_okay = false;
+            return;
+        }
+        args.rval().set(ObjectValue(iterator));
+    });
+    is_okay
+}
+
+/// `fs.stat(path)`, follows symlinks
+unsafe extern "C" fn fs_stat(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    promise_entry_point(ctx, argc, vp, "fs.stat", bridge_fs, |ctx, args| {
+        let path = path_arg(ctx, args, 0)?;
+        Ok(async move { Ok(FsValue::Stat(tokio::fs::metadata(path).await?.into())) })
+    })
+}
+
+/// `fs.lstat(path)`, does not follow symlinks
+unsafe extern "C" fn fs_lstat(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    promise_entry_point(ctx, argc, vp, "fs.lstat", bridge_fs, |ctx, args| {
+        let path = path_arg(ctx, args, 0)?;
+        Ok(async move { Ok(FsValue::Stat(tokio::fs::symlink_metadata(path).await?.into())) })
+    })
+}
+
+/// `fs.mkdir(path, { recursive })`
+unsafe extern "C" fn fs_mkdir(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    promise_entry_point(ctx, argc, vp, "fs.mkdir", bridge_fs, |ctx, args| {
+        let path = path_arg(ctx, args, 0)?;
+        let recursive = option_bool(ctx, unsafe { Handle::from_raw(args.get(1)) }, c"recursive");
+        Ok(async move {
+            if recursive {
+                tokio::fs::create_dir_all(path).await?;
+            } else {
+                tokio::fs::create_dir(path).await?;
+            }
+            Ok(FsValue::Unit)
+        })
+    })
+}
+
+/// `fs.rename(from, to)`
+unsafe extern "C" fn fs_rename(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    promise_entry_point(ctx, argc, vp, "fs.rename", bridge_fs, |ctx, args| {
+        let from = path_arg(ctx, args, 0)?;
+        let to = path_arg(ctx, args, 1)?;
+        Ok(async move {
+            tokio::fs::rename(from, to).await?;
+            Ok(FsValue::Unit)
+        })
+    })
+}
+
+/// `fs.unlink(path)`
+unsafe extern "C" fn fs_unlink(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    promise_entry_point(ctx, argc, vp, "fs.unlink", bridge_fs, |ctx, args| {
+        let path = path_arg(ctx, args, 0)?;
+        Ok(async move {
+            tokio::fs::remove_file(path).await?;
+            Ok(FsValue::Unit)
+        })
+    })
+}
+
+/// `fs.open(path, mode)`, resolves to a byte stream over the file
+///
+/// `mode` is `"r"` (default), `"r+"`, `"w"` (create/truncate) or
+/// `"a"` (create/append). The stream is readable and/or writable to
+/// match.
+unsafe extern "C" fn fs_open(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    promise_entry_point(ctx, argc, vp, "fs.open", bridge_fs, |ctx, args| {
+        let path = path_arg(ctx, args, 0)?;
+        let mode = arg_string(ctx, args, 1).unwrap_or_else(|| "r".to_string());
+        let mut options = tokio::fs::OpenOptions::new();
+        let (readable, writable) = match mode.as_str() {
+            "r" => (true, false),
+            "r+" => (true, true),
+            "w" => { options.create(true).truncate(true); (false, true) },
+            "a" => { options.create(true).append(true); (false, true) },
+            _ => return Err("mode must be 'r', 'r+', 'w' or 'a'"),
+        };
+        options.read(readable).write(writable);
+        Ok(async move {
+            let file = options.open(path).await?;
+            let parts = match (readable, writable) {
+                (true, true) => {
+                    let writer = file.try_clone().await?;
+                    ByteStreamParts::new(Some(Box::new(file)), Some(Box::new(writer)))
+                }
+                (true, false) => ByteStreamParts::new(Some(Box::new(file)), None),
+                _ => ByteStreamParts::new(None, Some(Box::new(file))),
+            };
+            Ok(FsValue::File(parts))
+        })
+    })
+}
+
+fn bridge_fs(result: io::Result<FsValue>) -> ResolutionMarshalling {
+    Box::new(move |realm: &mut AutoRealm, _promise: Handle<'_, *mut JSObject>, _global: Handle<'_, *mut JSObject>, mut ok: MutableHandle<'_,Value>, err: MutableHandle<'_,Value>| {
+        let cx = unsafe { realm.deref_mut().raw_cx() };
+        let value = match result {
+            Ok(value) => value,
+            Err(e) => {
+                new_io_error(cx, &e, err);
+                return;
+            }
+        };
+        match value {
+            FsValue::Unit => ok.set(UndefinedValue()),
+            FsValue::Bytes(bytes) => { new_uint8_array(cx, &bytes, ok); },
+            FsValue::Text(text) => unsafe { text.to_jsval(cx, ok) },
+            FsValue::Entries(entries) => unsafe { entries.to_jsval(cx, ok) },
+            FsValue::Stat(stat) => {
+                rooted!(in(cx) let obj = new_object(cx));
+                if obj.get().is_null() {
+                    return;
+                }
+                define_value(cx, obj.handle(), c"size", &(stat.size as f64));
+                define_value(cx, obj.handle(), c"mode", &stat.mode);
+                define_value(cx, obj.handle(), c"isFile", &stat.is_file);
+                define_value(cx, obj.handle(), c"isDirectory", &stat.is_directory);
+                define_value(cx, obj.handle(), c"isSymbolicLink", &stat.is_symbolic_link);
+                define_value(cx, obj.handle(), c"mtimeMs", &stat.mtime_ms);
+                define_value(cx, obj.handle(), c"atimeMs", &stat.atime_ms);
+                define_value(cx, obj.handle(), c"birthtimeMs", &stat.birthtime_ms);
+                ok.set(ObjectValue(obj.get()));
+            }
+            FsValue::File(parts) => {
+                let obj = new_byte_stream(cx, parts);
+                if !obj.is_null() {
+                    ok.set(ObjectValue(obj));
+                }
+            }
+        };
+    }) as ResolutionMarshalling
+}
+
+#[cfg(test)]
+mod tests {
+    use mozjs::{rooted};
+    use crate::runtime::testing::{with_runtime,new_test_global,eval_async,TempDir};
+
+    /// Runs `body` with `dir` bound to the temp dir's path
+    fn run_in(dir: &TempDir, body: &str) -> Result<String,String> {
+        let source = format!("const dir = {:?};\n{}", dir.path().display().to_string(), body);
+        with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            eval_async(ctx, global.handle(), &source)
+        })
+    }
+
+    #[test]
+    fn files_are_written_and_read_back() {
+        let dir = TempDir::new("fs");
+        let got = run_in(&dir, r#"
+            await fs.writeFile(`${dir}/hello.txt`, 'héllo');
+            const text = await fs.readFile(`${dir}/hello.txt`, 'UTF-8');
+            const bytes = await fs.readFile(`${dir}/hello.txt`);
+            return `${text} ${bytes instanceof Uint8Array} ${bytes.length}`;
+        "#);
+        assert_eq!(got.unwrap(), "héllo true 6");
+    }
+
+    #[test]
+    fn an_unknown_encoding_throws() {
+        let dir = TempDir::new("fs");
+        dir.write("data", "x");
+        let got = run_in(&dir, r#"
+            try {
+                await fs.readFile(`${dir}/data`, 'latin1');
+                return 'read';
+            } catch (e) {
+                return e.name;
+            }
+        "#);
+        assert_eq!(got.unwrap(), "TypeError");
+    }
+
+    #[test]
+    fn stat_describes_files_and_directories() {
+        let dir = TempDir::new("fs");
+        dir.write("data", "12345");
+        let got = run_in(&dir, r#"
+            const file = await fs.stat(`${dir}/data`);
+            const directory = await fs.stat(dir);
+            return [file.size, file.isFile, file.isDirectory, directory.isDirectory, typeof file.mtimeMs].join();
+        "#);
+        assert_eq!(got.unwrap(), "5,true,false,true,number");
+    }
+
+    #[test]
+    fn readdir_lists_sorted_names() {
+        let dir = TempDir::new("fs");
+        dir.write("b", "");
+        dir.write("a", "");
+        let got = run_in(&dir, r#"
+            await fs.mkdir(`${dir}/c`);
+            return (await fs.readdir(dir)).join();
+        "#);
+        assert_eq!(got.unwrap(), "a,b,c");
+    }
+
+    #[test]
+    fn a_missing_file_rejects_with_enoent() {
+        let dir = TempDir::new("fs");
+        let got = run_in(&dir, r#"
+            try {
+                await fs.readFile(`${dir}/missing`);
+                return 'read';
+            } catch (e) {
+                return `${e.code} ${e instanceof Error}`;
+            }
+        "#);
+        assert_eq!(got.unwrap(), "ENOENT true");
+    }
+
+    #[test]
+    fn a_non_string_path_throws() {
+        let dir = TempDir::new("fs");
+        let got = run_in(&dir, r#"
+            try {
+                await fs.readFile(42);
+                return 'read';
+            } catch (e) {
+                return e.name;
+            }
+        "#);
+        assert_eq!(got.unwrap(), "TypeError");
+    }
+}
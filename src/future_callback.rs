@@ -1,22 +1,18 @@
 use std::{
     ops::DerefMut,
-    ptr::{NonNull},
-    rc::{Rc},
 };
 
-use mozjs::{rooted};
 use mozjs::{
     realm::{AutoRealm},
     gc::{Handle,MutableHandle,},
-    jsapi::{JSObject,Value,CallArgs,Heap},
+    jsapi::{JSObject,Value,CallArgs},
     jsval::{ObjectValue},
-    context::{JSContext},
     panic::wrap_panic,
 };
 #[allow(unused_imports)]
 use tracing::{debug,trace,instrument,warn,error,info};
 
-use crate::runtime::resolvable_promise::{ResolutionMarshalling,Bridge,push_internal_promise};
+use crate::runtime::resolvable_promise::{ResolutionMarshalling,new_pending_promise,spawn_bridged};
 
 #[instrument(skip_all,name="tokio_sleep_entry_point")]
 pub unsafe extern "C" fn tokio_sleep_ms(
@@ -34,38 +30,16 @@ pub unsafe extern "C" fn tokio_sleep_ms(
         }
         let ms = args.get(0).to_number();
         let duration_ms = ms as u64;
-        let mut safe_ctx = unsafe {JSContext::from_ptr(NonNull::new(ctx).unwrap())};
-        rooted!(in(unsafe { safe_ctx.raw_cx() }) let promise = unsafe { mozjs::rust::wrappers2::NewPromiseObject(&mut safe_ctx, Handle::<'_,*mut JSObject>::null()) });
-        rooted!(in(unsafe { safe_ctx.raw_cx() }) let current_global = unsafe { mozjs::rust::wrappers2::CurrentGlobalOrNull(&safe_ctx)});
-        if current_global.get().is_null() {
+        let Some((promise_id, promise)) = new_pending_promise(ctx, "sleep_ms") else {
             is_okay = false;
-            error!("global is null");
             return;
-        }
-        let promise_id = unsafe {
-            if promise.get().is_null() {
-				is_okay = false;
-                error!("promise is null");
-                return;
-            }
-            if !mozjs::rust::wrappers2::IsPromiseObject(promise.handle()) {
-				is_okay = false;
-                error!("promise is not a promise");
-                return;
-            }
-
-            let promise_id = mozjs::rust::wrappers2::GetPromiseID(promise.handle());
-            args.rval().set(ObjectValue(promise.get()));
-            promise_id
-		};
+        };
+        args.rval().set(ObjectValue(promise));
 
-	    let task = tokio::task::spawn(async move {
+        spawn_bridged(promise_id, async move {
             tokio::time::sleep(std::time::Duration::from_millis(duration_ms)).await;
             duration_ms
-        });
-
-	    push_internal_promise(promise_id, Heap::boxed(promise.get()), Rc::new(Heap::boxed(current_global.get())));
-	    Bridge::new(async move { (promise_id,task.await.unwrap()) }, bridge_delay);
+        }, bridge_delay);
         info!("tokio sleep returning, state: '{}'", &is_okay);
     });
     is_okay
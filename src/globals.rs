@@ -0,0 +1,78 @@
+use std::{
+    ptr::NonNull,
+};
+use mozjs::{
+    gc::{Handle},
+    jsapi::{JSObject,Value,CallArgs,JSFUN_CONSTRUCTOR},
+    jsval::UndefinedValue,
+    realm::AutoRealm,
+    rust::wrappers2::{
+        InitRealmStandardClasses,
+        JS_DefineFunction,
+    },
+    panic::wrap_panic,
+};
+#[allow(unused_imports)]
+use tracing::{debug,trace,instrument,warn,error,info};
+
+use crate::{
+    future_callback::tokio_sleep_ms,
+    microtask::queue_microtask,
+    clone::structured_clone,
+    worker::worker_constructor,
+    message_channel::message_channel_constructor,
+    console::define_console,
+    fs::define_fs,
+    net::define_net,
+    streams::define_streams,
+    fetch::define_fetch,
+    serve::define_serve,
+    process::spawn,
+    signal::{on_signal,off_signal,wait_for_signal},
+};
+
+/// Defines the standard classes & every host function on a new global
+///
+/// Must be called from within the realm of `global_obj`.
+pub fn define_globals(realm: &mut AutoRealm, global_obj: Handle<'_,*mut JSObject>) {
+    unsafe {
+        InitRealmStandardClasses(realm);
+        JS_DefineFunction(realm,global_obj,c"print_stuff".as_ptr(),Some(print_stuff),1,0,);
+        JS_DefineFunction(realm,global_obj,c"sleep_ms".as_ptr(),Some(tokio_sleep_ms),1,0,);
+        JS_DefineFunction(realm,global_obj,c"queueMicrotask".as_ptr(),Some(queue_microtask),1,0,);
+        JS_DefineFunction(realm,global_obj,c"structuredClone".as_ptr(),Some(structured_clone),2,0,);
+        JS_DefineFunction(realm,global_obj,c"Worker".as_ptr(),Some(worker_constructor),1,JSFUN_CONSTRUCTOR,);
+        JS_DefineFunction(realm,global_obj,c"MessageChannel".as_ptr(),Some(message_channel_constructor),0,JSFUN_CONSTRUCTOR,);
+        JS_DefineFunction(realm,global_obj,c"spawn".as_ptr(),Some(spawn),3,0,);
+        JS_DefineFunction(realm,global_obj,c"onSignal".as_ptr(),Some(on_signal),2,0,);
+        JS_DefineFunction(realm,global_obj,c"offSignal".as_ptr(),Some(off_signal),2,0,);
+        JS_DefineFunction(realm,global_obj,c"signal".as_ptr(),Some(wait_for_signal),1,0,);
+    }
+    // the preludes need the globals above, & each other in this order
+    define_console(realm, global_obj);
+    define_streams(realm, global_obj);
+    define_fs(realm, global_obj);
+    define_net(realm, global_obj);
+    define_fetch(realm, global_obj);
+    define_serve(realm, global_obj);
+}
+
+unsafe extern "C" fn print_stuff(
+    ctx: *mut mozjs::context::RawJSContext,
+    argc: u32,
+    vp: *mut Value,
+) -> bool {
+    let mut is_okay = true;
+    wrap_panic(&mut || {
+        let args = unsafe { CallArgs::from_vp(vp, argc) };
+        if args.argc_ < 1 {
+            is_okay = false;
+            return;
+        }
+        let arg = args.get(0).to_string();
+        let s = unsafe { mozjs::conversions::jsstr_to_string(ctx, NonNull::new(arg).unwrap()) };
+        println!("{}", s);
+        args.rval().set(UndefinedValue());
+    });
+    true
+}
@@ -0,0 +1,403 @@
+use std::{
+    fmt::{Write as _},
+    io,
+    time::{Duration},
+};
+use tokio::{
+    io::{AsyncWriteExt,BufReader},
+    net::{TcpStream},
+    sync::{mpsc,watch},
+};
+#[allow(unused_imports)]
+use tracing::{debug,trace,instrument,warn,error,info};
+
+use crate::byte_stream::{BoxedReader};
+use super::{Headers,BodyKind,ChannelReader,header,read_head,read_body,write_head};
+
+/*
+ * HTTP/1.1 client behind `fetch`
+ *
+ * Plain `http:` only. A new connection per request, the body of the
+ * final response is streamed through a `ChannelReader` by a task of
+ * its own so the caller sees the head as soon as it arrives.
+ *
+ */
+
+/// Redirects followed before giving up
+const MAX_REDIRECTS: usize = 20;
+
+/// Body chunks buffered ahead of the reader
+const BODY_BUFFER_CHUNKS: usize = 4;
+
+/// `init.redirect`
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub(crate) enum RedirectMode {
+    Follow,
+    Manual,
+    Error,
+}
+impl RedirectMode {
+    pub fn parse(mode: &str) -> Option<RedirectMode> {
+        match mode {
+            "follow" => Some(RedirectMode::Follow),
+            "manual" => Some(RedirectMode::Manual),
+            "error" => Some(RedirectMode::Error),
+            _ => None,
+        }
+    }
+}
+
+/// An absolute `http:` URL
+#[derive(Clone,Debug,PartialEq)]
+pub(crate) struct Url {
+    pub host: String,
+    pub port: u16,
+    /// path & query, always starting with `/`, fragment removed & percent-encoded
+    pub target: String,
+}
+impl Url {
+    pub fn parse(url: &str) -> Result<Url,&'static str> {
+        let url = url.trim();
+        let (scheme, rest) = url.split_once("://").ok_or("invalid URL")?;
+        if scheme.eq_ignore_ascii_case("https") {
+            return Err("https is not supported");
+        }
+        if !scheme.eq_ignore_ascii_case("http") {
+            return Err("unsupported URL scheme");
+        }
+        let rest = rest.split('#').next().unwrap_or_default();
+        let (authority, target) = match rest.find(['/', '?']) {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let target = if target.starts_with('?') { format!("/{}", target) } else { target.to_string() };
+        let target = encode_target(&target)?;
+        if authority.contains(|c: char| c.is_whitespace() || c.is_control()) {
+            return Err("invalid URL host");
+        }
+        if authority.contains('@') {
+            return Err("credentials in URLs are not supported");
+        }
+        let (host, port) = if let Some(bracketed) = authority.strip_prefix('[') {
+            // [::1]:8080
+            let (host, port) = bracketed.split_once(']').ok_or("invalid URL")?;
+            (host, port.strip_prefix(':'))
+        } else {
+            match authority.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            }
+        };
+        if host.is_empty() {
+            return Err("invalid URL");
+        }
+        let port = match port {
+            None | Some("") => 80,
+            Some(port) => port.parse().map_err(|_| "invalid URL port")?,
+        };
+        Ok(Url { host: host.to_ascii_lowercase(), port, target })
+    }
+
+    /// Resolves a `Location` header against this URL
+    pub fn join(&self, location: &str) -> Result<Url,&'static str> {
+        if location.contains("://") {
+            return Url::parse(location);
+        }
+        if let Some(rest) = location.strip_prefix("//") {
+            return Url::parse(&format!("http://{}", rest));
+        }
+        let mut url = self.clone();
+        if location.starts_with('/') {
+            url.target = location.to_string();
+        } else if location.starts_with('?') {
+            let path = self.target.split('?').next().unwrap_or("/");
+            url.target = format!("{}{}", path, location);
+        } else {
+            let path = self.target.split('?').next().unwrap_or("/");
+            let dir = &path[..path.rfind('/').map(|i| i + 1).unwrap_or(1)];
+            url.target = format!("{}{}", dir, location);
+        }
+        url.target = encode_target(url.target.split('#').next().unwrap_or("/"))?;
+        Ok(url)
+    }
+
+    /// `Host` header value
+    fn authority(&self) -> String {
+        let host = if self.host.contains(':') { format!("[{}]", self.host) } else { self.host.clone() };
+        match self.port {
+            80 => host,
+            port => format!("{}:{}", host, port),
+        }
+    }
+}
+/// `target` as it can go in a request line
+///
+/// Spaces, quotes & anything outside ASCII are percent-encoded. Control
+/// characters (CR & LF among them) are refused, they could only end the
+/// request line early & smuggle in headers.
+fn encode_target(target: &str) -> Result<String,&'static str> {
+    let mut encoded = String::with_capacity(target.len());
+    for c in target.chars() {
+        match c {
+            c if c.is_control() => return Err("invalid URL, it contains control characters"),
+            ' ' | '"' | '<' | '>' | '`' => { let _ = write!(encoded, "%{:02X}", c as u32); },
+            c if !c.is_ascii() => {
+                let mut bytes = [0u8; 4];
+                for byte in c.encode_utf8(&mut bytes).bytes() {
+                    let _ = write!(encoded, "%{:02X}", byte);
+                }
+            }
+            c => encoded.push(c),
+        }
+    }
+    Ok(encoded)
+}
+
+impl std::fmt::Display for Url {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "http://{}{}", self.authority(), self.target)
+    }
+}
+
+pub(crate) struct ClientRequest {
+    pub method: String,
+    pub url: Url,
+    pub headers: Headers,
+    pub body: Option<Vec<u8>>,
+    pub redirect: RedirectMode,
+    /// applies until the response head arrives
+    pub timeout: Option<Duration>,
+}
+
+pub(crate) struct ClientResponse {
+    pub status: u16,
+    pub status_text: String,
+    /// the final URL, after redirects
+    pub url: String,
+    pub redirected: bool,
+    pub headers: Headers,
+    pub body: BoxedReader,
+}
+
+fn aborted() -> io::Error {
+    io::Error::new(io::ErrorKind::Interrupted, "the operation was aborted")
+}
+
+/// Waits until `abort` flips to `true`, forever if the sender is gone
+pub(crate) async fn wait_for_abort(abort: &mut watch::Receiver<bool>) {
+    if abort.wait_for(|aborted| *aborted).await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Performs `request`, following redirects per `request.redirect`
+///
+/// Resolves once the final response head arrives. Flipping `abort`
+/// fails the request, or the body stream if the head already arrived.
+#[instrument(skip_all, fields(method = %request.method, url = %request.url))]
+pub(crate) async fn send(mut request: ClientRequest, mut abort: watch::Receiver<bool>) -> io::Result<ClientResponse> {
+    let timeout = request.timeout;
+    let mut abort_head = abort.clone();
+    let head = async {
+        let mut redirects = 0;
+        loop {
+            let (head, reader) = exchange(&request).await?;
+            let status: u16 = head.start[1].parse()
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad status code"))?;
+            let location = head.header("location").map(str::to_string);
+            let is_redirect = matches!(status, 301 | 302 | 303 | 307 | 308) && location.is_some();
+            if !is_redirect || request.redirect == RedirectMode::Manual {
+                return Ok((status, head, reader, redirects > 0));
+            }
+            if request.redirect == RedirectMode::Error {
+                return Err(io::Error::other(format!("redirected to '{}'", location.unwrap_or_default())));
+            }
+            redirects += 1;
+            if redirects > MAX_REDIRECTS {
+                return Err(io::Error::other("too many redirects"));
+            }
+            let next = request.url.join(&location.unwrap_or_default())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            debug!("following redirect '{}' -> '{}'", request.url, next);
+            // 303 (and historically 301/302 for POST) switch to a bodyless GET
+            if status == 303 || (matches!(status, 301 | 302) && request.method == "POST") {
+                if request.method != "HEAD" {
+                    request.method = "GET".to_string();
+                }
+                request.body = None;
+                request.headers.retain(|(k, _)| !k.eq_ignore_ascii_case("content-type") && !k.eq_ignore_ascii_case("content-length"));
+            }
+            if next.host != request.url.host || next.port != request.url.port {
+                request.headers.retain(|(k, _)| !k.eq_ignore_ascii_case("authorization") && !k.eq_ignore_ascii_case("cookie"));
+            }
+            request.url = next;
+        }
+    };
+    let head = async {
+        match timeout {
+            Some(limit) => tokio::time::timeout(limit, head).await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "request timed out"))?,
+            None => head.await,
+        }
+    };
+    let (status, head, mut reader, redirected) = tokio::select! {
+        head = head => head?,
+        _ = wait_for_abort(&mut abort_head) => return Err(aborted()),
+    };
+
+    let kind = match status {
+        100..=199 | 204 | 304 => BodyKind::Empty,
+        _ if request.method == "HEAD" => BodyKind::Empty,
+        _ => BodyKind::from_headers(&head.headers, true)?,
+    };
+    let (sender, receiver) = mpsc::channel(BODY_BUFFER_CHUNKS);
+    tokio::spawn(async move {
+        let pump = read_body(&mut reader, kind, async |chunk: &[u8]| {
+            sender.send(Ok(chunk.to_vec())).await.is_ok()
+        });
+        let result = tokio::select! {
+            result = pump => result,
+            _ = wait_for_abort(&mut abort) => Err(aborted()),
+        };
+        if let Err(e) = result {
+            let _ = sender.send(Err(e)).await;
+        }
+    });
+
+    let status_text = head.start[2].clone();
+    Ok(ClientResponse {
+        status,
+        status_text,
+        url: request.url.to_string(),
+        redirected,
+        headers: head.headers,
+        body: Box::new(ChannelReader::new(receiver)),
+    })
+}
+
+/// Sends one request on a new connection & reads the response head
+async fn exchange(request: &ClientRequest) -> io::Result<(super::Head, BufReader<TcpStream>)> {
+    let mut stream = TcpStream::connect((request.url.host.as_str(), request.url.port)).await?;
+    let _ = stream.set_nodelay(true);
+
+    let mut headers: Headers = Vec::with_capacity(request.headers.len() + 4);
+    headers.push(("Host".to_string(), request.url.authority()));
+    for (name, value) in &request.headers {
+        if ["host", "connection", "content-length", "transfer-encoding"].iter().any(|h| name.eq_ignore_ascii_case(h)) {
+            continue;
+        }
+        headers.push((name.clone(), value.clone()));
+    }
+    if header(&headers, "user-agent").is_none() {
+        headers.push(("User-Agent".to_string(), concat!("async-demo/", env!("CARGO_PKG_VERSION")).to_string()));
+    }
+    if header(&headers, "accept").is_none() {
+        headers.push(("Accept".to_string(), "*/*".to_string()));
+    }
+    if let Some(body) = &request.body {
+        headers.push(("Content-Length".to_string(), body.len().to_string()));
+    }
+    headers.push(("Connection".to_string(), "close".to_string()));
+
+    write_head(&mut stream, &format!("{} {} HTTP/1.1", request.method, request.url.target), &headers).await?;
+    if let Some(body) = &request.body {
+        stream.write_all(body).await?;
+    }
+    stream.flush().await?;
+
+    let mut reader = BufReader::new(stream);
+    loop {
+        let head = read_head(&mut reader).await?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::ConnectionReset, "connection closed before a response"))?;
+        if !head.start[0].starts_with("HTTP/") {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not an HTTP response"));
+        }
+        // informational responses (other than upgrades) are skipped
+        if head.start[1].starts_with('1') && head.start[1] != "101" {
+            continue;
+        }
+        return Ok((head, reader));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt},
+        net::{TcpListener},
+    };
+    use super::*;
+    use crate::http::server::{IncomingRequest,OutgoingResponse,accept_loop};
+
+    #[test]
+    fn parse_encodes_what_a_request_line_cannot_carry() {
+        let url = Url::parse("http://example.com/a b/\"é\"?q=<1 2>#frag ment").unwrap();
+        assert_eq!(url.target, "/a%20b/%22%C3%A9%22?q=%3C1%202%3E");
+    }
+
+    #[test]
+    fn parse_rejects_control_characters() {
+        for url in ["http://example.com/a\r\nX-Injected: 1", "http://example.com/a\tb", "http://example.com/?q=\0", "http://exa mple.com/"] {
+            assert!(Url::parse(url).is_err(), "{:?}", url);
+        }
+    }
+
+    #[test]
+    fn join_encodes_locations() {
+        let base = Url::parse("http://example.com/dir/page").unwrap();
+        assert_eq!(base.join("other page").unwrap().target, "/dir/other%20page");
+        assert!(base.join("/a\r\nX-Injected: 1").is_err());
+    }
+
+    /// An in-process server answering `200 hello`, its address & every request it sees
+    async fn start_server() -> (std::net::SocketAddr, mpsc::UnboundedReceiver<(String,Headers)>, watch::Sender<bool>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (requests_tx, mut requests_rx) = mpsc::unbounded_channel::<IncomingRequest>();
+        let (seen_tx, seen_rx) = mpsc::unbounded_channel();
+        let (closed_tx, closed_rx) = watch::channel(false);
+        tokio::spawn(accept_loop(listener, requests_tx, closed_rx));
+        tokio::spawn(async move {
+            while let Some(request) = requests_rx.recv().await {
+                let _ = seen_tx.send((request.url.clone(), request.headers.clone()));
+                let mut response = OutgoingResponse::status(200);
+                response.body = Some(Box::new(std::io::Cursor::new(b"hello".to_vec())));
+                let _ = request.respond.send(response);
+            }
+        });
+        (address, seen_rx, closed_tx)
+    }
+
+    fn get(url: Url) -> ClientRequest {
+        ClientRequest { method: "GET".to_string(), url, headers: Headers::new(), body: None, redirect: RedirectMode::Follow, timeout: Some(Duration::from_secs(5)) }
+    }
+
+    #[tokio::test]
+    async fn encoded_targets_reach_the_server() {
+        let (address, mut seen, _closed) = start_server().await;
+        let url = Url::parse(&format!("http://{}/a b?q=1 2", address)).unwrap();
+        let mut response = send(get(url), watch::channel(false).1).await.unwrap();
+        assert_eq!(response.status, 200);
+        let mut body = Vec::new();
+        response.body.read_to_end(&mut body).await.unwrap();
+        assert_eq!(body, b"hello");
+        let (url, _) = seen.recv().await.unwrap();
+        assert_eq!(url, format!("http://{}/a%20b?q=1%202", address));
+    }
+
+    #[tokio::test]
+    async fn request_lines_with_newlines_are_never_sent() {
+        let (address, mut seen, _closed) = start_server().await;
+        // as if the target had bypassed `Url::parse`
+        let url = Url { host: address.ip().to_string(), port: address.port(), target: "/ HTTP/1.1\r\nX-Injected: 1\r\n\r\nGET /".to_string() };
+        let sent = send(get(url), watch::channel(false).1).await;
+        assert_eq!(sent.err().map(|e| e.kind()), Some(io::ErrorKind::InvalidInput));
+
+        // the connection was closed without a request, the next one is the first seen
+        let url = Url::parse(&format!("http://{}/next", address)).unwrap();
+        send(get(url), watch::channel(false).1).await.unwrap();
+        let (url, headers) = seen.recv().await.unwrap();
+        assert_eq!(url, format!("http://{}/next", address));
+        assert!(header(&headers, "x-injected").is_none());
+    }
+}
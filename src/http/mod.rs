@@ -0,0 +1,293 @@
+use std::{
+    io,
+    pin::{Pin},
+    task::{Context,Poll},
+};
+use tokio::{
+    io::{AsyncBufRead,AsyncBufReadExt,AsyncRead,AsyncReadExt,AsyncWrite,AsyncWriteExt,ReadBuf},
+    sync::{mpsc},
+};
+#[allow(unused_imports)]
+use tracing::{debug,trace,instrument,warn,error,info};
+
+pub mod client;
+pub mod server;
+
+/*
+ * Minimal HTTP/1.1
+ *
+ * Just enough to talk to (and be) a well behaved HTTP/1.1 peer over
+ * a plain TCP stream. Every connection is `Connection: close`, there
+ * is no pooling, pipelining or TLS.
+ *
+ */
+
+/// Largest request/status line + headers we'll accept
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// How much of a body is moved at once
+pub(crate) const BODY_CHUNK_SIZE: usize = 16 * 1024;
+
+pub(crate) type Headers = Vec<(String,String)>;
+
+/// The start line & headers of a request or response
+pub(crate) struct Head {
+    /// `["GET", "/path", "HTTP/1.1"]` or `["HTTP/1.1", "200", "OK"]`
+    pub start: [String; 3],
+    pub headers: Headers,
+}
+impl Head {
+    /// First value of header `name` (case insensitive)
+    pub fn header(&self, name: &str) -> Option<&str> {
+        header(&self.headers, name)
+    }
+}
+
+pub(crate) fn header<'a>(headers: &'a Headers, name: &str) -> Option<&'a str> {
+    headers.iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Reads a start line & headers, `None` if the peer closed before sending anything
+pub(crate) async fn read_head<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Option<Head>> {
+    let mut total = 0usize;
+    let mut line = String::new();
+    let mut lines: Vec<String> = Vec::new();
+    loop {
+        line.clear();
+        let read = reader.read_line(&mut line).await?;
+        total += read;
+        if total > MAX_HEAD_SIZE {
+            return Err(invalid("http head too large"));
+        }
+        if read == 0 {
+            if lines.is_empty() {
+                return Ok(None);
+            }
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        let trimmed = line.trim_end_matches(['\r', '\n']);
+        if trimmed.is_empty() {
+            if lines.is_empty() {
+                // tolerate leading blank lines
+                continue;
+            }
+            break;
+        }
+        lines.push(trimmed.to_string());
+    }
+
+    let mut parts = lines[0].splitn(3, ' ');
+    let start = [
+        parts.next().unwrap_or_default().to_string(),
+        parts.next().ok_or_else(|| invalid("malformed start line"))?.to_string(),
+        parts.next().unwrap_or_default().to_string(),
+    ];
+    let mut headers = Headers::new();
+    for line in &lines[1..] {
+        let (name, value) = line.split_once(':').ok_or_else(|| invalid("malformed header"))?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+    Ok(Some(Head { start, headers }))
+}
+
+/// How the length of a message body is determined
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub(crate) enum BodyKind {
+    Empty,
+    Length(u64),
+    Chunked,
+    /// response bodies without a length run until the connection closes
+    UntilClose,
+}
+impl BodyKind {
+    pub fn from_headers(headers: &Headers, is_response: bool) -> io::Result<BodyKind> {
+        if header(headers, "transfer-encoding").map(|v| v.to_ascii_lowercase().contains("chunked")).unwrap_or(false) {
+            return Ok(BodyKind::Chunked);
+        }
+        if let Some(len) = header(headers, "content-length") {
+            let len: u64 = len.parse().map_err(|_| invalid("bad content-length"))?;
+            return Ok(if len == 0 { BodyKind::Empty } else { BodyKind::Length(len) });
+        }
+        Ok(if is_response { BodyKind::UntilClose } else { BodyKind::Empty })
+    }
+}
+
+/// Reads a body of `kind` from `reader`, handing each piece to `sink`
+///
+/// Chunked encoding is decoded. `sink` returns `false` to stop early
+/// (e.g. nobody is reading anymore), which isn't an error. Returns the
+/// number of body bytes read.
+pub(crate) async fn read_body<R>(reader: &mut R, kind: BodyKind, mut sink: impl AsyncFnMut(&[u8]) -> bool) -> io::Result<u64>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut buffer = vec![0u8; BODY_CHUNK_SIZE];
+    let mut copied = 0u64;
+    // copies exactly `len` bytes, `false` if the sink stopped
+    macro_rules! copy_exact {
+        ($len:expr) => {{
+            let mut remaining: u64 = $len;
+            let mut more = true;
+            while remaining > 0 {
+                let want = remaining.min(buffer.len() as u64) as usize;
+                let read = reader.read(&mut buffer[..want]).await?;
+                if read == 0 {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+                }
+                remaining -= read as u64;
+                copied += read as u64;
+                if !sink(&buffer[..read]).await {
+                    more = false;
+                    break;
+                }
+            }
+            more
+        }};
+    }
+    match kind {
+        BodyKind::Empty => { },
+        BodyKind::Length(len) => { copy_exact!(len); },
+        BodyKind::UntilClose => {
+            loop {
+                let read = reader.read(&mut buffer).await?;
+                if read == 0 {
+                    break;
+                }
+                copied += read as u64;
+                if !sink(&buffer[..read]).await {
+                    break;
+                }
+            }
+        }
+        BodyKind::Chunked => {
+            let mut line = String::new();
+            loop {
+                line.clear();
+                if reader.read_line(&mut line).await? == 0 {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+                }
+                let size = line.trim().split(';').next().unwrap_or_default();
+                let size = u64::from_str_radix(size, 16).map_err(|_| invalid("bad chunk size"))?;
+                if size == 0 {
+                    // trailers, up to the blank line
+                    loop {
+                        line.clear();
+                        if reader.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+                            break;
+                        }
+                    }
+                    break;
+                }
+                if !copy_exact!(size) {
+                    break;
+                }
+                line.clear();
+                reader.read_line(&mut line).await?;
+            }
+        }
+    };
+    Ok(copied)
+}
+
+/// An `AsyncRead` over body chunks sent from another task
+///
+/// Errors sent down the channel are returned from `read`, the channel
+/// closing is EOF. The channel's capacity is the backpressure.
+pub(crate) struct ChannelReader {
+    receiver: mpsc::Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    offset: usize,
+}
+impl ChannelReader {
+    pub fn new(receiver: mpsc::Receiver<io::Result<Vec<u8>>>) -> Self {
+        ChannelReader { receiver, chunk: Vec::new(), offset: 0 }
+    }
+}
+impl AsyncRead for ChannelReader {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        while this.offset >= this.chunk.len() {
+            match this.receiver.poll_recv(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(e)),
+                Poll::Ready(Some(Ok(chunk))) => {
+                    this.chunk = chunk;
+                    this.offset = 0;
+                }
+            }
+        }
+        let n = buf.remaining().min(this.chunk.len() - this.offset);
+        buf.put_slice(&this.chunk[this.offset..this.offset + n]);
+        this.offset += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Writes a start line & headers
+pub(crate) async fn write_head<W: AsyncWrite + Unpin>(writer: &mut W, start: &str, headers: &Headers) -> io::Result<()> {
+    if start.contains(['\r', '\n']) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "start line contains a newline"));
+    }
+    let mut head = String::with_capacity(256);
+    head.push_str(start);
+    head.push_str("\r\n");
+    for (name, value) in headers {
+        if name.contains(['\r', '\n']) || value.contains(['\r', '\n']) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "header contains a newline"));
+        }
+        head.push_str(name);
+        head.push_str(": ");
+        head.push_str(value);
+        head.push_str("\r\n");
+    }
+    head.push_str("\r\n");
+    writer.write_all(head.as_bytes()).await
+}
+
+/// Writes one chunk of a chunked body, an empty chunk ends the body
+pub(crate) async fn write_chunk<W: AsyncWrite + Unpin>(writer: &mut W, chunk: &[u8]) -> io::Result<()> {
+    writer.write_all(format!("{:x}\r\n", chunk.len()).as_bytes()).await?;
+    writer.write_all(chunk).await?;
+    writer.write_all(b"\r\n").await
+}
+
+/// The reason phrase for common status codes
+pub(crate) fn status_text(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "",
+    }
+}
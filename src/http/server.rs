@@ -0,0 +1,178 @@
+use std::{
+    io,
+    net::{SocketAddr},
+};
+use tokio::{
+    io::{AsyncReadExt,AsyncWrite,AsyncWriteExt,BufReader},
+    net::{TcpListener,TcpStream},
+    sync::{mpsc,oneshot,watch},
+};
+#[allow(unused_imports)]
+use tracing::{debug,trace,instrument,warn,error,info};
+
+use crate::byte_stream::{BoxedReader};
+use super::{
+    Headers,BodyKind,ChannelReader,BODY_CHUNK_SIZE,
+    header,read_head,read_body,write_head,write_chunk,status_text,
+    client::{wait_for_abort},
+};
+
+/*
+ * HTTP/1.1 server behind `serve`
+ *
+ * Every connection gets a task of its own which parses one request,
+ * hands it to the JS thread as an `IncomingRequest` & writes back
+ * whatever comes out of `respond`. Response bodies are always sent
+ * chunked.
+ *
+ */
+
+/// Request body chunks buffered ahead of the handler
+const BODY_BUFFER_CHUNKS: usize = 4;
+
+pub(crate) struct IncomingRequest {
+    pub method: String,
+    /// absolute, built from the `Host` header (or the local address)
+    pub url: String,
+    pub headers: Headers,
+    /// `None` when the request has no body
+    pub body: Option<BoxedReader>,
+    pub remote_address: SocketAddr,
+    /// dropping this without sending responds with a 500
+    pub respond: oneshot::Sender<OutgoingResponse>,
+}
+
+pub(crate) struct OutgoingResponse {
+    pub status: u16,
+    pub status_text: String,
+    pub headers: Headers,
+    pub body: Option<BoxedReader>,
+}
+impl OutgoingResponse {
+    /// A bodyless response with the default reason phrase
+    pub fn status(status: u16) -> Self {
+        OutgoingResponse { status, status_text: status_text(status).to_string(), headers: Headers::new(), body: None }
+    }
+}
+
+/// Accepts connections until `closed` flips to `true`
+///
+/// Connections already accepted run to completion on their own.
+#[instrument(skip_all, fields(address = ?listener.local_addr().ok()))]
+pub(crate) async fn accept_loop(listener: TcpListener, requests: mpsc::UnboundedSender<IncomingRequest>, mut closed: watch::Receiver<bool>) {
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("accept failed: {}", e);
+                    continue;
+                }
+            },
+            _ = wait_for_abort(&mut closed) => break,
+        };
+        let requests = requests.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_connection(stream, peer, requests).await {
+                debug!("connection from '{}' failed: {}", peer, e);
+            }
+        });
+    }
+    debug!("server closed");
+}
+
+/// Handles the (single) request on a connection
+async fn serve_connection(stream: TcpStream, peer: SocketAddr, requests: mpsc::UnboundedSender<IncomingRequest>) -> io::Result<()> {
+    let _ = stream.set_nodelay(true);
+    let local = stream.local_addr()?;
+    let (read, mut write) = stream.into_split();
+    let mut reader = BufReader::new(read);
+
+    let head = match read_head(&mut reader).await {
+        Ok(Some(head)) => head,
+        Ok(None) => return Ok(()),
+        Err(e) => {
+            write_response(&mut write, OutgoingResponse::status(400), false).await?;
+            return Err(e);
+        }
+    };
+    let [method, target, version] = &head.start;
+    if !version.starts_with("HTTP/1.") {
+        return write_response(&mut write, OutgoingResponse::status(505), false).await;
+    }
+    let kind = match BodyKind::from_headers(&head.headers, false) {
+        Ok(kind) => kind,
+        Err(_) => return write_response(&mut write, OutgoingResponse::status(400), false).await,
+    };
+    if kind != BodyKind::Empty && head.header("expect").map(|v| v.eq_ignore_ascii_case("100-continue")).unwrap_or(false) {
+        write.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+    }
+
+    let body: Option<BoxedReader> = match kind {
+        BodyKind::Empty => None,
+        kind => {
+            let (sender, receiver) = mpsc::channel(BODY_BUFFER_CHUNKS);
+            tokio::spawn(async move {
+                let result = read_body(&mut reader, kind, async |chunk: &[u8]| {
+                    sender.send(Ok(chunk.to_vec())).await.is_ok()
+                }).await;
+                if let Err(e) = result {
+                    let _ = sender.send(Err(e)).await;
+                }
+            });
+            Some(Box::new(ChannelReader::new(receiver)))
+        }
+    };
+
+    let host = head.header("host").map(str::to_string).unwrap_or_else(|| local.to_string());
+    let target = if target.starts_with('/') { target.clone() } else { format!("/{}", target) };
+    let is_head = method == "HEAD";
+    let (respond, response) = oneshot::channel();
+    let request = IncomingRequest {
+        method: method.clone(),
+        url: format!("http://{}{}", host, target),
+        headers: head.headers.clone(),
+        body,
+        remote_address: peer,
+        respond,
+    };
+    trace!("{} {}", request.method, request.url);
+    if requests.send(request).is_err() {
+        return write_response(&mut write, OutgoingResponse::status(503), false).await;
+    }
+    let response = response.await.unwrap_or_else(|_| OutgoingResponse::status(500));
+    write_response(&mut write, response, is_head).await
+}
+
+/// Writes `response`, chunked if it has a body, & closes the connection
+async fn write_response<W: AsyncWrite + Unpin>(writer: &mut W, response: OutgoingResponse, is_head: bool) -> io::Result<()> {
+    let OutgoingResponse { status, status_text, mut headers, body } = response;
+    let bodyless = is_head || matches!(status, 100..=199 | 204 | 304);
+    headers.retain(|(k, _)| {
+        !["connection", "content-length", "transfer-encoding", "keep-alive"].iter().any(|h| k.eq_ignore_ascii_case(h))
+    });
+    match (&body, bodyless) {
+        (Some(_), false) => headers.push(("Transfer-Encoding".to_string(), "chunked".to_string())),
+        (None, false) => headers.push(("Content-Length".to_string(), "0".to_string())),
+        (_, true) => { },
+    };
+    if header(&headers, "server").is_none() {
+        headers.push(("Server".to_string(), concat!("async-demo/", env!("CARGO_PKG_VERSION")).to_string()));
+    }
+    headers.push(("Connection".to_string(), "close".to_string()));
+    write_head(writer, &format!("HTTP/1.1 {} {}", status, status_text), &headers).await?;
+
+    if let (Some(mut body), false) = (body, bodyless) {
+        let mut buffer = vec![0u8; BODY_CHUNK_SIZE];
+        loop {
+            let read = body.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            write_chunk(writer, &buffer[..read]).await?;
+        }
+        write_chunk(writer, &[]).await?;
+    }
+    writer.flush().await?;
+    writer.shutdown().await
+}
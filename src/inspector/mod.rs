@@ -0,0 +1,353 @@
+use std::{
+    cell::{RefCell,LazyCell},
+    collections::{BTreeMap},
+    io,
+    net::{SocketAddr},
+    ops::{DerefMut},
+    ptr::{null_mut},
+    sync::{Arc,mpsc as std_mpsc},
+    time::{SystemTime,UNIX_EPOCH},
+};
+use tokio::{
+    sync::{mpsc,Notify},
+};
+use mozjs::{rooted};
+use mozjs::{
+    context::{JSContext,RawJSContext},
+    gc::{Handle},
+    glue::{UncheckedUnwrapObject},
+    jsapi::{
+        JSObject,Value,CallArgs,OnNewGlobalHookOption,
+        JS_NewGlobalObject,JS_DefineDebuggerObject,
+    },
+    jsval::{UndefinedValue,ObjectValue,NullValue,DoubleValue},
+    realm::{AutoRealm},
+    rust::{SIMPLE_GLOBAL_CLASS,RealmOptions},
+    rust::wrappers2::{InitRealmStandardClasses,JS_DefineFunction},
+    panic::wrap_panic,
+};
+#[allow(unused_imports)]
+use tracing::{debug,trace,instrument,warn,error,info};
+
+use crate::{
+    console::{define_console},
+    realm::{evaluate,realm_id_of},
+    runtime::{
+        call::{call_value,is_callable},
+        conversions::{arg_string,new_object,define_value,define_property},
+        exception::{report_pending_exception},
+        incumbent_stack::{enter_incumbent_stack},
+        queue::{Macrotask},
+        resolvable_promise::{push_unref_task_source},
+        roots::{HeapRoot},
+    },
+};
+
+mod server;
+mod websocket;
+
+use server::{EventSender,InspectorEvent,Target};
+
+/*
+ * Inspector
+ *
+ * A Chrome DevTools Protocol server for the runtime on the main
+ * thread. The protocol itself is plain JS (`prelude.js`) on top of
+ * SpiderMonkey's `Debugger`, running in a realm of its own which the
+ * debugger can't see. Rust only moves messages:
+ *
+ *  - `server` accepts WebSocket sessions on the tokio runtime
+ *  - while scripts run, an unref'd task source hands each message to
+ *    the prelude as a macrotask
+ *  - while paused (the prelude is inside a `Debugger` hook) the
+ *    prelude calls `native.receive()`, which blocks the JS thread until
+ *    the next message. SpiderMonkey runs every hook between
+ *    `pushNewInterruptQueue` & `popInterruptQueue`, so the jobs,
+ *    macrotasks & futures of the paused event loop are set aside &
+ *    nothing but the debugger's own evaluations runs until it resumes
+ *
+ * The inspector doesn't keep the event loop alive. Like node.js, the
+ * address it listens on (& that it's waiting for a debugger) is printed
+ * to stderr whatever the log filter, it's part of the CLI's output.
+ *
+ */
+
+const PRELUDE: &str = include_str!("prelude.js");
+
+/// Where `--inspect` listens without an address, same as node.js
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:9229";
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum InspectMode {
+    /// start running straight away
+    Run,
+    /// wait for a debugger to send `Runtime.runIfWaitingForDebugger`
+    Wait,
+    /// wait, then pause before the first statement
+    Break,
+}
+
+struct Inspector {
+    events: std_mpsc::Receiver<InspectorEvent>,
+    sessions: BTreeMap<u64,mpsc::UnboundedSender<String>>,
+    /// the debugger realm's global
+    global: HeapRoot,
+    /// `native.listen(handler)`
+    handler: Option<HeapRoot>,
+}
+
+thread_local! {
+    static INSPECTOR: LazyCell<RefCell<Option<Inspector>>> = LazyCell::new(|| RefCell::new(None));
+}
+
+/// Starts the inspector for this thread's runtime, listening on `address`
+///
+/// Must come after `install_job_queue` & before the globals it should
+/// debug are made (earlier ones are found, but their scripts may have
+/// been collected). Blocks until a debugger says to run unless `mode`
+/// is `Run`.
+pub fn start_inspector(ctx: &mut JSContext, address: SocketAddr, mode: InspectMode) -> io::Result<()> {
+    let listener = server::bind(address)?;
+    let address = listener.local_addr()?;
+
+    rooted!(in(unsafe { ctx.raw_cx() }) let global = new_debugger_global(ctx));
+    if global.get().is_null() {
+        return Err(io::Error::other("could not create the debugger realm"));
+    }
+    let (events, receiver) = std_mpsc::channel();
+    let wake = Arc::new(Notify::new());
+    INSPECTOR.with(|i| *i.borrow_mut() = Some(Inspector {
+        events: receiver,
+        sessions: BTreeMap::new(),
+        global: HeapRoot::new(global.get()),
+        handler: None,
+    }));
+    let defined = enter_incumbent_stack(ctx, global.handle(), |realm: &mut AutoRealm, global_obj: Handle<'_,*mut JSObject>| -> bool {
+        define_debugger_realm(realm, global_obj)
+    });
+    if !defined {
+        INSPECTOR.with(|i| i.borrow_mut().take());
+        return Err(io::Error::other("could not evaluate the inspector prelude"));
+    }
+
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+    let target = Arc::new(Target {
+        id: format!("{:08x}-{:016x}", std::process::id(), nanos as u64),
+        title: std::env::args().next().unwrap_or_else(|| "async-demo".to_string()),
+        url: std::env::current_dir().map(|d| format!("file://{}", d.display())).unwrap_or_default(),
+    });
+    eprintln!("Debugger listening on ws://{}/{}", address, target.id);
+    tokio::spawn(server::serve(listener, target, EventSender { events, wake: wake.clone() }));
+    listen_for_events(wake);
+
+    if mode != InspectMode::Run {
+        eprintln!("Waiting for the debugger to disconnect or run...");
+        dispatch(ctx, |cx: *mut RawJSContext, event: Handle<'_,*mut JSObject>| -> bool {
+            define_value(cx, event, c"type", "wait") && define_value(cx, event, c"brk", &(mode == InspectMode::Break))
+        });
+    }
+    Ok(())
+}
+
+/// A global in a compartment of its own, which `Debugger` won't list
+fn new_debugger_global(ctx: &mut JSContext) -> *mut JSObject {
+    let mut options = RealmOptions::default();
+    options.creationOptions_.invisibleToDebugger_ = true;
+    unsafe {
+        JS_NewGlobalObject(
+            ctx.raw_cx(),
+            &SIMPLE_GLOBAL_CLASS,
+            null_mut(),
+            OnNewGlobalHookOption::DontFireOnNewGlobalHook,
+            &*options,
+        )
+    }
+}
+
+fn define_debugger_realm(realm: &mut AutoRealm, global_obj: Handle<'_,*mut JSObject>) -> bool {
+    let cx = unsafe { realm.deref_mut().raw_cx() };
+    unsafe {
+        if !InitRealmStandardClasses(realm) || !JS_DefineDebuggerObject(cx, global_obj.into()) {
+            report_pending_exception(cx, "inspector");
+            return false;
+        }
+    }
+    define_console(realm, global_obj);
+    rooted!(in(cx) let native = new_object(cx));
+    if native.get().is_null() {
+        error!("could not allocate inspector natives");
+        return false;
+    }
+    unsafe {
+        JS_DefineFunction(realm,native.handle(),c"listen".as_ptr(),Some(inspector_listen),1,0,);
+        JS_DefineFunction(realm,native.handle(),c"send".as_ptr(),Some(inspector_send),2,0,);
+        JS_DefineFunction(realm,native.handle(),c"receive".as_ptr(),Some(inspector_receive),0,0,);
+        JS_DefineFunction(realm,native.handle(),c"realmId".as_ptr(),Some(inspector_realm_id),1,0,);
+    }
+    rooted!(in(cx) let native_val = ObjectValue(native.get()));
+    define_property(cx, global_obj, c"__inspectorNative", native_val.handle());
+    evaluate(realm, global_obj, "inspector.js", PRELUDE).is_ok()
+}
+
+/// Delivers events as macrotasks whenever the network side sends some
+fn listen_for_events(wake: Arc<Notify>) {
+    push_unref_task_source(async move {
+        wake.notified().await;
+        Macrotask::new("inspector", move |ctx: &mut JSContext| {
+            // anything sent while paused was already received there
+            while let Some(event) = next_event(false) {
+                dispatch(ctx, |cx: *mut RawJSContext, obj: Handle<'_,*mut JSObject>| -> bool {
+                    define_event(cx, obj, &event)
+                });
+            }
+            listen_for_events(wake);
+        })
+    });
+}
+
+/// What the prelude sees of an event, after the sessions are updated
+enum Event {
+    Connect(u64),
+    Message(u64,String),
+    Disconnect(u64),
+}
+
+/// The next event, waiting for one if `block`, `None` once the server is gone
+fn next_event(block: bool) -> Option<Event> {
+    INSPECTOR.with(|i| {
+        let mut inspector = i.borrow_mut();
+        let inspector = inspector.as_mut()?;
+        let event = match block {
+            true => inspector.events.recv().ok()?,
+            false => inspector.events.try_recv().ok()?,
+        };
+        Some(match event {
+            InspectorEvent::Connected { session, outgoing } => {
+                inspector.sessions.insert(session, outgoing);
+                Event::Connect(session)
+            }
+            InspectorEvent::Message { session, text } => Event::Message(session, text),
+            InspectorEvent::Disconnected { session } => {
+                inspector.sessions.remove(&session);
+                Event::Disconnect(session)
+            }
+        })
+    })
+}
+
+/// `{ type, session, message }`
+fn define_event(cx: *mut RawJSContext, obj: Handle<'_,*mut JSObject>, event: &Event) -> bool {
+    let (kind, session, message) = match event {
+        Event::Connect(session) => ("connect", *session, None),
+        Event::Message(session, text) => ("message", *session, Some(text.as_str())),
+        Event::Disconnect(session) => ("disconnect", *session, None),
+    };
+    define_value(cx, obj, c"type", kind)
+        && define_value(cx, obj, c"session", &(session as f64))
+        && message.is_none_or(|m| define_value(cx, obj, c"message", m))
+}
+
+/// Calls the prelude's handler with an event object filled in by `fill`
+fn dispatch<F>(ctx: &mut JSContext, fill: F)
+where
+    F: FnOnce(*mut RawJSContext, Handle<'_,*mut JSObject>) -> bool,
+{
+    let Some((global, handler)) = INSPECTOR.with(|i| {
+        let inspector = i.borrow();
+        let inspector = inspector.as_ref()?;
+        Some((inspector.global.get(), inspector.handler.as_ref()?.get()))
+    }) else {
+        warn!("inspector: no handler, dropping event");
+        return;
+    };
+    rooted!(in(unsafe { ctx.raw_cx() }) let global = global);
+    enter_incumbent_stack(ctx, global.handle(), |realm: &mut AutoRealm, global_obj: Handle<'_,*mut JSObject>| {
+        let cx = unsafe { realm.deref_mut().raw_cx() };
+        rooted!(in(cx) let handler = ObjectValue(handler));
+        rooted!(in(cx) let event = new_object(cx));
+        if event.get().is_null() || !fill(cx, event.handle()) {
+            report_pending_exception(cx, "inspector event");
+            return;
+        }
+        rooted!(in(cx) let mut rval = UndefinedValue());
+        if !call_value(cx, global_obj, handler.handle(), [ObjectValue(event.get())], rval.handle_mut()) {
+            report_pending_exception(cx, "inspector");
+        }
+    });
+}
+
+/// `native.listen(handler)`, `handler(event)` is given every event from now on
+unsafe extern "C" fn inspector_listen(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    let mut is_okay = true;
+    wrap_panic(&mut || {
+        let args = unsafe { CallArgs::from_vp(vp, argc) };
+        let handler = unsafe { Handle::from_raw(args.get(0)) };
+        if !is_callable(handler) {
+            unsafe { mozjs::error::throw_type_error(ctx, "inspector: handler must be a function") };
+            is_okay = false;
+            return;
+        }
+        INSPECTOR.with(|i| {
+            if let Some(inspector) = i.borrow_mut().as_mut() {
+                inspector.handler = Some(HeapRoot::new(handler.to_object()));
+            }
+        });
+        args.rval().set(UndefinedValue());
+    });
+    is_okay
+}
+
+/// `native.send(session, message)`, dropped if the session is gone
+unsafe extern "C" fn inspector_send(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    wrap_panic(&mut || {
+        let args = unsafe { CallArgs::from_vp(vp, argc) };
+        let session = args.get(0);
+        let message = arg_string(ctx, &args, 1);
+        if let (true, Some(message)) = (session.is_number(), message) {
+            let session = session.to_number() as u64;
+            INSPECTOR.with(|i| {
+                let inspector = i.borrow();
+                if let Some(outgoing) = inspector.as_ref().and_then(|i| i.sessions.get(&session)) {
+                    let _ = outgoing.send(message);
+                }
+            });
+        }
+        args.rval().set(UndefinedValue());
+    });
+    true
+}
+
+/// `native.receive()`, blocks until the next event, `null` once there can be none
+unsafe extern "C" fn inspector_receive(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    let mut is_okay = true;
+    wrap_panic(&mut || {
+        let args = unsafe { CallArgs::from_vp(vp, argc) };
+        let Some(event) = next_event(true) else {
+            args.rval().set(NullValue());
+            return;
+        };
+        rooted!(in(ctx) let obj = new_object(ctx));
+        if obj.get().is_null() || !define_event(ctx, obj.handle(), &event) {
+            is_okay = false;
+            return;
+        }
+        args.rval().set(ObjectValue(obj.get()));
+    });
+    is_okay
+}
+
+/// `native.realmId(global)`, the id of a debuggee's realm (see `realm::realm_id`)
+unsafe extern "C" fn inspector_realm_id(_ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    wrap_panic(&mut || {
+        let args = unsafe { CallArgs::from_vp(vp, argc) };
+        args.rval().set(UndefinedValue());
+        if args.get(0).is_object() {
+            // a cross compartment wrapper, from `Debugger.Object.unsafeDereference`
+            let global = unsafe { UncheckedUnwrapObject(args.get(0).to_object(), false) };
+            if let Some(id) = realm_id_of(global) {
+                args.rval().set(DoubleValue(id as f64));
+            }
+        }
+    });
+    true
+}
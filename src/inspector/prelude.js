@@ -0,0 +1,979 @@
+// Chrome DevTools Protocol
+//
+// Evaluated once by `inspector::start_inspector` in the debugger realm,
+// which `Debugger` can't see. Every other global is a debuggee.
+// `__inspectorNative.listen` is given the handler for session events,
+// `receive` blocks for the next one while paused.
+//
+// Implemented: `Runtime` (contexts, evaluation, properties) &
+// `Debugger` (scripts, breakpoints, stepping, scopes, pausing).
+// Anything else is answered with a "wasn't found" error.
+//
+// `Debugger` lines & columns are 1-based, the protocol's are 0-based.
+(function (native) {
+    'use strict';
+
+    const dbg = new Debugger();
+
+    // session id -> { runtime, debugger } (which domains are enabled)
+    const sessions = new Map();
+
+    // debuggee global (`Debugger.Object`) -> execution context id
+    const contexts = new Map();
+    const contextGlobals = new Map();
+    let nextContextId = 1;
+
+    // `Debugger.Source` -> script id, & back
+    const sourceIds = new Map();
+    const sources = new Map();
+    // script id -> its `Debugger.scriptParsed` params
+    const parsedEvents = new Map();
+    let nextScriptId = 1;
+
+    // breakpoint id -> { url, urlRegex, scriptId, lineNumber, columnNumber, condition, set: [{ script, handler }], locations }
+    const breakpoints = new Map();
+    let breakpointsActive = true;
+    let skipAllPauses = false;
+    let pauseOnExceptions = 'none';
+    let lastException;
+
+    // object id -> { value, group }
+    const objects = new Map();
+    let nextObjectId = 1;
+
+    // while paused: call frame id -> `Debugger.Frame`
+    const callFrames = new Map();
+    let paused = false;
+    // set by the command that ends a pause: 'continue', 'over', 'into' or 'out'
+    let resumeWith = 'continue';
+    let waiting = false;
+    // frames with stepping hooks, cleared by the next pause
+    let steppingFrames = [];
+
+    function send(session, message) {
+        native.send(session, JSON.stringify(message));
+    }
+
+    function broadcast(domain, method, params) {
+        for (const [session, enabled] of sessions) {
+            if (enabled[domain]) {
+                send(session, { method, params });
+            }
+        }
+    }
+
+    function debugging() {
+        for (const enabled of sessions.values()) {
+            if (enabled.debugger) {
+                return true;
+            }
+        }
+        return false;
+    }
+
+    class ProtocolError extends Error { }
+
+    /*
+     * Execution contexts, one per debuggee global
+     */
+
+    function addGlobal(global) {
+        if (contexts.has(global)) {
+            return contexts.get(global);
+        }
+        const realmId = native.realmId(global.unsafeDereference());
+        const id = realmId ?? nextContextId++;
+        contexts.set(global, id);
+        contextGlobals.set(id, global);
+        broadcast('runtime', 'Runtime.executionContextCreated', { context: describeContext(global, id) });
+        return id;
+    }
+
+    function describeContext(global, id) {
+        return {
+            id,
+            origin: '',
+            name: `realm ${id}`,
+            uniqueId: String(id),
+            auxData: { isDefault: true },
+        };
+    }
+
+    function contextOf(global) {
+        return global ? contexts.get(global) : undefined;
+    }
+
+    function globalFor(contextId) {
+        if (contextId === undefined) {
+            const first = contextGlobals.values().next();
+            if (first.done) {
+                throw new ProtocolError('Cannot find default execution context');
+            }
+            return first.value;
+        }
+        const global = contextGlobals.get(contextId);
+        if (!global) {
+            throw new ProtocolError('Cannot find context with specified id');
+        }
+        return global;
+    }
+
+    /*
+     * Scripts, one per `Debugger.Source`
+     */
+
+    function sourceUrl(source) {
+        const url = source.url ?? '';
+        return url.startsWith('/') ? `file://${url}` : url;
+    }
+
+    function registerSource(source, global) {
+        if (sourceIds.has(source)) {
+            return sourceIds.get(source);
+        }
+        const scriptId = String(nextScriptId++);
+        sourceIds.set(source, scriptId);
+        sources.set(scriptId, source);
+        const text = source.text;
+        const lines = text.split('\n');
+        const parsed = {
+            scriptId,
+            url: sourceUrl(source),
+            startLine: 0,
+            startColumn: 0,
+            endLine: lines.length - 1,
+            endColumn: lines[lines.length - 1].length,
+            executionContextId: contextOf(global) ?? 0,
+            hash: '',
+            sourceMapURL: source.sourceMapURL ?? '',
+            hasSourceURL: false,
+            isModule: false,
+            length: text.length,
+            scriptLanguage: 'JavaScript',
+        };
+        parsedEvents.set(scriptId, parsed);
+        broadcast('debugger', 'Debugger.scriptParsed', parsed);
+        return scriptId;
+    }
+
+    function sourceFor(scriptId) {
+        const source = sources.get(scriptId);
+        if (!source) {
+            throw new ProtocolError('No script for id: ' + scriptId);
+        }
+        return source;
+    }
+
+    // every script (functions included) compiled from `source`
+    function scriptsOf(source) {
+        return dbg.findScripts({ source });
+    }
+
+    function location(script, offset) {
+        const meta = script.getOffsetMetadata(offset);
+        return {
+            scriptId: registerSource(script.source, script.global),
+            lineNumber: meta.lineNumber - 1,
+            columnNumber: meta.columnNumber - 1,
+        };
+    }
+
+    /*
+     * Breakpoints
+     */
+
+    // breakable positions on `line` (0-based) at or after `column`, the first line with any
+    function resolvePositions(source, lineNumber, columnNumber) {
+        const scripts = scriptsOf(source);
+        const last = source.text.split('\n').length;
+        for (let line = lineNumber + 1; line <= last; line++) {
+            const found = [];
+            for (const script of scripts) {
+                if (line < script.startLine || line > script.startLine + script.lineCount - 1) {
+                    continue;
+                }
+                for (const position of script.getPossibleBreakpoints({ line })) {
+                    if (line === lineNumber + 1 && position.columnNumber - 1 < (columnNumber ?? 0)) {
+                        continue;
+                    }
+                    found.push({ script, offset: position.offset, columnNumber: position.columnNumber });
+                }
+            }
+            if (found.length > 0) {
+                const column = Math.min(...found.map((p) => p.columnNumber));
+                return found.filter((p) => p.columnNumber === column);
+            }
+        }
+        return [];
+    }
+
+    function matchesBreakpoint(breakpoint, source) {
+        if (breakpoint.scriptId !== undefined) {
+            return sourceIds.get(source) === breakpoint.scriptId;
+        }
+        const url = sourceUrl(source);
+        if (breakpoint.url !== undefined) {
+            return url === breakpoint.url || source.url === breakpoint.url;
+        }
+        return breakpoint.urlRegex !== undefined && new RegExp(breakpoint.urlRegex).test(url);
+    }
+
+    // sets `breakpoint` in `source`, returns the locations it resolved to
+    function applyBreakpoint(id, breakpoint, source) {
+        const positions = resolvePositions(source, breakpoint.lineNumber, breakpoint.columnNumber);
+        const handler = { hit: (frame) => hitBreakpoint(frame, id) };
+        const locations = [];
+        for (const { script, offset } of positions) {
+            script.setBreakpoint(offset, handler);
+            breakpoint.set.push({ script, handler });
+            const resolved = location(script, offset);
+            if (!locations.some((l) => l.lineNumber === resolved.lineNumber && l.columnNumber === resolved.columnNumber)) {
+                locations.push(resolved);
+            }
+        }
+        breakpoint.locations.push(...locations);
+        return locations;
+    }
+
+    function addBreakpoint(id, breakpoint) {
+        if (breakpoints.has(id)) {
+            throw new ProtocolError('Breakpoint at specified location already exists.');
+        }
+        breakpoint.set = [];
+        breakpoint.locations = [];
+        breakpoints.set(id, breakpoint);
+        for (const source of sources.values()) {
+            if (matchesBreakpoint(breakpoint, source)) {
+                applyBreakpoint(id, breakpoint, source);
+            }
+        }
+        return breakpoint.locations;
+    }
+
+    function removeBreakpoint(id) {
+        const breakpoint = breakpoints.get(id);
+        if (!breakpoint) {
+            return;
+        }
+        for (const { script, handler } of breakpoint.set) {
+            script.clearBreakpoint(handler);
+        }
+        breakpoints.delete(id);
+    }
+
+    function hitBreakpoint(frame, id) {
+        if (!breakpointsActive) {
+            return undefined;
+        }
+        const breakpoint = breakpoints.get(id);
+        if (breakpoint?.condition) {
+            const completion = frame.eval(breakpoint.condition);
+            if (!completion || !('return' in completion) || !truthy(completion.return)) {
+                return undefined;
+            }
+        }
+        return pause(frame, 'other', undefined, [id]);
+    }
+
+    function truthy(value) {
+        return value instanceof Debugger.Object || Boolean(value);
+    }
+
+    /*
+     * Remote objects
+     */
+
+    function store(value, group) {
+        const objectId = String(nextObjectId++);
+        objects.set(objectId, { value, group });
+        return objectId;
+    }
+
+    function lookup(objectId) {
+        const entry = objects.get(objectId);
+        if (!entry) {
+            throw new ProtocolError('Could not find object with given id');
+        }
+        return entry.value;
+    }
+
+    function releaseGroup(group) {
+        for (const [id, entry] of objects) {
+            if (entry.group === group) {
+                objects.delete(id);
+            }
+        }
+    }
+
+    // `name` looked up the prototype chain without running getters
+    function peek(obj, name) {
+        for (let o = obj, depth = 0; o && depth < 32; o = o.proto, depth++) {
+            const descriptor = o.getOwnPropertyDescriptor(name);
+            if (descriptor) {
+                return 'value' in descriptor ? descriptor.value : undefined;
+            }
+        }
+        return undefined;
+    }
+
+    function remote(value, group, byValue) {
+        switch (typeof value) {
+            case 'undefined':
+                return { type: 'undefined' };
+            case 'string':
+                return { type: 'string', value };
+            case 'boolean':
+                return { type: 'boolean', value };
+            case 'number':
+                if (Object.is(value, -0) || !Number.isFinite(value)) {
+                    const unserializable = Object.is(value, -0) ? '-0' : String(value);
+                    return { type: 'number', unserializableValue: unserializable, description: unserializable };
+                }
+                return { type: 'number', value, description: String(value) };
+            case 'bigint':
+                return { type: 'bigint', unserializableValue: `${value}n`, description: `${value}n` };
+            case 'symbol':
+                return { type: 'symbol', description: value.toString() };
+        }
+        if (value === null) {
+            return { type: 'object', subtype: 'null', value: null };
+        }
+        if (!(value instanceof Debugger.Object)) {
+            // `{ optimizedOut }`, `{ uninitialized }` & co from `Environment.getVariable`
+            return { type: 'undefined', description: value.optimizedOut ? '<optimized out>' : '<unavailable>' };
+        }
+        if (byValue) {
+            return { type: value.callable ? 'function' : 'object', value: toJson(value) };
+        }
+        return describeObject(value, group);
+    }
+
+    function describeObject(obj, group) {
+        const className = obj.class;
+        const objectId = store(obj, group);
+        if (obj.callable) {
+            const name = obj.name ?? obj.displayName ?? '';
+            return { type: 'function', className: 'Function', description: `function ${name}() { [native code] }`, objectId };
+        }
+        if (obj.isProxy) {
+            return { type: 'object', subtype: 'proxy', className: 'Object', description: 'Proxy', objectId };
+        }
+        const described = { type: 'object', className, description: className, objectId };
+        switch (className) {
+            case 'Array': {
+                const length = peek(obj, 'length');
+                described.subtype = 'array';
+                described.description = `Array(${length})`;
+                break;
+            }
+            case 'Error': {
+                const name = peek(obj, 'name');
+                const message = peek(obj, 'message');
+                const stack = peek(obj, 'stack');
+                described.subtype = 'error';
+                described.description = `${typeof name === 'string' ? name : 'Error'}${message ? `: ${message}` : ''}`;
+                if (typeof stack === 'string' && stack !== '') {
+                    described.description += '\n' + stack.trimEnd().split('\n').map((f) => `    at ${f}`).join('\n');
+                }
+                break;
+            }
+            case 'Promise':
+                described.subtype = 'promise';
+                break;
+            case 'Map':
+            case 'Set':
+            case 'WeakMap':
+            case 'WeakSet':
+                described.subtype = className.toLowerCase();
+                break;
+            case 'Date':
+                described.subtype = 'date';
+                break;
+            case 'RegExp':
+                described.subtype = 'regexp';
+                break;
+            case 'Object': {
+                const constructor = peek(obj, 'constructor');
+                if (constructor instanceof Debugger.Object && constructor.name) {
+                    described.className = constructor.name;
+                    described.description = constructor.name;
+                }
+                break;
+            }
+        }
+        return described;
+    }
+
+    // a debuggee object as JSON, run in its own realm
+    function toJson(obj) {
+        const completion = obj.global?.executeInGlobalWithBindings('JSON.stringify(value)', { value: obj });
+        if (completion && 'return' in completion && typeof completion.return === 'string') {
+            return JSON.parse(completion.return);
+        }
+        return undefined;
+    }
+
+    // a protocol `CallArgument` as a value in `global`
+    function fromCallArgument(argument, global) {
+        if ('objectId' in argument) {
+            return lookup(argument.objectId);
+        }
+        if ('unserializableValue' in argument) {
+            return evaluated(global.executeInGlobal(argument.unserializableValue));
+        }
+        const value = argument.value;
+        if (value !== null && typeof value === 'object') {
+            return evaluated(global.executeInGlobal(`(${JSON.stringify(value)})`));
+        }
+        return value;
+    }
+
+    function evaluated(completion) {
+        if (completion && 'return' in completion) {
+            return completion.return;
+        }
+        throw new ProtocolError('could not create the argument');
+    }
+
+    // a completion value as `{ result, exceptionDetails }`
+    function completionResult(completion, group, byValue) {
+        if (completion === null) {
+            return { result: { type: 'undefined' }, exceptionDetails: { exceptionId: 0, text: 'Execution was terminated', lineNumber: 0, columnNumber: 0 } };
+        }
+        if ('return' in completion) {
+            return { result: remote(completion.return, group, byValue) };
+        }
+        const exception = remote(completion.throw, group, false);
+        return {
+            result: exception,
+            exceptionDetails: {
+                exceptionId: 1,
+                text: 'Uncaught',
+                lineNumber: 0,
+                columnNumber: 0,
+                exception,
+            },
+        };
+    }
+
+    /*
+     * Properties
+     */
+
+    function objectProperties(obj, ownProperties, group) {
+        const result = [];
+        const internalProperties = [];
+        if (obj.isProxy) {
+            internalProperties.push({ name: '[[Target]]', value: remote(obj.proxyTarget, group) });
+            internalProperties.push({ name: '[[Handler]]', value: remote(obj.proxyHandler, group) });
+            return { result, internalProperties };
+        }
+        if (obj.class === 'Promise') {
+            internalProperties.push({ name: '[[PromiseState]]', value: remote(obj.promiseState, group) });
+            if (obj.promiseState !== 'pending') {
+                const settled = obj.promiseState === 'fulfilled' ? obj.promiseValue : obj.promiseReason;
+                internalProperties.push({ name: '[[PromiseResult]]', value: remote(settled, group) });
+            }
+        }
+        if (obj.callable && obj.script) {
+            internalProperties.push({ name: '[[FunctionLocation]]', value: {
+                type: 'object',
+                subtype: 'internal#location',
+                value: location(obj.script, obj.script.mainOffset ?? 0),
+                description: 'Object',
+            } });
+        }
+        const names = [...obj.getOwnPropertyNames(), ...obj.getOwnPropertySymbols()];
+        for (const name of names) {
+            const descriptor = obj.getOwnPropertyDescriptor(name);
+            if (!descriptor) {
+                continue;
+            }
+            const property = {
+                name: typeof name === 'symbol' ? name.toString() : name,
+                configurable: Boolean(descriptor.configurable),
+                enumerable: Boolean(descriptor.enumerable),
+                isOwn: true,
+            };
+            if (typeof name === 'symbol') {
+                property.symbol = remote(name, group);
+            }
+            if ('value' in descriptor) {
+                property.value = remote(descriptor.value, group);
+                property.writable = Boolean(descriptor.writable);
+            } else {
+                if (descriptor.get !== undefined) {
+                    property.get = remote(descriptor.get, group);
+                }
+                if (descriptor.set !== undefined) {
+                    property.set = remote(descriptor.set, group);
+                }
+            }
+            result.push(property);
+        }
+        if (!ownProperties || obj.proto) {
+            result.push({ name: '__proto__', value: remote(obj.proto, group), configurable: true, enumerable: false, writable: true, isOwn: true });
+        }
+        return { result, internalProperties };
+    }
+
+    function environmentProperties(env, group) {
+        if (env.type === 'object' || env.type === 'with') {
+            return objectProperties(env.object, true, group);
+        }
+        const result = env.names().map((name) => ({
+            name,
+            value: remote(env.getVariable(name), group),
+            configurable: false,
+            enumerable: true,
+            writable: true,
+            isOwn: true,
+        }));
+        return { result };
+    }
+
+    /*
+     * Pausing
+     */
+
+    function describeScopes(frame, group) {
+        const scopes = [];
+        let sawFunction = false;
+        for (let env = frame.environment; env; env = env.parent) {
+            if (env.optimizedOut) {
+                continue;
+            }
+            let type;
+            if (env.type === 'object' && env.parent === null) {
+                type = 'global';
+            } else if (env.type === 'with') {
+                type = 'with';
+            } else if (env.callee) {
+                type = sawFunction ? 'closure' : 'local';
+                sawFunction = true;
+            } else if (env.parent?.type === 'object' && env.parent.parent === null) {
+                // the global lexical environment, `let` & `const` of scripts
+                type = 'script';
+            } else {
+                type = 'block';
+            }
+            const scope = {
+                type,
+                object: { type: 'object', className: 'Object', description: type === 'global' ? 'Global' : 'Object', objectId: store(env, group) },
+            };
+            if (env.callee?.name) {
+                scope.name = env.callee.name;
+            }
+            scopes.push(scope);
+        }
+        return scopes;
+    }
+
+    function describeFrames(youngest) {
+        const frames = [];
+        for (let frame = youngest; frame; frame = frame.older) {
+            if (!frame.script) {
+                continue;
+            }
+            const callFrameId = String(callFrames.size + 1);
+            callFrames.set(callFrameId, frame);
+            const described = {
+                callFrameId,
+                functionName: frame.callee?.name ?? frame.callee?.displayName ?? '',
+                location: location(frame.script, frame.offset),
+                url: sourceUrl(frame.script.source),
+                scopeChain: describeScopes(frame, 'backtrace'),
+                this: remote(frame.this, 'backtrace'),
+            };
+            if (frame.callee?.script) {
+                described.functionLocation = location(frame.callee.script, frame.callee.script.mainOffset ?? 0);
+            }
+            frames.push(described);
+        }
+        return frames;
+    }
+
+    // pauses in `frame` until a debugger resumes, the result is the hook's resumption value
+    function pause(frame, reason, data, hitBreakpoints) {
+        if (paused || skipAllPauses || !debugging()) {
+            return undefined;
+        }
+        clearStepping();
+        paused = true;
+        resumeWith = 'continue';
+        const params = { callFrames: describeFrames(frame), reason, hitBreakpoints: hitBreakpoints ?? [] };
+        if (data !== undefined) {
+            params.data = data;
+        }
+        broadcast('debugger', 'Debugger.paused', params);
+        // the rest of the event loop is set aside while this spins
+        while (paused) {
+            const event = native.receive();
+            if (event === null) {
+                paused = false;
+                break;
+            }
+            handleEvent(event);
+        }
+        callFrames.clear();
+        releaseGroup('backtrace');
+        if (debugging()) {
+            broadcast('debugger', 'Debugger.resumed', {});
+            startStepping(frame, resumeWith);
+        }
+        return undefined;
+    }
+
+    function resume(mode) {
+        if (!paused) {
+            throw new ProtocolError('Can only perform operation while paused.');
+        }
+        resumeWith = mode;
+        paused = false;
+        return {};
+    }
+
+    function clearStepping() {
+        for (const frame of steppingFrames) {
+            if (frame.onStack) {
+                frame.onStep = undefined;
+                frame.onPop = undefined;
+            }
+        }
+        steppingFrames = [];
+        dbg.onEnterFrame = undefined;
+    }
+
+    // a step hook pausing at the first breakable offset away from where `start` is
+    function stepper(start, reason = 'other') {
+        const origin = start && start.script.getOffsetMetadata(start.offset);
+        return function () {
+            const meta = this.script.getOffsetMetadata(this.offset);
+            if (!meta.isBreakpoint) {
+                return undefined;
+            }
+            if (this === start && meta.lineNumber === origin.lineNumber && !meta.isStepStart) {
+                return undefined;
+            }
+            return pause(this, reason);
+        };
+    }
+
+    function watch(frame, onStep) {
+        frame.onStep = onStep;
+        // returning: carry on in the caller
+        frame.onPop = function () {
+            const older = this.older;
+            if (older && older.script) {
+                watch(older, stepper(null));
+            }
+            return undefined;
+        };
+        steppingFrames.push(frame);
+    }
+
+    function pauseOnNextStatement(reason) {
+        dbg.onEnterFrame = (frame) => {
+            dbg.onEnterFrame = undefined;
+            watch(frame, stepper(null, reason));
+        };
+    }
+
+    function startStepping(frame, mode) {
+        if (mode === 'continue' || !frame.onStack) {
+            return;
+        }
+        if (mode === 'into') {
+            pauseOnNextStatement('other');
+        }
+        if (mode === 'out') {
+            // only the pop hook, pausing in the caller
+            watch(frame, undefined);
+        } else {
+            watch(frame, stepper(frame));
+        }
+    }
+
+    dbg.onDebuggerStatement = (frame) => pause(frame, 'other');
+
+    dbg.onExceptionUnwind = (frame, value) => {
+        // SpiderMonkey can't tell ahead of time whether a throw will be
+        // caught, so 'uncaught' pauses at every throw like 'all'
+        if (pauseOnExceptions === 'none' || value === lastException) {
+            return undefined;
+        }
+        lastException = value;
+        return pause(frame, 'exception', remote(value, 'backtrace'));
+    };
+
+    dbg.onNewGlobalObject = (global) => {
+        addGlobal(dbg.addDebuggee(global));
+    };
+
+    dbg.onNewScript = (script, global) => {
+        const source = script.source;
+        registerSource(source, global ?? script.global);
+        const scriptId = sourceIds.get(source);
+        for (const [id, breakpoint] of breakpoints) {
+            if (!matchesBreakpoint(breakpoint, source) || breakpoint.locations.some((l) => l.scriptId === scriptId)) {
+                continue;
+            }
+            for (const resolved of applyBreakpoint(id, breakpoint, source)) {
+                broadcast('debugger', 'Debugger.breakpointResolved', { breakpointId: id, location: resolved });
+            }
+        }
+    };
+
+    for (const global of dbg.findAllGlobals()) {
+        addGlobal(dbg.addDebuggee(global));
+    }
+
+    /*
+     * Methods
+     */
+
+    const methods = {
+        'Runtime.enable'(params, session) {
+            sessions.get(session).runtime = true;
+            for (const [global, id] of contexts) {
+                send(session, { method: 'Runtime.executionContextCreated', params: { context: describeContext(global, id) } });
+            }
+            return {};
+        },
+        'Runtime.disable'(params, session) {
+            sessions.get(session).runtime = false;
+            return {};
+        },
+        'Runtime.runIfWaitingForDebugger'() {
+            waiting = false;
+            return {};
+        },
+        'Runtime.evaluate'(params) {
+            const completion = paused && callFrames.size > 0 && params.contextId === undefined
+                ? callFrames.get('1').eval(params.expression)
+                : globalFor(params.contextId).executeInGlobal(params.expression, { url: 'evaluate.js' });
+            return completionResult(completion, params.objectGroup, params.returnByValue);
+        },
+        'Runtime.callFunctionOn'(params) {
+            const target = params.objectId !== undefined ? lookup(params.objectId) : undefined;
+            const global = target?.global ?? globalFor(params.executionContextId);
+            const declared = global.executeInGlobal(`(${params.functionDeclaration})`);
+            if (!declared || !('return' in declared) || !(declared.return instanceof Debugger.Object)) {
+                return completionResult(declared, params.objectGroup, false);
+            }
+            const args = (params.arguments ?? []).map((a) => fromCallArgument(a, global));
+            const thisValue = target instanceof Debugger.Object ? target : undefined;
+            return completionResult(declared.return.apply(thisValue, args), params.objectGroup, params.returnByValue);
+        },
+        'Runtime.getProperties'(params) {
+            const target = lookup(params.objectId);
+            const group = objects.get(params.objectId).group;
+            if (target instanceof Debugger.Object) {
+                return objectProperties(target, params.ownProperties, group);
+            }
+            return environmentProperties(target, group);
+        },
+        'Runtime.releaseObject'(params) {
+            objects.delete(params.objectId);
+            return {};
+        },
+        'Runtime.releaseObjectGroup'(params) {
+            releaseGroup(params.objectGroup);
+            return {};
+        },
+        'Runtime.globalLexicalScopeNames'() {
+            return { names: [] };
+        },
+        'Runtime.compileScript'() {
+            return {};
+        },
+
+        'Debugger.enable'(params, session) {
+            // scripts compiled before the first debugger was enabled
+            for (const script of dbg.findScripts()) {
+                registerSource(script.source, script.global);
+            }
+            for (const parsed of parsedEvents.values()) {
+                send(session, { method: 'Debugger.scriptParsed', params: parsed });
+            }
+            sessions.get(session).debugger = true;
+            return { debuggerId: 'async-demo' };
+        },
+        'Debugger.disable'(params, session) {
+            sessions.get(session).debugger = false;
+            if (!debugging()) {
+                stopDebugging();
+            }
+            return {};
+        },
+        'Debugger.getScriptSource'(params) {
+            return { scriptSource: sourceFor(params.scriptId).text };
+        },
+        'Debugger.setBreakpointByUrl'(params) {
+            const target = params.url ?? params.urlRegex;
+            if (target === undefined) {
+                throw new ProtocolError('Either url or urlRegex must be specified.');
+            }
+            const column = params.columnNumber ?? 0;
+            const id = `${params.url !== undefined ? 1 : 2}:${params.lineNumber}:${column}:${target}`;
+            const locations = addBreakpoint(id, {
+                url: params.url,
+                urlRegex: params.urlRegex,
+                lineNumber: params.lineNumber,
+                columnNumber: params.columnNumber,
+                condition: params.condition,
+            });
+            return { breakpointId: id, locations };
+        },
+        'Debugger.setBreakpoint'(params) {
+            const { scriptId, lineNumber, columnNumber } = params.location;
+            sourceFor(scriptId);
+            const id = `${scriptId}:${lineNumber}:${columnNumber ?? 0}`;
+            const locations = addBreakpoint(id, { scriptId, lineNumber, columnNumber, condition: params.condition });
+            if (locations.length === 0) {
+                removeBreakpoint(id);
+                throw new ProtocolError('Could not resolve breakpoint');
+            }
+            return { breakpointId: id, actualLocation: locations[0] };
+        },
+        'Debugger.removeBreakpoint'(params) {
+            removeBreakpoint(params.breakpointId);
+            return {};
+        },
+        'Debugger.getPossibleBreakpoints'(params) {
+            const { scriptId, lineNumber, columnNumber } = params.start;
+            const source = sourceFor(scriptId);
+            const endLine = params.end?.lineNumber ?? lineNumber;
+            const endColumn = params.end?.columnNumber;
+            const locations = [];
+            for (const script of scriptsOf(source)) {
+                for (const position of script.getPossibleBreakpoints({ minLine: lineNumber + 1, maxLine: endLine + 1 })) {
+                    const line = position.lineNumber - 1;
+                    const column = position.columnNumber - 1;
+                    if ((line === lineNumber && column < (columnNumber ?? 0)) || (params.end && line === endLine && column >= (endColumn ?? Infinity))) {
+                        continue;
+                    }
+                    locations.push({ scriptId, lineNumber: line, columnNumber: column });
+                }
+            }
+            locations.sort((a, b) => a.lineNumber - b.lineNumber || a.columnNumber - b.columnNumber);
+            return { locations };
+        },
+        'Debugger.setBreakpointsActive'(params) {
+            breakpointsActive = Boolean(params.active);
+            return {};
+        },
+        'Debugger.setSkipAllPauses'(params) {
+            skipAllPauses = Boolean(params.skip);
+            return {};
+        },
+        'Debugger.setPauseOnExceptions'(params) {
+            if (!['none', 'uncaught', 'caught', 'all'].includes(params.state)) {
+                throw new ProtocolError('Unknown pause on exceptions mode: ' + params.state);
+            }
+            pauseOnExceptions = params.state;
+            return {};
+        },
+        'Debugger.setAsyncCallStackDepth'() {
+            return {};
+        },
+        'Debugger.setBlackboxPatterns'() {
+            return {};
+        },
+        'Debugger.pause'() {
+            if (!paused) {
+                pauseOnNextStatement('other');
+            }
+            return {};
+        },
+        'Debugger.resume'() {
+            return resume('continue');
+        },
+        'Debugger.stepOver'() {
+            return resume('over');
+        },
+        'Debugger.stepInto'() {
+            return resume('into');
+        },
+        'Debugger.stepOut'() {
+            return resume('out');
+        },
+        'Debugger.evaluateOnCallFrame'(params) {
+            const frame = callFrames.get(params.callFrameId);
+            if (!frame) {
+                throw new ProtocolError('Could not find call frame with given id');
+            }
+            return completionResult(frame.eval(params.expression), params.objectGroup, params.returnByValue);
+        },
+    };
+
+    // the last debugging session went away, run freely again
+    function stopDebugging() {
+        for (const id of [...breakpoints.keys()]) {
+            removeBreakpoint(id);
+        }
+        clearStepping();
+        pauseOnExceptions = 'none';
+        skipAllPauses = false;
+        breakpointsActive = true;
+        paused = false;
+        waiting = false;
+    }
+
+    function handleMessage(session, text) {
+        let message;
+        try {
+            message = JSON.parse(text);
+        } catch {
+            return;
+        }
+        const { id, method, params } = message;
+        const implementation = Object.hasOwn(methods, method) ? methods[method] : undefined;
+        if (!implementation) {
+            send(session, { id, error: { code: -32601, message: `'${method}' wasn't found` } });
+            return;
+        }
+        try {
+            const result = implementation(params ?? {}, session);
+            send(session, { id, result });
+        } catch (e) {
+            const message = e instanceof ProtocolError ? e.message : `internal error: ${e}`;
+            send(session, { id, error: { code: -32000, message } });
+        }
+    }
+
+    function handleEvent(event) {
+        switch (event.type) {
+            case 'connect':
+                sessions.set(event.session, { runtime: false, debugger: false });
+                break;
+            case 'disconnect':
+                sessions.delete(event.session);
+                if (!debugging()) {
+                    stopDebugging();
+                }
+                break;
+            case 'message':
+                if (sessions.has(event.session)) {
+                    handleMessage(event.session, event.message);
+                }
+                break;
+            case 'wait':
+                waiting = true;
+                while (waiting) {
+                    const next = native.receive();
+                    if (next === null) {
+                        break;
+                    }
+                    handleEvent(next);
+                }
+                if (event.brk && debugging()) {
+                    pauseOnNextStatement('Break on start');
+                }
+                break;
+        }
+    }
+
+    native.listen(handleEvent);
+})(globalThis.__inspectorNative);
+delete globalThis.__inspectorNative;
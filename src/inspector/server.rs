@@ -0,0 +1,307 @@
+use std::{
+    io,
+    net::{IpAddr,SocketAddr},
+    sync::{Arc,mpsc as std_mpsc},
+    sync::atomic::{AtomicU64,Ordering},
+};
+use tokio::{
+    io::{AsyncRead,AsyncWrite,AsyncWriteExt,BufReader},
+    net::{TcpListener,TcpStream},
+    sync::{mpsc,Notify},
+};
+#[allow(unused_imports)]
+use tracing::{debug,trace,instrument,warn,error,info};
+
+use crate::http::{Headers,read_head,write_head,status_text};
+use super::websocket::{Incoming,accept_key,read_message,write_text,write_pong,write_close};
+
+/*
+ * The inspector's network side
+ *
+ * Runs on the tokio runtime, never touches the engine. Serves the
+ * discovery endpoints DevTools polls (`/json/list`, `/json/version`)
+ * & upgrades `/<target id>` to a WebSocket. Each WebSocket is a
+ * session: its messages are handed to the JS thread as
+ * `InspectorEvent`s, & whatever the JS thread sends back is written
+ * out in order.
+ *
+ * Like node.js, a request whose Host header names anything but
+ * `localhost` or an IP of the server is refused: a web page could
+ * otherwise point its own DNS name at us (DNS rebinding) & drive the
+ * debugger.
+ *
+ * Events go down a std channel so the JS thread can block on it while
+ * paused, `wake` tells the event loop there is something to read the
+ * rest of the time.
+ *
+ */
+
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
+pub(crate) enum InspectorEvent {
+    Connected {
+        session: u64,
+        /// CDP messages (JSON text) for the client
+        outgoing: mpsc::UnboundedSender<String>,
+    },
+    Message {
+        session: u64,
+        text: String,
+    },
+    Disconnected {
+        session: u64,
+    },
+}
+
+/// The sending half of the JS thread's event queue
+#[derive(Clone)]
+pub(crate) struct EventSender {
+    pub events: std_mpsc::Sender<InspectorEvent>,
+    pub wake: Arc<Notify>,
+}
+impl EventSender {
+    /// `false` once the JS thread has gone away
+    fn send(&self, event: InspectorEvent) -> bool {
+        let sent = self.events.send(event).is_ok();
+        self.wake.notify_one();
+        sent
+    }
+}
+
+/// What the discovery endpoints advertise
+pub(crate) struct Target {
+    pub id: String,
+    pub title: String,
+    pub url: String,
+}
+
+/// Serves discovery & sessions until the runtime shuts down
+#[instrument(skip_all, fields(address = ?listener.local_addr().ok()))]
+pub(crate) async fn serve(listener: TcpListener, target: Arc<Target>, events: EventSender) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("accept failed: {}", e);
+                continue;
+            }
+        };
+        let target = target.clone();
+        let events = events.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_connection(stream, target, events).await {
+                debug!("inspector connection from '{}' failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn serve_connection(stream: TcpStream, target: Arc<Target>, events: EventSender) -> io::Result<()> {
+    let _ = stream.set_nodelay(true);
+    let local = stream.local_addr()?;
+    let (read, mut write) = stream.into_split();
+    let mut reader = BufReader::new(read);
+    let Some(head) = read_head(&mut reader).await? else {
+        return Ok(());
+    };
+    let [method, path, _] = &head.start;
+    let path = path.split(['?', '#']).next().unwrap_or("");
+    let host = head.header("host").map(str::to_string).unwrap_or_else(|| local.to_string());
+    if !is_allowed_host(&host, local.ip()) {
+        debug!("refusing host '{}'", host);
+        return write_response(&mut write, 403, "text/plain", "host not allowed").await;
+    }
+    let upgrade = head.header("upgrade").map(|u| u.eq_ignore_ascii_case("websocket")).unwrap_or(false);
+
+    if upgrade && path.strip_prefix('/') == Some(target.id.as_str()) {
+        let Some(key) = head.header("sec-websocket-key") else {
+            return write_response(&mut write, 400, "text/plain", "missing Sec-WebSocket-Key").await;
+        };
+        let headers: Headers = vec![
+            ("Upgrade".to_string(), "websocket".to_string()),
+            ("Connection".to_string(), "Upgrade".to_string()),
+            ("Sec-WebSocket-Accept".to_string(), accept_key(key)),
+        ];
+        write_head(&mut write, &format!("HTTP/1.1 101 {}", status_text(101)), &headers).await?;
+        write.flush().await?;
+        return run_session(reader, write, events).await;
+    }
+
+    match (method.as_str(), path.trim_end_matches('/')) {
+        ("GET", "/json" | "/json/list") => {
+            let body = format!(
+                "[{{\"description\":\"async-demo\",\"devtoolsFrontendUrl\":{},\"id\":{},\"title\":{},\"type\":\"node\",\"url\":{},\"webSocketDebuggerUrl\":{}}}]",
+                json_string(&format!("devtools://devtools/bundled/js_app.html?experiments=true&v8only=true&ws={}/{}", host, target.id)),
+                json_string(&target.id),
+                json_string(&target.title),
+                json_string(&target.url),
+                json_string(&format!("ws://{}/{}", host, target.id)),
+            );
+            write_response(&mut write, 200, "application/json", &body).await
+        }
+        ("GET", "/json/version") => {
+            let body = format!(
+                "{{\"Browser\":{},\"Protocol-Version\":\"1.3\"}}",
+                json_string(concat!("async-demo/", env!("CARGO_PKG_VERSION"))),
+            );
+            write_response(&mut write, 200, "application/json", &body).await
+        }
+        _ => write_response(&mut write, 404, "text/plain", "not found").await,
+    }
+}
+
+/// Relays messages both ways until either side goes away
+async fn run_session<R, W>(mut reader: R, writer: W, events: EventSender) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let session = NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed);
+    let (outgoing, receiver) = mpsc::unbounded_channel();
+    let (pongs, pong_receiver) = mpsc::unbounded_channel();
+    let writing = tokio::spawn(write_session(writer, receiver, pong_receiver));
+    if !events.send(InspectorEvent::Connected { session, outgoing }) {
+        return Ok(());
+    }
+    info!("inspector session '{}' connected", session);
+    let result = loop {
+        match read_message(&mut reader).await {
+            Ok(Some(Incoming::Text(text))) => {
+                if !events.send(InspectorEvent::Message { session, text }) {
+                    break Ok(());
+                }
+            }
+            Ok(Some(Incoming::Ping(payload))) => {
+                let _ = pongs.send(payload);
+            }
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(e),
+        }
+    };
+    // the writer closes once the JS thread drops `outgoing` too
+    drop(pongs);
+    events.send(InspectorEvent::Disconnected { session });
+    info!("inspector session '{}' disconnected", session);
+    let _ = writing.await;
+    result
+}
+
+/// Writes the JS thread's messages & the reader's pongs, then a close
+async fn write_session<W: AsyncWrite + Unpin>(mut writer: W, mut outgoing: mpsc::UnboundedReceiver<String>, mut pongs: mpsc::UnboundedReceiver<Vec<u8>>) {
+    let result: io::Result<()> = async {
+        loop {
+            tokio::select! {
+                message = outgoing.recv() => match message {
+                    Some(text) => write_text(&mut writer, &text).await?,
+                    None => break,
+                },
+                Some(payload) = pongs.recv() => write_pong(&mut writer, &payload).await?,
+            }
+        }
+        write_close(&mut writer).await
+    }.await;
+    if let Err(e) = result {
+        debug!("inspector session write failed: {}", e);
+    }
+}
+
+async fn write_response<W: AsyncWrite + Unpin>(writer: &mut W, status: u16, content_type: &str, body: &str) -> io::Result<()> {
+    let headers: Headers = vec![
+        ("Content-Type".to_string(), format!("{}; charset=utf-8", content_type)),
+        ("Content-Length".to_string(), body.len().to_string()),
+        ("Connection".to_string(), "close".to_string()),
+    ];
+    write_head(writer, &format!("HTTP/1.1 {} {}", status, status_text(status)), &headers).await?;
+    writer.write_all(body.as_bytes()).await?;
+    writer.flush().await?;
+    writer.shutdown().await
+}
+
+/// Whether `host` (a Host header, port optional) names this server directly
+///
+/// `localhost`, a loopback IP or `local`, the address the request came in on.
+fn is_allowed_host(host: &str, local: IpAddr) -> bool {
+    let name = match host.strip_prefix('[') {
+        Some(rest) => match rest.split_once(']') {
+            Some((ip, port)) if port.is_empty() || port.starts_with(':') => ip,
+            _ => return false,
+        },
+        None => host.rsplit_once(':').map(|(name, _)| name).unwrap_or(host),
+    };
+    if name.eq_ignore_ascii_case("localhost") {
+        return true;
+    }
+    match name.parse::<IpAddr>() {
+        Ok(ip) => ip.is_loopback() || ip == local,
+        Err(_) => false,
+    }
+}
+
+/// `s` as a JSON string literal
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Binds `address` now, so a bad address is reported before any script runs
+pub(crate) fn bind(address: SocketAddr) -> io::Result<TcpListener> {
+    let listener = std::net::TcpListener::bind(address)?;
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_hosts_are_allowed() {
+        let local: IpAddr = "127.0.0.1".parse().unwrap();
+        for host in ["localhost", "LOCALHOST:9229", "127.0.0.1", "127.0.0.1:9229", "[::1]", "[::1]:9229"] {
+            assert!(is_allowed_host(host, local), "{}", host);
+        }
+        let lan: IpAddr = "192.168.1.20".parse().unwrap();
+        assert!(is_allowed_host("192.168.1.20:9229", lan));
+    }
+
+    #[test]
+    fn other_hosts_are_refused() {
+        let local: IpAddr = "127.0.0.1".parse().unwrap();
+        for host in ["evil.example", "evil.example:9229", "localhost.evil.example", "192.168.1.20:9229", "[::1]x", "[::1", ""] {
+            assert!(!is_allowed_host(host, local), "{}", host);
+        }
+    }
+
+    #[tokio::test]
+    async fn rebound_requests_are_refused() {
+        let listener = bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let address = listener.local_addr().unwrap();
+        let target = Arc::new(Target { id: "target".to_string(), title: "test".to_string(), url: String::new() });
+        let (events, _receiver) = std_mpsc::channel();
+        tokio::spawn(serve(listener, target, EventSender { events, wake: Arc::new(Notify::new()) }));
+
+        let get = |host: &'static str| async move {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            let request = format!("GET /json/version HTTP/1.1\r\nHost: {}\r\n\r\n", host);
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            tokio::io::AsyncReadExt::read_to_string(&mut stream, &mut response).await.unwrap();
+            response
+        };
+        assert!(get("evil.example:9229").await.starts_with("HTTP/1.1 403"));
+        assert!(get("localhost:9229").await.starts_with("HTTP/1.1 200"));
+    }
+}
@@ -0,0 +1,265 @@
+use std::{
+    io,
+};
+use tokio::{
+    io::{AsyncRead,AsyncReadExt,AsyncWrite,AsyncWriteExt},
+};
+#[allow(unused_imports)]
+use tracing::{debug,trace,instrument,warn,error,info};
+
+/*
+ * WebSocket (RFC 6455), server side only
+ *
+ * Just enough for the inspector: text messages (fragmented or not),
+ * pings & closing. Reading & writing are separate so each half can
+ * live in its own task. Frames from the client must be masked, ours never
+ * are. Extensions (e.g. compression) are never negotiated.
+ *
+ */
+
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Larger messages close the connection
+const MAX_MESSAGE_SIZE: u64 = 64 * 1024 * 1024;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+/// `Sec-WebSocket-Accept` for the client's `Sec-WebSocket-Key`
+pub(crate) fn accept_key(key: &str) -> String {
+    base64_encode(&sha1(format!("{}{}", key.trim(), ACCEPT_GUID).as_bytes()))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// A complete message from the client
+pub(crate) enum Incoming {
+    Text(String),
+    /// must be answered with `write_pong`
+    Ping(Vec<u8>),
+}
+
+/// Reads the next message, `None` once the client closes (or hangs up)
+pub(crate) async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<Incoming>> {
+    let mut message: Vec<u8> = Vec::new();
+    // within a text message, until its final frame
+    let mut fragmented = false;
+    loop {
+        let Some((fin, opcode, payload)) = read_frame(reader).await? else {
+            return Ok(None);
+        };
+        match opcode {
+            OPCODE_PING => return Ok(Some(Incoming::Ping(payload))),
+            OPCODE_PONG => { },
+            OPCODE_CLOSE => return Ok(None),
+            OPCODE_BINARY => return Err(invalid("websocket: binary messages are not supported")),
+            OPCODE_TEXT if !fragmented => {
+                message = payload;
+                fragmented = true;
+            }
+            OPCODE_CONTINUATION if fragmented => {
+                if (message.len() + payload.len()) as u64 > MAX_MESSAGE_SIZE {
+                    return Err(invalid("websocket: message too large"));
+                }
+                message.extend_from_slice(&payload);
+            }
+            _ => return Err(invalid("websocket: unexpected opcode")),
+        }
+        if fragmented && fin {
+            return String::from_utf8(message)
+                .map(|text| Some(Incoming::Text(text)))
+                .map_err(|_| invalid("websocket: text message isn't utf-8"));
+        }
+    }
+}
+
+/// `(fin, opcode, unmasked payload)`, `None` on EOF between frames
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<(bool, u8, Vec<u8>)>> {
+    let mut head = [0u8; 2];
+    match reader.read_exact(&mut head).await {
+        Ok(_) => { },
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let fin = head[0] & 0x80 != 0;
+    if head[0] & 0x70 != 0 {
+        return Err(invalid("websocket: reserved bits set"));
+    }
+    let opcode = head[0] & 0x0F;
+    if head[1] & 0x80 == 0 {
+        return Err(invalid("websocket: client frames must be masked"));
+    }
+    let len = match head[1] & 0x7F {
+        126 => reader.read_u16().await? as u64,
+        127 => reader.read_u64().await?,
+        len => len as u64,
+    };
+    if len > MAX_MESSAGE_SIZE {
+        return Err(invalid("websocket: message too large"));
+    }
+    let mut mask = [0u8; 4];
+    reader.read_exact(&mut mask).await?;
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload).await?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok(Some((fin, opcode, payload)))
+}
+
+/// Sends `text` as a single unfragmented frame
+pub(crate) async fn write_text<W: AsyncWrite + Unpin>(writer: &mut W, text: &str) -> io::Result<()> {
+    write_frame(writer, OPCODE_TEXT, text.as_bytes()).await
+}
+
+pub(crate) async fn write_pong<W: AsyncWrite + Unpin>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    write_frame(writer, OPCODE_PONG, payload).await
+}
+
+/// Closes the connection from our side, or answers the client's close
+pub(crate) async fn write_close<W: AsyncWrite + Unpin>(writer: &mut W) -> io::Result<()> {
+    // 1001 "going away"
+    write_frame(writer, OPCODE_CLOSE, &1001u16.to_be_bytes()).await
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut head = Vec::with_capacity(10);
+    head.push(0x80 | opcode);
+    match payload.len() {
+        len @ 0..=125 => head.push(len as u8),
+        len @ 126..=0xFFFF => {
+            head.push(126);
+            head.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            head.push(127);
+            head.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    writer.write_all(&head).await?;
+    writer.write_all(payload).await?;
+    writer.flush().await
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (h, v) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(v);
+        }
+    }
+    let mut out = [0u8; 20];
+    for (chunk, word) in out.chunks_mut(4).zip(h) {
+        chunk.copy_from_slice(&word.to_be_bytes());
+    }
+    out
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 0x3F] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A client frame, masked as clients must
+    fn masked(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![if fin { 0x80 } else { 0 } | opcode, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    async fn read(bytes: &[u8]) -> io::Result<Option<Incoming>> {
+        let mut reader = bytes;
+        read_message(&mut reader).await
+    }
+
+    #[test]
+    fn accept_keys_follow_the_rfc() {
+        // the example handshake of RFC 6455 section 1.3
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert_eq!(sha1(b"abc").iter().map(|b| format!("{:02x}", b)).collect::<String>(), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(base64_encode(b"ab"), "YWI=");
+    }
+
+    #[tokio::test]
+    async fn fragments_make_one_message() {
+        let mut bytes = masked(false, OPCODE_TEXT, b"hel");
+        bytes.extend(masked(false, OPCODE_CONTINUATION, b"lo "));
+        bytes.extend(masked(true, OPCODE_CONTINUATION, b"there"));
+        assert!(matches!(read(&bytes).await, Ok(Some(Incoming::Text(text))) if text == "hello there"));
+    }
+
+    #[tokio::test]
+    async fn pings_close_and_eof_are_told_apart() {
+        assert!(matches!(read(&masked(true, OPCODE_PING, b"hi")).await, Ok(Some(Incoming::Ping(payload))) if payload == b"hi"));
+        assert!(matches!(read(&masked(true, OPCODE_CLOSE, &[])).await, Ok(None)));
+        assert!(matches!(read(&[]).await, Ok(None)));
+    }
+
+    #[tokio::test]
+    async fn invalid_frames_are_errors() {
+        let unmasked = [0x81, 0x02, b'h', b'i'];
+        for bytes in [unmasked.to_vec(), masked(true, OPCODE_BINARY, b"hi"), masked(true, OPCODE_CONTINUATION, b"hi")] {
+            let outcome = read(&bytes).await;
+            assert_eq!(outcome.err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+        }
+    }
+
+    #[tokio::test]
+    async fn long_messages_use_an_extended_length() {
+        let mut written = Vec::new();
+        write_text(&mut written, &"x".repeat(200)).await.unwrap();
+        assert_eq!(&written[..4], &[0x80 | OPCODE_TEXT, 126, 0, 200]);
+        assert_eq!(written.len(), 4 + 200);
+    }
+}
@@ -1,96 +1,187 @@
-use std::{
-    ptr,
-    ptr::NonNull,
-};
 use mozjs::{
     rooted,
+    context::{JSContext},
     rust::{
-        JSEngine, Runtime, SIMPLE_GLOBAL_CLASS,
-        RealmOptions,
-        wrappers2::{
-            InitRealmStandardClasses,
-            JS_DefineFunction,
-        },
-    },
-    glue::CreateJobQueue,
-    jsapi::{
-        SetJobQueue, SetPromiseRejectionTrackerCallback,
-        OnNewGlobalHookOption, JS_NewGlobalObject,
-        Value, CallArgs, Heap,
+        JSEngine, Runtime,
     },
     jsval::UndefinedValue,
-    realm::AutoRealm,
-    panic::wrap_panic,
 };
-use tracing_subscriber::FmtSubscriber;
-use tracing::{Level,info};
+use tracing_subscriber::{
+    fmt::{self,format::FmtSpan},
+    filter::{Targets},
+    prelude::*,
+};
+use tracing::{Level,info,warn,error};
 mod runtime;
 mod future_callback;
+mod microtask;
+mod clone;
+mod globals;
+mod realm;
+mod worker;
+mod message_channel;
+mod console;
+mod fs;
+mod byte_stream;
+mod streams;
+mod net;
+mod http;
+mod fetch;
+mod serve;
+mod process;
+mod signal;
+mod inspector;
+mod test_runner;
 use self::{
-    runtime::callback::JOB_QUEUE_TRAPS,
+    runtime::callback::install_job_queue,
+    runtime::roots::install_root_tracer,
+    runtime::metrics::{install_gc_metrics,serve_prometheus},
+    runtime::introspection::{enable_dump_on_signal,install_dump_on_signal},
+    runtime::profiler::{default_profile_path,start_profiler,stop_profiler},
+    runtime::coverage::{enable_coverage,collect_coverage,write_coverage},
     runtime::incumbent_stack::{enter_incumbent_stack},
-    future_callback::tokio_sleep_ms,
+    globals::define_globals,
+    realm::new_global,
+    inspector::{InspectMode,start_inspector},
+    test_runner::{ReportFormat,TestOptions,run_tests},
 };
 
 fn main() {
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(Level::WARN)
-        .with_writer(std::io::stderr)
-        .finish();
-    tracing::subscriber::set_global_default(subscriber)
-        .unwrap();
+    // scripts' `console.log` is `INFO`, the runtime itself only warns,
+    // `RUST_LOG=async_demo::runtime=trace` follows every promise
+    let filter = std::env::var("RUST_LOG").ok()
+        .and_then(|directives| match directives.parse::<Targets>() {
+            Ok(filter) => Some(filter),
+            Err(e) => {
+                eprintln!("ignoring RUST_LOG: {}", e);
+                None
+            }
+        })
+        .unwrap_or_else(|| Targets::new()
+            .with_target(console::TARGET, Level::INFO)
+            .with_default(Level::WARN));
+    // closing a span logs how long it was busy & idle
+    tracing_subscriber::registry()
+        .with(fmt::layer().with_writer(std::io::stderr).with_span_events(FmtSpan::CLOSE))
+        .with(filter)
+        .init();
 
     info!("logger init");
 
+    let mut inspect = None;
+    let mut profile = None;
+    let mut coverage: Option<std::path::PathBuf> = None;
+    // microseconds, node.js' default
+    let mut profile_interval = std::time::Duration::from_micros(1000);
+    // `async-demo test [paths]` runs tests rather than the demo
+    let mut test_paths: Option<Vec<std::path::PathBuf>> = None;
+    let mut test_options = TestOptions::default();
+    for arg in std::env::args().skip(1) {
+        if !arg.starts_with("--") {
+            match test_paths.as_mut() {
+                Some(paths) => paths.push(arg.into()),
+                None if arg == "test" => test_paths = Some(Vec::new()),
+                None => warn!("ignoring unknown argument '{}'", arg),
+            }
+            continue;
+        }
+        let (flag, value) = match arg.split_once('=') {
+            Some((flag, value)) => (flag, Some(value)),
+            None => (arg.as_str(), None),
+        };
+        match flag {
+            // `kill -USR1 <pid>` prints what every runtime is waiting on
+            "--dump-on-sigusr1" => enable_dump_on_signal(),
+            // `--inspect[=host:port]`, like node.js
+            "--inspect" | "--inspect-wait" | "--inspect-brk" => {
+                let mode = match flag {
+                    "--inspect" => InspectMode::Run,
+                    "--inspect-wait" => InspectMode::Wait,
+                    _ => InspectMode::Break,
+                };
+                match value.unwrap_or(inspector::DEFAULT_ADDRESS).parse() {
+                    Ok(address) => inspect = Some((address, mode)),
+                    Err(e) => warn!("ignoring '{}': {}", arg, e),
+                }
+            }
+            // `--cpu-prof[=path]`, like node.js
+            "--cpu-prof" => profile = Some(value.map(Into::into).unwrap_or_else(default_profile_path)),
+            "--cpu-prof-interval" => match value.map(str::parse::<u64>) {
+                Some(Ok(micros)) if micros > 0 => profile_interval = std::time::Duration::from_micros(micros),
+                _ => warn!("ignoring '{}', expected microseconds", arg),
+            },
+            // `--coverage[=path]`, LCOV of every script file ran
+            "--coverage" => coverage = Some(value.unwrap_or("lcov.info").into()),
+            // `--test-reporter=tap|junit` & `--test-timeout=<ms>`, like node.js
+            "--test-reporter" => match value {
+                Some("tap") => test_options.format = ReportFormat::Tap,
+                Some("junit") => test_options.format = ReportFormat::Junit,
+                _ => warn!("ignoring '{}', expected 'tap' or 'junit'", arg),
+            },
+            "--test-timeout" => match value.map(str::parse::<u64>) {
+                Some(Ok(millis)) if millis > 0 => test_options.timeout = std::time::Duration::from_millis(millis),
+                _ => warn!("ignoring '{}', expected milliseconds", arg),
+            },
+            _ => warn!("ignoring unknown argument '{}'", arg),
+        }
+    }
+
     let tokio_rt = tokio::runtime::Runtime::new().unwrap();
     let _guard = tokio_rt.enter();
+    // e.g. `METRICS_ADDR=127.0.0.1:9464`, see `runtime::metrics`
+    if let Ok(address) = std::env::var("METRICS_ADDR") {
+        match address.parse() {
+            Ok(address) => {
+                tokio_rt.spawn(async move {
+                    if let Err(e) = serve_prometheus(address).await {
+                        error!("could not serve metrics on '{}': {}", address, e);
+                    }
+                });
+            }
+            Err(e) => error!("ignoring METRICS_ADDR '{}': {}", address, e),
+        }
+    }
+    // the engine only allows this before the first runtime
+    if coverage.is_some() {
+        enable_coverage();
+    }
     let engine = JSEngine::init().unwrap();
+    worker::set_engine_handle(engine.handle());
     let mut runtime = Runtime::new(engine.handle());
     let context = runtime.cx();
-    unsafe {
-        // setup using a hacky thread local queue
-        // no debug interupts
-        //
-        // this has to occur before any globals
-        let job_queue = CreateJobQueue(
-            &JOB_QUEUE_TRAPS,
-            ptr::null(),
-            ptr::null_mut(),
-        );
-        SetJobQueue(context.raw_cx(), job_queue);
+    // this has to occur before any globals
+    install_job_queue(context);
+    install_root_tracer(context);
+    install_gc_metrics(unsafe { context.raw_cx() });
+    install_dump_on_signal();
+    let exit_coverage = coverage.clone();
+    signal::set_exit_hook(move |context| finish(context, exit_coverage.as_deref()));
+    // before any script runs, see `start_profiler`
+    if let Some(path) = profile {
+        start_profiler(context, path, profile_interval);
+    }
+    // before any globals, so every script is seen
+    if let Some((address, mode)) = inspect {
+        if let Err(e) = start_inspector(context, address, mode) {
+            error!("could not start the inspector on '{}': {}", address, e);
+        }
+    }
 
-        // don't setup a promis rejection tracker
-        // because I know fuck all about java script
-        SetPromiseRejectionTrackerCallback(
-            context.raw_cx(),
-            None,
-            ptr::null_mut(),
-        );
+    if let Some(paths) = test_paths {
+        let passed = run_tests(context, &paths, &test_options);
+        finish(context, coverage.as_deref());
+        std::process::exit(if passed { 0 } else { 1 });
     }
-    let h_option = OnNewGlobalHookOption::FireOnNewGlobalHook;
-    let c_option = RealmOptions::default();
 
     for realm_id in 1..=10 {
-        rooted!(in(unsafe { context.raw_cx() }) let global = unsafe {
-            JS_NewGlobalObject(
-                context.raw_cx(),
-                &SIMPLE_GLOBAL_CLASS,
-                ptr::null_mut(),
-                h_option,
-                &*c_option,
-            )
-        });
+        rooted!(in(unsafe { context.raw_cx() }) let global = new_global(context));
 
         enter_incumbent_stack(context, global.handle(), |realm,global_obj| {
         //let mut realm = AutoRealm::new_from_handle(context, global.handle());
         //let (global_obj, realm) = realm.global_and_reborrow();
         //push_incumbent_stack(Heap::boxed(global_obj.get()));
         
-            unsafe {
-                InitRealmStandardClasses(realm);
-                JS_DefineFunction(realm,global_obj,c"print_stuff".as_ptr(),Some(print_stuff),1,0,);
-                JS_DefineFunction(realm,global_obj,c"sleep_ms".as_ptr(),Some(tokio_sleep_ms),1,0,);
-            }
+            define_globals(realm, global_obj);
 
             let script = format!(r#"
                 let callCount = 0;
@@ -103,7 +194,7 @@ fn main() {
                         let slept = await sleep_ms(sleepDuration);
                         callCount++;
                         let text = `promise id: '${{myId}}' call count: '${{callCount}}' I slept for '${{slept}}' ms`;
-                        print_stuff(text);
+                        console.log(text);
                     }}
                 }}
                 
@@ -118,25 +209,19 @@ fn main() {
     }
 
     crate::runtime::checkpoint::runtime_checkpoint(context);
+    finish(context, coverage.as_deref());
+    // before `engine` is dropped, or it waits on this handle forever
+    worker::clear_engine_handle();
 }
 
-
-unsafe extern "C" fn print_stuff(
-    ctx: *mut mozjs::context::RawJSContext,
-    argc: u32,
-    vp: *mut Value,
-) -> bool {
-    let mut is_okay = true;
-    wrap_panic(&mut || {
-        let args = unsafe { CallArgs::from_vp(vp, argc) };
-        if args.argc_ < 1 {
-            is_okay = false;
-            return;
+/// Writes what `--cpu-prof` & `--coverage` asked for, once the event loop is done
+fn finish(context: &mut JSContext, coverage: Option<&std::path::Path>) {
+    stop_profiler(context);
+    if let Some(path) = coverage {
+        collect_coverage(context);
+        if let Err(e) = write_coverage(path) {
+            error!("could not write coverage to '{}': {}", path.display(), e);
         }
-        let arg = args.get(0).to_string();
-        let s = unsafe { mozjs::conversions::jsstr_to_string(ctx, NonNull::new(arg).unwrap()) };
-        println!("{}", s);
-        args.rval().set(UndefinedValue());
-    });
-    true
+    }
 }
+
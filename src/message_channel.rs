@@ -0,0 +1,408 @@
+use std::{
+    cell::{RefCell,LazyCell},
+    collections::{BTreeMap,VecDeque},
+    ptr::{NonNull},
+    rc::{Rc},
+    sync::atomic::{AtomicU64,Ordering},
+};
+use mozjs::{rooted};
+use mozjs::{
+    context::{JSContext,RawJSContext},
+    gc::{Handle},
+    jsapi::{
+        JSObject,JSTracer,Value,CallArgs,Heap,JSPROP_ENUMERATE,
+        CurrentGlobalOrNull,JS_NewPlainObject,
+    },
+    jsval::{UndefinedValue,ObjectValue},
+    error::{throw_type_error},
+    rust::wrappers::{JS_DefineProperty},
+    rust::wrappers2::JS_DefineFunction,
+    panic::wrap_panic,
+};
+#[allow(unused_imports)]
+use tracing::{debug,trace,instrument,warn,error,info};
+
+use crate::runtime::{
+    host_object::{new_host_object,host_object_data,take_host_object_data},
+    message::{dispatch_message},
+    queue::{Macrotask,insert_macrotask},
+    roots::{trace_object},
+    structured_clone::{StructuredCloneData,write_structured_clone,CloneScope,transfer_list},
+};
+
+/*
+ * MessageChannel & MessagePort
+ *
+ * Both ends of a channel live in the same runtime (thread), but
+ * possibly different realms. A port's JS object is just a handle
+ * to an entry in `PORTS`, so when a port is transferred the entry
+ * is pointed at the new object (and realm) and nothing else changes.
+ *
+ * Messages are delivered as macrotasks, they do not keep the event
+ * loop alive on their own. A port's object is rooted until the port
+ * is closed.
+ *
+ * Ports can't be transferred to another thread (e.g. posted to a
+ * worker), the structured clone refuses to. Port ids are unique
+ * across threads all the same, so one can never be mistaken for
+ * another thread's port.
+ */
+
+static NEXT_PORT_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    static PORTS: LazyCell<RefCell<BTreeMap<u64,PortEntry>>> = LazyCell::new(|| RefCell::new(BTreeMap::new()));
+}
+
+struct PortEntry {
+    /// `None` while the port is being transferred
+    object: Option<Box<Heap<*mut JSObject>>>,
+    global: Option<Rc<Box<Heap<*mut JSObject>>>>,
+    /// `None` once either side has been closed
+    entangled: Option<u64>,
+    /// messages that arrived while the port was being transferred
+    pending: VecDeque<StructuredCloneData>,
+}
+
+/// Owned by a `MessagePort` object
+struct MessagePortHandle {
+    id: u64,
+    /// set when the object gives up the port (transfer/close)
+    detached: bool,
+}
+impl Drop for MessagePortHandle {
+    fn drop(&mut self) {
+        if !self.detached {
+            // collected without being closed
+            close_port(self.id);
+        }
+    }
+}
+
+fn next_port_id() -> u64 {
+    NEXT_PORT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Reports every port's object & realm to the GC, see `roots`
+pub(crate) unsafe fn trace_ports(trc: *mut JSTracer) {
+    PORTS.with(|p| {
+        for entry in p.borrow().values() {
+            if let (Some(object), Some(global)) = (&entry.object, &entry.global) {
+                unsafe {
+                    trace_object(trc, object, c"message port");
+                    trace_object(trc, global, c"message port global");
+                }
+            }
+        }
+    });
+}
+
+/// `new MessageChannel()`
+#[instrument(skip_all,name="message_channel_constructor_entry_point")]
+pub unsafe extern "C" fn message_channel_constructor(
+    ctx: *mut RawJSContext,
+    argc: u32,
+    vp: *mut Value,
+) -> bool {
+    let mut is_okay = true;
+    wrap_panic(&mut || {
+        let args = unsafe { CallArgs::from_vp(vp, argc) };
+        rooted!(in(ctx) let current_global = unsafe { CurrentGlobalOrNull(ctx) });
+        if current_global.get().is_null() {
+            error!("global is null");
+            is_okay = false;
+            return;
+        }
+        let (id1, id2) = (next_port_id(), next_port_id());
+        for (id, other) in [(id1, id2), (id2, id1)] {
+            PORTS.with(|p| p.borrow_mut().insert(id, PortEntry {
+                object: None,
+                global: None,
+                entangled: Some(other),
+                pending: VecDeque::new(),
+            }));
+        }
+        rooted!(in(ctx) let port1 = new_port_object(ctx, id1));
+        rooted!(in(ctx) let port2 = new_port_object(ctx, id2));
+        rooted!(in(ctx) let channel = unsafe { JS_NewPlainObject(ctx) });
+        if port1.get().is_null() || port2.get().is_null() || channel.get().is_null() {
+            error!("could not allocate message channel");
+            is_okay = false;
+            return;
+        }
+        rooted!(in(ctx) let port1_val = ObjectValue(port1.get()));
+        rooted!(in(ctx) let port2_val = ObjectValue(port2.get()));
+        unsafe {
+            JS_DefineProperty(ctx, channel.handle(), c"port1".as_ptr(), port1_val.handle(), JSPROP_ENUMERATE as u32);
+            JS_DefineProperty(ctx, channel.handle(), c"port2".as_ptr(), port2_val.handle(), JSPROP_ENUMERATE as u32);
+        }
+        args.rval().set(ObjectValue(channel.get()));
+    });
+    is_okay
+}
+
+/// Creates the JS object for port `id` in the current realm & points the entry at it
+///
+/// Any messages that arrived while the port had no object are queued
+/// for delivery. Returns null on failure.
+fn new_port_object(ctx: *mut RawJSContext, id: u64) -> *mut JSObject {
+    rooted!(in(ctx) let global = unsafe { CurrentGlobalOrNull(ctx) });
+    rooted!(in(ctx) let port = new_host_object(ctx, MessagePortHandle { id, detached: false }));
+    if port.get().is_null() || global.get().is_null() {
+        return std::ptr::null_mut();
+    }
+    let mut safe_ctx = unsafe { JSContext::from_ptr(NonNull::new(ctx).unwrap()) };
+    unsafe {
+        JS_DefineFunction(&mut safe_ctx, port.handle(), c"postMessage".as_ptr(), Some(port_post_message), 2, 0);
+        JS_DefineFunction(&mut safe_ctx, port.handle(), c"start".as_ptr(), Some(port_start), 0, 0);
+        JS_DefineFunction(&mut safe_ctx, port.handle(), c"close".as_ptr(), Some(port_close), 0, 0);
+    }
+    let pending = PORTS.with(|p| {
+        let mut ports = p.borrow_mut();
+        let Some(entry) = ports.get_mut(&id) else {
+            return VecDeque::new();
+        };
+        entry.object = Some(Heap::boxed(port.get()));
+        entry.global = Some(Rc::new(Heap::boxed(global.get())));
+        std::mem::take(&mut entry.pending)
+    });
+    for data in pending {
+        queue_delivery(id, data);
+    }
+    port.get()
+}
+
+/// Queues a macrotask which delivers `data` to port `id`
+///
+/// The port's object is looked up when the task runs, so a port
+/// transferred in the meantime still gets the message.
+fn queue_delivery(id: u64, data: StructuredCloneData) {
+    insert_macrotask(Macrotask::new("message port", move |ctx: &mut JSContext| {
+        let target = PORTS.with(|p| {
+            let mut ports = p.borrow_mut();
+            let entry = ports.get_mut(&id)?;
+            match (&entry.object, &entry.global) {
+                (Some(object), Some(global)) => Some((Heap::boxed(object.get()), global.clone(), data)),
+                _ => {
+                    entry.pending.push_back(data);
+                    None
+                }
+            }
+        });
+        if let Some((object, global, data)) = target {
+            dispatch_message(ctx, &object, &global, &data);
+        }
+    }));
+}
+
+/// Disentangles & forgets port `id`
+fn close_port(id: u64) {
+    let _ = PORTS.with(|p| {
+        let mut ports = p.try_borrow_mut().ok()?;
+        let entry = ports.remove(&id)?;
+        if let Some(other) = entry.entangled {
+            if let Some(other) = ports.get_mut(&other) {
+                other.entangled = None;
+            }
+        }
+        Some(())
+    });
+}
+
+fn this_port<'a>(args: &CallArgs) -> Option<&'a mut MessagePortHandle> {
+    let this = args.thisv();
+    if !this.is_object() {
+        return None;
+    }
+    unsafe { host_object_data::<MessagePortHandle>(this.to_object()) }
+}
+
+/// `port.postMessage(value, transfer)`
+unsafe extern "C" fn port_post_message(
+    ctx: *mut RawJSContext,
+    argc: u32,
+    vp: *mut Value,
+) -> bool {
+    let mut is_okay = true;
+    wrap_panic(&mut || {
+        let args = unsafe { CallArgs::from_vp(vp, argc) };
+        let Some(id) = this_port(&args).map(|port| port.id) else {
+            unsafe { throw_type_error(ctx, "postMessage: this is not an open MessagePort") };
+            is_okay = false;
+            return;
+        };
+        rooted!(in(ctx) let value = args.get(0).get());
+        rooted!(in(ctx) let mut transfer = UndefinedValue());
+        if !transfer_list(ctx, unsafe { Handle::from_raw(args.get(1)) }, transfer.handle_mut()) {
+            is_okay = false;
+            return;
+        }
+        // serializing can detach ports, so the target is read afterwards
+        let Ok(data) = write_structured_clone(ctx, value.handle(), transfer.handle(), CloneScope::SameThread) else {
+            is_okay = false;
+            return;
+        };
+        match PORTS.with(|p| p.borrow().get(&id).and_then(|entry| entry.entangled)) {
+            Some(target) => queue_delivery(target, data),
+            None => trace!("port '{}' is not entangled, dropping message", id),
+        };
+        args.rval().set(UndefinedValue());
+    });
+    is_okay
+}
+
+/// `port.start()`, ports are always started so this does nothing
+unsafe extern "C" fn port_start(
+    _ctx: *mut RawJSContext,
+    argc: u32,
+    vp: *mut Value,
+) -> bool {
+    wrap_panic(&mut || {
+        let args = unsafe { CallArgs::from_vp(vp, argc) };
+        args.rval().set(UndefinedValue());
+    });
+    true
+}
+
+/// `port.close()`
+unsafe extern "C" fn port_close(
+    _ctx: *mut RawJSContext,
+    argc: u32,
+    vp: *mut Value,
+) -> bool {
+    wrap_panic(&mut || {
+        let args = unsafe { CallArgs::from_vp(vp, argc) };
+        if let Some(port) = this_port(&args) {
+            port.detached = true;
+            close_port(port.id);
+            unsafe { take_host_object_data(args.thisv().to_object()) };
+        }
+        args.rval().set(UndefinedValue());
+    });
+    true
+}
+
+/*
+ * Transfer support, called from the structured clone callbacks
+ *
+ */
+
+/// Is `obj` a port which can be transferred
+pub(crate) fn can_transfer_port(obj: *mut JSObject) -> bool {
+    unsafe { host_object_data::<MessagePortHandle>(obj) }.is_some()
+}
+
+/// Detaches `obj` from its port, returning the port id to serialize
+pub(crate) fn write_transfer_port(obj: *mut JSObject) -> Option<u64> {
+    let port = unsafe { host_object_data::<MessagePortHandle>(obj) }?;
+    port.detached = true;
+    let id = port.id;
+    let exists = PORTS.with(|p| {
+        let mut ports = p.borrow_mut();
+        let Some(entry) = ports.get_mut(&id) else {
+            return false;
+        };
+        entry.object = None;
+        entry.global = None;
+        true
+    });
+    unsafe { take_host_object_data(obj) };
+    exists.then_some(id)
+}
+
+/// Creates the object for a transferred port in the current realm
+///
+/// Fails when the port belongs to another runtime, which a port
+/// written for another thread would (see `CloneScope`).
+pub(crate) fn read_transfer_port(ctx: *mut RawJSContext, id: u64) -> Option<*mut JSObject> {
+    if !PORTS.with(|p| p.borrow().contains_key(&id)) {
+        warn!("port '{}' is unknown to this runtime", id);
+        return None;
+    }
+    let obj = new_port_object(ctx, id);
+    (!obj.is_null()).then_some(obj)
+}
+
+#[cfg(test)]
+mod tests {
+    use mozjs::{rooted};
+    use crate::runtime::testing::{with_runtime,new_test_global,eval_async,TempDir};
+
+    fn run(body: &str) -> Result<String,String> {
+        with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            eval_async(ctx, global.handle(), body)
+        })
+    }
+
+    #[test]
+    fn messages_cross_the_channel() {
+        let got = run(r#"
+            const { port1, port2 } = new MessageChannel();
+            const got = new Promise((resolve) => { port2.onmessage = (event) => resolve(event.data); });
+            port1.postMessage({ n: 1 });
+            return JSON.stringify(await got);
+        "#);
+        assert_eq!(got.unwrap(), r#"{"n":1}"#);
+    }
+
+    #[test]
+    fn a_transferred_port_keeps_its_channel() {
+        let got = run(r#"
+            const a = new MessageChannel();
+            const b = new MessageChannel();
+            const moved = new Promise((resolve) => { b.port2.onmessage = (event) => resolve(event.data); });
+            b.port1.postMessage(a.port2, [a.port2]);
+            const port = await moved;
+            const got = new Promise((resolve) => { port.onmessage = (event) => resolve(event.data); });
+            a.port1.postMessage('through');
+            let detached = false;
+            try {
+                a.port2.postMessage('stale');
+            } catch (e) {
+                detached = e instanceof TypeError;
+            }
+            return `${await got} ${detached}`;
+        "#);
+        assert_eq!(got.unwrap(), "through true");
+    }
+
+    #[test]
+    fn ports_can_not_be_sent_to_a_worker() {
+        let dir = TempDir::new("ports");
+        let path = dir.write("worker.js", "");
+        let got = run(&format!(r#"
+            const {{ port1 }} = new MessageChannel();
+            const worker = new Worker({:?});
+            try {{
+                worker.postMessage(port1, [port1]);
+                return 'sent';
+            }} catch (e) {{
+                return 'refused';
+            }} finally {{
+                worker.terminate();
+            }}
+        "#, path));
+        assert_eq!(got.unwrap(), "refused");
+    }
+
+    #[test]
+    fn closing_a_port_disentangles_the_other() {
+        let got = run(r#"
+            const { port1, port2 } = new MessageChannel();
+            let got = 'nothing';
+            port1.onmessage = (event) => { got = event.data; };
+            port1.close();
+            port2.postMessage('late');
+            await sleep_ms(20);
+            let closed = false;
+            try {
+                port1.postMessage('after close');
+            } catch (e) {
+                closed = e instanceof TypeError;
+            }
+            return `${got} ${closed}`;
+        "#);
+        assert_eq!(got.unwrap(), "nothing true");
+    }
+}
@@ -0,0 +1,87 @@
+use std::{
+    ptr::{null_mut},
+};
+
+use mozjs::{rooted};
+use mozjs::{
+    jsapi::{JSObject,Value,CallArgs,Heap,IsCallable,CurrentGlobalOrNull},
+    jsval::{UndefinedValue},
+    error::{throw_type_error},
+    panic::wrap_panic,
+};
+#[allow(unused_imports)]
+use tracing::{debug,trace,instrument,warn,error,info};
+
+use crate::runtime::{
+    incumbent_stack::{peek_incumbent_stack},
+    queue::{insert_into_filo},
+    async_stack::{capture_saved_frame},
+};
+
+/// `queueMicrotask(fn)`
+///
+/// Places `fn` directly onto the FIFO, it runs with the incumbent
+/// global just like a promise reaction job would.
+#[instrument(skip_all,name="queue_microtask_entry_point")]
+pub unsafe extern "C" fn queue_microtask(
+    ctx: *mut mozjs::context::RawJSContext,
+    argc: u32,
+    vp: *mut Value,
+) -> bool {
+    let mut is_okay = true;
+    wrap_panic(&mut || {
+        let args = unsafe { CallArgs::from_vp(vp, argc) };
+        if args.argc_ < 1 || !args.get(0).is_object() || !unsafe { IsCallable(args.get(0).to_object()) } {
+            unsafe { throw_type_error(ctx, "queueMicrotask: argument 1 is not a function") };
+            is_okay = false;
+            return;
+        }
+        rooted!(in(ctx) let callback = args.get(0).to_object());
+        rooted!(in(ctx) let mut incumbent = null_mut::<JSObject>());
+        peek_incumbent_stack(&mut incumbent.handle_mut());
+        if incumbent.get().is_null() {
+            warn!("incumbent stack is empty, using the current global");
+            incumbent.set(unsafe { CurrentGlobalOrNull(ctx) });
+        }
+        if incumbent.get().is_null() {
+            error!("global is null");
+            is_okay = false;
+            return;
+        }
+        // like a promise reaction, the callback continues from the caller's stack
+        let allocation_site = capture_saved_frame(ctx);
+        insert_into_filo(Heap::boxed(callback.get()), Heap::boxed(incumbent.get()), allocation_site);
+        args.rval().set(UndefinedValue());
+    });
+    is_okay
+}
+
+#[cfg(test)]
+mod tests {
+    use mozjs::{rooted};
+    use crate::runtime::{
+        checkpoint::{microtask_checkpoint},
+        testing::{with_runtime,new_test_global,eval,gc},
+    };
+
+    #[test]
+    fn queued_callbacks_survive_a_gc() {
+        with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            eval(ctx, global.handle(), "globalThis.log = []; queueMicrotask(() => log.push('ran'));").unwrap();
+            eval(ctx, global.handle(), "for (let i = 0; i < 10000; i++) ({ garbage: [i] });").unwrap();
+            gc(ctx);
+            microtask_checkpoint(ctx);
+            assert_eq!(eval(ctx, global.handle(), "log.join()").unwrap(), "ran");
+        });
+    }
+
+    #[test]
+    fn a_non_function_throws() {
+        with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            let err = eval(ctx, global.handle(), "queueMicrotask(1)").unwrap_err();
+            assert!(err.contains("not a function"), "{}", err);
+        });
+    }
+}
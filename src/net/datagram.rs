@@ -0,0 +1,197 @@
+use std::{
+    io,
+    ptr::{NonNull},
+    sync::{Arc},
+};
+use tokio::{
+    net::{UdpSocket,UnixDatagram},
+    sync::{watch},
+};
+use mozjs::{rooted};
+use mozjs::{
+    context::{JSContext,RawJSContext},
+    jsapi::{JSObject,Value,CallArgs},
+    jsval::{UndefinedValue},
+    rust::wrappers2::JS_DefineFunction,
+    panic::wrap_panic,
+};
+#[allow(unused_imports)]
+use tracing::{debug,trace,instrument,warn,error,info};
+
+use crate::runtime::{
+    conversions::{arg_string,arg_bytes,define_value},
+    host_object::{new_host_object,host_object_data,take_host_object_data},
+    resolvable_promise::{promise_entry_point},
+};
+use super::{NetValue,bridge_net,tcp::port_arg};
+
+/*
+ * Datagram sockets
+ *
+ * UDP & unix datagram sockets share one host object, only the
+ * addressing of `sendTo` differs:
+ *
+ *  - `sendTo(data, host, port)` for UDP
+ *  - `sendTo(data, path)` for unix
+ *
+ * `recvFrom(size)` resolves to `{ data, address, port }` (`port` is
+ * `null` for unix sockets), closing the socket rejects any pending
+ * receive with `ECANCELED`.
+ * The descriptor is closed by `close()` or once the object is
+ * collected & no send is in flight.
+ *
+ */
+
+/// Largest datagram `recvFrom` accepts by default
+const DEFAULT_RECV_SIZE: usize = 64 * 1024;
+/// Larger sizes are cut down to this, the buffer is allocated up front
+const MAX_RECV_SIZE: usize = 1024 * 1024;
+
+pub(crate) enum Datagram {
+    Udp(UdpSocket),
+    Unix(UnixDatagram),
+}
+impl Datagram {
+    fn local_address(&self) -> Option<String> {
+        match self {
+            Datagram::Udp(socket) => socket.local_addr().ok().map(|a| a.to_string()),
+            Datagram::Unix(socket) => socket.local_addr().ok()
+                .and_then(|a| a.as_pathname().map(|p| p.display().to_string())),
+        }
+    }
+
+    fn port(&self) -> Option<u16> {
+        match self {
+            Datagram::Udp(socket) => socket.local_addr().ok().map(|a| a.port()),
+            Datagram::Unix(_) => None,
+        }
+    }
+
+    async fn recv_from(&self, size: usize) -> io::Result<NetValue> {
+        let mut buffer = vec![0u8; size.min(MAX_RECV_SIZE)];
+        let (read, address, port) = match self {
+            Datagram::Udp(socket) => {
+                let (read, addr) = socket.recv_from(&mut buffer).await?;
+                (read, Some(addr.ip().to_string()), Some(addr.port()))
+            }
+            Datagram::Unix(socket) => {
+                let (read, addr) = socket.recv_from(&mut buffer).await?;
+                (read, addr.as_pathname().map(|p| p.display().to_string()), None)
+            }
+        };
+        buffer.truncate(read);
+        Ok(NetValue::Received { data: buffer, address, port })
+    }
+}
+impl Drop for Datagram {
+    fn drop(&mut self) {
+        // bound unix sockets leave a file behind
+        if let Datagram::Unix(socket) = self {
+            if let Some(path) = socket.local_addr().ok().and_then(|a| a.as_pathname().map(|p| p.to_path_buf())) {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+}
+
+/// Where `sendTo` delivers to
+enum Target {
+    Udp(String, u16),
+    Unix(String),
+}
+
+/// Owned by a datagram socket object
+struct DatagramHandle {
+    socket: Arc<Datagram>,
+    /// flipped to `true` by `close()`, cancels any pending receives
+    closed: watch::Sender<bool>,
+}
+impl Drop for DatagramHandle {
+    fn drop(&mut self) {
+        self.closed.send_replace(true);
+    }
+}
+
+/// Creates the datagram socket object in the current realm, null on failure
+pub(crate) fn new_datagram(cx: *mut RawJSContext, socket: Datagram) -> *mut JSObject {
+    let local_address = socket.local_address();
+    let port = socket.port();
+    let handle = DatagramHandle { socket: Arc::new(socket), closed: watch::channel(false).0 };
+    rooted!(in(cx) let obj = new_host_object(cx, handle));
+    if obj.get().is_null() {
+        return obj.get();
+    }
+    let mut safe_ctx = unsafe { JSContext::from_ptr(NonNull::new(cx).unwrap()) };
+    unsafe {
+        JS_DefineFunction(&mut safe_ctx, obj.handle(), c"sendTo".as_ptr(), Some(datagram_send_to), 3, 0);
+        JS_DefineFunction(&mut safe_ctx, obj.handle(), c"recvFrom".as_ptr(), Some(datagram_recv_from), 1, 0);
+        JS_DefineFunction(&mut safe_ctx, obj.handle(), c"close".as_ptr(), Some(datagram_close), 0, 0);
+    }
+    if let Some(addr) = local_address {
+        define_value(cx, obj.handle(), c"address", &addr);
+    }
+    if let Some(port) = port {
+        define_value(cx, obj.handle(), c"port", &port);
+    }
+    obj.get()
+}
+
+fn this_datagram<'a>(args: &CallArgs) -> Option<&'a mut DatagramHandle> {
+    let this = args.thisv();
+    if !this.is_object() {
+        return None;
+    }
+    unsafe { host_object_data::<DatagramHandle>(this.to_object()) }
+}
+
+/// `socket.sendTo(data, host, port)` / `socket.sendTo(data, path)`, resolves to the bytes sent
+unsafe extern "C" fn datagram_send_to(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    promise_entry_point(ctx, argc, vp, "sendTo", bridge_net, |ctx, args| {
+        let socket = this_datagram(args).ok_or("socket is closed")?.socket.clone();
+        let data = arg_bytes(ctx, args, 0).ok_or("data must be a string, Uint8Array or ArrayBuffer")?;
+        let target = match &*socket {
+            Datagram::Udp(_) => Target::Udp(arg_string(ctx, args, 1).ok_or("host must be a string")?, port_arg(args, 2)?),
+            Datagram::Unix(_) => Target::Unix(arg_string(ctx, args, 1).ok_or("path must be a string")?),
+        };
+        Ok(async move {
+            let sent = match (&*socket, target) {
+                (Datagram::Udp(s), Target::Udp(host, port)) => s.send_to(&data, (host.as_str(), port)).await?,
+                (Datagram::Unix(s), Target::Unix(path)) => s.send_to(&data, path).await?,
+                _ => unreachable!("target is picked from the socket type"),
+            };
+            Ok(NetValue::Sent(sent))
+        })
+    })
+}
+
+/// `socket.recvFrom(size)`, resolves to `{ data, address, port }`, rejects once closed
+unsafe extern "C" fn datagram_recv_from(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    promise_entry_point(ctx, argc, vp, "recvFrom", bridge_net, |_ctx, args| {
+        let handle = this_datagram(args).ok_or("socket is closed")?;
+        let (socket, mut closed) = (handle.socket.clone(), handle.closed.subscribe());
+        let size = match args.argc_ {
+            0 => DEFAULT_RECV_SIZE,
+            _ if args.get(0).is_undefined() => DEFAULT_RECV_SIZE,
+            _ if args.get(0).is_number() && args.get(0).to_number() >= 1.0 => args.get(0).to_number() as usize,
+            _ => return Err("size must be a positive number"),
+        };
+        Ok(async move {
+            tokio::select! {
+                received = socket.recv_from(size) => received,
+                _ = closed.wait_for(|closed| *closed) => Err(io::Error::from_raw_os_error(libc::ECANCELED)),
+            }
+        })
+    })
+}
+
+/// `socket.close()`
+unsafe extern "C" fn datagram_close(_ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    wrap_panic(&mut || {
+        let args = unsafe { CallArgs::from_vp(vp, argc) };
+        if args.thisv().is_object() {
+            unsafe { take_host_object_data(args.thisv().to_object()) };
+        }
+        args.rval().set(UndefinedValue());
+    });
+    true
+}
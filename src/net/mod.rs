@@ -0,0 +1,271 @@
+use std::{
+    future::{Future},
+    io,
+    ops::{DerefMut},
+    path::{PathBuf},
+    ptr::{NonNull},
+    sync::{Arc},
+};
+use tokio::{
+    net::{TcpListener,UnixListener},
+    sync::{watch},
+};
+use mozjs::{rooted};
+use mozjs::{
+    context::{JSContext,RawJSContext},
+    gc::{Handle,MutableHandle},
+    jsapi::{JSObject,Value,CallArgs},
+    jsval::{UndefinedValue,ObjectValue},
+    realm::{AutoRealm},
+    rust::wrappers2::JS_DefineFunction,
+    panic::wrap_panic,
+};
+#[allow(unused_imports)]
+use tracing::{debug,trace,instrument,warn,error,info};
+
+use crate::{
+    byte_stream::{ByteStreamParts,new_byte_stream},
+    runtime::{
+        conversions::{new_io_error,new_uint8_array,new_object,define_value,define_property,define_async_iterator,new_iter_result},
+        host_object::{new_host_object,host_object_data,take_host_object_data},
+        resolvable_promise::{ResolutionMarshalling,promise_entry_point},
+    },
+};
+
+pub mod tcp;
+pub mod udp;
+pub mod unix;
+pub mod datagram;
+
+/*
+ * `net` global
+ *
+ * Connected sockets are byte streams (see `byte_stream`), listeners
+ * are async iterators of connected sockets & datagram sockets are
+ * in `datagram`. TCP/UDP & unix sockets share these host objects.
+ *
+ */
+
+/// Defines the `net` object on `global_obj`
+pub fn define_net(realm: &mut AutoRealm, global_obj: Handle<'_,*mut JSObject>) {
+    let cx = unsafe { realm.deref_mut().raw_cx() };
+    rooted!(in(cx) let net = new_object(cx));
+    if net.get().is_null() {
+        error!("could not allocate net object");
+        return;
+    }
+    unsafe {
+        JS_DefineFunction(realm,net.handle(),c"connect".as_ptr(),Some(tcp::tcp_connect),2,0,);
+        JS_DefineFunction(realm,net.handle(),c"listen".as_ptr(),Some(tcp::tcp_listen),2,0,);
+        JS_DefineFunction(realm,net.handle(),c"bindUdp".as_ptr(),Some(udp::udp_bind),2,0,);
+        JS_DefineFunction(realm,net.handle(),c"connectUnix".as_ptr(),Some(unix::unix_connect),1,0,);
+        JS_DefineFunction(realm,net.handle(),c"listenUnix".as_ptr(),Some(unix::unix_listen),1,0,);
+        JS_DefineFunction(realm,net.handle(),c"bindUnixDatagram".as_ptr(),Some(unix::unix_datagram_bind),1,0,);
+    }
+    rooted!(in(cx) let net_val = ObjectValue(net.get()));
+    define_property(cx, global_obj, c"net", net_val.handle());
+}
+
+/// What a network operation resolves to, converted to JS by `bridge_net`
+pub(crate) enum NetValue {
+    Stream(ByteStreamParts),
+    Listener(Listener),
+    Datagram(datagram::Datagram),
+    /// bytes sent by `sendTo`
+    Sent(usize),
+    /// a datagram from `recvFrom`
+    Received { data: Vec<u8>, address: Option<String>, port: Option<u16> },
+    /// the listener was closed while accepting
+    Closed,
+}
+
+/// Anything which accepts connections
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    /// the path is unlinked once the listener is dropped
+    Unix(UnixListener, PathBuf),
+}
+impl Listener {
+    async fn accept(&self) -> io::Result<ByteStreamParts> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, peer) = listener.accept().await?;
+                tcp::stream_parts(stream, Some(peer))
+            }
+            Listener::Unix(listener, _) => {
+                let (stream, _) = listener.accept().await?;
+                Ok(unix::stream_parts(stream))
+            }
+        }
+    }
+
+    fn local_address(&self) -> Option<String> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().ok().map(|a| a.to_string()),
+            Listener::Unix(_, path) => Some(path.display().to_string()),
+        }
+    }
+
+    fn port(&self) -> Option<u16> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().ok().map(|a| a.port()),
+            Listener::Unix(..) => None,
+        }
+    }
+}
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Owned by a listener object
+struct ListenerHandle {
+    listener: Arc<Listener>,
+    /// flipped to `true` by `close()`, wakes any pending accepts
+    closed: watch::Sender<bool>,
+}
+impl Drop for ListenerHandle {
+    fn drop(&mut self) {
+        self.closed.send_replace(true);
+    }
+}
+
+/// Creates the listener object in the current realm, null on failure
+fn new_listener(cx: *mut RawJSContext, listener: Listener) -> *mut JSObject {
+    let local_address = listener.local_address();
+    let port = listener.port();
+    let handle = ListenerHandle { listener: Arc::new(listener), closed: watch::channel(false).0 };
+    rooted!(in(cx) let obj = new_host_object(cx, handle));
+    if obj.get().is_null() {
+        return obj.get();
+    }
+    let mut safe_ctx = unsafe { JSContext::from_ptr(NonNull::new(cx).unwrap()) };
+    unsafe {
+        JS_DefineFunction(&mut safe_ctx, obj.handle(), c"accept".as_ptr(), Some(listener_accept), 0, 0);
+        JS_DefineFunction(&mut safe_ctx, obj.handle(), c"next".as_ptr(), Some(listener_next), 0, 0);
+        JS_DefineFunction(&mut safe_ctx, obj.handle(), c"close".as_ptr(), Some(listener_close), 0, 0);
+    }
+    define_async_iterator(cx, obj.handle());
+    if let Some(addr) = local_address {
+        define_value(cx, obj.handle(), c"address", &addr);
+    }
+    if let Some(port) = port {
+        define_value(cx, obj.handle(), c"port", &port);
+    }
+    obj.get()
+}
+
+fn this_listener<'a>(args: &CallArgs) -> Option<&'a mut ListenerHandle> {
+    let this = args.thisv();
+    if !this.is_object() {
+        return None;
+    }
+    unsafe { host_object_data::<ListenerHandle>(this.to_object()) }
+}
+
+/// Waits for the next connection, `Closed` once the listener is closed
+fn accept_future(args: &CallArgs) -> impl Future<Output=io::Result<NetValue>> + Send + 'static {
+    let handle = this_listener(args);
+    let state = handle.map(|h| (h.listener.clone(), h.closed.subscribe()));
+    async move {
+        let Some((listener, mut closed)) = state else {
+            return Ok(NetValue::Closed);
+        };
+        tokio::select! {
+            accepted = listener.accept() => Ok(NetValue::Stream(accepted?)),
+            _ = closed.wait_for(|closed| *closed) => Ok(NetValue::Closed),
+        }
+    }
+}
+
+/// `listener.accept()`, resolves to a socket, or `null` once closed
+unsafe extern "C" fn listener_accept(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    promise_entry_point(ctx, argc, vp, "accept", bridge_net, |_ctx, args| {
+        Ok(accept_future(args))
+    })
+}
+
+/// `listener.next()`, the async iterator protocol over `accept()`
+unsafe extern "C" fn listener_next(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    promise_entry_point(ctx, argc, vp, "next", bridge_net_iter, |_ctx, args| {
+        Ok(accept_future(args))
+    })
+}
+
+/// `listener.close()`
+unsafe extern "C" fn listener_close(_ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    wrap_panic(&mut || {
+        let args = unsafe { CallArgs::from_vp(vp, argc) };
+        if args.thisv().is_object() {
+            unsafe { take_host_object_data(args.thisv().to_object()) };
+        }
+        args.rval().set(UndefinedValue());
+    });
+    true
+}
+
+/// Converts a `NetValue` into a JS value, `false` if an exception is pending
+fn net_value_to_js(cx: *mut RawJSContext, value: NetValue, mut rval: MutableHandle<'_,Value>) -> bool {
+    let obj = match value {
+        NetValue::Closed => {
+            rval.set(mozjs::jsval::NullValue());
+            return true;
+        }
+        NetValue::Sent(n) => {
+            rval.set(mozjs::jsval::DoubleValue(n as f64));
+            return true;
+        }
+        NetValue::Received { data, address, port } => {
+            rooted!(in(cx) let obj = new_object(cx));
+            rooted!(in(cx) let mut bytes = UndefinedValue());
+            if obj.get().is_null() || !new_uint8_array(cx, &data, bytes.handle_mut()) {
+                return false;
+            }
+            define_property(cx, obj.handle(), c"data", bytes.handle());
+            define_value(cx, obj.handle(), c"address", &address);
+            define_value(cx, obj.handle(), c"port", &port);
+            obj.get()
+        }
+        NetValue::Stream(parts) => new_byte_stream(cx, parts),
+        NetValue::Listener(listener) => new_listener(cx, listener),
+        NetValue::Datagram(socket) => datagram::new_datagram(cx, socket),
+    };
+    if obj.is_null() {
+        return false;
+    }
+    rval.set(ObjectValue(obj));
+    true
+}
+
+pub(crate) fn bridge_net(result: io::Result<NetValue>) -> ResolutionMarshalling {
+    Box::new(move |realm: &mut AutoRealm, _promise: Handle<'_, *mut JSObject>, _global: Handle<'_, *mut JSObject>, ok: MutableHandle<'_,Value>, err: MutableHandle<'_,Value>| {
+        let cx = unsafe { realm.deref_mut().raw_cx() };
+        match result {
+            Ok(value) => { net_value_to_js(cx, value, ok); },
+            Err(e) => { new_io_error(cx, &e, err); },
+        };
+    }) as ResolutionMarshalling
+}
+
+/// Same as `bridge_net` but wraps the value in `{ value, done }`, `Closed` is `done`
+fn bridge_net_iter(result: io::Result<NetValue>) -> ResolutionMarshalling {
+    Box::new(move |realm: &mut AutoRealm, _promise: Handle<'_, *mut JSObject>, _global: Handle<'_, *mut JSObject>, ok: MutableHandle<'_,Value>, err: MutableHandle<'_,Value>| {
+        let cx = unsafe { realm.deref_mut().raw_cx() };
+        let value = match result {
+            Ok(value) => value,
+            Err(e) => {
+                new_io_error(cx, &e, err);
+                return;
+            }
+        };
+        let done = matches!(value, NetValue::Closed);
+        rooted!(in(cx) let mut item = UndefinedValue());
+        if !done && !net_value_to_js(cx, value, item.handle_mut()) {
+            return;
+        }
+        new_iter_result(cx, item.handle(), done, ok);
+    }) as ResolutionMarshalling
+}
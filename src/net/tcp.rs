@@ -0,0 +1,145 @@
+use std::{
+    io,
+    net::{SocketAddr},
+};
+use tokio::{
+    net::{TcpStream,TcpListener},
+};
+use mozjs::{
+    context::{RawJSContext},
+    jsapi::{Value,CallArgs},
+};
+#[allow(unused_imports)]
+use tracing::{debug,trace,instrument,warn,error,info};
+
+use crate::{
+    byte_stream::{ByteStreamParts},
+    runtime::{
+        conversions::{arg_string},
+        resolvable_promise::{promise_entry_point},
+    },
+};
+use super::{NetValue,Listener,bridge_net};
+
+/// Splits a connected stream into the parts of a byte stream object
+pub(crate) fn stream_parts(stream: TcpStream, peer: Option<SocketAddr>) -> io::Result<ByteStreamParts> {
+    let local = stream.local_addr().ok();
+    let peer = peer.or_else(|| stream.peer_addr().ok());
+    stream.set_nodelay(true)?;
+    let (reader, writer) = stream.into_split();
+    let mut parts = ByteStreamParts::new(Some(Box::new(reader)), Some(Box::new(writer)));
+    parts.local_address = local.map(|a| a.to_string());
+    parts.remote_address = peer.map(|a| a.to_string());
+    Ok(parts)
+}
+
+/// `args[index]` as a port number
+pub(crate) fn port_arg(args: &CallArgs, index: u32) -> Result<u16,&'static str> {
+    let val = args.get(index);
+    if index >= args.argc_ || !val.is_number() {
+        return Err("port must be a number");
+    }
+    let port = val.to_number();
+    if port.fract() != 0.0 || !(0.0..=65535.0).contains(&port) {
+        return Err("port must be between 0 and 65535");
+    }
+    Ok(port as u16)
+}
+
+/// `net.connect(host, port)`, resolves to a socket
+pub unsafe extern "C" fn tcp_connect(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    promise_entry_point(ctx, argc, vp, "net.connect", bridge_net, |ctx, args| {
+        let host = arg_string(ctx, args, 0).ok_or("host must be a string")?;
+        let port = port_arg(args, 1)?;
+        Ok(async move {
+            let stream = TcpStream::connect((host.as_str(), port)).await?;
+            Ok(NetValue::Stream(stream_parts(stream, None)?))
+        })
+    })
+}
+
+/// `net.listen(port, host = "127.0.0.1")`, resolves to a listener
+///
+/// Port `0` picks a free port, read it back from `listener.port`.
+pub unsafe extern "C" fn tcp_listen(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    promise_entry_point(ctx, argc, vp, "net.listen", bridge_net, |ctx, args| {
+        let port = port_arg(args, 0)?;
+        let host = match args.argc_ > 1 && !args.get(1).is_undefined() {
+            true => arg_string(ctx, args, 1).ok_or("host must be a string")?,
+            false => "127.0.0.1".to_string(),
+        };
+        Ok(async move {
+            let listener = TcpListener::bind((host.as_str(), port)).await?;
+            debug!("listening on '{:?}'", listener.local_addr());
+            Ok(NetValue::Listener(Listener::Tcp(listener)))
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use mozjs::{rooted};
+    use crate::runtime::testing::{with_runtime,new_test_global,eval_async};
+
+    /// Runs `body` in a realm with `server` & `client`, the two ends of a loopback connection
+    fn over_loopback(body: &str) -> Result<String,String> {
+        run(&format!(r#"
+            const listener = await net.listen(0);
+            const [server, client] = await Promise.all([listener.accept(), net.connect('127.0.0.1', listener.port)]);
+            listener.close();
+            {}
+        "#, body))
+    }
+
+    fn run(body: &str) -> Result<String,String> {
+        with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            let body = format!("const text = (bytes) => bytes === null ? null : String.fromCharCode(...bytes);\n{}", body);
+            eval_async(ctx, global.handle(), &body)
+        })
+    }
+
+    #[test]
+    fn echoes_over_loopback() {
+        let echoed = over_loopback(r#"
+            await client.write('ping');
+            await server.write(await server.read());
+            return text(await client.read());
+        "#);
+        assert_eq!(echoed.as_deref(), Ok("ping"));
+    }
+
+    #[test]
+    fn read_resolves_to_null_once_the_peer_shuts_down() {
+        let read = over_loopback("await server.shutdown(); return text(await client.read());");
+        assert_eq!(read.as_deref(), Ok("null"));
+    }
+
+    #[test]
+    fn huge_reads_are_clamped() {
+        let read = over_loopback("await client.write('x'); return text(await server.read(1e15));");
+        assert_eq!(read.as_deref(), Ok("x"));
+    }
+
+    #[test]
+    fn read_size_must_be_positive() {
+        let thrown = over_loopback("try { server.read(0); return 'returned'; } catch (e) { return e.name; }");
+        assert_eq!(thrown.as_deref(), Ok("TypeError"));
+    }
+
+    #[test]
+    fn connecting_to_a_closed_port_rejects_with_a_code() {
+        let code = run(r#"
+            const listener = await net.listen(0);
+            const port = listener.port;
+            listener.close();
+            try {
+                await net.connect('127.0.0.1', port);
+                return 'connected';
+            } catch (e) {
+                return e.code;
+            }
+        "#);
+        assert_eq!(code.as_deref(), Ok("ECONNREFUSED"));
+    }
+}
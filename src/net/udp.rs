@@ -0,0 +1,92 @@
+use tokio::{
+    net::{UdpSocket},
+};
+use mozjs::{
+    context::{RawJSContext},
+    jsapi::{Value},
+};
+#[allow(unused_imports)]
+use tracing::{debug,trace,instrument,warn,error,info};
+
+use crate::runtime::{
+    conversions::{arg_string},
+    resolvable_promise::{promise_entry_point},
+};
+use super::{NetValue,bridge_net,datagram::Datagram,tcp::port_arg};
+
+/// `net.bindUdp(port, host = "127.0.0.1")`, resolves to a datagram socket
+///
+/// Port `0` picks a free port, read it back from `socket.port`.
+pub unsafe extern "C" fn udp_bind(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    promise_entry_point(ctx, argc, vp, "net.bindUdp", bridge_net, |ctx, args| {
+        let port = port_arg(args, 0)?;
+        let host = match args.argc_ > 1 && !args.get(1).is_undefined() {
+            true => arg_string(ctx, args, 1).ok_or("host must be a string")?,
+            false => "127.0.0.1".to_string(),
+        };
+        Ok(async move {
+            let socket = UdpSocket::bind((host.as_str(), port)).await?;
+            debug!("udp bound to '{:?}'", socket.local_addr());
+            Ok(NetValue::Datagram(Datagram::Udp(socket)))
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use mozjs::{rooted};
+    use crate::runtime::testing::{with_runtime,new_test_global,eval_async};
+
+    fn run(body: &str) -> Result<String,String> {
+        with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            let body = format!("const text = (bytes) => String.fromCharCode(...bytes);\n{}", body);
+            eval_async(ctx, global.handle(), &body)
+        })
+    }
+
+    #[test]
+    fn datagrams_cross_loopback() {
+        let got = run(r#"
+            const [a, b] = await Promise.all([net.bindUdp(0), net.bindUdp(0)]);
+            const received = b.recvFrom();
+            const sent = await a.sendTo('ping', '127.0.0.1', b.port);
+            const { data, address, port } = await received;
+            a.close();
+            b.close();
+            return [sent, text(data), address, port === a.port].join();
+        "#);
+        assert_eq!(got.as_deref(), Ok("4,ping,127.0.0.1,true"));
+    }
+
+    #[test]
+    fn closing_rejects_a_pending_receive() {
+        let got = run(r#"
+            const socket = await net.bindUdp(0);
+            const received = socket.recvFrom();
+            socket.close();
+            try {
+                await received;
+                return 'received';
+            } catch (e) {
+                return e.code;
+            }
+        "#);
+        assert_eq!(got.as_deref(), Ok("ECANCELED"));
+    }
+
+    #[test]
+    fn a_closed_socket_throws() {
+        let got = run(r#"
+            const socket = await net.bindUdp(0);
+            socket.close();
+            try {
+                socket.recvFrom();
+                return 'returned';
+            } catch (e) {
+                return e.name;
+            }
+        "#);
+        assert_eq!(got.as_deref(), Ok("TypeError"));
+    }
+}
@@ -0,0 +1,133 @@
+use std::{
+    io,
+    path::{PathBuf},
+};
+use tokio::{
+    net::{UnixStream,UnixListener,UnixDatagram},
+};
+use mozjs::{
+    context::{RawJSContext},
+    jsapi::{Value},
+};
+#[allow(unused_imports)]
+use tracing::{debug,trace,instrument,warn,error,info};
+
+use crate::{
+    byte_stream::{ByteStreamParts},
+    runtime::{
+        conversions::{arg_string},
+        resolvable_promise::{promise_entry_point},
+    },
+};
+use super::{NetValue,Listener,bridge_net,datagram::Datagram};
+
+/// Splits a connected unix stream into the parts of a byte stream object
+pub(crate) fn stream_parts(stream: UnixStream) -> ByteStreamParts {
+    fn name(addr: io::Result<tokio::net::unix::SocketAddr>) -> Option<String> {
+        addr.ok()?.as_pathname().map(|p| p.display().to_string())
+    }
+    let local = name(stream.local_addr());
+    let peer = name(stream.peer_addr());
+    let (reader, writer) = stream.into_split();
+    let mut parts = ByteStreamParts::new(Some(Box::new(reader)), Some(Box::new(writer)));
+    parts.local_address = local;
+    parts.remote_address = peer;
+    parts
+}
+
+/// `net.connectUnix(path)`, resolves to a socket
+pub unsafe extern "C" fn unix_connect(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    promise_entry_point(ctx, argc, vp, "net.connectUnix", bridge_net, |ctx, args| {
+        let path = arg_string(ctx, args, 0).ok_or("path must be a string")?;
+        Ok(async move {
+            let stream = UnixStream::connect(path).await?;
+            Ok(NetValue::Stream(stream_parts(stream)))
+        })
+    })
+}
+
+/// `net.listenUnix(path)`, resolves to a listener, the socket file is removed on close
+pub unsafe extern "C" fn unix_listen(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    promise_entry_point(ctx, argc, vp, "net.listenUnix", bridge_net, |ctx, args| {
+        let path = PathBuf::from(arg_string(ctx, args, 0).ok_or("path must be a string")?);
+        Ok(async move {
+            let listener = UnixListener::bind(&path)?;
+            debug!("listening on '{}'", path.display());
+            Ok(NetValue::Listener(Listener::Unix(listener, path)))
+        })
+    })
+}
+
+/// `net.bindUnixDatagram(path)`, resolves to a datagram socket, the socket file is removed on close
+pub unsafe extern "C" fn unix_datagram_bind(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    promise_entry_point(ctx, argc, vp, "net.bindUnixDatagram", bridge_net, |ctx, args| {
+        let path = arg_string(ctx, args, 0).ok_or("path must be a string")?;
+        Ok(async move {
+            let socket = UnixDatagram::bind(path)?;
+            Ok(NetValue::Datagram(Datagram::Unix(socket)))
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use mozjs::{rooted};
+    use crate::runtime::testing::{with_runtime,new_test_global,eval_async,TempDir};
+
+    /// Runs `body` with `dir` bound to a fresh temp dir for the socket files
+    fn run(body: &str) -> Result<String,String> {
+        let dir = TempDir::new("unix");
+        with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            let body = format!(
+                "const dir = {:?};\nconst text = (bytes) => bytes === null ? null : String.fromCharCode(...bytes);\n{}",
+                dir.path().display().to_string(), body,
+            );
+            eval_async(ctx, global.handle(), &body)
+        })
+    }
+
+    #[test]
+    fn streams_echo_over_a_socket_file() {
+        let got = run(r#"
+            const listener = await net.listenUnix(`${dir}/echo.sock`);
+            const [server, client] = await Promise.all([listener.accept(), net.connectUnix(`${dir}/echo.sock`)]);
+            listener.close();
+            await client.write('ping');
+            await server.write(await server.read());
+            return text(await client.read());
+        "#);
+        assert_eq!(got.as_deref(), Ok("ping"));
+    }
+
+    #[test]
+    fn datagrams_carry_the_sender_path() {
+        let got = run(r#"
+            const a = await net.bindUnixDatagram(`${dir}/a.sock`);
+            const b = await net.bindUnixDatagram(`${dir}/b.sock`);
+            const received = b.recvFrom();
+            await a.sendTo('ping', `${dir}/b.sock`);
+            const { data, address, port } = await received;
+            a.close();
+            b.close();
+            return [text(data), address === `${dir}/a.sock`, port].join();
+        "#);
+        assert_eq!(got.as_deref(), Ok("ping,true,"));
+    }
+
+    #[test]
+    fn closing_rejects_a_pending_receive() {
+        let got = run(r#"
+            const socket = await net.bindUnixDatagram(`${dir}/idle.sock`);
+            const received = socket.recvFrom();
+            socket.close();
+            try {
+                await received;
+                return 'received';
+            } catch (e) {
+                return e.code;
+            }
+        "#);
+        assert_eq!(got.as_deref(), Ok("ECANCELED"));
+    }
+}
@@ -0,0 +1,331 @@
+use std::{
+    io,
+    ops::{DerefMut},
+    os::unix::process::{ExitStatusExt},
+    process::{ExitStatus,Stdio},
+    ptr::{NonNull},
+    sync::{Arc,Mutex},
+};
+use tokio::{
+    process::{Child,Command},
+    signal::unix::{SignalKind,signal},
+};
+use mozjs::{rooted};
+use mozjs::{
+    context::{JSContext,RawJSContext},
+    error::{throw_type_error},
+    gc::{Handle,MutableHandle},
+    jsapi::{JSObject,Value,CallArgs},
+    jsval::{UndefinedValue,ObjectValue,NullValue},
+    realm::{AutoRealm},
+    panic::wrap_panic,
+    rust::wrappers2::JS_DefineFunction,
+};
+#[allow(unused_imports)]
+use tracing::{debug,trace,instrument,warn,error,info};
+
+use crate::{
+    byte_stream::{ByteStreamParts,new_byte_stream},
+    runtime::{
+        conversions::{arg_string,value_to_strings,object_entries,get_option,option_bool,option_string,new_io_error,throw_io_error,new_object,define_value,define_property},
+        host_object::{new_host_object,host_object_data},
+        resolvable_promise::{ResolutionMarshalling,new_pending_promise,spawn_bridged},
+    },
+};
+
+/*
+ * `spawn(cmd, args, options)`, child processes via `tokio::process`
+ *
+ * Returns a process object:
+ *
+ *  - `pid`
+ *  - `stdin` (writable), `stdout` & `stderr` (readable) byte streams,
+ *    `null` unless piped
+ *  - `status`, resolves to `{ success, code, signal }` on exit
+ *  - `kill(signal = "SIGTERM")`
+ *
+ * `options` are `cwd`, `env` (an object), `clearEnv` & `stdin`,
+ * `stdout`, `stderr` each one of `"piped"` (default), `"inherit"`
+ * or `"null"`. The child is not killed when the object is collected.
+ *
+ * The child is reaped & signalled under the same lock, `kill` never
+ * reaches a recycled pid.
+ *
+ */
+
+/// Owned by a process object
+struct ProcessHandle {
+    /// `None` once the child has been reaped, its pid may be reused after
+    pid: Arc<Mutex<Option<u32>>>,
+}
+
+/// Signals scripts may refer to by name
+const SIGNALS: &[(&str, libc::c_int)] = &[
+    ("SIGHUP", libc::SIGHUP),
+    ("SIGINT", libc::SIGINT),
+    ("SIGQUIT", libc::SIGQUIT),
+    ("SIGKILL", libc::SIGKILL),
+    ("SIGUSR1", libc::SIGUSR1),
+    ("SIGUSR2", libc::SIGUSR2),
+    ("SIGPIPE", libc::SIGPIPE),
+    ("SIGALRM", libc::SIGALRM),
+    ("SIGTERM", libc::SIGTERM),
+    ("SIGCHLD", libc::SIGCHLD),
+    ("SIGCONT", libc::SIGCONT),
+    ("SIGSTOP", libc::SIGSTOP),
+    ("SIGTSTP", libc::SIGTSTP),
+    ("SIGWINCH", libc::SIGWINCH),
+];
+
+/// `"SIGTERM"` -> 15
+pub(crate) fn signal_number(name: &str) -> Option<libc::c_int> {
+    SIGNALS.iter().find(|(n, _)| *n == name).map(|(_, s)| *s)
+}
+
+/// 15 -> `"SIGTERM"`
+pub(crate) fn signal_name(signal: libc::c_int) -> Option<&'static str> {
+    SIGNALS.iter().find(|(_, s)| *s == signal).map(|(n, _)| *n)
+}
+
+fn stdio_option(cx: *mut RawJSContext, options: Handle<'_,Value>, name: &std::ffi::CStr) -> Result<Stdio,&'static str> {
+    match option_string(cx, options, name).as_deref() {
+        None | Some("piped") | Some("pipe") => Ok(Stdio::piped()),
+        Some("inherit") => Ok(Stdio::inherit()),
+        Some("null") => Ok(Stdio::null()),
+        Some(_) => Err("stdio must be 'piped', 'inherit' or 'null'"),
+    }
+}
+
+/// Builds the `Command` from `spawn`'s arguments
+fn command(cx: *mut RawJSContext, args: &CallArgs) -> Result<Command,&'static str> {
+    let program = arg_string(cx, args, 0).ok_or("cmd must be a string")?;
+    let mut command = Command::new(program);
+    if args.argc_ > 1 && !args.get(1).is_null_or_undefined() {
+        let argv = value_to_strings(cx, unsafe { Handle::from_raw(args.get(1)) }).ok_or("args must be an array of strings")?;
+        command.args(argv);
+    }
+    let options = match args.argc_ {
+        0..=2 => Handle::undefined(),
+        _ => unsafe { Handle::from_raw(args.get(2)) },
+    };
+    if let Some(cwd) = option_string(cx, options, c"cwd") {
+        command.current_dir(cwd);
+    }
+    if option_bool(cx, options, c"clearEnv") {
+        command.env_clear();
+    }
+    rooted!(in(cx) let mut env = UndefinedValue());
+    get_option(cx, options, c"env", env.handle_mut());
+    if !env.is_null_or_undefined() {
+        let vars = object_entries(cx, env.handle()).ok_or("env must be an object")?;
+        command.envs(vars);
+    }
+    command
+        .stdin(stdio_option(cx, options, c"stdin")?)
+        .stdout(stdio_option(cx, options, c"stdout")?)
+        .stderr(stdio_option(cx, options, c"stderr")?)
+        .kill_on_drop(false);
+    Ok(command)
+}
+
+/// `spawn(cmd, args, options)`
+pub(crate) unsafe extern "C" fn spawn(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    let mut is_okay = true;
+    wrap_panic(&mut || {
+        let args = unsafe { CallArgs::from_vp(vp, argc) };
+        let mut command = match command(ctx, &args) {
+            Ok(command) => command,
+            Err(msg) => {
+                unsafe { throw_type_error(ctx, &format!("spawn: {}", msg)) };
+                is_okay = false;
+                return;
+            }
+        };
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                is_okay = throw_io_error(ctx, &e);
+                return;
+            }
+        };
+        let pid = child.id().unwrap_or_default();
+        debug!("spawned process '{}'", pid);
+        let live = Arc::new(Mutex::new(Some(pid)));
+
+        rooted!(in(ctx) let obj = new_host_object(ctx, ProcessHandle { pid: live.clone() }));
+        if obj.get().is_null() {
+            is_okay = false;
+            return;
+        }
+        define_value(ctx, obj.handle(), c"pid", &pid);
+        let stdin = child.stdin.take().map(|w| ByteStreamParts::new(None, Some(Box::new(w))));
+        let stdout = child.stdout.take().map(|r| ByteStreamParts::new(Some(Box::new(r)), None));
+        let stderr = child.stderr.take().map(|r| ByteStreamParts::new(Some(Box::new(r)), None));
+        for (name, parts) in [(c"stdin", stdin), (c"stdout", stdout), (c"stderr", stderr)] {
+            rooted!(in(ctx) let mut stream = NullValue());
+            if let Some(parts) = parts {
+                let stream_obj = new_byte_stream(ctx, parts);
+                if stream_obj.is_null() {
+                    is_okay = false;
+                    return;
+                }
+                stream.set(ObjectValue(stream_obj));
+            }
+            define_property(ctx, obj.handle(), name, stream.handle());
+        }
+
+        let Some((promise_id, promise)) = new_pending_promise(ctx, "spawn") else {
+            is_okay = false;
+            return;
+        };
+        rooted!(in(ctx) let promise = ObjectValue(promise));
+        define_property(ctx, obj.handle(), c"status", promise.handle());
+        let mut safe_ctx = unsafe { JSContext::from_ptr(NonNull::new(ctx).unwrap()) };
+        unsafe { JS_DefineFunction(&mut safe_ctx, obj.handle(), c"kill".as_ptr(), Some(process_kill), 1, 0) };
+
+        spawn_bridged(promise_id, reap(child, live), bridge_status);
+        args.rval().set(ObjectValue(obj.get()));
+    });
+    is_okay
+}
+
+/// `process.kill(signal)`, `false` if the process already exited
+unsafe extern "C" fn process_kill(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    let mut is_okay = true;
+    wrap_panic(&mut || {
+        let args = unsafe { CallArgs::from_vp(vp, argc) };
+        let this = args.thisv();
+        let Some(process) = (this.is_object()).then(|| unsafe { host_object_data::<ProcessHandle>(this.to_object()) }).flatten() else {
+            unsafe { throw_type_error(ctx, "kill: not a process") };
+            is_okay = false;
+            return;
+        };
+        let signal = match arg_string(ctx, &args, 0) {
+            None => libc::SIGTERM,
+            Some(name) => match signal_number(&name) {
+                Some(signal) => signal,
+                None => {
+                    unsafe { throw_type_error(ctx, &format!("kill: unknown signal '{}'", name)) };
+                    is_okay = false;
+                    return;
+                }
+            },
+        };
+        // held until the signal is sent, the child can't be reaped meanwhile
+        let pid = process.pid.lock().unwrap();
+        let Some(pid) = *pid else {
+            args.rval().set(mozjs::jsval::BooleanValue(false));
+            return;
+        };
+        if unsafe { libc::kill(pid as libc::pid_t, signal) } != 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::ESRCH) {
+                args.rval().set(mozjs::jsval::BooleanValue(false));
+                return;
+            }
+            is_okay = throw_io_error(ctx, &e);
+            return;
+        }
+        args.rval().set(mozjs::jsval::BooleanValue(true));
+    });
+    is_okay
+}
+
+/// Waits for `child` to exit, clearing `pid` as it is reaped
+async fn reap(mut child: Child, pid: Arc<Mutex<Option<u32>>>) -> io::Result<ExitStatus> {
+    // created before the first check, an exit in between is not missed
+    let mut exits = signal(SignalKind::child())?;
+    loop {
+        {
+            let mut pid = pid.lock().unwrap();
+            if let Some(status) = child.try_wait()? {
+                *pid = None;
+                return Ok(status);
+            }
+        }
+        if exits.recv().await.is_none() {
+            return child.wait().await;
+        }
+    }
+}
+
+/// Resolves to `{ success, code, signal }`
+fn bridge_status(result: io::Result<ExitStatus>) -> ResolutionMarshalling {
+    Box::new(move |realm: &mut AutoRealm, _promise: Handle<'_, *mut JSObject>, _global: Handle<'_, *mut JSObject>, mut ok: MutableHandle<'_,Value>, err: MutableHandle<'_,Value>| {
+        let cx = unsafe { realm.deref_mut().raw_cx() };
+        let status = match result {
+            Ok(status) => status,
+            Err(e) => {
+                new_io_error(cx, &e, err);
+                return;
+            }
+        };
+        rooted!(in(cx) let obj = new_object(cx));
+        if obj.get().is_null() {
+            return;
+        }
+        define_value(cx, obj.handle(), c"success", &status.success());
+        define_value(cx, obj.handle(), c"code", &status.code());
+        define_value(cx, obj.handle(), c"signal", &status.signal().map(|s| signal_name(s).map(str::to_string).unwrap_or_else(|| s.to_string())));
+        ok.set(ObjectValue(obj.get()));
+    }) as ResolutionMarshalling
+}
+
+#[cfg(test)]
+mod tests {
+    use mozjs::{rooted};
+    use crate::runtime::testing::{with_runtime,new_test_global,eval_async};
+
+    fn run(body: &str) -> Result<String,String> {
+        with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            eval_async(ctx, global.handle(), body)
+        })
+    }
+
+    #[test]
+    fn status_carries_the_exit_code() {
+        let got = run(r#"
+            const { success, code, signal } = await spawn('sh', ['-c', 'exit 3']).status;
+            return [success, code, signal].join();
+        "#);
+        assert_eq!(got.as_deref(), Ok("false,3,"));
+    }
+
+    #[test]
+    fn killing_an_exited_child_returns_false() {
+        let got = run(r#"
+            const child = spawn('sh', ['-c', 'exit 0']);
+            await child.status;
+            return child.kill();
+        "#);
+        assert_eq!(got.as_deref(), Ok("false"));
+    }
+
+    #[test]
+    fn kill_signals_a_running_child() {
+        let got = run(r#"
+            const child = spawn('sleep', ['10'], { stdin: 'null', stdout: 'null', stderr: 'null' });
+            const killed = child.kill('SIGKILL');
+            const { success, code, signal } = await child.status;
+            return [killed, success, code, signal].join();
+        "#);
+        assert_eq!(got.as_deref(), Ok("true,false,,SIGKILL"));
+    }
+
+    #[test]
+    fn unknown_signals_throw() {
+        let got = run(r#"
+            const child = spawn('sh', ['-c', 'exit 0']);
+            try {
+                child.kill('SIGNOPE');
+                return 'sent';
+            } catch (e) {
+                return e.message;
+            } finally {
+                await child.status;
+            }
+        "#);
+        assert_eq!(got.as_deref(), Ok("kill: unknown signal 'SIGNOPE'"));
+    }
+}
@@ -0,0 +1,134 @@
+use std::{
+    cell::{RefCell,LazyCell},
+    ops::{DerefMut},
+    ptr::{null_mut},
+    sync::atomic::{AtomicU64,Ordering},
+};
+use mozjs::{rooted};
+use mozjs::{
+    context::{JSContext,RawJSContext},
+    gc::{Handle},
+    jsapi::{Heap,JSObject,JSTracer,OnNewGlobalHookOption,JS_NewGlobalObject,CurrentGlobalOrNull},
+    jsval::{UndefinedValue},
+    realm::{AutoRealm},
+    rust::{SIMPLE_GLOBAL_CLASS,RealmOptions,CompileOptionsWrapper,evaluate_script},
+};
+#[allow(unused_imports)]
+use tracing::{debug,trace,instrument,warn,error,info};
+
+use crate::runtime::exception::{report_pending_exception};
+use crate::runtime::roots::{trace_object};
+use crate::runtime::source_map::{register_source};
+
+/// Realm ids are unique across every thread
+static NEXT_REALM_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    /// every global made on this thread with its realm id, until forgotten
+    static REALMS: LazyCell<RefCell<Vec<(Box<Heap<*mut JSObject>>,u64)>>> = LazyCell::new(|| RefCell::new(Vec::new()));
+}
+
+/// Reports every global to the GC, see `roots`
+pub(crate) unsafe fn trace_realms(trc: *mut JSTracer) {
+    REALMS.with(|r| {
+        for (global, _) in r.borrow().iter() {
+            unsafe { trace_object(trc, global, c"realm global") };
+        }
+    });
+}
+
+/// Creates a new global object, every global is its own realm
+///
+/// Nothing is defined on it, see `globals::define_globals`. Returns
+/// null on failure.
+pub fn new_global(ctx: &mut JSContext) -> *mut JSObject {
+    let h_option = OnNewGlobalHookOption::FireOnNewGlobalHook;
+    let c_option = RealmOptions::default();
+    let global = unsafe {
+        JS_NewGlobalObject(
+            ctx.raw_cx(),
+            &SIMPLE_GLOBAL_CLASS,
+            null_mut(),
+            h_option,
+            &*c_option,
+        )
+    };
+    if !global.is_null() {
+        let id = NEXT_REALM_ID.fetch_add(1, Ordering::Relaxed);
+        debug!("new realm '{}'", id);
+        REALMS.with(|r| r.borrow_mut().push((Heap::boxed(global), id)));
+    }
+    global
+}
+
+/// The id of the current realm, `None` outside of one made by `new_global`
+pub fn realm_id(cx: *mut RawJSContext) -> Option<u64> {
+    realm_id_of(unsafe { CurrentGlobalOrNull(cx) })
+}
+
+/// The id of the realm whose global is `global`
+pub fn realm_id_of(global: *mut JSObject) -> Option<u64> {
+    if global.is_null() {
+        return None;
+    }
+    REALMS.with(|r| r.borrow().iter().find(|(g, _)| g.get() == global).map(|(_, id)| *id))
+}
+
+/// The id of every realm made on this thread, oldest first
+pub fn realm_ids() -> Vec<u64> {
+    REALMS.with(|r| r.borrow().iter().map(|(_, id)| *id).collect())
+}
+
+/// The global of realm `id`, null unless it was made on this thread
+pub fn realm_global(id: u64) -> *mut JSObject {
+    REALMS.with(|r| r.borrow().iter().find(|(_, i)| *i == id).map(|(g, _)| g.get()).unwrap_or(null_mut()))
+}
+
+/// Drops realm `id`, its global can be collected & it has no id from then on
+pub(crate) fn forget_realm(id: u64) {
+    REALMS.with(|r| r.borrow_mut().retain(|(_, i)| *i != id));
+}
+
+/// Evaluates a classic script within `realm`
+///
+/// Uncaught exceptions are reported & cleared. A `sourceMappingURL`
+/// at the end of `source` is loaded for stacks from `filename`.
+#[instrument(skip(realm, global_obj, source))]
+pub fn evaluate(realm: &mut AutoRealm, global_obj: Handle<'_,*mut JSObject>, filename: &str, source: &str) -> Result<(),()> {
+    register_source(unsafe { realm.deref_mut().raw_cx() }, filename, source);
+    rooted!(&in(realm) let mut rval = UndefinedValue());
+    let options = CompileOptionsWrapper::new(realm, filename, 1);
+    match evaluate_script(realm, global_obj, source, rval.handle_mut(), options) {
+        Ok(()) => Ok(()),
+        Err(()) => {
+            report_pending_exception(unsafe { realm.deref_mut().raw_cx() }, filename);
+            Err(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::testing::{with_runtime,gc};
+
+    #[test]
+    fn realms_are_kept_until_forgotten() {
+        with_runtime(|ctx| {
+            let id = {
+                rooted!(in(unsafe { ctx.raw_cx() }) let global = new_global(ctx));
+                realm_id_of(global.get()).unwrap()
+            };
+            // only the store keeps the global alive
+            gc(ctx);
+            let global = realm_global(id);
+            assert!(!global.is_null());
+            assert_eq!(realm_id_of(global), Some(id));
+            assert!(realm_ids().contains(&id));
+
+            forget_realm(id);
+            assert!(realm_global(id).is_null());
+            assert!(!realm_ids().contains(&id));
+        });
+    }
+}
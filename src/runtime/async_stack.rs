@@ -0,0 +1,179 @@
+use std::{
+    cell::{RefCell},
+    ffi::{CStr},
+    mem::{MaybeUninit},
+    ptr::{NonNull,null_mut},
+};
+use mozjs::{rooted};
+use mozjs::{
+    context::{RawJSContext},
+    conversions::{ToJSValConvertible},
+    gc::{Handle},
+    glue::{JS_StackCapture_AllFrames},
+    jsapi::{
+        Heap,JSObject,JSString,Value,StackFormat,
+        AutoSetAsyncStackForNewCalls,AutoSetAsyncStackForNewCalls_AsyncCallKind,
+        CaptureCurrentStack,BuildStackString,JS_ClearPendingException,JS_GetStringLength,
+    },
+    jsval::{UndefinedValue},
+    rust::wrappers::{JS_GetProperty,JS_DefineProperty},
+};
+#[allow(unused_imports)] use tracing::{trace,debug,info,warn,error,instrument};
+
+/*
+ * Async stacks
+ *
+ * A promise made by a host function is settled from a macrotask, with
+ * no script on the stack. To keep track of who asked for it:
+ *
+ *  - `new_pending_promise` captures the caller's `SavedFrame`
+ *  - `setup_to_resolve` settles within `with_async_parent`, so the
+ *    reaction jobs queued by settling are given that frame as their
+ *    allocation site, & an `Error` it rejects with gets it as its
+ *    `stack` (its own is empty)
+ *  - `Task::call` runs a job with its allocation site as the async
+ *    parent of the frames it pushes, so `new Error().stack` (and
+ *    `console.trace`) continue into the originating script
+ *
+ */
+
+thread_local! {
+    /// the frame `with_async_parent` was given, innermost last
+    static ASYNC_PARENTS: RefCell<Vec<Box<Heap<*mut JSObject>>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// The `SavedFrame` of the running script, null when there is none
+pub(crate) fn capture_saved_frame(cx: *mut RawJSContext) -> *mut JSObject {
+    rooted!(in(cx) let mut frame = null_mut::<JSObject>());
+    let captured = unsafe {
+        let mut capture = MaybeUninit::uninit();
+        JS_StackCapture_AllFrames(capture.as_mut_ptr());
+        let mut capture = capture.assume_init();
+        CaptureCurrentStack(cx, frame.handle_mut().into(), &mut capture)
+    };
+    if !captured {
+        unsafe { JS_ClearPendingException(cx) };
+        return null_mut();
+    }
+    frame.get()
+}
+
+/// Renders a `SavedFrame` chain the way `Error.prototype.stack` would
+pub(crate) fn stack_string(cx: *mut RawJSContext, frame: Handle<'_,*mut JSObject>) -> Option<String> {
+    if frame.get().is_null() {
+        return None;
+    }
+    rooted!(in(cx) let mut string = null_mut::<JSString>());
+    if !unsafe { BuildStackString(cx, null_mut(), frame.into(), string.handle_mut().into(), 0, StackFormat::SpiderMonkey) } {
+        unsafe { JS_ClearPendingException(cx) };
+        return None;
+    }
+    let string = NonNull::new(string.get())?;
+    Some(unsafe { mozjs::conversions::jsstr_to_string(cx, string) })
+}
+
+/// Runs `f` with `frame` as the async parent of anything it calls or queues
+///
+/// A null `frame` just runs `f`.
+pub(crate) fn with_async_parent<R>(cx: *mut RawJSContext, frame: Handle<'_,*mut JSObject>, cause: &'static CStr, f: impl FnOnce() -> R) -> R {
+    if frame.get().is_null() {
+        return f();
+    }
+    ASYNC_PARENTS.with(|p| p.borrow_mut().push(Heap::boxed(frame.get())));
+    let mut guard = unsafe {
+        AutoSetAsyncStackForNewCalls::new(cx, frame.into(), cause.as_ptr(), AutoSetAsyncStackForNewCalls_AsyncCallKind::IMPLICIT)
+    };
+    let out = f();
+    unsafe { guard.destruct() };
+    ASYNC_PARENTS.with(|p| p.borrow_mut().pop());
+    out
+}
+
+/// The innermost `with_async_parent` frame, null outside of one
+pub(crate) fn current_async_parent() -> *mut JSObject {
+    ASYNC_PARENTS.with(|p| p.borrow().last().map(|f| f.get()).unwrap_or(null_mut()))
+}
+
+/// Gives an error without a stack of its own `frame`'s, as an own `stack` property
+pub(crate) fn attach_async_stack(cx: *mut RawJSContext, error: Handle<'_,Value>, frame: Handle<'_,*mut JSObject>) {
+    if !error.is_object() || frame.get().is_null() {
+        return;
+    }
+    rooted!(in(cx) let obj = error.to_object());
+    rooted!(in(cx) let mut stack = UndefinedValue());
+    if !unsafe { JS_GetProperty(cx, obj.handle(), c"stack".as_ptr(), stack.handle_mut()) } {
+        unsafe { JS_ClearPendingException(cx) };
+        return;
+    }
+    // only errors made natively, a script's own stack is left alone
+    let empty = stack.is_string() && unsafe { JS_GetStringLength(stack.to_string()) } == 0;
+    if !empty {
+        return;
+    }
+    let Some(rendered) = stack_string(cx, frame) else {
+        return;
+    };
+    rooted!(in(cx) let mut rendered_val = UndefinedValue());
+    unsafe { rendered.to_jsval(cx, rendered_val.handle_mut()) };
+    // writable & configurable but not enumerable, like the accessor it shadows
+    if !unsafe { JS_DefineProperty(cx, obj.handle(), c"stack".as_ptr(), rendered_val.handle(), 0) } {
+        unsafe { JS_ClearPendingException(cx) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mozjs::{rooted};
+    use super::*;
+    use crate::runtime::testing::{with_runtime,new_test_global,eval_async};
+
+    fn run(body: &str) -> Result<String,String> {
+        with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            eval_async(ctx, global.handle(), body)
+        })
+    }
+
+    #[test]
+    fn host_errors_get_the_callers_stack() {
+        let stack = run(r#"
+            function readMissing() {
+                return fs.readFile('/does/not/exist');
+            }
+            try {
+                await readMissing();
+                return 'read';
+            } catch (e) {
+                return e.stack;
+            }
+        "#).unwrap();
+        assert!(stack.contains("readMissing@test.js"), "{}", stack);
+    }
+
+    #[test]
+    fn reactions_continue_into_the_originating_script() {
+        let stack = run(r#"
+            function report() {
+                return new Error().stack;
+            }
+            function originate() {
+                return sleep_ms(1).then(report);
+            }
+            return await originate();
+        "#).unwrap();
+        let report = stack.find("report@").unwrap();
+        let originate = stack.find("originate@").unwrap();
+        assert!(report < originate, "{}", stack);
+    }
+
+    #[test]
+    fn there_is_no_parent_outside_a_settlement() {
+        with_runtime(|ctx| {
+            assert!(current_async_parent().is_null());
+            let cx = unsafe { ctx.raw_cx() };
+            assert!(capture_saved_frame(cx).is_null());
+            rooted!(in(cx) let frame = null_mut::<JSObject>());
+            assert_eq!(stack_string(cx, frame.handle()), None);
+        });
+    }
+}
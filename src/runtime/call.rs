@@ -0,0 +1,54 @@
+use mozjs::{rooted};
+use mozjs::{
+    context::{RawJSContext},
+    gc::{Handle,MutableHandle},
+    jsapi::{JSObject,Value,IsCallable},
+    rust::{ValueArray,wrappers::{JS_CallFunctionValue,JS_GetProperty}},
+};
+
+/// Calls `fval` with `this` bound to `this_obj`
+///
+/// `args` are rooted before the call is made. On failure an
+/// exception is pending on `cx`.
+pub fn call_value<const N: usize>(
+    cx: *mut RawJSContext,
+    this_obj: Handle<'_,*mut JSObject>,
+    fval: Handle<'_,Value>,
+    args: [Value; N],
+    rval: MutableHandle<'_,Value>,
+) -> bool {
+    rooted!(in(cx) let argv = ValueArray::new(args));
+    unsafe {
+        let argv = argv.to_handle_value_array();
+        JS_CallFunctionValue(cx, this_obj, fval, &argv, rval)
+    }
+}
+
+/// Looks up `obj[name]` and calls it, if it is callable
+///
+/// Returns `Ok(false)` when there is nothing to call, `Err(())` when
+/// the lookup or call threw.
+pub fn call_method<const N: usize>(
+    cx: *mut RawJSContext,
+    obj: Handle<'_,*mut JSObject>,
+    name: &std::ffi::CStr,
+    args: [Value; N],
+    rval: MutableHandle<'_,Value>,
+) -> Result<bool,()> {
+    rooted!(in(cx) let mut method = mozjs::jsval::UndefinedValue());
+    if !unsafe { JS_GetProperty(cx, obj, name.as_ptr(), method.handle_mut()) } {
+        return Err(());
+    }
+    if !is_callable(method.handle()) {
+        return Ok(false);
+    }
+    if call_value(cx, obj, method.handle(), args, rval) {
+        Ok(true)
+    } else {
+        Err(())
+    }
+}
+
+pub fn is_callable(val: Handle<'_,Value>) -> bool {
+    val.is_object() && unsafe { IsCallable(val.to_object()) }
+}
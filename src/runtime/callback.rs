@@ -13,13 +13,15 @@ use mozjs::{
     },
     panic::{wrap_panic},
     jsval::{UndefinedValue,ObjectValue},
-    glue::JobQueueTraps,
+    glue::{JobQueueTraps,CreateJobQueue},
 };
 
 use super::{
     incumbent_stack::{peek_incumbent_stack},
-    queue::{insert_into_filo},
-    checkpoint::{runtime_checkpoint,is_empty},
+    queue::{insert_into_filo,push_interrupt_queue,pop_interrupt_queue,drop_interrupt_queues as drop_saved_queues},
+    checkpoint::{microtask_checkpoint,is_empty},
+    async_stack::{current_async_parent},
+    rejection::{track_rejection},
 };
 #[allow(unused_imports)] use tracing::{trace,debug,info,warn,error,instrument};
 
@@ -106,7 +108,7 @@ unsafe extern "C" fn enqueue_promise_job(
     cx: *mut mozjs::context::RawJSContext,
     _promise: HandleObject,
     job: HandleObject,
-    _allocation_site: HandleObject,
+    allocation_site: HandleObject,
     host_defined_data: HandleObject,
 ) -> bool {
     wrap_panic(&mut || {
@@ -119,26 +121,57 @@ unsafe extern "C" fn enqueue_promise_job(
             if incumbent_obj.get().is_null() {
                 warn!("incumbent stack item is null pointer");
             }
-            insert_into_filo(Heap::boxed(job.get()), Heap::boxed(incumbent_obj.get()));
+            // settling a host promise has no script on the stack to allocate from
+            let allocation_site = match allocation_site.get() {
+                site if site.is_null() => current_async_parent(),
+                site => site,
+            };
+            insert_into_filo(Heap::boxed(job.get()), Heap::boxed(incumbent_obj.get()), allocation_site);
         }
     });
     true
 }
 
+/// Called when SpiderMonkey needs a fresh job queue (e.g. debugger pause)
+///
+/// The current queue & pending futures are set aside until the
+/// matching `pop_new_interrupt_queue`. The queue's id stands in for
+/// a pointer, the engine only compares it with what the pop returns.
+/// Returning null tells the engine the queue could not be saved.
 unsafe extern "C" fn push_new_interrupt_queue(_: *mut c_void) -> *const c_void {
-    null()
+    let mut queue: *const c_void = null();
+    wrap_panic(&mut || {
+        queue = push_interrupt_queue() as *const c_void;
+    });
+    queue
 }
 
-unsafe extern "C" fn pop_interrupt_queue(_: *mut c_void) -> *const c_void {
-    null()
+/// Restores the queue saved by the matching `push_new_interrupt_queue`
+unsafe extern "C" fn pop_new_interrupt_queue(_: *mut c_void) -> *const c_void {
+    let mut queue: *const c_void = null();
+    wrap_panic(&mut || {
+        match pop_interrupt_queue() {
+            Some(id) => queue = id as *const c_void,
+            None => error!("popped an interrupt queue that was never pushed"),
+        };
+    });
+    queue
 }
 
-unsafe extern "C" fn drop_interrupt_queues(_: *mut c_void) {}
+unsafe extern "C" fn drop_interrupt_queues(_: *mut c_void) {
+    wrap_panic(&mut || {
+        drop_saved_queues();
+    });
+}
 
+/// Engine requested microtask checkpoint
+///
+/// Only promise jobs are drained, macrotasks are left for the
+/// event loop in `runtime_checkpoint`.
 unsafe extern "C" fn run_jobs(_extra: *const c_void, cx: *mut mozjs::context::RawJSContext) {
     wrap_panic(&mut || {
         let mut ctx = unsafe { JSContext::from_ptr(NonNull::new(cx).unwrap()) };
-        runtime_checkpoint(&mut ctx);
+        microtask_checkpoint(&mut ctx);
     });
 }
 
@@ -156,8 +189,29 @@ pub const JOB_QUEUE_TRAPS: JobQueueTraps = JobQueueTraps {
     runJobs: Some(run_jobs),
     empty: Some(empty),
     pushNewInterruptQueue: Some(push_new_interrupt_queue),
-    popInterruptQueue: Some(pop_interrupt_queue),
+    popInterruptQueue: Some(pop_new_interrupt_queue),
     dropInterruptQueues: Some(drop_interrupt_queues),
 };
 
-
+/// Points the context's job queue at our thread local queue
+///
+/// This has to occur before any globals are created.
+pub fn install_job_queue(ctx: &mut JSContext) {
+    unsafe {
+        // setup using a hacky thread local queue
+        // interupt queues save & restore that thread local state
+        let job_queue = CreateJobQueue(
+            &JOB_QUEUE_TRAPS,
+            null(),
+            null_mut(),
+        );
+        mozjs::jsapi::SetJobQueue(ctx.raw_cx(), job_queue);
+
+        // unhandled rejections are logged after each microtask checkpoint
+        mozjs::jsapi::SetPromiseRejectionTrackerCallback(
+            ctx.raw_cx(),
+            Some(track_rejection),
+            null_mut(),
+        );
+    }
+}
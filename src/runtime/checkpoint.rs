@@ -1,16 +1,22 @@
 use std::{
     cell::{Cell},
+    time::{Instant},
 };
 use mozjs::{
     context::{JSContext},
 };
+#[allow(unused_imports)] use tracing::{trace,debug,info,warn,error,instrument,debug_span};
 use super::{
-    queue::{remove_from_filo,filo_empty},
-    resolvable_promise::{futures_empty, setup_to_resolve, poll_futures},
+    queue::{remove_from_filo,filo_empty,insert_macrotask,remove_macrotask,macrotasks_empty,clear_queues},
+    resolvable_promise::{futures_empty, poll_futures, wait_for_futures, cancel_pending_futures, set_draining},
+    metrics::{observe_checkpoint_jobs},
+    rejection::{report_unhandled_rejections,clear_unhandled_rejections},
+    profiler::{event_loop_label},
 };
 
 thread_local! {
     static CHECKPOINT: Cell<bool> = Cell::new(false);
+    static MICROTASK_CHECKPOINT: Cell<bool> = Cell::new(false);
 }
 
 pub fn set_checkpoint(b: bool) {
@@ -21,31 +27,206 @@ pub fn get_checkpoint() -> bool {
     CHECKPOINT.with(|c| c.get())
 }
 
+pub fn set_microtask_checkpoint(b: bool) {
+    MICROTASK_CHECKPOINT.with(|c| c.set(b));
+}
+
+pub fn get_microtask_checkpoint() -> bool {
+    MICROTASK_CHECKPOINT.with(|c| c.get())
+}
 
+
+/// Runs the event loop until there is nothing left to do
+///
+/// Follows the HTML model:
+///
+/// 1. completed futures are moved onto the macrotask queue
+/// 2. the oldest macrotask is ran
+/// 3. the microtask queue is drained (this includes any
+///    microtasks queued while draining)
+///
+/// Scripts evaluated before the first checkpoint are treated as the
+/// first macrotask, so microtasks are drained before anything else.
+///
+/// With no macrotask ready the thread sleeps until a future completes.
 pub fn runtime_checkpoint(ctx: &mut JSContext) {
+    run_until(ctx, None, || false);
+}
+
+/// Runs the event loop until `done` or there is nothing left to do
+///
+/// `done` is asked before every turn, whatever is left is ran by the
+/// next checkpoint. The loop never sleeps past `deadline`, so a `done`
+/// which checks the time is asked again once it passes. Returns
+/// whether `done` was satisfied.
+pub fn run_until<F: FnMut() -> bool>(ctx: &mut JSContext, deadline: Option<Instant>, mut done: F) -> bool {
     if get_checkpoint() {
         // function must be rrentrant
-        return;
+        return done();
     }
     set_checkpoint(true);
+    // whatever isn't a task is the loop's overhead
+    let _label = event_loop_label("(event loop)");
 
-    loop {
-        while let Some(task) = remove_from_filo() {
-            task.call(ctx);
+    microtask_checkpoint(ctx);
+    let finished = loop {
+        if done() {
+            break true;
         }
-
-        for (key,lambda)in poll_futures() {
-            setup_to_resolve(ctx, key, lambda);
+        for task in poll_futures() {
+            trace!("queueing macrotask from '{}'", task.source());
+            insert_macrotask(task);
         }
 
-        if futures_empty() && filo_empty() {
-            break;
-        }
-    }
+        match remove_macrotask() {
+            Some(task) => {
+                let span = debug_span!("event loop turn", source = task.source());
+                let _enter = span.enter();
+                debug!("macrotask phase");
+                task.call(ctx);
+                microtask_checkpoint(ctx);
+            }
+            None if is_empty() => break done(),
+            None if futures_empty() => microtask_checkpoint(ctx),
+            None => {
+                for task in wait_for_futures(deadline) {
+                    insert_macrotask(task);
+                }
+            }
+        };
+    };
 
     set_checkpoint(false);
+    finished
+}
+
+/// Drains the microtask queue
+///
+/// Microtasks queued by microtasks are ran in the same checkpoint,
+/// then any promise left rejected without a handler is reported.
+pub fn microtask_checkpoint(ctx: &mut JSContext) {
+    if get_microtask_checkpoint() {
+        return;
+    }
+    set_microtask_checkpoint(true);
+
+    let span = debug_span!("microtask checkpoint");
+    let _enter = span.enter();
+    let _label = event_loop_label("(microtasks)");
+    let mut ran = 0usize;
+    while let Some(task) = remove_from_filo() {
+        task.call(ctx);
+        ran += 1;
+    }
+    debug!("microtask phase complete, ran '{}' jobs", ran);
+    observe_checkpoint_jobs(ran);
+    report_unhandled_rejections(ctx);
+
+    set_microtask_checkpoint(false);
 }
 
 pub fn is_empty() -> bool {
-    futures_empty() && filo_empty()
+    futures_empty() && filo_empty() && macrotasks_empty()
+}
+
+/// Abandons all outstanding work so `runtime_checkpoint` returns
+///
+/// Safe to call from within a macrotask, the event loop exits after
+/// the current task finishes.
+#[instrument(skip_all)]
+pub fn shutdown() {
+    let futures = cancel_pending_futures();
+    let tasks = clear_queues();
+    clear_unhandled_rejections();
+    info!("shutdown dropped '{}' futures and '{}' queued tasks", futures, tasks);
+}
+
+/// Stops waiting on the outside world, but lets queued work finish
+///
+/// Pending futures are dropped (their promises never settle) & no
+/// new ones are accepted, so `runtime_checkpoint` returns once the
+/// tasks already queued, and the microtasks they queue, have ran.
+#[instrument(skip_all)]
+pub fn drain() {
+    set_draining(true);
+    let futures = cancel_pending_futures();
+    info!("draining, dropped '{}' futures", futures);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration};
+    use mozjs::{rooted};
+    use super::*;
+    use crate::runtime::{
+        queue::{Macrotask},
+        roots::{HeapRoot},
+        testing::{with_runtime,new_test_global,eval},
+    };
+
+    /// CPU time used by this thread so far
+    fn thread_cpu_time() -> Duration {
+        let mut time = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+        unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut time) };
+        Duration::new(time.tv_sec as u64, time.tv_nsec as u32)
+    }
+
+    #[test]
+    fn microtasks_drain_after_each_macrotask() {
+        with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            eval(ctx, global.handle(), "globalThis.log = [];").unwrap();
+            for n in 1..=2 {
+                let target = HeapRoot::new(global.get());
+                insert_macrotask(Macrotask::new("test", move |ctx: &mut JSContext| {
+                    rooted!(in(unsafe { ctx.raw_cx() }) let global = target.get());
+                    let source = format!("log.push('task{n}'); Promise.resolve().then(() => log.push('micro{n}'));");
+                    eval(ctx, global.handle(), &source).unwrap();
+                }));
+            }
+            runtime_checkpoint(ctx);
+            assert_eq!(eval(ctx, global.handle(), "log.join()").unwrap(), "task1,micro1,task2,micro2");
+        });
+    }
+
+    #[test]
+    fn scripts_are_the_first_macrotask() {
+        with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            eval(ctx, global.handle(), r#"
+                globalThis.log = ['script'];
+                sleep_ms(0).then(() => log.push('timer'));
+                Promise.resolve().then(() => log.push('micro'));
+                log.push('end');
+            "#).unwrap();
+            runtime_checkpoint(ctx);
+            assert_eq!(eval(ctx, global.handle(), "log.join()").unwrap(), "script,end,micro,timer");
+        });
+    }
+
+    #[test]
+    fn waiting_sleeps_instead_of_spinning() {
+        with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            eval(ctx, global.handle(), "globalThis.done = false; sleep_ms(300).then(() => done = true);").unwrap();
+            let (started, cpu) = (Instant::now(), thread_cpu_time());
+            runtime_checkpoint(ctx);
+            assert_eq!(eval(ctx, global.handle(), "done").unwrap(), "true");
+            assert!(started.elapsed() >= Duration::from_millis(300));
+            let used = thread_cpu_time() - cpu;
+            assert!(used < Duration::from_millis(150), "used {:?} of CPU", used);
+        });
+    }
+
+    #[test]
+    fn waiting_stops_at_the_deadline() {
+        with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            eval(ctx, global.handle(), "sleep_ms(60000);").unwrap();
+            let deadline = Instant::now() + Duration::from_millis(100);
+            assert!(run_until(ctx, Some(deadline), || Instant::now() >= deadline));
+            assert!(Instant::now() < deadline + Duration::from_secs(5));
+            assert!(!futures_empty());
+        });
+    }
 }
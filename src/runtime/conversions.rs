@@ -0,0 +1,356 @@
+use std::{
+    ffi::{CStr,CString},
+    io,
+};
+use mozjs::{rooted,typedarray};
+use mozjs::{
+    context::{RawJSContext},
+    conversions::{ToJSValConvertible,FromJSValConvertible,ConversionResult,StringificationBehavior},
+    gc::{Handle,MutableHandle},
+    jsapi::{JSObject,Value,CallArgs,JSPROP_ENUMERATE,JS_ReportErrorUTF8,JS_ClearPendingException,JS_NewPlainObject,SymbolCode,CurrentGlobalOrNull,ExceptionStackBehavior},
+    jsapi::JS::{GetWellKnownSymbol},
+    jsid::{SymbolId},
+    jsval::{UndefinedValue,ObjectValue},
+    rust::wrappers::{JS_DefineProperty,JS_GetPendingException,JS_SetPendingException,JS_DefineFunctionById},
+    typedarray::{Uint8Array,ArrayBuffer,CreateWith},
+};
+#[allow(unused_imports)] use tracing::{trace,debug,info,warn,error,instrument};
+
+use super::{
+    call::{call_method},
+    exception::{value_to_string},
+};
+
+/*
+ * Helpers for moving values between rust & JS
+ *
+ * Every `*mut RawJSContext` here must be within a realm.
+ */
+
+/// `args[index]` as a string, `None` when the argument is missing
+pub fn arg_string(cx: *mut RawJSContext, args: &CallArgs, index: u32) -> Option<String> {
+    if index >= args.argc_ {
+        return None;
+    }
+    value_to_string(cx, unsafe { Handle::from_raw(args.get(index)) })
+}
+
+/// `args[index]` if it is a string, `None` for anything else
+pub fn arg_exact_string(cx: *mut RawJSContext, args: &CallArgs, index: u32) -> Option<String> {
+    if index >= args.argc_ || !args.get(index).is_string() {
+        return None;
+    }
+    value_to_string(cx, unsafe { Handle::from_raw(args.get(index)) })
+}
+
+/// `args[index]` as bytes, accepts strings (as UTF-8), `Uint8Array` & `ArrayBuffer`
+pub fn arg_bytes(cx: *mut RawJSContext, args: &CallArgs, index: u32) -> Option<Vec<u8>> {
+    if index >= args.argc_ {
+        return None;
+    }
+    value_to_bytes(cx, unsafe { Handle::from_raw(args.get(index)) })
+}
+
+/// Copies the contents of a string, `Uint8Array` or `ArrayBuffer`
+pub fn value_to_bytes(cx: *mut RawJSContext, val: Handle<'_,Value>) -> Option<Vec<u8>> {
+    if val.is_string() {
+        return value_to_string(cx, val).map(String::into_bytes);
+    }
+    if !val.is_object() {
+        return None;
+    }
+    rooted!(in(cx) let obj = val.to_object());
+    typedarray!(in(cx) let array: Uint8Array = obj.get());
+    if let Ok(array) = array {
+        return Some(unsafe { array.as_slice() }.to_vec());
+    }
+    typedarray!(in(cx) let buffer: ArrayBuffer = obj.get());
+    if let Ok(buffer) = buffer {
+        return Some(unsafe { buffer.as_slice() }.to_vec());
+    }
+    None
+}
+
+/// Converts any iterable (e.g. an array) into strings, `None` if it isn't one
+///
+/// Clears the exception raised by a failed conversion.
+pub fn value_to_strings(cx: *mut RawJSContext, val: Handle<'_,Value>) -> Option<Vec<String>> {
+    match unsafe { Vec::<String>::from_jsval(cx, val, StringificationBehavior::Default) } {
+        Ok(ConversionResult::Success(strings)) => Some(strings),
+        Ok(ConversionResult::Failure(_)) => None,
+        Err(()) => {
+            unsafe { JS_ClearPendingException(cx) };
+            None
+        }
+    }
+}
+
+/// The own enumerable `[key, value]` pairs of an object, values as strings
+///
+/// Goes through `Object.entries` of the current global. `None` when
+/// `val` isn't an object or the conversion throws (the exception is
+/// cleared).
+pub fn object_entries(cx: *mut RawJSContext, val: Handle<'_,Value>) -> Option<Vec<(String,String)>> {
+    if !val.is_object() {
+        return None;
+    }
+    let global = unsafe { CurrentGlobalOrNull(cx) };
+    if global.is_null() {
+        return None;
+    }
+    rooted!(in(cx) let global = ObjectValue(global));
+    rooted!(in(cx) let mut object = UndefinedValue());
+    if !get_option(cx, global.handle(), c"Object", object.handle_mut()) || !object.is_object() {
+        unsafe { JS_ClearPendingException(cx) };
+        return None;
+    }
+    rooted!(in(cx) let object = object.to_object());
+    rooted!(in(cx) let mut entries = UndefinedValue());
+    if call_method(cx, object.handle(), c"entries", [val.get()], entries.handle_mut()) != Ok(true) {
+        unsafe { JS_ClearPendingException(cx) };
+        return None;
+    }
+    match unsafe { Vec::<Vec<String>>::from_jsval(cx, entries.handle(), StringificationBehavior::Default) } {
+        Ok(ConversionResult::Success(pairs)) => Some(pairs.into_iter()
+            .filter_map(|mut pair| (pair.len() == 2).then(|| (pair.remove(0), pair.remove(0))))
+            .collect()),
+        _ => {
+            unsafe { JS_ClearPendingException(cx) };
+            None
+        }
+    }
+}
+
+/// Sets `rval` to a new `Uint8Array` holding a copy of `bytes`
+pub fn new_uint8_array(cx: *mut RawJSContext, bytes: &[u8], mut rval: MutableHandle<'_,Value>) -> bool {
+    rooted!(in(cx) let mut array = std::ptr::null_mut::<JSObject>());
+    if unsafe { Uint8Array::create(cx, CreateWith::Slice(bytes), array.handle_mut()) }.is_err() {
+        error!("could not allocate Uint8Array of '{}' bytes", bytes.len());
+        return false;
+    }
+    rval.set(ObjectValue(array.get()));
+    true
+}
+
+/// Sets `rval` to a new `Error`, with `error.code` when given
+pub fn new_error(cx: *mut RawJSContext, message: &str, code: Option<&str>, mut rval: MutableHandle<'_,Value>) -> bool {
+    // the simplest way to get a real `Error` (with a stack) is to throw one
+    let message = CString::new(message.replace('\0', "")).unwrap_or_default();
+    rooted!(in(cx) let mut exn = UndefinedValue());
+    unsafe {
+        JS_ReportErrorUTF8(cx, c"%s".as_ptr(), message.as_ptr());
+        let ok = JS_GetPendingException(cx, exn.handle_mut());
+        JS_ClearPendingException(cx);
+        if !ok || !exn.is_object() {
+            error!("could not create error object");
+            return false;
+        }
+    }
+    if let Some(code) = code {
+        rooted!(in(cx) let obj = exn.to_object());
+        rooted!(in(cx) let mut code_val = UndefinedValue());
+        unsafe { code.to_jsval(cx, code_val.handle_mut()) };
+        define_property(cx, obj.handle(), c"code", code_val.handle());
+    }
+    rval.set(exn.get());
+    true
+}
+
+/// Sets `rval` to an `Error` describing `e`, `error.code` is the errno name (e.g. `ENOENT`)
+pub fn new_io_error(cx: *mut RawJSContext, e: &io::Error, rval: MutableHandle<'_,Value>) -> bool {
+    let code = io_error_code(e);
+    new_error(cx, &format!("{}: {}", code, e), Some(code), rval)
+}
+
+/// Throws an `Error` describing `e`, always returns `false` for the caller to return
+pub fn throw_io_error(cx: *mut RawJSContext, e: &io::Error) -> bool {
+    rooted!(in(cx) let mut exn = UndefinedValue());
+    if new_io_error(cx, e, exn.handle_mut()) {
+        unsafe { JS_SetPendingException(cx, exn.handle(), ExceptionStackBehavior::Capture) };
+    }
+    false
+}
+
+/// Maps an I/O error onto the name node.js (and libuv) would use
+///
+/// The OS error code is used when there is one, the error's kind only
+/// when there isn't (e.g. errors made by rust itself).
+pub fn io_error_code(e: &io::Error) -> &'static str {
+    use io::ErrorKind::*;
+    if let Some(code) = e.raw_os_error() {
+        return errno_name(code).unwrap_or("UNKNOWN");
+    }
+    match e.kind() {
+        NotFound => "ENOENT",
+        PermissionDenied => "EACCES",
+        AlreadyExists => "EEXIST",
+        ConnectionRefused => "ECONNREFUSED",
+        ConnectionReset => "ECONNRESET",
+        ConnectionAborted => "ECONNABORTED",
+        NotConnected => "ENOTCONN",
+        AddrInUse => "EADDRINUSE",
+        AddrNotAvailable => "EADDRNOTAVAIL",
+        BrokenPipe => "EPIPE",
+        WouldBlock => "EAGAIN",
+        InvalidInput | InvalidFilename => "EINVAL",
+        TimedOut => "ETIMEDOUT",
+        Interrupted => "EINTR",
+        Unsupported => "ENOTSUP",
+        OutOfMemory => "ENOMEM",
+        IsADirectory => "EISDIR",
+        NotADirectory => "ENOTDIR",
+        DirectoryNotEmpty => "ENOTEMPTY",
+        ReadOnlyFilesystem => "EROFS",
+        CrossesDevices => "EXDEV",
+        _ => "EIO",
+    }
+}
+
+/// The name of an `errno` value, `None` for ones node.js has no name for
+fn errno_name(code: i32) -> Option<&'static str> {
+    let name = match code {
+        libc::EPERM => "EPERM",
+        libc::ENOENT => "ENOENT",
+        libc::ESRCH => "ESRCH",
+        libc::EINTR => "EINTR",
+        libc::EIO => "EIO",
+        libc::ENXIO => "ENXIO",
+        libc::E2BIG => "E2BIG",
+        libc::EBADF => "EBADF",
+        libc::EAGAIN => "EAGAIN",
+        libc::ENOMEM => "ENOMEM",
+        libc::EACCES => "EACCES",
+        libc::EFAULT => "EFAULT",
+        libc::EBUSY => "EBUSY",
+        libc::EEXIST => "EEXIST",
+        libc::EXDEV => "EXDEV",
+        libc::ENODEV => "ENODEV",
+        libc::ENOTDIR => "ENOTDIR",
+        libc::EISDIR => "EISDIR",
+        libc::EINVAL => "EINVAL",
+        libc::ENFILE => "ENFILE",
+        libc::EMFILE => "EMFILE",
+        libc::ENOTTY => "ENOTTY",
+        libc::ETXTBSY => "ETXTBSY",
+        libc::EFBIG => "EFBIG",
+        libc::ENOSPC => "ENOSPC",
+        libc::ESPIPE => "ESPIPE",
+        libc::EROFS => "EROFS",
+        libc::EMLINK => "EMLINK",
+        libc::EPIPE => "EPIPE",
+        libc::ERANGE => "ERANGE",
+        libc::ENAMETOOLONG => "ENAMETOOLONG",
+        libc::ENOSYS => "ENOSYS",
+        libc::ENOTEMPTY => "ENOTEMPTY",
+        libc::ELOOP => "ELOOP",
+        libc::EPROTO => "EPROTO",
+        libc::EMSGSIZE => "EMSGSIZE",
+        libc::EPROTONOSUPPORT => "EPROTONOSUPPORT",
+        libc::ENOTSUP => "ENOTSUP",
+        libc::EAFNOSUPPORT => "EAFNOSUPPORT",
+        libc::EADDRINUSE => "EADDRINUSE",
+        libc::EADDRNOTAVAIL => "EADDRNOTAVAIL",
+        libc::ENETDOWN => "ENETDOWN",
+        libc::ENETUNREACH => "ENETUNREACH",
+        libc::ECONNABORTED => "ECONNABORTED",
+        libc::ECONNRESET => "ECONNRESET",
+        libc::ENOBUFS => "ENOBUFS",
+        libc::EISCONN => "EISCONN",
+        libc::ENOTCONN => "ENOTCONN",
+        libc::ETIMEDOUT => "ETIMEDOUT",
+        libc::ECONNREFUSED => "ECONNREFUSED",
+        libc::EHOSTUNREACH => "EHOSTUNREACH",
+        libc::EALREADY => "EALREADY",
+        libc::EINPROGRESS => "EINPROGRESS",
+        libc::ECANCELED => "ECANCELED",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// Creates an empty object in the current realm, null on OOM
+pub fn new_object(cx: *mut RawJSContext) -> *mut JSObject {
+    unsafe { JS_NewPlainObject(cx) }
+}
+
+/// `obj[name] = value` as an enumerable data property
+pub fn define_property(cx: *mut RawJSContext, obj: Handle<'_,*mut JSObject>, name: &CStr, value: Handle<'_,Value>) -> bool {
+    unsafe { JS_DefineProperty(cx, obj, name.as_ptr(), value, JSPROP_ENUMERATE as u32) }
+}
+
+/// `obj[name] = value` for anything which converts to JS
+pub fn define_value<T: ToJSValConvertible + ?Sized>(cx: *mut RawJSContext, obj: Handle<'_,*mut JSObject>, name: &CStr, value: &T) -> bool {
+    rooted!(in(cx) let mut val = UndefinedValue());
+    unsafe { value.to_jsval(cx, val.handle_mut()) };
+    define_property(cx, obj, name, val.handle())
+}
+
+/// Reads `options[name]` into `rval`, leaves it `undefined` when `options` isn't an object
+pub fn get_option(cx: *mut RawJSContext, options: Handle<'_,Value>, name: &CStr, rval: MutableHandle<'_,Value>) -> bool {
+    if !options.is_object() {
+        return true;
+    }
+    rooted!(in(cx) let obj = options.to_object());
+    unsafe { mozjs::rust::wrappers::JS_GetProperty(cx, obj.handle(), name.as_ptr(), rval) }
+}
+
+/// `options[name]` coerced to a boolean, `false` when missing
+pub fn option_bool(cx: *mut RawJSContext, options: Handle<'_,Value>, name: &CStr) -> bool {
+    rooted!(in(cx) let mut val = UndefinedValue());
+    if !get_option(cx, options, name, val.handle_mut()) {
+        unsafe { JS_ClearPendingException(cx) };
+        return false;
+    }
+    unsafe { mozjs::rust::ToBoolean(val.handle()) }
+}
+
+/// `options[name]` as a string, `None` when missing
+pub fn option_string(cx: *mut RawJSContext, options: Handle<'_,Value>, name: &CStr) -> Option<String> {
+    rooted!(in(cx) let mut val = UndefinedValue());
+    if !get_option(cx, options, name, val.handle_mut()) {
+        unsafe { JS_ClearPendingException(cx) };
+        return None;
+    }
+    if val.is_undefined() || val.is_null() {
+        return None;
+    }
+    value_to_string(cx, val.handle())
+}
+
+/// `options[name]` as a number, `None` when missing or not a number
+pub fn option_number(cx: *mut RawJSContext, options: Handle<'_,Value>, name: &CStr) -> Option<f64> {
+    rooted!(in(cx) let mut val = UndefinedValue());
+    if !get_option(cx, options, name, val.handle_mut()) {
+        unsafe { JS_ClearPendingException(cx) };
+        return None;
+    }
+    val.is_number().then(|| val.to_number())
+}
+
+/// Makes `obj` async iterable by defining `obj[Symbol.asyncIterator]` to return `obj`
+///
+/// `obj` is expected to have its own `next()`.
+pub fn define_async_iterator(cx: *mut RawJSContext, obj: Handle<'_,*mut JSObject>) -> bool {
+    rooted!(in(cx) let symbol = unsafe { GetWellKnownSymbol(cx, SymbolCode::asyncIterator) });
+    rooted!(in(cx) let id = SymbolId(symbol.get()));
+    !unsafe { JS_DefineFunctionById(cx, obj, id.handle(), Some(return_this), 0, 0) }.is_null()
+}
+
+unsafe extern "C" fn return_this(_cx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    let args = unsafe { CallArgs::from_vp(vp, argc) };
+    args.rval().set(args.thisv().get());
+    true
+}
+
+/// Sets `rval` to an iterator result `{ value, done }`
+pub fn new_iter_result(cx: *mut RawJSContext, value: Handle<'_,Value>, done: bool, mut rval: MutableHandle<'_,Value>) -> bool {
+    rooted!(in(cx) let obj = new_object(cx));
+    if obj.get().is_null() {
+        return false;
+    }
+    if !define_property(cx, obj.handle(), c"value", value) || !define_value(cx, obj.handle(), c"done", &done) {
+        return false;
+    }
+    rval.set(ObjectValue(obj.get()));
+    true
+}
@@ -0,0 +1,304 @@
+use std::{
+    collections::{BTreeMap},
+    fmt::{Write as _},
+    io,
+    ops::{DerefMut},
+    path::{Path},
+    ptr::{null_mut},
+    sync::{Mutex,LazyLock},
+    sync::atomic::{AtomicBool,Ordering},
+};
+use mozjs::{rooted};
+use mozjs::{
+    context::{JSContext},
+    gc::{Handle},
+    jsapi::{JSObject,OnNewGlobalHookOption,JS_NewGlobalObject,JS_NewPlainObject,EnableCodeCoverage,DefineTestingFunctions},
+    jsval::{UndefinedValue,ObjectValue},
+    realm::{AutoRealm},
+    rust::{SIMPLE_GLOBAL_CLASS,RealmOptions,wrappers::{JS_WrapValue}},
+};
+#[allow(unused_imports)] use tracing::{trace,debug,info,warn,error,instrument};
+
+use super::{
+    call::{call_method},
+    exception::{report_pending_exception,value_to_string},
+    incumbent_stack::{enter_incumbent_stack},
+};
+use crate::realm::{realm_ids,realm_global};
+
+/*
+ * Code coverage
+ *
+ * `enable_coverage` turns on SpiderMonkey's LCOV coverage for every
+ * realm, it must be called before the first runtime is made. Once a
+ * thread's event loop has finished, `collect_coverage` asks the engine
+ * for the LCOV of each of its realms (through the `getLcovInfo` testing
+ * function, called from a private realm) & merges it into one report
+ * shared by every thread. `write_coverage` writes the report. A realm
+ * which is forgotten before then, e.g. a test file's, is collected by
+ * `collect_realm_coverage` beforehand.
+ *
+ * A script ran by several realms or workers has its counts summed.
+ * Records for scripts which aren't files, e.g. the runtime's own
+ * preludes, are dropped: LCOV tools need the source.
+ *
+ */
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static REPORT: LazyLock<Mutex<BTreeMap<String,FileCoverage>>> = LazyLock::new(|| Mutex::new(BTreeMap::new()));
+
+/// The counts for one source file, merged from every record naming it
+#[derive(Default,Debug)]
+struct FileCoverage {
+    /// function name to the line it starts on
+    functions: BTreeMap<String,u32>,
+    /// function name to how many times it was called
+    function_hits: BTreeMap<String,u64>,
+    /// `(line, block, branch)` to how many times it was taken, `None` if never reached
+    branches: BTreeMap<(u32,u32,u32),Option<u64>>,
+    /// line to how many times it ran
+    lines: BTreeMap<u32,u64>,
+}
+
+/// Adds every record of an LCOV tracefile to `report`
+fn merge_lcov(report: &mut BTreeMap<String,FileCoverage>, lcov: &str) {
+    let mut current: Option<&mut FileCoverage> = None;
+    for line in lcov.lines() {
+        let (key, value) = line.split_once(':').unwrap_or((line, ""));
+        if key == "SF" {
+            current = Some(report.entry(value.to_string()).or_default());
+            continue;
+        }
+        if key == "end_of_record" {
+            current = None;
+            continue;
+        }
+        let Some(file) = current.as_deref_mut() else {
+            continue;
+        };
+        // the totals (`FNF`, `LH`, ...) are recomputed when written
+        match key {
+            "FN" => if let Some((line, name)) = value.split_once(',') {
+                if let Ok(line) = line.parse() {
+                    file.functions.entry(name.to_string()).or_insert(line);
+                }
+            },
+            "FNDA" => if let Some((count, name)) = value.split_once(',') {
+                if let Ok(count) = count.parse::<u64>() {
+                    *file.function_hits.entry(name.to_string()).or_default() += count;
+                }
+            },
+            "BRDA" => {
+                let fields: Vec<&str> = value.split(',').collect();
+                if let [line, block, branch, taken] = fields[..] {
+                    if let (Ok(line), Ok(block), Ok(branch)) = (line.parse(), block.parse(), branch.parse()) {
+                        let entry = file.branches.entry((line, block, branch)).or_default();
+                        if let Ok(taken) = taken.parse::<u64>() {
+                            *entry = Some(entry.unwrap_or(0) + taken);
+                        }
+                    }
+                }
+            }
+            "DA" => if let Some((line, count)) = value.split_once(',') {
+                if let (Ok(line), Ok(count)) = (line.parse(), count.parse::<u64>()) {
+                    *file.lines.entry(line).or_default() += count;
+                }
+            },
+            _ => { },
+        }
+    }
+}
+
+/// `report` as an LCOV tracefile
+fn render_lcov(report: &BTreeMap<String,FileCoverage>) -> String {
+    let mut out = String::new();
+    for (path, file) in report {
+        let _ = writeln!(out, "TN:");
+        let _ = writeln!(out, "SF:{}", path);
+        for (name, line) in &file.functions {
+            let _ = writeln!(out, "FN:{},{}", line, name);
+        }
+        for name in file.functions.keys() {
+            let _ = writeln!(out, "FNDA:{},{}", file.function_hits.get(name).copied().unwrap_or(0), name);
+        }
+        let _ = writeln!(out, "FNF:{}", file.functions.len());
+        let _ = writeln!(out, "FNH:{}", file.functions.keys().filter(|name| file.function_hits.get(*name).is_some_and(|hits| *hits > 0)).count());
+        for ((line, block, branch), taken) in &file.branches {
+            match taken {
+                Some(taken) => { let _ = writeln!(out, "BRDA:{},{},{},{}", line, block, branch, taken); }
+                None => { let _ = writeln!(out, "BRDA:{},{},{},-", line, block, branch); }
+            }
+        }
+        let _ = writeln!(out, "BRF:{}", file.branches.len());
+        let _ = writeln!(out, "BRH:{}", file.branches.values().filter(|taken| taken.is_some_and(|t| t > 0)).count());
+        for (line, count) in &file.lines {
+            let _ = writeln!(out, "DA:{},{}", line, count);
+        }
+        let _ = writeln!(out, "LF:{}", file.lines.len());
+        let _ = writeln!(out, "LH:{}", file.lines.values().filter(|count| **count > 0).count());
+        let _ = writeln!(out, "end_of_record");
+    }
+    out
+}
+
+/// Records coverage for every realm from now on, in every thread
+///
+/// The engine refuses once a runtime exists.
+pub fn enable_coverage() {
+    unsafe { EnableCodeCoverage() };
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn coverage_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Merges the coverage of every realm made on this thread into the report
+///
+/// Call once the event loop has finished, later runs aren't seen.
+pub fn collect_coverage(ctx: &mut JSContext) {
+    collect(ctx, realm_ids());
+}
+
+/// Merges the coverage of realm `id` so far, for a realm about to be forgotten
+pub(crate) fn collect_realm_coverage(ctx: &mut JSContext, id: u64) {
+    collect(ctx, vec![id]);
+}
+
+#[instrument(skip_all)]
+fn collect(ctx: &mut JSContext, ids: Vec<u64>) {
+    if !coverage_enabled() {
+        return;
+    }
+    if ids.is_empty() {
+        return;
+    }
+    rooted!(in(unsafe { ctx.raw_cx() }) let global = new_coverage_global(ctx));
+    if global.get().is_null() {
+        error!("could not create the coverage realm");
+        return;
+    }
+    let mut collected = Vec::with_capacity(ids.len());
+    enter_incumbent_stack(ctx, global.handle(), |realm: &mut AutoRealm, _: Handle<'_,*mut JSObject>| {
+        let cx = unsafe { realm.deref_mut().raw_cx() };
+        rooted!(in(cx) let testing = unsafe { JS_NewPlainObject(cx) });
+        if testing.get().is_null() || !unsafe { DefineTestingFunctions(cx, testing.handle().into(), false, false) } {
+            report_pending_exception(cx, "coverage");
+            return;
+        }
+        for id in ids {
+            let target = realm_global(id);
+            if target.is_null() {
+                continue;
+            }
+            rooted!(in(cx) let mut target = ObjectValue(target));
+            rooted!(in(cx) let mut lcov = UndefinedValue());
+            if !unsafe { JS_WrapValue(cx, target.handle_mut()) } {
+                report_pending_exception(cx, "coverage");
+                continue;
+            }
+            match call_method(cx, testing.handle(), c"getLcovInfo", [target.get()], lcov.handle_mut()) {
+                Ok(true) => { },
+                Ok(false) => {
+                    error!("the engine has no 'getLcovInfo'");
+                    return;
+                }
+                Err(()) => {
+                    report_pending_exception(cx, "coverage");
+                    continue;
+                }
+            }
+            match value_to_string(cx, lcov.handle()) {
+                Some(lcov) => collected.push(lcov),
+                None => warn!("realm '{}' has no coverage", id),
+            }
+        }
+    });
+    let mut report = REPORT.lock().unwrap();
+    for lcov in &collected {
+        merge_lcov(&mut report, lcov);
+    }
+    debug!("merged coverage from '{}' realms", collected.len());
+}
+
+/// Writes every thread's coverage so far to `path`
+pub fn write_coverage(path: &Path) -> io::Result<()> {
+    let mut report = REPORT.lock().unwrap();
+    report.retain(|source, _| Path::new(source).is_file());
+    std::fs::write(path, render_lcov(&report))?;
+    info!("wrote coverage of '{}' files to '{}'", report.len(), path.display());
+    Ok(())
+}
+
+/// A realm of our own to call the testing functions from, never covered itself
+fn new_coverage_global(ctx: &mut JSContext) -> *mut JSObject {
+    let mut options = RealmOptions::default();
+    options.creationOptions_.invisibleToDebugger_ = true;
+    unsafe {
+        JS_NewGlobalObject(
+            ctx.raw_cx(),
+            &SIMPLE_GLOBAL_CLASS,
+            null_mut(),
+            OnNewGlobalHookOption::DontFireOnNewGlobalHook,
+            &*options,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCRIPT: &str = "\
+TN:
+SF:/a.js
+FN:1,add
+FN:5,unused
+FNDA:2,add
+FNDA:0,unused
+FNF:2
+FNH:1
+BRDA:2,0,0,1
+BRDA:2,0,1,-
+BRF:2
+BRH:1
+DA:1,1
+DA:2,2
+DA:6,0
+LF:3
+LH:2
+end_of_record
+";
+
+    #[test]
+    fn records_are_rendered_with_recomputed_totals() {
+        let mut report = BTreeMap::new();
+        merge_lcov(&mut report, SCRIPT);
+        assert_eq!(render_lcov(&report), SCRIPT);
+    }
+
+    #[test]
+    fn records_naming_one_file_are_summed() {
+        let mut report = BTreeMap::new();
+        merge_lcov(&mut report, SCRIPT);
+        merge_lcov(&mut report, "SF:/a.js\nFNDA:1,unused\nBRDA:2,0,1,3\nDA:6,4\nend_of_record\nSF:/b.js\nDA:1,0\nend_of_record\n");
+        let a = &report["/a.js"];
+        assert_eq!(a.function_hits["add"], 2);
+        assert_eq!(a.function_hits["unused"], 1);
+        assert_eq!(a.branches[&(2, 0, 1)], Some(3));
+        assert_eq!(a.lines[&6], 4);
+        let rendered = render_lcov(&report);
+        assert!(rendered.contains("FNH:2\n"));
+        assert!(rendered.contains("BRH:2\n"));
+        assert!(rendered.contains("LH:3\n"));
+        assert!(rendered.ends_with("SF:/b.js\nFNF:0\nFNH:0\nBRF:0\nBRH:0\nDA:1,0\nLF:1\nLH:0\nend_of_record\n"));
+    }
+
+    #[test]
+    fn lines_outside_of_a_record_are_ignored() {
+        let mut report = BTreeMap::new();
+        merge_lcov(&mut report, "DA:1,1\nSF:/a.js\nDA:x,1\nBRDA:1,0\nend_of_record\nDA:2,1\n");
+        assert!(report["/a.js"].lines.is_empty());
+        assert!(report["/a.js"].branches.is_empty());
+    }
+}
@@ -0,0 +1,99 @@
+use std::{
+    ffi::{CStr},
+    ptr::{NonNull},
+};
+use mozjs::{rooted};
+use mozjs::{
+    context::{RawJSContext},
+    conversions::{FromJSValConvertible,ConversionResult},
+    gc::{Handle},
+    jsapi::{Value,JS_IsExceptionPending,JS_ClearPendingException},
+    jsval::{UndefinedValue},
+    rust::wrappers::{JS_GetPendingException,JS_GetProperty},
+};
+#[allow(unused_imports)] use tracing::{trace,debug,info,warn,error,instrument};
+
+use super::{
+    source_map::{map_stack},
+};
+
+/// Takes the pending exception (if any) off the context and logs it
+///
+/// `origin` is what was running when the exception escaped (e.g.
+/// `"promise job"`). Returns `true` if there was an exception.
+pub fn report_pending_exception(cx: *mut RawJSContext, origin: &str) -> bool {
+    unsafe {
+        if !JS_IsExceptionPending(cx) {
+            return false;
+        }
+        rooted!(in(cx) let mut exn = UndefinedValue());
+        if !JS_GetPendingException(cx, exn.handle_mut()) {
+            JS_ClearPendingException(cx);
+            error!("{}: exception is pending but could not be retrieved", origin);
+            return true;
+        }
+        JS_ClearPendingException(cx);
+        let (message, stack) = describe_exception(cx, exn.handle());
+        error!("{}: uncaught exception: {}\n{}", origin, message, stack);
+    }
+    true
+}
+
+/// Returns `(message, stack)` for a thrown value
+///
+/// `stack` is empty when the value isn't an `Error` (or lacks one). An
+/// error without a stack (e.g. a `SyntaxError` from compiling) gets
+/// its `fileName:lineNumber:columnNumber` instead. Frames within
+/// scripts with a source map are rewritten to their original source.
+pub fn describe_exception(cx: *mut RawJSContext, exn: Handle<'_,Value>) -> (String, String) {
+    let message = value_to_string(cx, exn).unwrap_or_else(|| "<unprintable exception>".to_string());
+    let mut stack = String::new();
+    if exn.is_object() {
+        rooted!(in(cx) let obj = exn.to_object());
+        rooted!(in(cx) let mut val = UndefinedValue());
+        if unsafe { JS_GetProperty(cx, obj.handle(), c"stack".as_ptr(), val.handle_mut()) } && val.is_string() {
+            stack = value_to_string(cx, val.handle()).unwrap_or_default();
+        } else {
+            unsafe { JS_ClearPendingException(cx) };
+        }
+        if stack.is_empty() {
+            stack = error_location(cx, exn).unwrap_or_default();
+        }
+    }
+    (message, map_stack(&stack))
+}
+
+/// `@fileName:lineNumber:columnNumber`, a one frame stack
+fn error_location(cx: *mut RawJSContext, exn: Handle<'_,Value>) -> Option<String> {
+    rooted!(in(cx) let obj = exn.to_object());
+    rooted!(in(cx) let mut val = UndefinedValue());
+    let mut property = |name: &CStr| -> Option<String> {
+        if !unsafe { JS_GetProperty(cx, obj.handle(), name.as_ptr(), val.handle_mut()) } {
+            unsafe { JS_ClearPendingException(cx) };
+            return None;
+        }
+        if val.is_undefined() {
+            return None;
+        }
+        value_to_string(cx, val.handle())
+    };
+    let file = property(c"fileName")?;
+    let line = property(c"lineNumber")?;
+    let column = property(c"columnNumber").unwrap_or_else(|| "1".to_string());
+    Some(format!("@{}:{}:{}\n", file, line, column))
+}
+
+/// Converts any value to a string the same way `String(value)` would
+pub fn value_to_string(cx: *mut RawJSContext, val: Handle<'_,Value>) -> Option<String> {
+    if val.is_string() {
+        let s = NonNull::new(val.to_string())?;
+        return Some(unsafe { mozjs::conversions::jsstr_to_string(cx, s) });
+    }
+    match unsafe { String::from_jsval(cx, val, ()) } {
+        Ok(ConversionResult::Success(s)) => Some(s),
+        _ => {
+            unsafe { JS_ClearPendingException(cx) };
+            None
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use std::{
+    any::{Any},
+    ffi::{c_void},
+    ptr::{null},
+};
+use mozjs::{
+    JSCLASS_RESERVED_SLOTS_MASK,
+    context::{RawJSContext},
+    jsapi::{
+        JSObject,JSClass,JSClassOps,GCContext,
+        JSCLASS_RESERVED_SLOTS_SHIFT, JSCLASS_FOREGROUND_FINALIZE,
+        JS_NewObject,JS_SetReservedSlot,
+    },
+    jsval::{UndefinedValue,PrivateValue},
+    rust::{get_object_class},
+};
+#[allow(unused_imports)] use tracing::{trace,debug,info,warn,error,instrument};
+
+/*
+ * Host objects
+ *
+ * A JS object which owns a rust value. The value lives in reserved
+ * slot 0 as a `Box<Box<dyn Any>>` and is dropped by the finalizer,
+ * so sockets/threads/etc. are closed once the object is collected.
+ *
+ */
+
+static HOST_OBJECT_OPS: JSClassOps = JSClassOps {
+    addProperty: None,
+    delProperty: None,
+    enumerate: None,
+    newEnumerate: None,
+    resolve: None,
+    mayResolve: None,
+    finalize: Some(finalize_host_object),
+    call: None,
+    construct: None,
+    trace: None,
+};
+
+static HOST_OBJECT_CLASS: JSClass = JSClass {
+    name: c"HostObject".as_ptr(),
+    flags: JSCLASS_FOREGROUND_FINALIZE | ((1 & JSCLASS_RESERVED_SLOTS_MASK) << JSCLASS_RESERVED_SLOTS_SHIFT),
+    cOps: &HOST_OBJECT_OPS,
+    spec: null(),
+    ext: null(),
+    oOps: null(),
+};
+
+type HostData = Box<dyn Any>;
+
+/// Creates a new object (in the current realm) which owns `value`
+///
+/// Returns null on OOM.
+pub fn new_host_object<T: Any>(cx: *mut RawJSContext, value: T) -> *mut JSObject {
+    let obj = unsafe { JS_NewObject(cx, &HOST_OBJECT_CLASS) };
+    if obj.is_null() {
+        error!("could not allocate host object");
+        return obj;
+    }
+    let data: Box<HostData> = Box::new(Box::new(value));
+    let slot = PrivateValue(Box::into_raw(data) as *const c_void);
+    unsafe { JS_SetReservedSlot(obj, 0, &slot) };
+    obj
+}
+
+/// Borrows the value owned by a host object
+///
+/// Returns `None` if `obj` isn't a host object, or owns a different type.
+/// The caller must not hold the reference across anything that can GC.
+pub unsafe fn host_object_data<'a, T: Any>(obj: *mut JSObject) -> Option<&'a mut T> {
+    let data = unsafe { host_data_ptr(obj) }?;
+    unsafe { (*data).downcast_mut::<T>() }
+}
+
+/// Drops the value owned by a host object early (e.g. `close()`)
+///
+/// The object stays alive, but `host_object_data` returns `None` from now on.
+pub unsafe fn take_host_object_data(obj: *mut JSObject) {
+    if let Some(data) = unsafe { host_data_ptr(obj) } {
+        let empty = UndefinedValue();
+        unsafe {
+            JS_SetReservedSlot(obj, 0, &empty);
+            drop(Box::from_raw(data));
+        }
+    }
+}
+
+unsafe fn host_data_ptr(obj: *mut JSObject) -> Option<*mut HostData> {
+    if obj.is_null() || unsafe { get_object_class(obj) } != &HOST_OBJECT_CLASS as *const JSClass {
+        return None;
+    }
+    let mut slot = UndefinedValue();
+    unsafe { mozjs::glue::JS_GetReservedSlot(obj, 0, &mut slot) };
+    if slot.is_undefined() {
+        return None;
+    }
+    Some(slot.to_private() as *mut HostData)
+}
+
+unsafe extern "C" fn finalize_host_object(_gcx: *mut GCContext, obj: *mut JSObject) {
+    if let Some(data) = unsafe { host_data_ptr(obj) } {
+        trace!("finalizing host object");
+        drop(unsafe { Box::from_raw(data) });
+    }
+}
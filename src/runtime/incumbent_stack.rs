@@ -11,6 +11,8 @@ use mozjs::{
 };
 #[allow(unused_imports)] use tracing::{trace,debug,info,warn,error,instrument};
 
+use crate::realm::{realm_id_of};
+
 thread_local! {
     static INCUMBENT_STACK: LazyCell<RefCell<Vec<Box<Heap<*mut JSObject>>>>> = LazyCell::new(|| RefCell::new(Vec::new()));
 }
@@ -33,6 +35,11 @@ fn pop_incumbent_stack() -> Option<Box<Heap<*mut JSObject>>> {
     })
 }
 
+/// The realm of every entry, bottom of the stack first
+pub(crate) fn incumbent_realms() -> Vec<Option<u64>> {
+    INCUMBENT_STACK.with(|inner| inner.borrow().iter().map(|g| realm_id_of(g.get())).collect())
+}
+
 /// Peak at what is on the top of our stack
 #[instrument(skip_all)]
 pub(crate) fn peek_incumbent_stack(target: &mut MutableHandle<'_,*mut JSObject>) {
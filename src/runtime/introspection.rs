@@ -0,0 +1,210 @@
+use std::{
+    fmt,
+    sync::atomic::{AtomicBool,Ordering},
+    time::{Duration},
+};
+use tokio::{
+    signal::unix::{Signal,SignalKind},
+};
+use mozjs::{
+    context::{JSContext},
+};
+#[allow(unused_imports)] use tracing::{trace,debug,info,warn,error,instrument};
+
+use super::{
+    incumbent_stack::{incumbent_realms},
+    queue::{Macrotask,queued_microtasks,queued_macrotasks},
+    resolvable_promise::{pending_promises,push_unref_task_source},
+};
+
+/*
+ * Runtime introspection
+ *
+ * What is this thread's event loop waiting on? `report` lists:
+ *
+ *  - every promise a `Bridge` will settle, with the host function
+ *    which made it, its realm, its age & the JS stack at the time
+ *  - the queued microtasks (by realm) & macrotasks (by source)
+ *  - the incumbent stack
+ *
+ * Capturing a stack for every promise isn't free, so stacks are only
+ * recorded once `set_capture_stacks(true)` has been called.
+ *
+ */
+
+static CAPTURE_STACKS: AtomicBool = AtomicBool::new(false);
+static DUMP_ON_SIGNAL: AtomicBool = AtomicBool::new(false);
+
+/// Records the JS stack of every promise made from now on, on every thread
+pub fn set_capture_stacks(capture: bool) {
+    CAPTURE_STACKS.store(capture, Ordering::Relaxed);
+}
+
+pub(crate) fn capture_stacks() -> bool {
+    CAPTURE_STACKS.load(Ordering::Relaxed)
+}
+
+#[derive(Clone,Debug)]
+pub struct PendingPromise {
+    pub id: u64,
+    /// the host function which made it (e.g. `"sleep_ms"`)
+    pub source: &'static str,
+    pub realm: Option<u64>,
+    pub age: Duration,
+    pub stack: Option<String>,
+}
+
+#[derive(Clone,Debug)]
+pub struct RuntimeReport {
+    /// oldest first
+    pub pending_promises: Vec<PendingPromise>,
+    /// the realm of each, in the order they'll run
+    pub microtasks: Vec<Option<u64>>,
+    /// the source of each, in the order they'll run
+    pub macrotasks: Vec<&'static str>,
+    /// the realm of each entry, bottom first
+    pub incumbent_stack: Vec<Option<u64>>,
+}
+
+/// Everything this thread's event loop is waiting on
+///
+/// Must be called on a JS thread, anything else has nothing to report.
+pub fn report() -> RuntimeReport {
+    RuntimeReport {
+        pending_promises: pending_promises(),
+        microtasks: queued_microtasks(),
+        macrotasks: queued_macrotasks(),
+        incumbent_stack: incumbent_realms(),
+    }
+}
+
+struct Realm(Option<u64>);
+impl fmt::Display for Realm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(id) => write!(f, "realm {}", id),
+            None => write!(f, "unknown realm"),
+        }
+    }
+}
+
+impl fmt::Display for RuntimeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let thread = std::thread::current();
+        writeln!(f, "runtime on thread '{}'", thread.name().unwrap_or("unnamed"))?;
+        writeln!(f, "pending promises: {}", self.pending_promises.len())?;
+        for p in &self.pending_promises {
+            writeln!(f, "  promise {} from '{}' in {}, pending for {:?}", p.id, p.source, Realm(p.realm), p.age)?;
+            match &p.stack {
+                Some(stack) => {
+                    for frame in stack.lines().filter(|l| !l.is_empty()) {
+                        writeln!(f, "    at {}", frame)?;
+                    }
+                }
+                None => writeln!(f, "    (no stack captured)")?,
+            }
+        }
+        writeln!(f, "queued microtasks: {}", self.microtasks.len())?;
+        for realm in &self.microtasks {
+            writeln!(f, "  job in {}", Realm(*realm))?;
+        }
+        writeln!(f, "queued macrotasks: {}", self.macrotasks.len())?;
+        for source in &self.macrotasks {
+            writeln!(f, "  '{}'", source)?;
+        }
+        writeln!(f, "incumbent stack: {}", self.incumbent_stack.len())?;
+        for realm in self.incumbent_stack.iter().rev() {
+            writeln!(f, "  {}", Realm(*realm))?;
+        }
+        Ok(())
+    }
+}
+
+/// Dumps a `report` to stderr on every SIGUSR1, from every runtime which installs it
+///
+/// Also turns on stack capture. Runtimes call `install_dump_on_signal`,
+/// which does nothing unless this has been called first.
+pub fn enable_dump_on_signal() {
+    set_capture_stacks(true);
+    DUMP_ON_SIGNAL.store(true, Ordering::Relaxed);
+}
+
+/// Arms the SIGUSR1 dump for this thread's runtime, if it is enabled
+///
+/// The listener doesn't keep the event loop alive.
+pub fn install_dump_on_signal() {
+    if !DUMP_ON_SIGNAL.load(Ordering::Relaxed) {
+        return;
+    }
+    match tokio::signal::unix::signal(SignalKind::user_defined1()) {
+        Ok(stream) => rearm(stream),
+        Err(e) => warn!("could not listen for SIGUSR1: {}", e),
+    }
+}
+
+fn rearm(mut stream: Signal) {
+    push_unref_task_source(async move {
+        stream.recv().await;
+        Macrotask::new("introspection dump", move |_ctx: &mut JSContext| {
+            eprint!("{}", report());
+            rearm(stream);
+        })
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use mozjs::{rooted};
+    use super::*;
+    use crate::{
+        realm::{realm_id_of},
+        runtime::{
+            incumbent_stack::{enter_incumbent_stack},
+            queue::{insert_macrotask},
+            testing::{with_runtime,new_test_global,eval},
+        },
+    };
+
+    #[test]
+    fn what_the_loop_waits_on_is_reported() {
+        with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            let realm = realm_id_of(global.get());
+            eval(ctx, global.handle(), "globalThis.slept = sleep_ms(60000); Promise.resolve().then(() => {});").unwrap();
+            insert_macrotask(Macrotask::new("queued", |_: &mut JSContext| {}));
+
+            let report = report();
+            let promise = report.pending_promises.iter().find(|p| p.source == "sleep_ms").unwrap();
+            assert_eq!(promise.realm, realm);
+            assert!(report.microtasks.contains(&realm));
+            assert_eq!(report.macrotasks.last(), Some(&"queued"));
+            assert!(report.incumbent_stack.is_empty());
+
+            let rendered = report.to_string();
+            assert!(rendered.contains(&format!("promise {} from 'sleep_ms' in realm {}", promise.id, realm.unwrap())), "{}", rendered);
+            assert!(rendered.contains("  'queued'"), "{}", rendered);
+        });
+    }
+
+    #[test]
+    fn captured_stacks_show_where_promises_were_made() {
+        set_capture_stacks(true);
+        with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            eval(ctx, global.handle(), "function waitALongTime() { return sleep_ms(60000); } globalThis.slept = waitALongTime();").unwrap();
+            let report = report();
+            let promise = report.pending_promises.iter().find(|p| p.source == "sleep_ms").unwrap();
+            assert!(promise.stack.as_deref().is_some_and(|s| s.contains("waitALongTime")), "{:?}", promise.stack);
+        });
+    }
+
+    #[test]
+    fn the_incumbent_stack_is_reported() {
+        with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            let realm = realm_id_of(global.get());
+            let stack = enter_incumbent_stack(ctx, global.handle(), |_, _| report().incumbent_stack);
+            assert_eq!(stack, [realm]);
+        });
+    }
+}
@@ -0,0 +1,344 @@
+use std::{
+    cell::{Cell,RefCell,LazyCell},
+    collections::{BTreeMap},
+    future::{Future},
+    ops::{DerefMut},
+    rc::{Rc},
+};
+use tokio::{
+    sync::{mpsc,oneshot},
+};
+use mozjs::{rooted};
+use mozjs::{
+    context::{JSContext,RawJSContext},
+    conversions::{ToJSValConvertible},
+    gc::{Handle},
+    jsapi::{Heap,JSObject,JSTracer,Value,CurrentGlobalOrNull,JS_ClearPendingException,NewArrayObject1},
+    jsval::{UndefinedValue,ObjectValue},
+    rust::wrappers::{JS_GetPendingException,JS_GetProperty,JS_SetElement},
+};
+#[allow(unused_imports)] use tracing::{trace,debug,info,warn,error,instrument};
+
+use super::{
+    call::{call_value,is_callable},
+    exception::{report_pending_exception},
+    incumbent_stack::{enter_incumbent_stack},
+    promise_reaction::{on_settled},
+    queue::{Macrotask},
+    resolvable_promise::{push_task_source},
+    roots::{trace_object},
+};
+
+/*
+ * Calling JS functions from any thread
+ *
+ * A `JsCallbackHandle` roots a JS function along with the realm it
+ * was made in & can be cloned into any `Send` task. Calling it sends
+ * the call back to the JS thread, where it is ran as a macrotask
+ * within that realm:
+ *
+ *  - the arguments are built on the JS thread by an `ArgsMarshalling`
+ *  - `call` ignores the result (exceptions are reported)
+ *  - `call_with_result` converts the result with a `ResultMarshalling`
+ *    & hands it back as a future. A returned promise is awaited
+ *    first, a throw or rejection is the `Err` side.
+ *
+ * The function stays rooted (see `roots`), & the event loop alive,
+ * until every clone of the handle has been dropped.
+ *
+ */
+
+thread_local! {
+    static CALLBACKS: LazyCell<RefCell<BTreeMap<u64,CallbackEntry>>> = LazyCell::new(|| RefCell::new(BTreeMap::new()));
+    static NEXT_CALLBACK_ID: Cell<u64> = const { Cell::new(1) };
+}
+
+struct CallbackEntry {
+    callback: Box<Heap<*mut JSObject>>,
+    /// `callback.apply` as it was when the handle was made
+    apply: Box<Heap<*mut JSObject>>,
+    global: Rc<Box<Heap<*mut JSObject>>>,
+}
+
+/// Reports every stored callback to the GC, see `roots`
+pub(crate) unsafe fn trace_callbacks(trc: *mut JSTracer) {
+    CALLBACKS.with(|c| {
+        for entry in c.borrow().values() {
+            unsafe {
+                trace_object(trc, &entry.callback, c"callback");
+                trace_object(trc, &entry.apply, c"callback apply");
+                trace_object(trc, &entry.global, c"callback global");
+            }
+        }
+    });
+}
+
+/// Fills in a call's arguments on the JS thread, `false` if an exception is pending
+pub type ArgsMarshalling = Box<dyn FnOnce(*mut RawJSContext, &mut CallbackArgs<'_>) -> bool + Send>;
+
+/// Converts a call's outcome on the JS thread: `Ok(returned)` or `Err(thrown)`
+pub type ResultMarshalling<T> = Box<dyn for<'a> FnOnce(*mut RawJSContext, Result<Handle<'a,Value>,Handle<'a,Value>>) -> T + Send>;
+
+/// The arguments of a call, kept rooted in an array as they are pushed
+pub struct CallbackArgs<'a> {
+    cx: *mut RawJSContext,
+    array: Handle<'a,*mut JSObject>,
+    len: u32,
+}
+impl CallbackArgs<'_> {
+    pub fn cx(&self) -> *mut RawJSContext {
+        self.cx
+    }
+
+    pub fn push(&mut self, value: Handle<'_,Value>) -> bool {
+        if !unsafe { JS_SetElement(self.cx, self.array, self.len, value) } {
+            return false;
+        }
+        self.len += 1;
+        true
+    }
+
+    pub fn push_value<T: ToJSValConvertible + ?Sized>(&mut self, value: &T) -> bool {
+        rooted!(in(self.cx) let mut val = UndefinedValue());
+        unsafe { value.to_jsval(self.cx, val.handle_mut()) };
+        self.push(val.handle())
+    }
+}
+
+struct QueuedCall {
+    args: ArgsMarshalling,
+    /// `None` when the result is ignored
+    result: Option<Box<dyn for<'a> FnOnce(*mut RawJSContext, Result<Handle<'a,Value>,Handle<'a,Value>>) + Send>>,
+}
+
+/// A JS function which can be called from any thread
+#[derive(Clone)]
+pub struct JsCallbackHandle {
+    sender: mpsc::UnboundedSender<QueuedCall>,
+}
+impl JsCallbackHandle {
+    /// Roots `callback` in the current realm, `None` if it isn't callable
+    ///
+    /// Must be called on the JS thread, within a realm.
+    pub fn new(cx: *mut RawJSContext, callback: Handle<'_,Value>) -> Option<Self> {
+        if !is_callable(callback) {
+            return None;
+        }
+        rooted!(in(cx) let callback_obj = callback.to_object());
+        rooted!(in(cx) let mut apply = UndefinedValue());
+        if !unsafe { JS_GetProperty(cx, callback_obj.handle(), c"apply".as_ptr(), apply.handle_mut()) } || !is_callable(apply.handle()) {
+            unsafe { JS_ClearPendingException(cx) };
+            return None;
+        }
+        let id = NEXT_CALLBACK_ID.with(|n| {
+            let id = n.get();
+            n.set(id + 1);
+            id
+        });
+        let global = unsafe { CurrentGlobalOrNull(cx) };
+        CALLBACKS.with(|c| c.borrow_mut().insert(id, CallbackEntry {
+            callback: Heap::boxed(callback_obj.get()),
+            apply: Heap::boxed(apply.to_object()),
+            global: Rc::new(Heap::boxed(global)),
+        }));
+        let (sender, receiver) = mpsc::unbounded_channel();
+        listen_for_calls(id, receiver);
+        Some(JsCallbackHandle { sender })
+    }
+
+    /// Queues a call, `false` if the JS side has gone away
+    pub fn call(&self, args: ArgsMarshalling) -> bool {
+        self.sender.send(QueuedCall { args, result: None }).is_ok()
+    }
+
+    /// Queues a call & resolves to its converted result
+    ///
+    /// `None` if the call never ran: the JS side went away or the
+    /// arguments couldn't be marshalled.
+    pub fn call_with_result<T>(&self, args: ArgsMarshalling, result: ResultMarshalling<T>) -> impl Future<Output=Option<T>> + Send + 'static
+    where
+        T: Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let call = QueuedCall {
+            args,
+            result: Some(Box::new(move |cx: *mut RawJSContext, outcome: Result<Handle<'_,Value>,Handle<'_,Value>>| {
+                let _ = sender.send((result)(cx, outcome));
+            })),
+        };
+        let queued = self.sender.send(call).is_ok();
+        async move {
+            if !queued {
+                return None;
+            }
+            receiver.await.ok()
+        }
+    }
+}
+
+/// Runs each call as its own macrotask, the entry is dropped with the last handle
+fn listen_for_calls(id: u64, mut receiver: mpsc::UnboundedReceiver<QueuedCall>) {
+    push_task_source(async move {
+        let call = receiver.recv().await;
+        Macrotask::new("js callback", move |ctx: &mut JSContext| {
+            match call {
+                Some(call) => {
+                    run_call(ctx, id, call);
+                    listen_for_calls(id, receiver);
+                }
+                None => {
+                    trace!("callback '{}' released", id);
+                    CALLBACKS.with(|c| c.borrow_mut().remove(&id));
+                }
+            }
+        })
+    });
+}
+
+#[instrument(skip(ctx, call))]
+fn run_call(ctx: &mut JSContext, id: u64, call: QueuedCall) {
+    let Some((callback, apply, global)) = CALLBACKS.with(|c| {
+        c.borrow().get(&id).map(|e| (e.callback.get(), e.apply.get(), e.global.get()))
+    }) else {
+        warn!("callback '{}' is gone", id);
+        return;
+    };
+    rooted!(in(unsafe { ctx.raw_cx() }) let global = global);
+    enter_incumbent_stack(ctx, global.handle(), |realm, _global| {
+        let cx = unsafe { realm.deref_mut().raw_cx() };
+        rooted!(in(cx) let array = unsafe { NewArrayObject1(cx, 0) });
+        if array.get().is_null() {
+            report_pending_exception(cx, "js callback");
+            return;
+        }
+        let mut args = CallbackArgs { cx, array: array.handle(), len: 0 };
+        if !(call.args)(cx, &mut args) {
+            report_pending_exception(cx, "js callback arguments");
+            return;
+        }
+        rooted!(in(cx) let callback = callback);
+        rooted!(in(cx) let apply = ObjectValue(apply));
+        rooted!(in(cx) let mut rval = UndefinedValue());
+        let returned = call_value(cx, callback.handle(), apply.handle(), [UndefinedValue(), ObjectValue(array.get())], rval.handle_mut());
+        let Some(result) = call.result else {
+            if !returned {
+                report_pending_exception(cx, "js callback");
+            }
+            return;
+        };
+        if !returned {
+            rooted!(in(cx) let mut exn = UndefinedValue());
+            if !unsafe { JS_GetPendingException(cx, exn.handle_mut()) } {
+                report_pending_exception(cx, "js callback");
+                return;
+            }
+            unsafe { JS_ClearPendingException(cx) };
+            (result)(cx, Err(exn.handle()));
+            return;
+        }
+        if !on_settled(cx, rval.handle(), result) {
+            report_pending_exception(cx, "js callback");
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration};
+    use futures_util::{FutureExt};
+    use mozjs::{
+        rust::{CompileOptionsWrapper,evaluate_script},
+    };
+    use super::*;
+    use crate::runtime::{
+        checkpoint::{runtime_checkpoint},
+        exception::{describe_exception,value_to_string},
+        testing::{with_runtime,new_test_global,eval},
+    };
+
+    /// A handle to the function `source` evaluates to, within `global`'s realm
+    fn handle_for(ctx: &mut JSContext, global: Handle<'_,*mut JSObject>, source: &str) -> JsCallbackHandle {
+        enter_incumbent_stack(ctx, global, |realm, global_obj| {
+            rooted!(&in(realm) let mut function = UndefinedValue());
+            let options = CompileOptionsWrapper::new(realm, "test.js", 1);
+            evaluate_script(realm, global_obj, source, function.handle_mut(), options).unwrap();
+            let cx = unsafe { realm.deref_mut().raw_cx() };
+            JsCallbackHandle::new(cx, function.handle()).unwrap()
+        })
+    }
+
+    fn add_args() -> ArgsMarshalling {
+        Box::new(|_cx: *mut RawJSContext, args: &mut CallbackArgs<'_>| args.push_value(&2) && args.push_value(&3))
+    }
+
+    /// The returned value as a string, or the message of what was thrown
+    fn to_strings() -> ResultMarshalling<Result<String,String>> {
+        Box::new(|cx: *mut RawJSContext, outcome: Result<Handle<'_,Value>,Handle<'_,Value>>| match outcome {
+            Ok(value) => Ok(value_to_string(cx, value).unwrap_or_default()),
+            Err(exn) => Err(describe_exception(cx, exn).0),
+        })
+    }
+
+    fn callback_count() -> usize {
+        CALLBACKS.with(|c| c.borrow().len())
+    }
+
+    #[test]
+    fn calls_from_another_thread_run_in_the_realm() {
+        with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            let handle = handle_for(ctx, global.handle(), "(a, b) => { globalThis.sum = a + b; }");
+            let sent = std::thread::spawn(move || handle.call(add_args())).join().unwrap();
+            assert!(sent);
+            runtime_checkpoint(ctx);
+            assert_eq!(eval(ctx, global.handle(), "sum").as_deref(), Ok("5"));
+        });
+    }
+
+    #[test]
+    fn returned_promises_are_awaited() {
+        with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            let handle = handle_for(ctx, global.handle(), "async (a, b) => { await null; return a * b; }");
+            let result = handle.call_with_result(add_args(), to_strings());
+            drop(handle);
+            runtime_checkpoint(ctx);
+            assert_eq!(result.now_or_never(), Some(Some(Ok("6".to_string()))));
+        });
+    }
+
+    #[test]
+    fn throws_and_rejections_are_the_err_side() {
+        with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            let throws = handle_for(ctx, global.handle(), "() => { throw new Error('thrown'); }");
+            let rejects = handle_for(ctx, global.handle(), "async () => { await null; throw new Error('rejected'); }");
+            let thrown = throws.call_with_result(add_args(), to_strings());
+            let rejected = rejects.call_with_result(add_args(), to_strings());
+            drop((throws, rejects));
+            runtime_checkpoint(ctx);
+            assert!(matches!(thrown.now_or_never(), Some(Some(Err(message))) if message.contains("thrown")));
+            assert!(matches!(rejected.now_or_never(), Some(Some(Err(message))) if message.contains("rejected")));
+        });
+    }
+
+    #[test]
+    fn the_loop_waits_for_every_clone() {
+        with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            let before = callback_count();
+            let handle = handle_for(ctx, global.handle(), "(a, b) => { globalThis.sum = a + b; }");
+            let clone = handle.clone();
+            drop(handle);
+            let caller = std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(50));
+                clone.call(add_args())
+            });
+            // only returns once the clone is gone, after its call ran
+            runtime_checkpoint(ctx);
+            assert!(caller.join().unwrap());
+            assert_eq!(eval(ctx, global.handle(), "sum").as_deref(), Ok("5"));
+            assert_eq!(callback_count(), before);
+        });
+    }
+}
@@ -0,0 +1,97 @@
+use std::{
+    ops::{DerefMut},
+    rc::{Rc},
+};
+use tokio::sync::mpsc::{UnboundedReceiver};
+use mozjs::{rooted};
+use mozjs::{
+    context::{JSContext},
+    jsapi::{Heap,JSObject,JSPROP_ENUMERATE,JS_NewPlainObject},
+    jsval::{UndefinedValue,ObjectValue},
+    rust::wrappers::{JS_DefineProperty},
+};
+#[allow(unused_imports)] use tracing::{trace,debug,info,warn,error,instrument};
+
+use super::{
+    incumbent_stack::{enter_incumbent_stack},
+    exception::{report_pending_exception},
+    call::{call_method},
+    queue::{Macrotask},
+    resolvable_promise::{push_task_source,push_unref_task_source},
+    roots::{HeapRoot},
+    structured_clone::{StructuredCloneData,read_structured_clone},
+};
+
+/// Delivers messages from a channel to `target.onmessage`
+///
+/// Each message is its own macrotask. The listener re-arms itself
+/// after every message until the sending side of the channel is
+/// dropped, at which point `on_close` is ran (as a macrotask). Unless
+/// `keep_alive` is false it keeps the event loop alive until then.
+/// `target` & `global` are rooted until then too, so a target nothing
+/// else refers to still gets its messages.
+pub struct MessageListener {
+    pub receiver: UnboundedReceiver<StructuredCloneData>,
+    pub target: HeapRoot,
+    pub global: Rc<HeapRoot>,
+    pub source: &'static str,
+    pub keep_alive: bool,
+    pub on_close: Option<Box<dyn 'static + FnOnce(&mut JSContext)>>,
+}
+impl MessageListener {
+    pub fn listen(self) {
+        let source = self.source;
+        let keep_alive = self.keep_alive;
+        let next = async move {
+            let mut listener = self;
+            let message = listener.receiver.recv().await;
+            Macrotask::new(source, move |ctx: &mut JSContext| {
+                match message {
+                    Some(data) => {
+                        dispatch_message(ctx, &listener.target, &listener.global, &data);
+                        listener.listen();
+                    }
+                    None => {
+                        debug!("'{}' channel closed", listener.source);
+                        if let Some(on_close) = listener.on_close.take() {
+                            (on_close)(ctx);
+                        }
+                    }
+                };
+            })
+        };
+        match keep_alive {
+            true => push_task_source(next),
+            false => push_unref_task_source(next),
+        }
+    }
+}
+
+/// Calls `target.onmessage({ data })` within `global`'s realm
+///
+/// The message is dropped if `onmessage` isn't callable.
+#[instrument(skip_all)]
+pub fn dispatch_message(ctx: &mut JSContext, target: &Heap<*mut JSObject>, global: &Heap<*mut JSObject>, data: &StructuredCloneData) {
+    rooted!(in(unsafe { ctx.raw_cx() }) let global = global.get());
+    rooted!(in(unsafe { ctx.raw_cx() }) let target = target.get());
+    enter_incumbent_stack(ctx, global.handle(), |realm, _| {
+        let cx = unsafe { realm.deref_mut().raw_cx() };
+        rooted!(in(cx) let mut value = UndefinedValue());
+        if read_structured_clone(cx, data, value.handle_mut()).is_err() {
+            report_pending_exception(cx, "message deserialization");
+            return;
+        }
+        rooted!(in(cx) let event = unsafe { JS_NewPlainObject(cx) });
+        if event.get().is_null() {
+            error!("could not allocate message event");
+            return;
+        }
+        unsafe { JS_DefineProperty(cx, event.handle(), c"data".as_ptr(), value.handle(), JSPROP_ENUMERATE as u32) };
+        rooted!(in(cx) let mut rval = UndefinedValue());
+        match call_method(cx, target.handle(), c"onmessage", [ObjectValue(event.get())], rval.handle_mut()) {
+            Ok(true) => { },
+            Ok(false) => trace!("no onmessage handler, dropping message"),
+            Err(()) => { report_pending_exception(cx, "onmessage"); },
+        };
+    });
+}
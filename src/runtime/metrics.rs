@@ -0,0 +1,375 @@
+use std::{
+    cell::{Cell},
+    collections::{BTreeMap},
+    ffi::{c_void},
+    fmt::{Write},
+    io,
+    net::{SocketAddr},
+    ptr::{null_mut},
+    sync::{Arc,Mutex,LazyLock,atomic::{AtomicU64,Ordering}},
+    time::{Duration,Instant},
+};
+use tokio::{
+    net::{TcpListener},
+    sync::{mpsc,watch},
+};
+use mozjs::{
+    context::{RawJSContext},
+    jsapi::{JSGCStatus,GCReason,JS_SetGCCallback},
+};
+#[allow(unused_imports)] use tracing::{trace,debug,info,warn,error,instrument};
+
+use crate::http::server::{IncomingRequest,OutgoingResponse,accept_loop};
+
+/*
+ * Event loop metrics
+ *
+ * Every thread running a JS runtime (the main thread & each worker)
+ * registers a `RuntimeMetrics` the first time it records anything,
+ * & unregisters it when the thread exits. The event loop records:
+ *
+ *  - the depth of the microtask & macrotask queues
+ *  - how many futures are pending (unref'd ones included)
+ *  - how many microtasks each checkpoint ran
+ *  - the time from a bridged future completing to its promise being
+ *    settled, i.e. how long the macrotask waited in the queue
+ *  - how long each microtask & macrotask took to run
+ *  - how long each GC took, see `install_gc_metrics`
+ *
+ * Everything is atomics so `snapshot` & `render_prometheus` can be
+ * called from any thread, `serve_prometheus` exposes the latter.
+ *
+ */
+
+/// Upper bounds of the duration histograms, in seconds
+const DURATION_BUCKETS: &[f64] = &[0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+/// Upper bounds of the jobs per checkpoint histogram
+const JOB_BUCKETS: &[f64] = &[0.0, 1.0, 2.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 1000.0];
+
+static RUNTIMES: LazyLock<Mutex<BTreeMap<u64,Arc<RuntimeMetrics>>>> = LazyLock::new(|| Mutex::new(BTreeMap::new()));
+static NEXT_RUNTIME_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    static CURRENT: Registration = Registration::new();
+    /// set by `JSGC_BEGIN`, taken by `JSGC_END`
+    static GC_STARTED: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// A histogram with fixed buckets, safe to observe from any thread
+pub struct Histogram {
+    bounds: &'static [f64],
+    /// one per bound plus `+Inf`, not cumulative
+    buckets: Box<[AtomicU64]>,
+    count: AtomicU64,
+    /// the bits of an `f64`
+    sum: AtomicU64,
+}
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0f64.to_bits()),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        let bucket = self.bounds.iter().position(|bound| value <= *bound).unwrap_or(self.bounds.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let _ = self.sum.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+            Some((f64::from_bits(bits) + value).to_bits())
+        });
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        let mut total = 0;
+        let cumulative = self.buckets.iter().map(|b| {
+            total += b.load(Ordering::Relaxed);
+            total
+        }).collect();
+        HistogramSnapshot {
+            bounds: self.bounds,
+            cumulative,
+            count: self.count.load(Ordering::Relaxed),
+            sum: f64::from_bits(self.sum.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+#[derive(Clone,Debug)]
+pub struct HistogramSnapshot {
+    pub bounds: &'static [f64],
+    /// observations `<=` each bound, the last is `+Inf`
+    pub cumulative: Vec<u64>,
+    pub count: u64,
+    pub sum: f64,
+}
+
+/// What one JS runtime has recorded, see the module docs
+pub struct RuntimeMetrics {
+    id: u64,
+    thread: String,
+    microtask_depth: AtomicU64,
+    macrotask_depth: AtomicU64,
+    pending_futures: AtomicU64,
+    checkpoint_jobs: Histogram,
+    resolution_latency: Histogram,
+    microtask_duration: Histogram,
+    macrotask_duration: Histogram,
+    gc_duration: Histogram,
+}
+
+#[derive(Clone,Debug)]
+pub struct RuntimeSnapshot {
+    pub id: u64,
+    pub thread: String,
+    pub microtask_depth: u64,
+    pub macrotask_depth: u64,
+    pub pending_futures: u64,
+    pub checkpoint_jobs: HistogramSnapshot,
+    /// seconds
+    pub resolution_latency: HistogramSnapshot,
+    /// seconds
+    pub microtask_duration: HistogramSnapshot,
+    /// seconds
+    pub macrotask_duration: HistogramSnapshot,
+    /// seconds
+    pub gc_duration: HistogramSnapshot,
+}
+
+/// Owned by the thread's `CURRENT`, so exiting the thread unregisters it
+struct Registration(Arc<RuntimeMetrics>);
+impl Registration {
+    fn new() -> Self {
+        let id = NEXT_RUNTIME_ID.fetch_add(1, Ordering::Relaxed);
+        let thread = std::thread::current().name().map(str::to_string).unwrap_or_else(|| format!("runtime-{}", id));
+        let metrics = Arc::new(RuntimeMetrics {
+            id,
+            thread,
+            microtask_depth: AtomicU64::new(0),
+            macrotask_depth: AtomicU64::new(0),
+            pending_futures: AtomicU64::new(0),
+            checkpoint_jobs: Histogram::new(JOB_BUCKETS),
+            resolution_latency: Histogram::new(DURATION_BUCKETS),
+            microtask_duration: Histogram::new(DURATION_BUCKETS),
+            macrotask_duration: Histogram::new(DURATION_BUCKETS),
+            gc_duration: Histogram::new(DURATION_BUCKETS),
+        });
+        RUNTIMES.lock().unwrap().insert(id, metrics.clone());
+        Registration(metrics)
+    }
+}
+impl Drop for Registration {
+    fn drop(&mut self) {
+        if let Ok(mut runtimes) = RUNTIMES.lock() {
+            runtimes.remove(&self.0.id);
+        }
+    }
+}
+
+/// Runs `f` with this thread's metrics, does nothing while the thread is exiting
+fn record(f: impl FnOnce(&RuntimeMetrics)) {
+    let _ = CURRENT.try_with(|r| f(&r.0));
+}
+
+pub(crate) fn set_microtask_depth(depth: usize) {
+    record(|m| m.microtask_depth.store(depth as u64, Ordering::Relaxed));
+}
+
+pub(crate) fn set_macrotask_depth(depth: usize) {
+    record(|m| m.macrotask_depth.store(depth as u64, Ordering::Relaxed));
+}
+
+pub(crate) fn set_pending_futures(pending: usize) {
+    record(|m| m.pending_futures.store(pending as u64, Ordering::Relaxed));
+}
+
+pub(crate) fn observe_checkpoint_jobs(ran: usize) {
+    record(|m| m.checkpoint_jobs.observe(ran as f64));
+}
+
+pub(crate) fn observe_resolution_latency(waited: Duration) {
+    record(|m| m.resolution_latency.observe(waited.as_secs_f64()));
+}
+
+pub(crate) fn observe_microtask(took: Duration) {
+    record(|m| m.microtask_duration.observe(took.as_secs_f64()));
+}
+
+pub(crate) fn observe_macrotask(took: Duration) {
+    record(|m| m.macrotask_duration.observe(took.as_secs_f64()));
+}
+
+/// Times every GC of this context
+///
+/// Measured from `JSGC_BEGIN` to `JSGC_END`, so the slices of an
+/// incremental collection (& the mutator running between them) count
+/// as one.
+pub fn install_gc_metrics(cx: *mut RawJSContext) {
+    unsafe { JS_SetGCCallback(cx, Some(gc_callback), null_mut()) };
+}
+
+unsafe extern "C" fn gc_callback(_cx: *mut RawJSContext, status: JSGCStatus, _reason: GCReason, _data: *mut c_void) {
+    match status {
+        JSGCStatus::JSGC_BEGIN => GC_STARTED.with(|s| s.set(Some(Instant::now()))),
+        JSGCStatus::JSGC_END => {
+            if let Some(started) = GC_STARTED.with(|s| s.take()) {
+                let took = started.elapsed();
+                trace!("gc took '{:?}'", took);
+                record(|m| m.gc_duration.observe(took.as_secs_f64()));
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The metrics of every running JS runtime, ordered by id
+pub fn snapshot() -> Vec<RuntimeSnapshot> {
+    let runtimes: Vec<Arc<RuntimeMetrics>> = RUNTIMES.lock().unwrap().values().cloned().collect();
+    runtimes.iter().map(|m| RuntimeSnapshot {
+        id: m.id,
+        thread: m.thread.clone(),
+        microtask_depth: m.microtask_depth.load(Ordering::Relaxed),
+        macrotask_depth: m.macrotask_depth.load(Ordering::Relaxed),
+        pending_futures: m.pending_futures.load(Ordering::Relaxed),
+        checkpoint_jobs: m.checkpoint_jobs.snapshot(),
+        resolution_latency: m.resolution_latency.snapshot(),
+        microtask_duration: m.microtask_duration.snapshot(),
+        macrotask_duration: m.macrotask_duration.snapshot(),
+        gc_duration: m.gc_duration.snapshot(),
+    }).collect()
+}
+
+/// `snapshot()` in the Prometheus text exposition format
+pub fn render_prometheus() -> String {
+    let runtimes = snapshot();
+    let mut out = String::new();
+    let labels = |r: &RuntimeSnapshot| format!("runtime=\"{}\",thread=\"{}\"", r.id, escape_label(&r.thread));
+
+    let gauges: [(&str, &str, fn(&RuntimeSnapshot) -> u64); 3] = [
+        ("js_microtask_queue_depth", "Microtasks waiting to run", |r| r.microtask_depth),
+        ("js_macrotask_queue_depth", "Macrotasks waiting to run", |r| r.macrotask_depth),
+        ("js_pending_futures", "Futures the event loop is waiting on", |r| r.pending_futures),
+    ];
+    for (name, help, get) in gauges {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} gauge", name, help, name);
+        for r in &runtimes {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels(r), get(r));
+        }
+    }
+
+    let histograms: [(&str, &str, fn(&RuntimeSnapshot) -> &HistogramSnapshot); 5] = [
+        ("js_checkpoint_jobs", "Microtasks ran per checkpoint", |r| &r.checkpoint_jobs),
+        ("js_resolution_latency_seconds", "Time from a bridged future completing to its promise settling", |r| &r.resolution_latency),
+        ("js_microtask_duration_seconds", "Time spent running each microtask", |r| &r.microtask_duration),
+        ("js_macrotask_duration_seconds", "Time spent running each macrotask", |r| &r.macrotask_duration),
+        ("js_gc_duration_seconds", "Time from the start to the end of each GC", |r| &r.gc_duration),
+    ];
+    for (name, help, get) in histograms {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} histogram", name, help, name);
+        for r in &runtimes {
+            let h = get(r);
+            let labels = labels(r);
+            for (bound, count) in h.bounds.iter().zip(&h.cumulative) {
+                let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, bound, count);
+            }
+            let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, h.count);
+            let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, h.sum);
+            let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, h.count);
+        }
+    }
+    out
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Serves `render_prometheus()` at `GET /metrics` until the process exits
+///
+/// Runs on tokio alone, so it neither needs nor keeps alive an event loop.
+pub async fn serve_prometheus(address: SocketAddr) -> io::Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    info!("serving metrics on 'http://{}/metrics'", listener.local_addr()?);
+    let (_closed, closed_rx) = watch::channel(false);
+    let (requests, mut receiver) = mpsc::unbounded_channel::<IncomingRequest>();
+    tokio::spawn(accept_loop(listener, requests, closed_rx));
+    while let Some(request) = receiver.recv().await {
+        let path = request.url.splitn(4, '/').nth(3).unwrap_or("");
+        let path = path.split(['?', '#']).next().unwrap_or("");
+        let response = match (request.method.as_str(), path) {
+            ("GET" | "HEAD", "metrics") => {
+                let body = render_prometheus().into_bytes();
+                OutgoingResponse {
+                    headers: vec![("content-type".to_string(), "text/plain; version=0.0.4".to_string())],
+                    body: Some(Box::new(io::Cursor::new(body))),
+                    ..OutgoingResponse::status(200)
+                }
+            }
+            (_, "metrics") => OutgoingResponse::status(405),
+            _ => OutgoingResponse::status(404),
+        };
+        let _ = request.respond.send(response);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use mozjs::{rooted};
+    use super::*;
+    use crate::runtime::testing::{with_runtime,new_test_global,eval_async,gc};
+
+    /// This thread's runtime, as of now
+    fn current() -> RuntimeSnapshot {
+        let id = CURRENT.with(|r| r.0.id);
+        snapshot().into_iter().find(|r| r.id == id).unwrap()
+    }
+
+    #[test]
+    fn histograms_are_cumulative() {
+        let histogram = Histogram::new(&[1.0, 2.0]);
+        for value in [0.5, 1.0, 1.5, 3.0] {
+            histogram.observe(value);
+        }
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.cumulative, [2, 3, 4]);
+        assert_eq!(snapshot.count, 4);
+        assert_eq!(snapshot.sum, 6.0);
+    }
+
+    #[test]
+    fn labels_are_escaped() {
+        assert_eq!(escape_label("a \"b\"\\c\nd"), "a \\\"b\\\"\\\\c\\nd");
+    }
+
+    #[test]
+    fn the_event_loop_is_measured() {
+        with_runtime(|ctx| {
+            install_gc_metrics(unsafe { ctx.raw_cx() });
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            let slept = eval_async(ctx, global.handle(), "await sleep_ms(1); await null; return 'slept';");
+            assert_eq!(slept.as_deref(), Ok("slept"));
+            gc(ctx);
+        });
+        let runtime = current();
+        assert!(runtime.resolution_latency.count >= 1);
+        assert!(runtime.macrotask_duration.count >= 1);
+        assert!(runtime.microtask_duration.count >= 1);
+        assert!(runtime.checkpoint_jobs.count >= 1);
+        assert!(runtime.gc_duration.count >= 1);
+        assert_eq!(runtime.macrotask_depth, 0);
+    }
+
+    #[test]
+    fn every_runtime_is_rendered() {
+        set_pending_futures(3);
+        let runtime = current();
+        let rendered = render_prometheus();
+        let line = format!("js_pending_futures{{runtime=\"{}\",thread=\"{}\"}} 3", runtime.id, escape_label(&runtime.thread));
+        assert!(rendered.lines().any(|l| l == line), "{}", rendered);
+        assert!(rendered.contains("# TYPE js_gc_duration_seconds histogram"));
+    }
+}
@@ -5,3 +5,25 @@ pub mod polling;
 pub mod queue;
 pub mod callback;
 pub mod checkpoint;
+pub mod exception;
+pub mod structured_clone;
+pub mod host_object;
+pub mod call;
+pub mod message;
+pub mod conversions;
+
+
+
+pub mod promise_reaction;
+pub mod stream_iterator;
+pub mod js_callback;
+pub mod metrics;
+pub mod introspection;
+pub mod async_stack;
+pub mod source_map;
+pub mod rejection;
+pub mod profiler;
+pub mod coverage;
+pub mod roots;
+#[cfg(test)]
+pub mod testing;
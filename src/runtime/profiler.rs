@@ -0,0 +1,565 @@
+use std::{
+    cell::{RefCell,LazyCell},
+    collections::{HashMap},
+    ffi::{CStr,CString,c_char,c_void},
+    fmt::{Write as _},
+    path::{PathBuf},
+    ptr::{null_mut},
+    sync::{Arc,Mutex},
+    sync::atomic::{AtomicBool,AtomicI32,AtomicPtr,AtomicU32,AtomicU64,Ordering},
+    time::{Duration,Instant,SystemTime,UNIX_EPOCH},
+};
+use tokio::{
+    time::{MissedTickBehavior},
+};
+use mozjs::{
+    context::{JSContext},
+    jsapi::{
+        ProfilingStack,ProfilingStackFrame,ProfilingCategoryPair,JSJitCompilerOption,
+        SetContextProfilingStack,EnableContextProfilingStack,JS_SetGlobalJitCompilerOption,
+    },
+};
+#[allow(unused_imports)] use tracing::{trace,debug,info,warn,error,instrument};
+
+use super::source_map;
+
+/*
+ * CPU profiler
+ *
+ * SpiderMonkey keeps a "profiling stack" for the Gecko profiler: the
+ * interpreter pushes a frame for each JS function it enters & C++
+ * pushes labels around interesting work (e.g. GC). We hand the engine
+ * a stack we can see into, push our own labels for host functions &
+ * the event loop, & a tokio task copies the stack every `interval`.
+ *
+ * The JIT tiers don't push frames, so they're turned off while
+ * profiling: functions run slower, but every one of them is seen.
+ *
+ * `stop_profiler` writes the samples as a `.cpuprofile` (the format
+ * of V8's `Profiler.stop`), which Chrome DevTools' performance panel &
+ * speedscope both load. Self time is also logged by kind: JS, host
+ * functions, event loop overhead, the engine & everything else.
+ *
+ * Only the thread which called `start_profiler` is profiled.
+ *
+ */
+
+/// Frames in our stack, it must never be grown (see `MirrorStack`)
+///
+/// The interpreter stops at 50k frames, leaving plenty for labels.
+const CAPACITY: usize = 1 << 16;
+
+/// `ProfilingStackFrame::Flags`
+const IS_LABEL_FRAME: u32 = 1 << 0;
+const IS_SP_MARKER_FRAME: u32 = 1 << 1;
+const IS_JS_FRAME: u32 = 1 << 2;
+const FLAGS_BITCOUNT: u32 = 16;
+
+/// The label of our host function frames, their name is the dynamic string
+static HOST_LABEL: &CStr = c"host";
+/// The label of our event loop frames, see `HOST_LABEL`
+static EVENT_LOOP_LABEL: &CStr = c"event loop";
+
+/// `js::ProfilingStackFrame`, field for field
+#[repr(C)]
+struct MirrorFrame {
+    label: AtomicPtr<c_char>,
+    dynamic_string: AtomicPtr<c_char>,
+    sp_or_script: AtomicPtr<c_void>,
+    realm_id: AtomicU64,
+    pc_offset_if_js: AtomicI32,
+    flags_and_category_pair: AtomicU32,
+}
+
+/// `ProfilingStack`, field for field
+///
+/// The engine grows a full stack by `delete[]`ing the old frames, so
+/// ours are allocated up front & never freed, see `CAPACITY`.
+#[repr(C)]
+struct MirrorStack {
+    capacity: u32,
+    frames: AtomicPtr<MirrorFrame>,
+    stack_pointer: AtomicU32,
+}
+
+const _: () = assert!(size_of::<MirrorFrame>() == size_of::<ProfilingStackFrame>());
+const _: () = assert!(size_of::<MirrorStack>() == size_of::<ProfilingStack>());
+
+impl MirrorStack {
+    fn leak() -> &'static MirrorStack {
+        let frames: Box<[MirrorFrame]> = (0..CAPACITY).map(|_| MirrorFrame {
+            label: AtomicPtr::new(null_mut()),
+            dynamic_string: AtomicPtr::new(null_mut()),
+            sp_or_script: AtomicPtr::new(null_mut()),
+            realm_id: AtomicU64::new(0),
+            pc_offset_if_js: AtomicI32::new(0),
+            flags_and_category_pair: AtomicU32::new(0),
+        }).collect();
+        Box::leak(Box::new(MirrorStack {
+            capacity: CAPACITY as u32,
+            frames: AtomicPtr::new(Box::leak(frames).as_mut_ptr()),
+            stack_pointer: AtomicU32::new(0),
+        }))
+    }
+
+    /// `ProfilingStack::pushLabelFrame`, `false` when full
+    fn push_label(&self, label: &'static CStr, name: &'static CStr) -> bool {
+        let sp = self.stack_pointer.load(Ordering::Relaxed);
+        if sp >= self.capacity {
+            return false;
+        }
+        let frame = unsafe { &*self.frames.load(Ordering::SeqCst).add(sp as usize) };
+        frame.label.store(label.as_ptr() as *mut c_char, Ordering::Release);
+        frame.dynamic_string.store(name.as_ptr() as *mut c_char, Ordering::Release);
+        frame.sp_or_script.store(null_mut(), Ordering::Release);
+        frame.realm_id.store(0, Ordering::Release);
+        frame.pc_offset_if_js.store(0, Ordering::Release);
+        let category = ProfilingCategoryPair::OTHER as u32;
+        frame.flags_and_category_pair.store(IS_LABEL_FRAME | (category << FLAGS_BITCOUNT), Ordering::Release);
+        // the frame is complete before a sampler can see it
+        self.stack_pointer.store(sp + 1, Ordering::Release);
+        true
+    }
+
+    fn pop(&self) {
+        let sp = self.stack_pointer.load(Ordering::Relaxed);
+        self.stack_pointer.store(sp - 1, Ordering::Release);
+    }
+
+    /// The stack right now, bottom first
+    ///
+    /// The JS thread isn't stopped, so a frame can be popped (& its
+    /// slot reused) while we read it: a sample may be off by a frame.
+    fn sample(&self) -> Vec<CallFrame> {
+        let sp = self.stack_pointer.load(Ordering::Acquire).min(self.capacity);
+        let frames = self.frames.load(Ordering::SeqCst);
+        let mut stack = Vec::with_capacity(sp as usize);
+        for i in 0..sp as usize {
+            let frame = unsafe { &*frames.add(i) };
+            let flags = frame.flags_and_category_pair.load(Ordering::Acquire);
+            let label = frame.label.load(Ordering::Acquire);
+            let dynamic = frame.dynamic_string.load(Ordering::Acquire);
+            if flags & IS_SP_MARKER_FRAME != 0 {
+                continue;
+            }
+            // a JS frame's string lives as long as its script, which
+            // can't be collected while it's on the stack
+            let string = |ptr: *mut c_char| (!ptr.is_null())
+                .then(|| unsafe { CStr::from_ptr(ptr) }.to_string_lossy().into_owned());
+            let frame = if flags & IS_JS_FRAME != 0 {
+                let Some(profile_string) = string(dynamic) else {
+                    continue;
+                };
+                CallFrame::js(&profile_string)
+            } else if label == HOST_LABEL.as_ptr() as *mut c_char {
+                CallFrame::native(FrameKind::Host, format!("(host) {}", string(dynamic).unwrap_or_default()))
+            } else if label == EVENT_LOOP_LABEL.as_ptr() as *mut c_char {
+                CallFrame::native(FrameKind::EventLoop, string(dynamic).unwrap_or_default())
+            } else {
+                let name = match (string(label).filter(|l| !l.is_empty()), string(dynamic)) {
+                    (Some(label), Some(dynamic)) => format!("{} {}", label, dynamic),
+                    (Some(name), None) | (None, Some(name)) => name,
+                    (None, None) => continue,
+                };
+                CallFrame::native(FrameKind::Engine, name)
+            };
+            stack.push(frame);
+        }
+        stack
+    }
+}
+
+#[derive(Clone,Copy,Debug,PartialEq,Eq,Hash)]
+enum FrameKind {
+    Root,
+    /// time outside of any frame, e.g. compiling before a script runs
+    Program,
+    Js,
+    Host,
+    EventLoop,
+    Engine,
+}
+
+/// Where a node's time went, as a `.cpuprofile` `callFrame`
+#[derive(Clone,Debug,PartialEq,Eq,Hash)]
+struct CallFrame {
+    kind: FrameKind,
+    name: String,
+    url: String,
+    /// one based, `0` if unknown
+    line: u32,
+    column: u32,
+}
+impl CallFrame {
+    fn native(kind: FrameKind, name: String) -> Self {
+        CallFrame { kind, name, url: String::new(), line: 0, column: 0 }
+    }
+
+    /// From the engine's `name (file:line:column)` or `file:line:column`
+    fn js(profile_string: &str) -> Self {
+        let (name, location) = match profile_string.strip_suffix(')').and_then(|s| s.rsplit_once(" (")) {
+            Some((name, location)) => (name, location),
+            None => ("", profile_string),
+        };
+        // no location, shown as is
+        let unknown = || CallFrame::native(FrameKind::Js, profile_string.to_string());
+        let mut parts = location.rsplitn(3, ':');
+        let (Some(column), Some(line), Some(url)) = (parts.next(), parts.next(), parts.next()) else {
+            return unknown();
+        };
+        let (Ok(line), Ok(column)) = (line.parse(), column.parse()) else {
+            return unknown();
+        };
+        CallFrame {
+            kind: FrameKind::Js,
+            name: name.to_string(),
+            url: url.to_string(),
+            line,
+            column,
+        }
+    }
+}
+
+struct Node {
+    frame: CallFrame,
+    children: Vec<usize>,
+    hits: u64,
+}
+
+/// The call tree & the leaf of every sample so far
+struct Samples {
+    /// the root is `0`
+    nodes: Vec<Node>,
+    index: HashMap<(usize,CallFrame),usize>,
+    samples: Vec<(usize,Instant)>,
+}
+impl Samples {
+    fn new() -> Self {
+        Samples {
+            nodes: vec![Node { frame: CallFrame::native(FrameKind::Root, "(root)".to_string()), children: Vec::new(), hits: 0 }],
+            index: HashMap::new(),
+            samples: Vec::new(),
+        }
+    }
+
+    fn record(&mut self, mut stack: Vec<CallFrame>, at: Instant) {
+        if stack.is_empty() {
+            stack.push(CallFrame::native(FrameKind::Program, "(program)".to_string()));
+        }
+        let mut node = 0;
+        for frame in stack {
+            node = match self.index.get(&(node, frame.clone())) {
+                Some(child) => *child,
+                None => {
+                    let child = self.nodes.len();
+                    self.nodes.push(Node { frame: frame.clone(), children: Vec::new(), hits: 0 });
+                    self.nodes[node].children.push(child);
+                    self.index.insert((node, frame), child);
+                    child
+                }
+            };
+        }
+        self.nodes[node].hits += 1;
+        self.samples.push((node, at));
+    }
+
+    /// Self time by kind, most first
+    fn summary(&self) -> Vec<(FrameKind,u64)> {
+        let mut totals: HashMap<FrameKind,u64> = HashMap::new();
+        for node in &self.nodes {
+            if node.hits > 0 {
+                *totals.entry(node.frame.kind).or_default() += node.hits;
+            }
+        }
+        let mut totals: Vec<(FrameKind,u64)> = totals.into_iter().collect();
+        totals.sort_by(|a, b| b.1.cmp(&a.1));
+        totals
+    }
+
+    /// The `.cpuprofile` JSON, times in microseconds since the epoch
+    fn render(&self, started: Instant, started_at: SystemTime, ended: Instant) -> String {
+        let epoch = started_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64;
+        let micros = |at: Instant| epoch + at.saturating_duration_since(started).as_micros() as u64;
+        let mut script_ids: HashMap<&str,usize> = HashMap::new();
+        let mut out = String::from("{\"nodes\":[");
+        for (id, node) in self.nodes.iter().enumerate() {
+            let frame = &node.frame;
+            // `callFrame` positions are zero based
+            let (mut name, mut url, mut line, mut column) = (frame.name.clone(), frame.url.clone(), frame.line as i64 - 1, frame.column as i64 - 1);
+            if let Some(original) = source_map::lookup(&frame.url, frame.line, frame.column) {
+                url = original.source;
+                line = original.line as i64 - 1;
+                column = original.column as i64 - 1;
+                if let Some(original_name) = original.name.filter(|_| name.is_empty()) {
+                    name = original_name;
+                }
+            }
+            if frame.kind == FrameKind::Js && name.is_empty() {
+                name = "(anonymous)".to_string();
+            }
+            let script_id = match frame.url.is_empty() {
+                true => 0,
+                false => {
+                    let next = script_ids.len() + 1;
+                    *script_ids.entry(frame.url.as_str()).or_insert(next)
+                }
+            };
+            if id > 0 {
+                out.push(',');
+            }
+            let _ = write!(out, "{{\"id\":{},\"callFrame\":{{\"functionName\":", id + 1);
+            push_json_string(&mut out, &name);
+            let _ = write!(out, ",\"scriptId\":\"{}\",\"url\":", script_id);
+            push_json_string(&mut out, &url);
+            let _ = write!(out, ",\"lineNumber\":{},\"columnNumber\":{}}},\"hitCount\":{},\"children\":[", line, column, node.hits);
+            for (i, child) in node.children.iter().enumerate() {
+                let _ = write!(out, "{}{}", if i > 0 { "," } else { "" }, child + 1);
+            }
+            out.push_str("]}");
+        }
+        let _ = write!(out, "],\"startTime\":{},\"endTime\":{},\"samples\":[", micros(started), micros(ended));
+        for (i, (node, _)) in self.samples.iter().enumerate() {
+            let _ = write!(out, "{}{}", if i > 0 { "," } else { "" }, node + 1);
+        }
+        out.push_str("],\"timeDeltas\":[");
+        let mut previous = started;
+        for (i, (_, at)) in self.samples.iter().enumerate() {
+            let _ = write!(out, "{}{}", if i > 0 { "," } else { "" }, at.saturating_duration_since(previous).as_micros());
+            previous = *at;
+        }
+        out.push_str("]}");
+        out
+    }
+}
+
+fn push_json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+struct Profiler {
+    stack: &'static MirrorStack,
+    samples: Arc<Mutex<Samples>>,
+    stopped: Arc<AtomicBool>,
+    path: PathBuf,
+    started: Instant,
+    started_at: SystemTime,
+}
+
+thread_local! {
+    static PROFILER: LazyCell<RefCell<Option<Profiler>>> = LazyCell::new(|| RefCell::new(None));
+    /// host function & event loop names, as the C strings our labels point at
+    static NAMES: LazyCell<RefCell<HashMap<&'static str,&'static CStr>>> = LazyCell::new(|| RefCell::new(HashMap::new()));
+}
+
+/// `CPU.<unix millis>.<pid>.cpuprofile` in the working directory, like node.js
+pub fn default_profile_path() -> PathBuf {
+    let millis = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+    PathBuf::from(format!("CPU.{}.{}.cpuprofile", millis, std::process::id()))
+}
+
+/// Profiles this thread's JS until `stop_profiler`, sampling every `interval`
+///
+/// Must be called before any script runs, a function already on the
+/// stack would never be popped from ours.
+#[instrument(skip(ctx))]
+pub fn start_profiler(ctx: &mut JSContext, path: PathBuf, interval: Duration) {
+    if PROFILER.with(|p| p.borrow().is_some()) {
+        warn!("the profiler is already running");
+        return;
+    }
+    let stack = MirrorStack::leak();
+    let cx = unsafe { ctx.raw_cx() };
+    unsafe {
+        for option in [
+            JSJitCompilerOption::JSJITCOMPILER_BASELINE_INTERPRETER_ENABLE,
+            JSJitCompilerOption::JSJITCOMPILER_BASELINE_ENABLE,
+            JSJitCompilerOption::JSJITCOMPILER_ION_ENABLE,
+        ] {
+            JS_SetGlobalJitCompilerOption(cx, option, 0);
+        }
+        SetContextProfilingStack(cx, stack as *const MirrorStack as *mut ProfilingStack);
+        EnableContextProfilingStack(cx, true);
+    }
+
+    let samples = Arc::new(Mutex::new(Samples::new()));
+    let stopped = Arc::new(AtomicBool::new(false));
+    tokio::spawn(sample_stack(stack, samples.clone(), stopped.clone(), interval));
+    PROFILER.with(|p| *p.borrow_mut() = Some(Profiler {
+        stack,
+        samples,
+        stopped,
+        path,
+        started: Instant::now(),
+        started_at: SystemTime::now(),
+    }));
+    info!("profiling every {:?}", interval);
+}
+
+async fn sample_stack(stack: &'static MirrorStack, samples: Arc<Mutex<Samples>>, stopped: Arc<AtomicBool>, interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    // a late tick is one sample, not a burst
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    loop {
+        ticks.tick().await;
+        if stopped.load(Ordering::Acquire) {
+            break;
+        }
+        let frames = stack.sample();
+        samples.lock().unwrap().record(frames, Instant::now());
+    }
+}
+
+/// Stops sampling & writes the profile, a no-op unless profiling
+///
+/// The engine keeps our stack, it stops pushing to it.
+pub fn stop_profiler(ctx: &mut JSContext) {
+    if PROFILER.with(|p| p.borrow().is_none()) {
+        return;
+    }
+    unsafe { EnableContextProfilingStack(ctx.raw_cx(), false) };
+    write_profile();
+}
+
+/// The same as `stop_profiler`, for when the process is about to exit
+/// without returning to the engine
+pub fn write_profile() {
+    let Some(profiler) = PROFILER.with(|p| p.borrow_mut().take()) else {
+        return;
+    };
+    profiler.stopped.store(true, Ordering::Release);
+    let ended = Instant::now();
+    let samples = profiler.samples.lock().unwrap();
+    let total = samples.samples.len().max(1) as f64;
+    for (kind, hits) in samples.summary() {
+        info!("{:?}: {} samples ({:.1}%)", kind, hits, hits as f64 * 100.0 / total);
+    }
+    let json = samples.render(profiler.started, profiler.started_at, ended);
+    match std::fs::write(&profiler.path, json) {
+        Ok(()) => info!("wrote '{}' samples to '{}'", samples.samples.len(), profiler.path.display()),
+        Err(e) => error!("could not write the profile to '{}': {}", profiler.path.display(), e),
+    }
+}
+
+/// Pops its label when dropped, see `host_label` & `event_loop_label`
+pub(crate) struct ProfileLabel {
+    stack: Option<&'static MirrorStack>,
+}
+impl Drop for ProfileLabel {
+    fn drop(&mut self) {
+        if let Some(stack) = self.stack {
+            stack.pop();
+        }
+    }
+}
+
+/// Attributes the time until the label is dropped to host function `name`
+pub(crate) fn host_label(name: &'static str) -> ProfileLabel {
+    push_label(HOST_LABEL, name)
+}
+
+/// Attributes the time until the label is dropped to event loop phase `name`
+pub(crate) fn event_loop_label(name: &'static str) -> ProfileLabel {
+    push_label(EVENT_LOOP_LABEL, name)
+}
+
+fn push_label(label: &'static CStr, name: &'static str) -> ProfileLabel {
+    let Some(stack) = PROFILER.with(|p| p.borrow().as_ref().map(|p| p.stack)) else {
+        return ProfileLabel { stack: None };
+    };
+    let name = NAMES.with(|n| *n.borrow_mut().entry(name).or_insert_with(|| {
+        let name = CString::new(name.replace('\0', "")).unwrap_or_default();
+        Box::leak(name.into_boxed_c_str())
+    }));
+    ProfileLabel {
+        stack: stack.push_label(label, name).then_some(stack),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn js_frames_are_parsed_from_the_profile_string() {
+        let frame = CallFrame::js("add (file:///a.js:3:14)");
+        assert_eq!((frame.name.as_str(), frame.url.as_str(), frame.line, frame.column), ("add", "file:///a.js", 3, 14));
+        let frame = CallFrame::js("file:///a.js:1:1");
+        assert_eq!((frame.name.as_str(), frame.url.as_str(), frame.line, frame.column), ("", "file:///a.js", 1, 1));
+        let frame = CallFrame::js("self-hosted");
+        assert_eq!((frame.name.as_str(), frame.url.as_str(), frame.line), ("self-hosted", "", 0));
+    }
+
+    #[test]
+    fn labels_are_sampled_until_popped() {
+        let stack = MirrorStack::leak();
+        assert!(stack.push_label(EVENT_LOOP_LABEL, c"macrotasks"));
+        assert!(stack.push_label(HOST_LABEL, c"fetch"));
+        let sampled = stack.sample();
+        assert_eq!(sampled, [
+            CallFrame::native(FrameKind::EventLoop, "macrotasks".to_string()),
+            CallFrame::native(FrameKind::Host, "(host) fetch".to_string()),
+        ]);
+        stack.pop();
+        assert_eq!(stack.sample().len(), 1);
+        stack.pop();
+        assert!(stack.sample().is_empty());
+    }
+
+    #[test]
+    fn labels_are_no_ops_unless_profiling() {
+        let label = host_label("fetch");
+        assert!(label.stack.is_none());
+    }
+
+    #[test]
+    fn samples_build_a_call_tree() {
+        let mut samples = Samples::new();
+        let at = Instant::now();
+        let main = CallFrame::js("main (file:///a.js:1:1)");
+        let add = CallFrame::js("add (file:///a.js:2:3)");
+        samples.record(vec![main.clone(), add.clone()], at);
+        samples.record(vec![main.clone(), add], at);
+        samples.record(vec![main], at);
+        samples.record(Vec::new(), at);
+        // root, main, add & (program)
+        assert_eq!(samples.nodes.len(), 4);
+        assert_eq!(samples.nodes[0].children, [1, 3]);
+        assert_eq!(samples.nodes[2].hits, 2);
+        assert_eq!(samples.summary(), [(FrameKind::Js, 3), (FrameKind::Program, 1)]);
+    }
+
+    #[test]
+    fn profiles_are_rendered_as_cpuprofile_json() {
+        let mut samples = Samples::new();
+        let started = Instant::now();
+        let started_at = UNIX_EPOCH + Duration::from_secs(1);
+        samples.record(vec![CallFrame::js("file:///a.js:2:5")], started + Duration::from_micros(10));
+        samples.record(vec![CallFrame::native(FrameKind::Host, "(host) \"x\"".to_string())], started + Duration::from_micros(25));
+        let json = samples.render(started, started_at, started + Duration::from_micros(30));
+        assert_eq!(json, concat!(
+            "{\"nodes\":[",
+            "{\"id\":1,\"callFrame\":{\"functionName\":\"(root)\",\"scriptId\":\"0\",\"url\":\"\",\"lineNumber\":-1,\"columnNumber\":-1},\"hitCount\":0,\"children\":[2,3]},",
+            "{\"id\":2,\"callFrame\":{\"functionName\":\"(anonymous)\",\"scriptId\":\"1\",\"url\":\"file:///a.js\",\"lineNumber\":1,\"columnNumber\":4},\"hitCount\":1,\"children\":[]},",
+            "{\"id\":3,\"callFrame\":{\"functionName\":\"(host) \\\"x\\\"\",\"scriptId\":\"0\",\"url\":\"\",\"lineNumber\":-1,\"columnNumber\":-1},\"hitCount\":1,\"children\":[]}",
+            "],\"startTime\":1000000,\"endTime\":1000030,\"samples\":[2,3],\"timeDeltas\":[10,15]}",
+        ));
+    }
+
+    #[test]
+    fn json_strings_are_escaped() {
+        let mut out = String::new();
+        push_json_string(&mut out, "a\"b\\c\nd");
+        assert_eq!(out, "\"a\\\"b\\\\c\\u000ad\"");
+    }
+}
@@ -0,0 +1,103 @@
+use std::{
+    cell::{Cell,RefCell,LazyCell},
+    collections::{BTreeMap},
+};
+use mozjs::{rooted};
+use mozjs::{
+    context::{RawJSContext},
+    gc::{Handle},
+    jsapi::{Value,CallArgs,JS_GetFunctionObject},
+    jsapi::js::{NewFunctionWithReserved,SetFunctionNativeReserved,GetFunctionNativeReserved},
+    jsval::{UndefinedValue,DoubleValue},
+    rust::wrappers::{AddPromiseReactions,CallOriginalPromiseResolve},
+    panic::wrap_panic,
+};
+#[allow(unused_imports)] use tracing::{trace,debug,info,warn,error,instrument};
+
+/*
+ * Promise reactions in rust
+ *
+ * The other direction to `Bridge`: rust waiting on a JS promise. The
+ * callback runs as a regular promise reaction (so from a microtask,
+ * within the promise's realm) & is expected to convert the settled
+ * value into rust data, usually sending it down a channel to
+ * whatever future is waiting.
+ *
+ */
+
+thread_local! {
+    static REACTIONS: LazyCell<RefCell<BTreeMap<u64,Reaction>>> = LazyCell::new(|| RefCell::new(BTreeMap::new()));
+    static NEXT_REACTION_ID: Cell<u64> = const { Cell::new(1) };
+}
+
+/// Called once with `Ok(value)` when fulfilled, `Err(reason)` when rejected
+pub(crate) type Reaction = Box<dyn 'static + for<'a> FnOnce(*mut RawJSContext, Result<Handle<'a,Value>,Handle<'a,Value>>)>;
+
+/// Runs `reaction` once `value` settles
+///
+/// `value` doesn't have to be a promise, it is passed through
+/// `Promise.resolve` first. Returns `false` (with an exception
+/// pending) if the reaction could not be attached, in which case
+/// `reaction` is dropped without being called.
+pub(crate) fn on_settled(cx: *mut RawJSContext, value: Handle<'_,Value>, reaction: Reaction) -> bool {
+    rooted!(in(cx) let promise = unsafe { CallOriginalPromiseResolve(cx, value) });
+    if promise.get().is_null() {
+        return false;
+    }
+    let id = NEXT_REACTION_ID.with(|n| {
+        let id = n.get();
+        n.set(id + 1);
+        id
+    });
+    rooted!(in(cx) let on_fulfilled = new_reaction_function(cx, Some(reaction_fulfilled), id));
+    rooted!(in(cx) let on_rejected = new_reaction_function(cx, Some(reaction_rejected), id));
+    if on_fulfilled.get().is_null() || on_rejected.get().is_null() {
+        return false;
+    }
+    REACTIONS.with(|r| r.borrow_mut().insert(id, reaction));
+    if !unsafe { AddPromiseReactions(cx, promise.handle(), on_fulfilled.handle(), on_rejected.handle()) } {
+        REACTIONS.with(|r| r.borrow_mut().remove(&id));
+        return false;
+    }
+    true
+}
+
+/// A function object carrying the reaction id in its reserved slot
+fn new_reaction_function(cx: *mut RawJSContext, native: mozjs::jsapi::JSNative, id: u64) -> *mut mozjs::jsapi::JSObject {
+    let fun = unsafe { NewFunctionWithReserved(cx, native, 1, 0, c"reaction".as_ptr()) };
+    if fun.is_null() {
+        return std::ptr::null_mut();
+    }
+    let obj = unsafe { JS_GetFunctionObject(fun) };
+    // ids stay well below 2^53
+    let slot = DoubleValue(id as f64);
+    unsafe { SetFunctionNativeReserved(obj, 0, &slot) };
+    obj
+}
+
+unsafe extern "C" fn reaction_fulfilled(cx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    run_reaction(cx, argc, vp, true)
+}
+
+unsafe extern "C" fn reaction_rejected(cx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    run_reaction(cx, argc, vp, false)
+}
+
+fn run_reaction(cx: *mut RawJSContext, argc: u32, vp: *mut Value, fulfilled: bool) -> bool {
+    wrap_panic(&mut || {
+        let args = unsafe { CallArgs::from_vp(vp, argc) };
+        let id = unsafe { &*GetFunctionNativeReserved(args.callee(), 0) }.to_number() as u64;
+        let Some(reaction) = REACTIONS.with(|r| r.borrow_mut().remove(&id)) else {
+            warn!("reaction '{}' already ran", id);
+            return;
+        };
+        rooted!(in(cx) let value = if argc > 0 { *args.get(0) } else { UndefinedValue() });
+        if fulfilled {
+            (reaction)(cx, Ok(value.handle()));
+        } else {
+            (reaction)(cx, Err(value.handle()));
+        }
+        args.rval().set(UndefinedValue());
+    });
+    true
+}
@@ -1,55 +1,199 @@
 use std::{
-    ptr::{NonNull,null},
-    cell::{RefCell,LazyCell},
+    ptr::{NonNull,null,null_mut},
+    cell::{Cell,RefCell,LazyCell},
     ops::{DerefMut},
     collections::VecDeque,
+    time::{Instant},
 };
 use mozjs::{rooted};
 use mozjs::{
     realm::{AutoRealm},
     context::{JSContext},
-    jsapi::{Heap,JSObject},
+    jsapi::{Heap,JSObject,JSTracer},
     jsval::{UndefinedValue,ObjectValue},
     gc::{Handle},
 };
 #[allow(unused_imports)] use tracing::{trace,debug,info,warn,error,instrument};
+use tracing::{Span,field};
 
-use super::incumbent_stack::{enter_incumbent_stack};
+use super::{
+    incumbent_stack::{enter_incumbent_stack},
+    exception::{report_pending_exception},
+    resolvable_promise::{PendingFutures,take_pending_futures,restore_pending_futures},
+    checkpoint::{get_checkpoint,set_checkpoint,get_microtask_checkpoint,set_microtask_checkpoint},
+    metrics::{set_microtask_depth,set_macrotask_depth,observe_microtask,observe_macrotask},
+    async_stack::{with_async_parent},
+    profiler::{host_label},
+    roots::{trace_object},
+};
+use crate::realm::{realm_id,realm_id_of};
 
 thread_local! {
     static QUEUE: LazyCell<RefCell<VecDeque<Task>>> = LazyCell::new(|| RefCell::new(VecDeque::new()));
+    static MACROTASKS: LazyCell<RefCell<VecDeque<Macrotask>>> = LazyCell::new(|| RefCell::new(VecDeque::new()));
+    /// Outer queues which have been set aside while a nested queue is active
+    static INTERRUPT_QUEUES: LazyCell<RefCell<Vec<InterruptQueue>>> = LazyCell::new(|| RefCell::new(Vec::new()));
+    static NEXT_INTERRUPT_QUEUE_ID: Cell<usize> = Cell::new(1);
 }
 
+/*
+ * Microtasks
+ *
+ * Promise reactions (from `enqueue_promise_job`) and `queueMicrotask`.
+ * The whole FIFO is drained after every macrotask.
+ */
+
 /// Insert an item into the FIFO runtime queue
-pub fn insert_into_filo(job: Box<Heap<*mut JSObject>>, global: Box<Heap<*mut JSObject>>) {
+///
+/// `allocation_site` is the `SavedFrame` the job continues from, it
+/// may be null.
+pub fn insert_into_filo(job: Box<Heap<*mut JSObject>>, global: Box<Heap<*mut JSObject>>, allocation_site: *mut JSObject) {
+    let allocation_site = (!allocation_site.is_null()).then(|| Heap::boxed(allocation_site));
     QUEUE.with(|q| {
-        q.borrow_mut().push_back(Task { job, obj: global });
+        let mut q = q.borrow_mut();
+        q.push_back(Task { job, obj: global, allocation_site });
+        set_microtask_depth(q.len());
     });
 }
 
 /// Remoe a item into the FIFO runtime queue
 pub fn remove_from_filo() -> Option<Task> {
     QUEUE.with(|q| {
-        q.borrow_mut().pop_front()
+        let mut q = q.borrow_mut();
+        let task = q.pop_front();
+        set_microtask_depth(q.len());
+        task
     })
 }
 
+/// The realm of every queued microtask, in the order they'll run
+pub(crate) fn queued_microtasks() -> Vec<Option<u64>> {
+    QUEUE.with(|q| q.borrow().iter().map(|t| realm_id_of(t.obj.get())).collect())
+}
+
+/// Reports every queued job to the GC, those set aside by `push_interrupt_queue` too
+///
+/// Macrotasks aren't traced: a macrotask holds no `Heap` of its own, it
+/// looks its objects up when ran or keeps `HeapRoot`s.
+pub(crate) unsafe fn trace_queues(trc: *mut JSTracer) {
+    QUEUE.with(|q| unsafe { trace_tasks(trc, &q.borrow()) });
+    INTERRUPT_QUEUES.with(|i| {
+        for saved in i.borrow().iter() {
+            unsafe { trace_tasks(trc, &saved.tasks) };
+        }
+    });
+}
+
+unsafe fn trace_tasks(trc: *mut JSTracer, tasks: &VecDeque<Task>) {
+    for task in tasks {
+        unsafe {
+            trace_object(trc, &task.job, c"microtask");
+            trace_object(trc, &task.obj, c"microtask global");
+            if let Some(site) = &task.allocation_site {
+                trace_object(trc, site, c"microtask allocation site");
+            }
+        }
+    }
+}
+
 pub fn filo_empty() -> bool {
     QUEUE.with(|q| {
         q.borrow().is_empty()
     })
 }
 
+/*
+ * Macrotasks
+ *
+ * Timers, I/O completions, messages. Anything that originates outside
+ * the JS engine. Exactly one is ran between microtask checkpoints.
+ */
+
+/// Insert a macrotask at the back of the task queue
+pub fn insert_macrotask(task: Macrotask) {
+    MACROTASKS.with(|q| {
+        let mut q = q.borrow_mut();
+        q.push_back(task);
+        set_macrotask_depth(q.len());
+    });
+}
+
+/// Remove the oldest macrotask
+pub fn remove_macrotask() -> Option<Macrotask> {
+    MACROTASKS.with(|q| {
+        let mut q = q.borrow_mut();
+        let task = q.pop_front();
+        set_macrotask_depth(q.len());
+        task
+    })
+}
+
+/// The source of every queued macrotask, in the order they'll run
+pub(crate) fn queued_macrotasks() -> Vec<&'static str> {
+    MACROTASKS.with(|q| q.borrow().iter().map(|t| t.source()).collect())
+}
+
+pub fn macrotasks_empty() -> bool {
+    MACROTASKS.with(|q| {
+        q.borrow().is_empty()
+    })
+}
+
+/// Drops every queued microtask & macrotask, returns how many were dropped
+pub(crate) fn clear_queues() -> usize {
+    let jobs = QUEUE.with(|q| q.borrow_mut().drain(..).count());
+    let tasks = MACROTASKS.with(|q| q.borrow_mut().drain(..).count());
+    set_microtask_depth(0);
+    set_macrotask_depth(0);
+    jobs + tasks
+}
+
+/// A unit of work that originates outside the engine
+///
+/// `source` is only used for tracing, it names what queued the task
+/// (e.g. `"sleep_ms"`). `run` must not capture a `Heap`, see
+/// `trace_queues`.
+pub struct Macrotask {
+    source: &'static str,
+    run: Box<dyn 'static + FnOnce(&mut JSContext)>,
+}
+impl Macrotask {
+    pub fn new<F>(source: &'static str, run: F) -> Self
+    where
+        F: 'static + FnOnce(&mut JSContext),
+    {
+        Macrotask { source, run: Box::new(run) }
+    }
+
+    pub fn source(&self) -> &'static str {
+        self.source
+    }
+
+    #[instrument(skip_all, name = "macrotask", fields(source = self.source, elapsed_ms = field::Empty))]
+    pub fn call(self, ctx: &mut JSContext) {
+        let started = Instant::now();
+        let _label = host_label(self.source);
+        (self.run)(ctx);
+        let took = started.elapsed();
+        observe_macrotask(took);
+        Span::current().record("elapsed_ms", took.as_secs_f64() * 1000.0);
+    }
+}
+
 /// Task contains everyting it needs to setup and run its job
 pub struct Task {
     job: Box<Heap<*mut JSObject>>,
-    obj: Box<Heap<*mut JSObject>>
+    obj: Box<Heap<*mut JSObject>>,
+    /// the async parent of the frames the job pushes
+    allocation_site: Option<Box<Heap<*mut JSObject>>>,
 }
 impl Task {
-    #[instrument(skip_all)]
+    #[instrument(skip_all, name = "microtask", fields(realm = field::Empty, elapsed_ms = field::Empty))]
     pub fn call(self, ctx: &mut JSContext) {
+        let started = Instant::now();
         rooted!(in(unsafe { ctx.raw_cx() }) let globals = self.obj.get());
         enter_incumbent_stack(ctx, globals.handle(), |realm: &mut AutoRealm, _ :Handle<'_,*mut JSObject>| -> () {
+            Span::current().record("realm", realm_id(unsafe { realm.deref_mut().raw_cx() }));
             //push_incumbent_stack(Heap::boxed(self.obj.get()));
             //let mut realm = AutoRealm::new(ctx, NonNull::new(self.obj.get()).unwrap());
             //let (_globals, realm) = realm.global_and_reborrow();
@@ -59,17 +203,237 @@ impl Task {
                 length_: 0,
                 elements_: null(),
             };
-            unsafe {
-                let _ = mozjs::jsapi::JS::Call(
-                    realm.deref_mut().raw_cx(),
+            rooted!(in(unsafe { realm.deref_mut().raw_cx() } ) let allocation_site = self.allocation_site.as_ref().map(|s| s.get()).unwrap_or(null_mut()));
+            let cx = unsafe { realm.deref_mut().raw_cx() };
+            let ok = with_async_parent(cx, allocation_site.handle(), c"promise callback", || unsafe {
+                mozjs::jsapi::JS::Call(
+                    cx,
                     mozjs::gc::HandleValue::undefined().into(),
                     callback.handle().into(),
                     &args,
                     rval.handle_mut().into(),
-                );
+                )
+            });
+            if !ok {
+                // promise jobs catch their own errors, `queueMicrotask` callbacks don't
+                report_pending_exception(unsafe { realm.deref_mut().raw_cx() }, "microtask");
             }
         });
         //pop_incumbent_stack();
+        let took = started.elapsed();
+        observe_microtask(took);
+        Span::current().record("elapsed_ms", took.as_secs_f64() * 1000.0);
     }
 }
 
+/// Everything an outer event loop owns that a nested event loop must not touch.
+///
+/// SpiderMonkey asks for a fresh queue when the debugger (or anything
+/// else spinning a nested event loop) needs to run code without
+/// interleaving it with the jobs that were already scheduled. `id` is
+/// handed back to the engine as the identity of the nested queue, it
+/// is never zero so it can't be mistaken for a failure.
+pub(crate) struct InterruptQueue {
+    id: usize,
+    tasks: VecDeque<Task>,
+    macrotasks: VecDeque<Macrotask>,
+    pending: PendingFutures,
+    checkpoint: bool,
+    microtask_checkpoint: bool,
+}
+
+/// Sets aside the current FIFO & pending futures, leaving empty ones behind
+///
+/// Returns the id of the saved queue, `pop_interrupt_queue` returns
+/// the same id once it's restored.
+pub(crate) fn push_interrupt_queue() -> usize {
+    let id = NEXT_INTERRUPT_QUEUE_ID.with(|n| {
+        let id = n.get();
+        n.set(id + 1);
+        id
+    });
+    let saved = InterruptQueue {
+        id,
+        tasks: QUEUE.with(|q| std::mem::take(&mut *q.borrow_mut())),
+        macrotasks: MACROTASKS.with(|q| std::mem::take(&mut *q.borrow_mut())),
+        pending: take_pending_futures(),
+        checkpoint: get_checkpoint(),
+        microtask_checkpoint: get_microtask_checkpoint(),
+    };
+    // the outer checkpoint may be on the stack, the nested one must still run
+    set_checkpoint(false);
+    set_microtask_checkpoint(false);
+    // the gauges follow whichever queue is active
+    set_microtask_depth(0);
+    set_macrotask_depth(0);
+    INTERRUPT_QUEUES.with(|i| {
+        let mut stack = i.borrow_mut();
+        stack.push(saved);
+        debug!("pushed interrupt queue '{}', depth: '{}'", id, stack.len());
+    });
+    id
+}
+
+/// Restores the most recently saved queue, returns the id of the queue popped
+pub(crate) fn pop_interrupt_queue() -> Option<usize> {
+    let saved = INTERRUPT_QUEUES.with(|i| i.borrow_mut().pop())?;
+    let InterruptQueue { id, mut tasks, mut macrotasks, pending, checkpoint, microtask_checkpoint } = saved;
+
+    QUEUE.with(|q| {
+        let mut q = q.borrow_mut();
+        if !q.is_empty() {
+            // nested loop didn't drain, keep the jobs but after the outer ones
+            warn!("interrupt queue popped with '{}' jobs remaining", q.len());
+        }
+        tasks.extend(q.drain(..));
+        *q = tasks;
+        set_microtask_depth(q.len());
+    });
+    MACROTASKS.with(|q| {
+        let mut q = q.borrow_mut();
+        macrotasks.extend(q.drain(..));
+        *q = macrotasks;
+        set_macrotask_depth(q.len());
+    });
+    restore_pending_futures(pending);
+    set_checkpoint(checkpoint);
+    set_microtask_checkpoint(microtask_checkpoint);
+    debug!("popped interrupt queue '{}'", id);
+    Some(id)
+}
+
+/// Discards every saved queue, used when the job queue itself is torn down
+pub(crate) fn drop_interrupt_queues() {
+    INTERRUPT_QUEUES.with(|i| {
+        let mut stack = i.borrow_mut();
+        if !stack.is_empty() {
+            warn!("dropping '{}' interrupt queues", stack.len());
+        }
+        stack.clear();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::{Rc};
+    use super::*;
+    use crate::runtime::{
+        checkpoint::{microtask_checkpoint},
+        testing::{with_runtime,new_test_global,eval,gc,capture},
+    };
+    use crate::realm::{realm_id_of};
+
+    /// Queues a macrotask which records `name` when ran
+    fn queue_named(ran: &Rc<RefCell<Vec<&'static str>>>, name: &'static str) {
+        let ran = ran.clone();
+        insert_macrotask(Macrotask::new(name, move |_: &mut JSContext| ran.borrow_mut().push(name)));
+    }
+
+    fn run_macrotasks(ctx: &mut JSContext) {
+        while let Some(task) = remove_macrotask() {
+            task.call(ctx);
+        }
+    }
+
+    #[test]
+    fn nested_queues_run_apart_from_outer_ones() {
+        with_runtime(|ctx| {
+            let ran = Rc::new(RefCell::new(Vec::new()));
+            queue_named(&ran, "outer");
+            let first = push_interrupt_queue();
+            assert!(macrotasks_empty());
+            queue_named(&ran, "nested");
+            let second = push_interrupt_queue();
+            assert_ne!(first, second);
+            assert_ne!(second, 0);
+            queue_named(&ran, "innermost");
+
+            run_macrotasks(ctx);
+            assert_eq!(*ran.borrow(), ["innermost"]);
+            assert_eq!(pop_interrupt_queue(), Some(second));
+            assert_eq!(queued_macrotasks(), ["nested"]);
+            run_macrotasks(ctx);
+            assert_eq!(pop_interrupt_queue(), Some(first));
+            run_macrotasks(ctx);
+            assert_eq!(*ran.borrow(), ["innermost", "nested", "outer"]);
+            assert_eq!(pop_interrupt_queue(), None);
+        });
+    }
+
+    #[test]
+    fn undrained_nested_tasks_run_after_outer_ones() {
+        with_runtime(|ctx| {
+            let ran = Rc::new(RefCell::new(Vec::new()));
+            queue_named(&ran, "outer");
+            let id = push_interrupt_queue();
+            queue_named(&ran, "nested");
+            assert_eq!(pop_interrupt_queue(), Some(id));
+            assert_eq!(queued_macrotasks(), ["outer", "nested"]);
+            run_macrotasks(ctx);
+            assert_eq!(*ran.borrow(), ["outer", "nested"]);
+        });
+    }
+
+    #[test]
+    fn checkpoints_are_restored_when_popped() {
+        with_runtime(|_| {
+            set_checkpoint(true);
+            set_microtask_checkpoint(true);
+            let id = push_interrupt_queue();
+            assert!(!get_checkpoint() && !get_microtask_checkpoint());
+            assert_eq!(pop_interrupt_queue(), Some(id));
+            assert!(get_checkpoint() && get_microtask_checkpoint());
+            set_checkpoint(false);
+            set_microtask_checkpoint(false);
+        });
+    }
+
+    #[test]
+    fn promise_jobs_wait_for_the_outer_queue() {
+        with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            assert!(!global.get().is_null());
+            eval(ctx, global.handle(), "globalThis.log = []; Promise.resolve().then(() => log.push('outer'));").unwrap();
+            let id = push_interrupt_queue();
+            assert!(filo_empty());
+            eval(ctx, global.handle(), "Promise.resolve().then(() => log.push('nested'));").unwrap();
+            microtask_checkpoint(ctx);
+            assert_eq!(eval(ctx, global.handle(), "log.join()").unwrap(), "nested");
+            assert_eq!(pop_interrupt_queue(), Some(id));
+            microtask_checkpoint(ctx);
+            assert_eq!(eval(ctx, global.handle(), "log.join()").unwrap(), "nested,outer");
+        });
+    }
+
+    #[test]
+    fn saved_jobs_survive_a_gc() {
+        with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            eval(ctx, global.handle(), "globalThis.log = []; Promise.resolve().then(() => log.push('outer'));").unwrap();
+            let id = push_interrupt_queue();
+            eval(ctx, global.handle(), "for (let i = 0; i < 10000; i++) ({ garbage: [i] });").unwrap();
+            gc(ctx);
+            assert_eq!(pop_interrupt_queue(), Some(id));
+            gc(ctx);
+            microtask_checkpoint(ctx);
+            assert_eq!(eval(ctx, global.handle(), "log.join()").unwrap(), "outer");
+        });
+    }
+
+    #[test]
+    fn tasks_run_within_spans() {
+        let (realm, captured) = capture(|| with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            insert_macrotask(Macrotask::new("spanned", |_: &mut JSContext| {}));
+            run_macrotasks(ctx);
+            eval(ctx, global.handle(), "Promise.resolve().then(() => {})").unwrap();
+            microtask_checkpoint(ctx);
+            realm_id_of(global.get())
+        }));
+        let macrotask = captured.spans_named("macrotask").find(|s| s.fields["source"] == "spanned").unwrap();
+        assert!(macrotask.fields.contains_key("elapsed_ms"));
+        let microtask = captured.spans_named("microtask").next().unwrap();
+        assert_eq!(microtask.fields["realm"], realm.unwrap().to_string());
+        assert!(microtask.fields.contains_key("elapsed_ms"));
+    }
+}
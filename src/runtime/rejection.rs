@@ -0,0 +1,123 @@
+use std::{
+    cell::{RefCell,LazyCell},
+    ffi::{c_void},
+    ops::{DerefMut},
+    ptr::{NonNull},
+};
+use mozjs::{rooted};
+use mozjs::{
+    context::{JSContext,RawJSContext},
+    jsapi::{Heap,JSObject,HandleObject,PromiseRejectionHandlingState,GetPromiseResult},
+    realm::{AutoRealm},
+    panic::{wrap_panic},
+};
+#[allow(unused_imports)] use tracing::{trace,debug,info,warn,error,instrument};
+
+use super::{
+    exception::{describe_exception},
+};
+use crate::realm::{realm_id};
+
+/*
+ * Unhandled rejections
+ *
+ * The engine tells `track_rejection` whenever a promise is rejected
+ * without a handler & again if one is attached later. Whatever is
+ * still unhandled once a microtask checkpoint has drained is logged,
+ * source mapped like every other reported error.
+ *
+ */
+
+thread_local! {
+    /// rejected promises without a handler, oldest first
+    static UNHANDLED: LazyCell<RefCell<Vec<Box<Heap<*mut JSObject>>>>> = LazyCell::new(|| RefCell::new(Vec::new()));
+}
+
+/// The `PromiseRejectionTrackerCallback`, see `install_job_queue`
+pub(crate) unsafe extern "C" fn track_rejection(
+    _cx: *mut RawJSContext,
+    _muted_errors: bool,
+    promise: HandleObject,
+    state: PromiseRejectionHandlingState,
+    _data: *mut c_void,
+) {
+    wrap_panic(&mut || {
+        let promise = promise.get();
+        match state {
+            PromiseRejectionHandlingState::Unhandled => {
+                UNHANDLED.with(|u| u.borrow_mut().push(Heap::boxed(promise)));
+            }
+            PromiseRejectionHandlingState::Handled => {
+                UNHANDLED.with(|u| u.borrow_mut().retain(|p| p.get() != promise));
+            }
+        }
+    });
+}
+
+/// Logs & forgets every promise still rejected without a handler
+///
+/// Ran at the end of each microtask checkpoint, so a handler attached
+/// by a later job in the same checkpoint is in time.
+pub(crate) fn report_unhandled_rejections(ctx: &mut JSContext) {
+    let unhandled = UNHANDLED.with(|u| std::mem::take(&mut *u.borrow_mut()));
+    for promise in unhandled {
+        let Some(obj) = NonNull::new(promise.get()) else {
+            continue;
+        };
+        let mut realm = AutoRealm::new(ctx, obj);
+        let cx = unsafe { realm.deref_mut().raw_cx() };
+        rooted!(in(cx) let promise = obj.as_ptr());
+        rooted!(in(cx) let reason = unsafe { GetPromiseResult(promise.handle().into()) });
+        let (message, stack) = describe_exception(cx, reason.handle());
+        error!(realm = realm_id(cx), "unhandled rejection: {}\n{}", message, stack);
+    }
+}
+
+/// Drops every tracked promise, used when the queues are torn down
+pub(crate) fn clear_unhandled_rejections() {
+    UNHANDLED.with(|u| u.borrow_mut().clear());
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing::{Level};
+    use super::*;
+    use crate::runtime::{
+        checkpoint::{microtask_checkpoint},
+        testing::{Captured,with_runtime,new_test_global,eval,capture},
+    };
+
+    /// What's reported once `source` ran & the microtasks it queued drained
+    fn reported(source: &str) -> Captured {
+        capture(|| with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            eval(ctx, global.handle(), source).unwrap();
+            microtask_checkpoint(ctx);
+        })).1
+    }
+
+    fn rejections(captured: &Captured) -> Vec<&str> {
+        captured.events.iter()
+            .filter(|e| e.level == Level::ERROR && e.fields["message"].starts_with("unhandled rejection"))
+            .map(|e| e.fields["message"].as_str())
+            .collect()
+    }
+
+    #[test]
+    fn unhandled_rejections_are_reported() {
+        let captured = reported("function fail() { return Promise.reject(new Error('nobody caught this')); } fail();");
+        let reported = rejections(&captured);
+        assert_eq!(reported.len(), 1);
+        assert!(reported[0].contains("nobody caught this"), "{}", reported[0]);
+        assert!(reported[0].contains("fail@test.js"), "{}", reported[0]);
+    }
+
+    #[test]
+    fn handlers_attached_within_the_checkpoint_are_in_time() {
+        let captured = reported(r#"
+            const late = Promise.reject(new Error('handled later'));
+            Promise.resolve().then(() => late.catch(() => {}));
+        "#);
+        assert!(rejections(&captured).is_empty());
+    }
+}
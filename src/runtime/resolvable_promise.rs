@@ -5,11 +5,14 @@ use std::{
     ptr::{NonNull},
     rc::{Rc},
     marker::{PhantomData},
+    ops::{DerefMut},
     cell::{RefCell,LazyCell},
     collections::{BTreeMap},
+    time::{Instant},
 };
 use tokio::{
     runtime::{Handle as TokioHandle},
+    task::{JoinError},
 };
 use futures_util::{
     stream::futures_unordered::FuturesUnordered,
@@ -18,37 +21,230 @@ use futures_util::{
 use mozjs::{rooted};
 use mozjs::{
     realm::{AutoRealm},
-    context::{JSContext},
-    jsapi::{Heap,JSObject,Value},
-    jsval::{UndefinedValue},
+    context::{JSContext,RawJSContext},
+    jsapi::{Heap,JSObject,JSTracer,Value,CallArgs},
+    jsval::{UndefinedValue,ObjectValue},
+    error::{throw_type_error},
+    panic::{wrap_panic},
     gc::{Handle,MutableHandle},
 };
 
-use super::incumbent_stack::{enter_incumbent_stack};
+#[allow(unused_imports)] use tracing::{trace,debug,info,warn,error,instrument};
+use tracing::{Instrument,Span,debug_span,trace_span,field};
+
+use super::{
+    incumbent_stack::{enter_incumbent_stack},
+    queue::{Macrotask},
+    metrics::{set_pending_futures,observe_resolution_latency},
+    introspection::{PendingPromise,capture_stacks},
+    async_stack::{capture_saved_frame,stack_string,with_async_parent,attach_async_stack},
+    source_map::{map_stack},
+    profiler::{host_label},
+    roots::{trace_object},
+    conversions::{new_error},
+};
+use crate::realm::{realm_id};
 
 
 thread_local! {
     static FAKE_PENDING_PROMISES: LazyCell<RefCell<BTreeMap<u64,InternalPromise>>> = LazyCell::new(|| RefCell::new(BTreeMap::new()));
     /// Ensures our promise handling is non-reentrant
-    static PENDING: LazyCell<RefCell<FuturesUnordered<Pin<Box<dyn Future<Output=(u64,ResolutionMarshalling)> + 'static>>>>> = LazyCell::new(|| RefCell::new(FuturesUnordered::new()));
+    static PENDING: LazyCell<RefCell<PendingFutures>> = LazyCell::new(|| RefCell::new(FuturesUnordered::new()));
+    /// Polled like `PENDING`, but doesn't keep the event loop alive
+    static UNREF_PENDING: LazyCell<RefCell<PendingFutures>> = LazyCell::new(|| RefCell::new(FuturesUnordered::new()));
+    /// Set by `checkpoint::drain`, new task sources are dropped
+    static DRAINING: std::cell::Cell<bool> = std::cell::Cell::new(false);
+}
+
+/// Every future the runtime is waiting on, each completes into a macrotask
+pub(crate) type PendingFutures = FuturesUnordered<Pin<Box<dyn Future<Output=Macrotask> + 'static>>>;
+
+/// Registers a future which queues a macrotask once it completes
+///
+/// `Bridge` is built on this, but anything that isn't resolving a
+/// single promise (timers, message ports, signals) can use it directly.
+pub(crate) fn push_task_source<F>(future: F)
+where
+    F: Future<Output=Macrotask> + 'static,
+{
+    if DRAINING.with(|d| d.get()) {
+        debug!("draining, dropping new task source");
+        return;
+    }
+    PENDING.with(|p| p.borrow().push(Box::pin(future)));
+    record_pending();
+}
+
+/// Same as `push_task_source`, except the event loop may exit while it is pending
+///
+/// For sources which only matter while something else is going on
+/// (e.g. `onSignal` listeners).
+pub(crate) fn push_unref_task_source<F>(future: F)
+where
+    F: Future<Output=Macrotask> + 'static,
+{
+    if DRAINING.with(|d| d.get()) {
+        debug!("draining, dropping new task source");
+        return;
+    }
+    UNREF_PENDING.with(|p| p.borrow().push(Box::pin(future)));
+    record_pending();
+}
+
+/// Updates the pending futures gauge, unref'd ones included
+fn record_pending() {
+    let pending = PENDING.with(|p| p.borrow().len()) + UNREF_PENDING.with(|p| p.borrow().len());
+    set_pending_futures(pending);
+}
+
+pub(crate) fn set_draining(draining: bool) {
+    DRAINING.with(|d| d.set(draining));
 }
 
 pub(crate) struct InternalPromise {
     pub(crate) promise: Box<Heap<*mut JSObject>>,
     pub(crate) global: Rc<Box<Heap<*mut JSObject>>>,
+    /// open from creation until the promise is settled (or abandoned)
+    pub(crate) span: Span,
+    pub(crate) created: Instant,
+    /// the host function which made it
+    pub(crate) source: &'static str,
+    pub(crate) realm: Option<u64>,
+    /// the JS stack when it was made, see `introspection::set_capture_stacks`
+    pub(crate) stack: Option<String>,
+    /// the caller's `SavedFrame`, null if it was made without script on the stack
+    pub(crate) async_parent: Box<Heap<*mut JSObject>>,
 }
 
-pub(crate) fn push_internal_promise(id: u64, promise: Box<Heap<*mut JSObject>>, global: Rc<Box<Heap<*mut JSObject>>>) {
-    FAKE_PENDING_PROMISES.with(|f| f.borrow_mut().insert(id, InternalPromise { promise, global }));
+pub(crate) fn push_internal_promise(id: u64, promise: InternalPromise) {
+    FAKE_PENDING_PROMISES.with(|f| f.borrow_mut().insert(id, promise));
+}
+
+/// Reports every promise waiting on a `Bridge` to the GC, see `roots`
+pub(crate) unsafe fn trace_pending_promises(trc: *mut JSTracer) {
+    FAKE_PENDING_PROMISES.with(|f| {
+        for promise in f.borrow().values() {
+            unsafe {
+                trace_object(trc, &promise.promise, c"pending promise");
+                trace_object(trc, &promise.global, c"pending promise global");
+                trace_object(trc, &promise.async_parent, c"pending promise async parent");
+            }
+        }
+    });
+}
+
+/// Every promise waiting on a `Bridge`, oldest first
+pub(crate) fn pending_promises() -> Vec<PendingPromise> {
+    let mut pending: Vec<PendingPromise> = FAKE_PENDING_PROMISES.with(|f| {
+        f.borrow().iter().map(|(id, p)| PendingPromise {
+            id: *id,
+            source: p.source,
+            realm: p.realm,
+            age: p.created.elapsed(),
+            stack: p.stack.as_deref().map(map_stack),
+        }).collect()
+    });
+    pending.sort_by(|a, b| b.age.cmp(&a.age));
+    pending
+}
+
+/// The lifecycle span of pending promise `id`, disabled once it is settled
+///
+/// Work done on behalf of the promise (the spawned future, the `Bridge`
+/// polling it) should be a child of this span.
+pub(crate) fn promise_span(id: u64) -> Span {
+    FAKE_PENDING_PROMISES.with(|f| f.borrow().get(&id).map(|p| p.span.clone())).unwrap_or_else(Span::none)
+}
+
+/// Creates a promise in the current realm for a `Bridge` to resolve
+///
+/// This is what every host function returning a promise does before
+/// spawning its future. The promise is registered under its id, which
+/// is returned along with the promise itself (the caller should
+/// root it & set it as the return value). `source` names the host
+/// function for tracing, the promise's span is a child of the current
+/// span & is closed once the promise is settled (see `promise_span`).
+pub(crate) fn new_pending_promise(ctx: *mut RawJSContext, source: &'static str) -> Option<(u64,*mut JSObject)> {
+    let mut safe_ctx = unsafe { JSContext::from_ptr(NonNull::new(ctx)?) };
+    rooted!(in(ctx) let promise = unsafe { mozjs::rust::wrappers2::NewPromiseObject(&mut safe_ctx, Handle::<'_,*mut JSObject>::null()) });
+    rooted!(in(ctx) let current_global = unsafe { mozjs::rust::wrappers2::CurrentGlobalOrNull(&safe_ctx)});
+    if current_global.get().is_null() {
+        error!("global is null");
+        return None;
+    }
+    if promise.get().is_null() {
+        error!("promise is null");
+        return None;
+    }
+    if !unsafe { mozjs::rust::wrappers2::IsPromiseObject(promise.handle()) } {
+        error!("promise is not a promise");
+        return None;
+    }
+    let promise_id = unsafe { mozjs::rust::wrappers2::GetPromiseID(promise.handle()) };
+    let realm = realm_id(ctx);
+    let span = debug_span!(
+        "promise",
+        promise_id,
+        realm,
+        source,
+        outcome = field::Empty,
+        elapsed_ms = field::Empty,
+    );
+    rooted!(in(ctx) let async_parent = capture_saved_frame(ctx));
+    let stack = match capture_stacks() {
+        true => stack_string(ctx, async_parent.handle()),
+        false => None,
+    };
+    push_internal_promise(promise_id, InternalPromise {
+        promise: Heap::boxed(promise.get()),
+        global: Rc::new(Heap::boxed(current_global.get())),
+        span,
+        created: Instant::now(),
+        source,
+        realm,
+        stack,
+        async_parent: Heap::boxed(async_parent.get()),
+    });
+    trace!("promise '{}' created", promise_id);
+    Some((promise_id, promise.get()))
 }
 pub fn futures_empty() -> bool {
     PENDING.with(|p| p.borrow().is_empty())
 }
 
-pub fn poll_futures() -> Vec<(u64,ResolutionMarshalling)> {
+/// Drops every pending future & the promises they would have resolved
+///
+/// The promises are left pending forever. Returns how many futures
+/// were dropped (unref'd ones included).
+pub(crate) fn cancel_pending_futures() -> usize {
+    let dropped = take_pending_futures().len()
+        + UNREF_PENDING.with(|p| std::mem::take(&mut *p.borrow_mut()).len());
+    record_pending();
+    FAKE_PENDING_PROMISES.with(|f| f.borrow_mut().clear());
+    dropped
+}
+
+/// Removes every pending future, leaving an empty set in place
+pub(crate) fn take_pending_futures() -> PendingFutures {
+    let taken = PENDING.with(|p| std::mem::take(&mut *p.borrow_mut()));
+    record_pending();
+    taken
+}
+
+/// Puts back a set from `take_pending_futures`, anything pushed since is kept
+pub(crate) fn restore_pending_futures(saved: PendingFutures) {
+    PENDING.with(|p| {
+        let mut p = p.borrow_mut();
+        let nested = std::mem::replace(&mut *p, saved);
+        p.extend(nested);
+    });
+    record_pending();
+}
+
+pub fn poll_futures() -> Vec<Macrotask> {
 
     // types get really funky so this is in its own place
-    fn poll_the_stream(pool: &mut FuturesUnordered<Pin<Box<dyn Future<Output=(u64,ResolutionMarshalling)> + 'static>>>) -> Vec<(u64,ResolutionMarshalling)> {
+    fn poll_the_stream(pool: &mut PendingFutures) -> Vec<Macrotask> {
         let handle = TokioHandle::current();
         let mut tasks = Vec::new();
         handle.block_on(poll_fn(|ctx: &mut Context<'_>| -> Poll<()> {
@@ -61,12 +257,12 @@ pub fn poll_futures() -> Vec<(u64,ResolutionMarshalling)> {
                 // so when we see a `Poll::Pending` we assume our Context/Waker
                 // is setup right and return what ever we've captured so far.
                 //
-                let pin: Pin<&mut FuturesUnordered<Pin<Box<dyn Future<Output=(u64,ResolutionMarshalling)> + 'static>>>> = Pin::new(pool);
+                let pin: Pin<&mut PendingFutures> = Pin::new(pool);
                 match pin.poll_next(ctx) {
                     Poll::Pending => return Poll::Ready(()),
                     Poll::Ready(None) => return Poll::Ready(()),
-                    Poll::Ready(Some((key,arg))) => {
-                        tasks.push((key,arg));
+                    Poll::Ready(Some(task)) => {
+                        tasks.push(task);
                         continue;
                     }
                 };
@@ -76,27 +272,78 @@ pub fn poll_futures() -> Vec<(u64,ResolutionMarshalling)> {
     }
 
 
-    PENDING.with(|p| poll_the_stream(&mut p.borrow_mut()))
+    let mut tasks = PENDING.with(|p| poll_the_stream(&mut p.borrow_mut()));
+    tasks.extend(UNREF_PENDING.with(|p| poll_the_stream(&mut p.borrow_mut())));
+    record_pending();
+    tasks
 }
 
+/// Blocks until a pending future completes or `deadline` passes
+///
+/// Returns the macrotask of every future which completed, nothing if
+/// the deadline passed first. Unref'd futures are waited on too, but
+/// only while there is a ref'd one, so this returns at once without.
+pub fn wait_for_futures(deadline: Option<Instant>) -> Vec<Macrotask> {
+    let handle = TokioHandle::current();
+    let tasks = PENDING.with(|p| UNREF_PENDING.with(|u| {
+        let (mut pending, mut unref) = (p.borrow_mut(), u.borrow_mut());
+        let completed = poll_fn(|ctx: &mut Context<'_>| -> Poll<Vec<Macrotask>> {
+            let mut tasks = Vec::new();
+            for pool in [&mut *pending, &mut *unref] {
+                while let Poll::Ready(Some(task)) = Pin::new(&mut *pool).poll_next(ctx) {
+                    tasks.push(task);
+                }
+            }
+            // woken by whichever future's waker fires first
+            if tasks.is_empty() && !pending.is_empty() { Poll::Pending } else { Poll::Ready(tasks) }
+        });
+        handle.block_on(async {
+            match deadline {
+                Some(deadline) => tokio::time::timeout_at(deadline.into(), completed).await.unwrap_or_default(),
+                None => completed.await,
+            }
+        })
+    }));
+    record_pending();
+    tasks
+}
+
+/// Settles promise `id` with what `lambda` makes of the result
+///
+/// Nothing happens if the promise was abandoned in the meantime, e.g.
+/// by `checkpoint::drain` after its result was queued.
 pub fn setup_to_resolve(ctx: &mut JSContext, id: u64, lambda: ResolutionMarshalling) {
-    let data: InternalPromise = FAKE_PENDING_PROMISES.with(|f| f.borrow_mut().remove(&id).unwrap());
+    let Some(data) = FAKE_PENDING_PROMISES.with(|f| f.borrow_mut().remove(&id)) else {
+        debug!("promise '{}' was abandoned, leaving it pending", id);
+        return;
+    };
+    let _entered = trace_span!(parent: &data.span, "setup_to_resolve", promise_id = id).entered();
 
     rooted!(in(unsafe { ctx.raw_cx() }) let global = data.global.get());
     rooted!(in(unsafe { ctx.raw_cx() }) let promise = data.promise.get());
+    rooted!(in(unsafe { ctx.raw_cx() }) let async_parent = data.async_parent.get());
     rooted!(in(unsafe { ctx.raw_cx() }) let mut ok = UndefinedValue());
     rooted!(in(unsafe { ctx.raw_cx() }) let mut err = UndefinedValue());
     
     enter_incumbent_stack(ctx, global.handle(), |realm,global| {
         //let mut realm = AutoRealm::new(ctx, NonNull::new(global.handle().get()).unwrap());
         //let (global, realm) = realm.global_and_reborrow();
-        (lambda)(realm, promise.handle(), global, ok.handle_mut(), err.handle_mut());
-        if !err.is_undefined() {
-            unsafe { mozjs::rust::wrappers2::RejectPromise(realm, promise.handle(), err.handle()) };
-        } else {
-            unsafe { mozjs::rust::wrappers2::ResolvePromise(realm, promise.handle(), ok.handle()) };
-        }
+        let cx = unsafe { realm.deref_mut().raw_cx() };
+        // reactions queued by settling continue the caller's stack
+        with_async_parent(cx, async_parent.handle(), c"host promise", || {
+            (lambda)(realm, promise.handle(), global, ok.handle_mut(), err.handle_mut());
+            if !err.is_undefined() {
+                data.span.record("outcome", "rejected");
+                attach_async_stack(cx, err.handle(), async_parent.handle());
+                unsafe { mozjs::rust::wrappers2::RejectPromise(realm, promise.handle(), err.handle()) };
+            } else {
+                data.span.record("outcome", "resolved");
+                unsafe { mozjs::rust::wrappers2::ResolvePromise(realm, promise.handle(), ok.handle()) };
+            }
+        });
     });
+    data.span.record("elapsed_ms", data.created.elapsed().as_secs_f64() * 1000.0);
+    trace!("promise '{}' settled", id);
 }
 
 
@@ -142,6 +389,10 @@ pub fn setup_to_resolve(ctx: &mut JSContext, id: u64, lambda: ResolutionMarshall
 pub struct Bridge<R> {
     pub internal: Pin<Box<dyn Future<Output=(u64,R)> + Send + 'static>>,
     pub bridge: fn(R) -> ResolutionMarshalling,
+    /// entered while polling, a child of whatever was current in `new`
+    span: Span,
+    polls: u64,
+    created: Instant,
     // ensure this type cannot be sent between threads
     _marker: PhantomData<Rc<()>>,
 }
@@ -149,6 +400,9 @@ impl<R> Bridge<R>
 where
     R: Send + 'static,
 {
+    ///
+    /// Call this within the promise's span (see `promise_span`) so the
+    /// polling shows up under it.
     pub(crate) fn new<F>(future: F, bridge: fn(R) -> ResolutionMarshalling)
     where
         F: Future<Output=(u64,R)> + Send + 'static,
@@ -156,12 +410,92 @@ where
         let b = Bridge {
             internal: Box::pin(future),
             bridge,
+            span: trace_span!("bridge", polls = field::Empty, elapsed_ms = field::Empty),
+            polls: 0,
+            created: Instant::now(),
             _marker: PhantomData,
         };
-        PENDING.with(|p| p.borrow().push(Box::pin(b)));
+        push_task_source(async move {
+            let (id, lambda) = b.await;
+            let completed = Instant::now();
+            Macrotask::new("bridge", move |ctx: &mut JSContext| {
+                setup_to_resolve(ctx, id, lambda);
+                observe_resolution_latency(completed.elapsed());
+            })
+        });
     }
 }
 
+/// Spawns `future` onto tokio, promise `id` is resolved with its output by way of `bridge`
+///
+/// Shorthand for what most host functions do after `new_pending_promise`.
+/// The future & the `Bridge` both run within the promise's span. If
+/// the task panics (or tokio cancels it) the promise is rejected.
+pub(crate) fn spawn_bridged<R,F>(id: u64, future: F, bridge: fn(R) -> ResolutionMarshalling)
+where
+    R: Send + 'static,
+    F: Future<Output=R> + Send + 'static,
+{
+    let span = promise_span(id);
+    let task = tokio::task::spawn(future.instrument(span.clone()));
+    span.in_scope(|| Bridge::new(async move { (id, (task.await, bridge)) }, bridge_joined::<R>));
+}
+
+/// `bridge` for a task which finished, a rejection for one which didn't
+fn bridge_joined<R>((joined, bridge): (Result<R,JoinError>, fn(R) -> ResolutionMarshalling)) -> ResolutionMarshalling {
+    match joined {
+        Ok(result) => bridge(result),
+        Err(e) => {
+            error!("promise task failed: {}", e);
+            let message = format!("internal error: {}", e);
+            Box::new(move |realm: &mut AutoRealm, _promise: Handle<'_,*mut JSObject>, _global: Handle<'_,*mut JSObject>, _ok: MutableHandle<'_,Value>, err: MutableHandle<'_,Value>| {
+                new_error(unsafe { realm.deref_mut().raw_cx() }, &message, None, err);
+            }) as ResolutionMarshalling
+        }
+    }
+}
+
+/// Shared body of host functions which return a bridged promise
+///
+/// `op` validates the arguments & returns the future to run, or the
+/// message of a `TypeError` to throw (prefixed with `name`). The
+/// future's output is handed to `bridge` to settle the promise.
+pub(crate) fn promise_entry_point<R,F,Fut>(
+    ctx: *mut RawJSContext,
+    argc: u32,
+    vp: *mut Value,
+    name: &'static str,
+    bridge: fn(R) -> ResolutionMarshalling,
+    op: F,
+) -> bool
+where
+    R: Send + 'static,
+    F: FnOnce(*mut RawJSContext, &CallArgs) -> Result<Fut,&'static str>,
+    Fut: Future<Output=R> + Send + 'static,
+{
+    let mut is_okay = true;
+    let mut op = Some(op);
+    let _label = host_label(name);
+    wrap_panic(&mut || {
+        let args = unsafe { CallArgs::from_vp(vp, argc) };
+        let future = match (op.take().unwrap())(ctx, &args) {
+            Ok(future) => future,
+            Err(msg) => {
+                unsafe { throw_type_error(ctx, &format!("{}: {}", name, msg)) };
+                is_okay = false;
+                return;
+            }
+        };
+        let Some((promise_id, promise)) = new_pending_promise(ctx, name) else {
+            is_okay = false;
+            return;
+        };
+        args.rval().set(ObjectValue(promise));
+        spawn_bridged(promise_id, future, bridge);
+    });
+    is_okay
+}
+
 /// Converts the `R` returned by `Bridge` into a resolvable promise
 pub type ResolutionMarshalling = Box<dyn 'static + for<'a> FnOnce(&mut AutoRealm,Handle<'a,*mut JSObject>,Handle<'a,*mut JSObject>,MutableHandle<'a,Value>,MutableHandle<'a,Value>)>;
 
@@ -172,11 +506,77 @@ where
     type Output = (u64,ResolutionMarshalling);
     fn poll(self: Pin<&mut Self>, ctx: &mut std::task::Context<'_>) -> Poll<Self::Output> {
         let this: &mut Self = self.get_mut();
+        let _entered = this.span.enter();
+        this.polls += 1;
+        this.span.record("polls", this.polls);
         let (id, result): (u64, R) = match this.internal.as_mut().poll(ctx) {
             Poll::Pending => return Poll::Pending,
             Poll::Ready(tup) => tup,
         };
+        this.span.record("elapsed_ms", this.created.elapsed().as_secs_f64() * 1000.0);
+        trace!("bridge for promise '{}' ready", id);
         let lambda = (this.bridge)(result);
         Poll::Ready((id,lambda))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::{
+        conversions::{define_property},
+        testing::{with_runtime,new_test_global,eval_async,capture},
+    };
+    use crate::realm::{realm_id_of};
+
+    fn bridge_unit(_: ()) -> ResolutionMarshalling {
+        Box::new(move |_realm: &mut AutoRealm, _promise: Handle<'_, *mut JSObject>, _global: Handle<'_, *mut JSObject>, mut ok: MutableHandle<'_,Value>, _err: MutableHandle<'_,Value>| {
+            ok.set(UndefinedValue());
+        }) as ResolutionMarshalling
+    }
+
+    #[test]
+    fn a_panicking_task_rejects_its_promise() {
+        with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            enter_incumbent_stack(ctx, global.handle(), |realm: &mut AutoRealm, global_obj: Handle<'_,*mut JSObject>| {
+                let cx = unsafe { realm.deref_mut().raw_cx() };
+                let (id, promise) = new_pending_promise(cx, "test").unwrap();
+                rooted!(in(cx) let promise = ObjectValue(promise));
+                define_property(cx, global_obj, c"promise", promise.handle());
+                spawn_bridged(id, async { panic!("boom") }, bridge_unit);
+            });
+            let outcome = eval_async(ctx, global.handle(), "try { await promise; return 'resolved'; } catch (e) { return e.message; }");
+            assert!(outcome.as_deref().is_ok_and(|message| message.contains("panicked")), "{:?}", outcome);
+        });
+    }
+
+    #[test]
+    fn a_settled_promise_closes_its_span() {
+        let (realm, captured) = capture(|| with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            let slept = eval_async(ctx, global.handle(), "await sleep_ms(1); return 'slept';");
+            assert_eq!(slept.as_deref(), Ok("slept"));
+            realm_id_of(global.get())
+        }));
+        let promise = captured.spans_named("promise").find(|s| s.fields["source"] == "sleep_ms").unwrap();
+        assert_eq!(promise.parent, Some("tokio_sleep_entry_point"));
+        assert_eq!(promise.fields["realm"], realm.unwrap().to_string());
+        assert_eq!(promise.fields["outcome"], "resolved");
+        assert!(promise.fields.contains_key("elapsed_ms"));
+        // polled & settled on its behalf
+        let bridge = captured.spans_named("bridge").find(|s| s.parent == Some("promise")).unwrap();
+        assert!(bridge.fields.contains_key("polls"));
+        assert!(captured.spans_named("setup_to_resolve").any(|s| s.parent == Some("promise")));
+    }
+
+    #[test]
+    fn a_rejected_promise_records_its_outcome() {
+        let (_, captured) = capture(|| with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            eval_async(ctx, global.handle(), "await fs.readFile('/does/not/exist'); return 'read';")
+        }));
+        let promise = captured.spans_named("promise").find(|s| s.fields["source"] == "fs.readFile").unwrap();
+        assert_eq!(promise.fields["outcome"], "rejected");
+    }
+}
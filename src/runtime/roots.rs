@@ -0,0 +1,119 @@
+use std::{
+    cell::{RefCell},
+    collections::{HashSet},
+    ffi::{c_void,CStr},
+    ops::{Deref},
+};
+use mozjs::{
+    context::{JSContext},
+    jsapi::{Heap,JSObject,JSTracer,JS_AddExtraGCRootsTracer},
+    glue::{CallObjectTracer},
+    panic::{wrap_panic},
+};
+#[allow(unused_imports)] use tracing::{trace,debug,info,warn,error,instrument};
+
+use super::{
+    js_callback::{trace_callbacks},
+    queue::{trace_queues},
+    resolvable_promise::{trace_pending_promises},
+};
+use crate::{
+    message_channel::{trace_ports},
+    realm::{trace_realms},
+    signal::{trace_signal_handlers},
+    streams::{trace_byte_stream_hooks},
+};
+
+/*
+ * Thread local roots
+ *
+ * The GC can't see a `Heap` kept in a thread local, whatever it points
+ * at is collected (or moved) unless something else keeps it alive. So
+ * every thread local store of `Heap`s has a tracer, which reports its
+ * entries as roots for as long as they're stored. `install_root_tracer`
+ * hands them all to a runtime's GC.
+ *
+ * A `Heap` which isn't in a store, e.g. one owned by a pending future,
+ * can be a `HeapRoot` instead: it's a root until it is dropped.
+ *
+ */
+
+thread_local! {
+    /// The address of every live `HeapRoot` on this thread
+    static HEAP_ROOTS: RefCell<HashSet<*const Heap<*mut JSObject>>> = RefCell::new(HashSet::new());
+}
+
+/// Every thread local store of `Heap`s
+const TRACERS: &[unsafe fn(*mut JSTracer)] = &[
+    trace_heap_roots,
+    trace_queues,
+    trace_callbacks,
+    trace_pending_promises,
+    trace_ports,
+    trace_signal_handlers,
+    trace_byte_stream_hooks,
+    trace_realms,
+];
+
+unsafe extern "C" fn trace_roots(trc: *mut JSTracer, _: *mut c_void) {
+    wrap_panic(&mut || {
+        for tracer in TRACERS {
+            unsafe { tracer(trc) };
+        }
+    });
+}
+
+/// Roots the thread local stores for every GC of the context's runtime
+///
+/// Call once per runtime, before anything is stored.
+pub fn install_root_tracer(ctx: &mut JSContext) {
+    if !unsafe { JS_AddExtraGCRootsTracer(ctx.raw_cx(), Some(trace_roots), std::ptr::null_mut()) } {
+        error!("could not install the thread local roots tracer");
+    }
+}
+
+/// Reports `heap` as a root, updating it if the object is moved
+///
+/// # Safety
+///
+/// Only from within a tracer, `heap` must not move until it's done.
+pub(crate) unsafe fn trace_object(trc: *mut JSTracer, heap: &Heap<*mut JSObject>, name: &CStr) {
+    if heap.get().is_null() {
+        return;
+    }
+    unsafe { CallObjectTracer(trc, heap as *const Heap<*mut JSObject> as *mut Heap<*mut JSObject>, name.as_ptr()) };
+}
+
+/// A `Heap` that is a GC root for as long as it's alive
+///
+/// Must be dropped on the thread it was made on.
+pub struct HeapRoot {
+    heap: Box<Heap<*mut JSObject>>,
+}
+impl HeapRoot {
+    pub fn new(obj: *mut JSObject) -> Self {
+        let heap = Heap::boxed(obj);
+        HEAP_ROOTS.with(|r| r.borrow_mut().insert(&*heap as *const Heap<*mut JSObject>));
+        HeapRoot { heap }
+    }
+}
+impl Deref for HeapRoot {
+    type Target = Heap<*mut JSObject>;
+    fn deref(&self) -> &Heap<*mut JSObject> {
+        &self.heap
+    }
+}
+impl Drop for HeapRoot {
+    fn drop(&mut self) {
+        // nothing is traced once the thread is being torn down
+        let _ = HEAP_ROOTS.try_with(|r| r.borrow_mut().remove(&(&*self.heap as *const Heap<*mut JSObject>)));
+    }
+}
+
+unsafe fn trace_heap_roots(trc: *mut JSTracer) {
+    HEAP_ROOTS.with(|r| {
+        for heap in r.borrow().iter() {
+            unsafe { trace_object(trc, &**heap, c"heap root") };
+        }
+    });
+}
@@ -0,0 +1,403 @@
+use std::{
+    collections::{BTreeMap},
+    path::{Path},
+    sync::{Arc,RwLock,LazyLock},
+};
+use mozjs::{rooted};
+use mozjs::{
+    context::{RawJSContext},
+    jsapi::{JS_ClearPendingException},
+    jsval::{UndefinedValue},
+    rust::wrappers::{JS_ParseJSON},
+};
+#[allow(unused_imports)] use tracing::{trace,debug,info,warn,error,instrument};
+
+use super::{
+    conversions::{get_option,option_string,value_to_strings},
+};
+
+/*
+ * Source maps
+ *
+ * `realm::evaluate` hands every script to `register_source`, which
+ * looks for a trailing `//# sourceMappingURL=` comment & loads the
+ * map it points at: an inline `data:` URL or a file relative to the
+ * script. Maps are shared by every thread, keyed by the filename the
+ * script was compiled with.
+ *
+ * `map_stack` rewrites each `name@file:line:column` frame of a
+ * SpiderMonkey stack which falls within a mapped script, every error
+ * the runtime reports goes through it (see `exception`). Only version
+ * 3 maps without `sections` are understood.
+ *
+ */
+
+static SOURCE_MAPS: LazyLock<RwLock<BTreeMap<String,Arc<SourceMap>>>> = LazyLock::new(|| RwLock::new(BTreeMap::new()));
+
+/// One decoded segment, everything zero based
+#[derive(Clone,Copy,Debug)]
+struct Mapping {
+    column: u32,
+    source: u32,
+    line: u32,
+    original_column: u32,
+    name: Option<u32>,
+}
+
+#[derive(Debug)]
+pub struct SourceMap {
+    sources: Vec<String>,
+    names: Vec<String>,
+    /// per generated line, ordered by column
+    lines: Vec<Vec<Mapping>>,
+}
+
+/// Where a generated position came from, `line` & `column` are one based
+#[derive(Clone,Debug,PartialEq)]
+pub struct OriginalPosition {
+    pub source: String,
+    pub line: u32,
+    pub column: u32,
+    pub name: Option<String>,
+}
+
+impl SourceMap {
+    /// Decodes `mappings`, `sources` are already joined with the `sourceRoot`
+    pub fn new(sources: Vec<String>, names: Vec<String>, mappings: &str) -> Option<Self> {
+        let mut lines = Vec::new();
+        // everything but the generated column carries over between lines
+        let (mut source, mut line, mut original_column, mut name) = (0i64, 0i64, 0i64, 0i64);
+        for encoded_line in mappings.split(';') {
+            let mut column = 0i64;
+            let mut segments = Vec::new();
+            for segment in encoded_line.split(',').filter(|s| !s.is_empty()) {
+                let fields = decode_vlq(segment)?;
+                column += *fields.first()?;
+                if fields.len() < 4 {
+                    // a generated position without an original one
+                    continue;
+                }
+                source += fields[1];
+                line += fields[2];
+                original_column += fields[3];
+                let mapped_name = match fields.get(4) {
+                    Some(delta) => {
+                        name += delta;
+                        Some(u32::try_from(name).ok()?)
+                    }
+                    None => None,
+                };
+                segments.push(Mapping {
+                    column: u32::try_from(column).ok()?,
+                    source: u32::try_from(source).ok()?,
+                    line: u32::try_from(line).ok()?,
+                    original_column: u32::try_from(original_column).ok()?,
+                    name: mapped_name,
+                });
+            }
+            segments.sort_by_key(|m| m.column);
+            lines.push(segments);
+        }
+        Some(SourceMap { sources, names, lines })
+    }
+
+    /// Maps a one based generated position, `None` when nothing covers it
+    pub fn lookup(&self, line: u32, column: u32) -> Option<OriginalPosition> {
+        let segments = self.lines.get(line.checked_sub(1)? as usize)?;
+        let column = column.saturating_sub(1);
+        let index = segments.partition_point(|m| m.column <= column).checked_sub(1)?;
+        let mapping = segments[index];
+        Some(OriginalPosition {
+            source: self.sources.get(mapping.source as usize)?.clone(),
+            line: mapping.line + 1,
+            column: mapping.original_column + 1,
+            name: mapping.name.and_then(|n| self.names.get(n as usize).cloned()),
+        })
+    }
+}
+
+/// The fields of one segment
+fn decode_vlq(segment: &str) -> Option<Vec<i64>> {
+    let mut fields = Vec::with_capacity(5);
+    let (mut value, mut shift) = (0i64, 0u32);
+    for c in segment.bytes() {
+        let digit = base64_value(c)? as i64;
+        if shift > 60 {
+            return None;
+        }
+        value |= (digit & 0b11111) << shift;
+        if digit & 0b100000 != 0 {
+            shift += 5;
+            continue;
+        }
+        fields.push(if value & 1 == 1 { -(value >> 1) } else { value >> 1 });
+        value = 0;
+        shift = 0;
+    }
+    (shift == 0 && !fields.is_empty()).then_some(fields)
+}
+
+fn base64_value(c: u8) -> Option<u8> {
+    match c {
+        b'A'..=b'Z' => Some(c - b'A'),
+        b'a'..=b'z' => Some(c - b'a' + 26),
+        b'0'..=b'9' => Some(c - b'0' + 52),
+        b'+' | b'-' => Some(62),
+        b'/' | b'_' => Some(63),
+        _ => None,
+    }
+}
+
+fn decode_base64(encoded: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(encoded.len() * 3 / 4);
+    let (mut bits, mut count) = (0u32, 0u32);
+    for c in encoded.bytes().filter(|c| !c.is_ascii_whitespace() && *c != b'=') {
+        bits = (bits << 6) | base64_value(c)? as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+        }
+    }
+    Some(out)
+}
+
+fn decode_percent(encoded: &str) -> Vec<u8> {
+    let bytes = encoded.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(b) => {
+                out.push(b);
+                i += 3;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+/// The URL of the last `//# sourceMappingURL=` comment, if it ends the script
+fn source_mapping_url(source: &str) -> Option<&str> {
+    for line in source.lines().rev() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let url = line.strip_prefix("//# sourceMappingURL=")
+            .or_else(|| line.strip_prefix("//@ sourceMappingURL="))?;
+        return Some(url.trim()).filter(|u| !u.is_empty());
+    }
+    None
+}
+
+/// The JSON of the map at `url`, relative to the script `filename`
+fn load_map(filename: &str, url: &str) -> Option<String> {
+    if let Some(data) = url.strip_prefix("data:") {
+        let (meta, payload) = data.split_once(',')?;
+        let bytes = match meta.ends_with(";base64") {
+            true => decode_base64(payload)?,
+            false => decode_percent(payload),
+        };
+        return String::from_utf8(bytes).ok();
+    }
+    let path = url.strip_prefix("file://").unwrap_or(url);
+    let path = match Path::new(filename).parent() {
+        Some(dir) if !Path::new(path).is_absolute() => dir.join(path),
+        _ => Path::new(path).to_path_buf(),
+    };
+    match std::fs::read_to_string(&path) {
+        Ok(json) => Some(json),
+        Err(e) => {
+            warn!("could not read source map '{}' for '{}': {}", path.display(), filename, e);
+            None
+        }
+    }
+}
+
+/// Loads the source map `source` refers to, if any, for stacks from `filename`
+///
+/// `cx` must be within a realm, the map is parsed with its `JSON`.
+/// Scripts without a map (or with one that can't be loaded) are left
+/// unmapped.
+pub fn register_source(cx: *mut RawJSContext, filename: &str, source: &str) {
+    let Some(url) = source_mapping_url(source) else {
+        SOURCE_MAPS.write().unwrap().remove(filename);
+        return;
+    };
+    let Some(json) = load_map(filename, url) else {
+        return;
+    };
+    let Some(map) = parse_map(cx, filename, &json) else {
+        warn!("ignoring the source map of '{}', it could not be parsed", filename);
+        return;
+    };
+    debug!("loaded a source map for '{}' with '{}' sources", filename, map.sources.len());
+    SOURCE_MAPS.write().unwrap().insert(filename.to_string(), Arc::new(map));
+}
+
+fn parse_map(cx: *mut RawJSContext, filename: &str, json: &str) -> Option<SourceMap> {
+    let chars: Vec<u16> = json.encode_utf16().collect();
+    rooted!(in(cx) let mut parsed = UndefinedValue());
+    if !unsafe { JS_ParseJSON(cx, chars.as_ptr(), chars.len() as u32, parsed.handle_mut()) } {
+        unsafe { JS_ClearPendingException(cx) };
+        return None;
+    }
+    if !parsed.is_object() {
+        return None;
+    }
+    let mappings = option_string(cx, parsed.handle(), c"mappings")?;
+    let source_root = option_string(cx, parsed.handle(), c"sourceRoot").unwrap_or_default();
+    rooted!(in(cx) let mut list = UndefinedValue());
+    get_option(cx, parsed.handle(), c"sources", list.handle_mut());
+    let sources = value_to_strings(cx, list.handle())?;
+    get_option(cx, parsed.handle(), c"names", list.handle_mut());
+    let names = value_to_strings(cx, list.handle()).unwrap_or_default();
+    let dir = Path::new(filename).parent();
+    let sources = sources.into_iter().map(|s| resolve_source(dir, &source_root, &s)).collect();
+    SourceMap::new(sources, names, &mappings)
+}
+
+/// A source relative to the `sourceRoot`, then to the script's directory
+fn resolve_source(dir: Option<&Path>, source_root: &str, source: &str) -> String {
+    if source.contains("://") || Path::new(source).is_absolute() {
+        return source.to_string();
+    }
+    let joined = match source_root {
+        "" => source.to_string(),
+        root if root.ends_with('/') => format!("{}{}", root, source),
+        root => format!("{}/{}", root, source),
+    };
+    match dir {
+        Some(dir) if !dir.as_os_str().is_empty() && !joined.contains("://") && !Path::new(&joined).is_absolute() => {
+            dir.join(joined).to_string_lossy().into_owned()
+        }
+        _ => joined,
+    }
+}
+
+/// Where `file:line:column` (one based) came from, `None` for unmapped scripts
+pub fn lookup(file: &str, line: u32, column: u32) -> Option<OriginalPosition> {
+    let map = SOURCE_MAPS.read().unwrap().get(file).cloned()?;
+    map.lookup(line, column)
+}
+
+/// Rewrites every frame of a SpiderMonkey stack which falls within a mapped script
+pub fn map_stack(stack: &str) -> String {
+    if SOURCE_MAPS.read().unwrap().is_empty() {
+        return stack.to_string();
+    }
+    let mut out = String::with_capacity(stack.len());
+    for (i, frame) in stack.split('\n').enumerate() {
+        if i > 0 {
+            out.push('\n');
+        }
+        match map_frame(frame) {
+            Some(mapped) => out.push_str(&mapped),
+            None => out.push_str(frame),
+        }
+    }
+    out
+}
+
+/// `[cause*]name@file:line:column`
+fn map_frame(frame: &str) -> Option<String> {
+    let (name, location) = frame.split_once('@')?;
+    let mut parts = location.rsplitn(3, ':');
+    let column: u32 = parts.next()?.parse().ok()?;
+    let line: u32 = parts.next()?.parse().ok()?;
+    let file = parts.next()?;
+    let original = lookup(file, line, column)?;
+    let name = match (name.rsplit_once('*'), &original.name) {
+        // an async cause keeps its prefix
+        (Some((cause, "")), Some(mapped)) => format!("{}*{}", cause, mapped),
+        (None, Some(mapped)) if name.is_empty() => mapped.clone(),
+        _ => name.to_string(),
+    };
+    Some(format!("{}@{}:{}:{}", name, original.source, original.line, original.column))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::{DerefMut};
+    use mozjs::{
+        context::{JSContext},
+        gc::{Handle},
+        jsapi::{JSObject},
+        realm::{AutoRealm},
+    };
+    use super::*;
+    use crate::runtime::{
+        incumbent_stack::{enter_incumbent_stack},
+        testing::{with_runtime,new_test_global},
+    };
+
+    /// `a.ts` line 1 at columns 0 & 5 (the latter named `run`), then line 2
+    fn map() -> SourceMap {
+        SourceMap::new(vec!["a.ts".to_string()], vec!["run".to_string()], "AAAA,KAAKA;AACL").unwrap()
+    }
+
+    #[test]
+    fn vlq_fields_decode() {
+        assert_eq!(decode_vlq("AAAA"), Some(vec![0, 0, 0, 0]));
+        assert_eq!(decode_vlq("D"), Some(vec![-1]));
+        assert_eq!(decode_vlq("2H"), Some(vec![123]));
+        // a continuation without an end
+        assert_eq!(decode_vlq("g"), None);
+        assert_eq!(decode_vlq("A!"), None);
+    }
+
+    #[test]
+    fn positions_map_to_the_segment_before_them() {
+        let map = map();
+        let at = |line, column| map.lookup(line, column).map(|p| (p.source, p.line, p.column, p.name));
+        assert_eq!(at(1, 1), Some(("a.ts".to_string(), 1, 1, None)));
+        assert_eq!(at(1, 9), Some(("a.ts".to_string(), 1, 6, Some("run".to_string()))));
+        assert_eq!(at(2, 4), Some(("a.ts".to_string(), 2, 1, None)));
+        assert_eq!(at(3, 1), None);
+    }
+
+    #[test]
+    fn mapped_frames_are_rewritten() {
+        SOURCE_MAPS.write().unwrap().insert("map_stack_test.js".to_string(), Arc::new(map()));
+        let stack = "@map_stack_test.js:1:9\nouter@map_stack_test.js:2:1\nother@elsewhere.js:1:1";
+        assert_eq!(map_stack(stack), "run@a.ts:1:6\nouter@a.ts:2:1\nother@elsewhere.js:1:1");
+    }
+
+    #[test]
+    fn mapping_urls_are_found_at_the_end() {
+        assert_eq!(source_mapping_url("x;\n//# sourceMappingURL=x.js.map\n\n"), Some("x.js.map"));
+        assert_eq!(source_mapping_url("//# sourceMappingURL=x.js.map\nx;"), None);
+        assert_eq!(decode_base64("aGVsbG8="), Some(b"hello".to_vec()));
+        assert_eq!(decode_percent("a%20b%zz"), b"a b%zz");
+    }
+
+    #[test]
+    fn sources_resolve_against_the_root_and_script() {
+        assert_eq!(resolve_source(Some(Path::new("dist")), "src", "a.ts"), "dist/src/a.ts");
+        assert_eq!(resolve_source(Some(Path::new("dist")), "", "/abs/a.ts"), "/abs/a.ts");
+        assert_eq!(resolve_source(None, "https://example.com/", "a.ts"), "https://example.com/a.ts");
+    }
+
+    #[test]
+    fn inline_maps_are_registered() {
+        with_runtime(|ctx: &mut JSContext| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            enter_incumbent_stack(ctx, global.handle(), |realm: &mut AutoRealm, _: Handle<'_,*mut JSObject>| {
+                let cx = unsafe { realm.deref_mut().raw_cx() };
+                let source = r#"f();
+//# sourceMappingURL=data:application/json,{"version":3,"sources":["inline.ts"],"names":[],"mappings":"AAAA"}"#;
+                register_source(cx, "inline_map_test.js", source);
+            });
+        });
+        let mapped = lookup("inline_map_test.js", 1, 1).unwrap();
+        assert_eq!((mapped.source.as_str(), mapped.line, mapped.column), ("inline.ts", 1, 1));
+    }
+}
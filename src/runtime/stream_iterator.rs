@@ -0,0 +1,273 @@
+use std::{
+    ops::{DerefMut},
+    pin::{Pin},
+    ptr::{NonNull},
+    sync::{Arc},
+};
+use tokio::{
+    sync::{Mutex,watch},
+};
+use futures_util::{
+    stream::{Stream,StreamExt},
+};
+use mozjs::{rooted};
+use mozjs::{
+    context::{JSContext,RawJSContext},
+    gc::{Handle,MutableHandle},
+    jsapi::{JSObject,Value,CallArgs},
+    jsval::{UndefinedValue},
+    realm::{AutoRealm},
+    rust::wrappers2::JS_DefineFunction,
+};
+#[allow(unused_imports)] use tracing::{trace,debug,info,warn,error,instrument};
+
+use super::{
+    conversions::{define_async_iterator,new_iter_result},
+    host_object::{new_host_object,host_object_data,take_host_object_data},
+    resolvable_promise::{ResolutionMarshalling,promise_entry_point},
+};
+
+/*
+ * Rust streams as JS async iterators
+ *
+ * `Bridge` settles one promise with one value, `new_stream_iterator`
+ * does the same for every item of a `Stream`. Each `next()` is a
+ * bridged promise settled in the iterator's realm by the stream's
+ * next item, marshalled by the same kind of `bridge` function a
+ * `Bridge` takes & wrapped in `{ value, done }`:
+ *
+ *  - the end of the stream is `done`
+ *  - a `bridge` which rejects rejects that `next()`, the stream is
+ *    still there for the one after
+ *  - `return()` drops the stream, a pending `next()` is `done`
+ *
+ * Items are pulled in order, a `next()` made while another is
+ * pending waits for it.
+ *
+ */
+
+/// An item with the `bridge` for it, so `next` needn't be generic
+type Deferred = Box<dyn FnOnce() -> ResolutionMarshalling + Send>;
+type ErasedStream = Pin<Box<dyn Stream<Item=Deferred> + Send>>;
+
+/// Owned by an iterator object
+struct StreamIterator {
+    stream: Arc<Mutex<Option<ErasedStream>>>,
+    /// flipped by `return()`, wakes a pending `next()`
+    returned: watch::Sender<bool>,
+}
+impl Drop for StreamIterator {
+    fn drop(&mut self) {
+        self.returned.send_replace(true);
+    }
+}
+
+/// Creates an async iterator over `stream` in the current realm, null on failure
+///
+/// Each item is marshalled by `bridge` once its `next()` is settled.
+pub(crate) fn new_stream_iterator<S,R>(cx: *mut RawJSContext, stream: S, bridge: fn(R) -> ResolutionMarshalling) -> *mut JSObject
+where
+    S: Stream<Item=R> + Send + 'static,
+    R: Send + 'static,
+{
+    let erased: ErasedStream = Box::pin(stream.map(move |item| Box::new(move || bridge(item)) as Deferred));
+    let iterator = StreamIterator {
+        stream: Arc::new(Mutex::new(Some(erased))),
+        returned: watch::channel(false).0,
+    };
+    rooted!(in(cx) let obj = new_host_object(cx, iterator));
+    if obj.get().is_null() {
+        return obj.get();
+    }
+    let mut safe_ctx = unsafe { JSContext::from_ptr(NonNull::new(cx).unwrap()) };
+    unsafe {
+        JS_DefineFunction(&mut safe_ctx, obj.handle(), c"next".as_ptr(), Some(iterator_next), 0, 0);
+        JS_DefineFunction(&mut safe_ctx, obj.handle(), c"return".as_ptr(), Some(iterator_return), 1, 0);
+    }
+    if !define_async_iterator(cx, obj.handle()) {
+        return std::ptr::null_mut();
+    }
+    obj.get()
+}
+
+fn this_iterator<'a>(args: &CallArgs) -> Option<&'a mut StreamIterator> {
+    let this = args.thisv();
+    if !this.is_object() {
+        return None;
+    }
+    unsafe { host_object_data::<StreamIterator>(this.to_object()) }
+}
+
+/// `iterator.next()`, `done` once the stream ends or `return()` is called
+unsafe extern "C" fn iterator_next(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    promise_entry_point(ctx, argc, vp, "next", bridge_next, |_ctx, args| {
+        let state = this_iterator(args).map(|i| (i.stream.clone(), i.returned.subscribe()));
+        Ok(async move {
+            let (stream, mut returned) = state?;
+            let mut guard = tokio::select! {
+                guard = stream.lock() => guard,
+                _ = returned.wait_for(|r| *r) => return None,
+            };
+            let item = tokio::select! {
+                item = async { guard.as_mut()?.next().await } => item,
+                _ = returned.wait_for(|r| *r) => None,
+            };
+            if item.is_none() {
+                // ended, or returned while we waited, either way it's finished with
+                *guard = None;
+            }
+            item
+        })
+    })
+}
+
+/// `iterator.return()`, drops the stream once any pending `next()` lets go of it
+unsafe extern "C" fn iterator_return(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    promise_entry_point(ctx, argc, vp, "return", bridge_next, |_ctx, args| {
+        if args.thisv().is_object() {
+            unsafe { take_host_object_data(args.thisv().to_object()) };
+        }
+        Ok(async { None })
+    })
+}
+
+/// Settles a `next()` with `{ value, done }`, or rejects it if the item's `bridge` does
+fn bridge_next(item: Option<Deferred>) -> ResolutionMarshalling {
+    Box::new(move |realm: &mut AutoRealm, promise: Handle<'_, *mut JSObject>, global: Handle<'_, *mut JSObject>, ok: MutableHandle<'_,Value>, mut err: MutableHandle<'_,Value>| {
+        let cx = unsafe { realm.deref_mut().raw_cx() };
+        let Some(deferred) = item else {
+            new_iter_result(cx, Handle::undefined(), true, ok);
+            return;
+        };
+        rooted!(in(cx) let mut value = UndefinedValue());
+        rooted!(in(cx) let mut item_err = UndefinedValue());
+        (deferred())(realm, promise, global, value.handle_mut(), item_err.handle_mut());
+        if !item_err.is_undefined() {
+            err.set(item_err.get());
+            return;
+        }
+        new_iter_result(cx, value.handle(), false, ok);
+    }) as ResolutionMarshalling
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool,Ordering};
+    use futures_util::stream;
+    use mozjs::jsval::{Int32Value,ObjectValue};
+    use super::*;
+    use crate::runtime::{
+        conversions::{new_error,define_property},
+        incumbent_stack::{enter_incumbent_stack},
+        testing::{with_runtime,new_test_global,eval,eval_async,gc},
+    };
+
+    /// Flags the stream it's moved into as dropped
+    struct DropFlag(Arc<AtomicBool>);
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn tracked<S: Stream + Send + 'static>(inner: S) -> (impl Stream<Item=S::Item> + Send + 'static, Arc<AtomicBool>) {
+        let dropped = Arc::new(AtomicBool::new(false));
+        let flag = DropFlag(dropped.clone());
+        (inner.map(move |item| { let _ = &flag; item }), dropped)
+    }
+
+    fn bridge_item(item: Result<i32,&'static str>) -> ResolutionMarshalling {
+        Box::new(move |realm: &mut AutoRealm, _promise: Handle<'_, *mut JSObject>, _global: Handle<'_, *mut JSObject>, mut ok: MutableHandle<'_,Value>, err: MutableHandle<'_,Value>| {
+            let cx = unsafe { realm.deref_mut().raw_cx() };
+            match item {
+                Ok(n) => ok.set(Int32Value(n)),
+                Err(message) => { new_error(cx, message, None, err); },
+            }
+        }) as ResolutionMarshalling
+    }
+
+    /// Runs `body` with `globalThis.iterator` over `items`
+    fn iterate<S>(items: S, body: &str) -> Result<String,String>
+    where
+        S: Stream<Item=Result<i32,&'static str>> + Send + 'static,
+    {
+        with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            define_iterator(ctx, global.handle(), items);
+            eval_async(ctx, global.handle(), body)
+        })
+    }
+
+    fn define_iterator<S>(ctx: &mut JSContext, global: Handle<'_,*mut JSObject>, items: S)
+    where
+        S: Stream<Item=Result<i32,&'static str>> + Send + 'static,
+    {
+        enter_incumbent_stack(ctx, global, |realm, global_obj| {
+            let cx = unsafe { realm.deref_mut().raw_cx() };
+            rooted!(in(cx) let iterator = ObjectValue(new_stream_iterator(cx, items, bridge_item)));
+            define_property(cx, global_obj, c"iterator", iterator.handle());
+        });
+    }
+
+    #[test]
+    fn next_yields_every_item_then_done() {
+        let got = iterate(stream::iter([Ok(1), Ok(2)]), r#"
+            const results = [await iterator.next(), await iterator.next(), await iterator.next()];
+            return results.map(({ value, done }) => `${value}:${done}`).join();
+        "#);
+        assert_eq!(got.as_deref(), Ok("1:false,2:false,undefined:true"));
+    }
+
+    #[test]
+    fn a_rejected_item_leaves_the_stream_open() {
+        let got = iterate(stream::iter([Ok(1), Err("bad item"), Ok(3)]), r#"
+            const first = await iterator.next();
+            const second = await iterator.next().catch((e) => e.message);
+            const third = await iterator.next();
+            return [first.value, second, third.value].join();
+        "#);
+        assert_eq!(got.as_deref(), Ok("1,bad item,3"));
+    }
+
+    #[test]
+    fn breaking_out_drops_the_stream() {
+        let (items, dropped) = tracked(stream::iter((1..).map(Ok)));
+        let got = iterate(items, r#"
+            let sum = 0;
+            for await (const n of iterator) {
+                sum += n;
+                if (n === 3) {
+                    break;
+                }
+            }
+            return sum;
+        "#);
+        assert_eq!(got.as_deref(), Ok("6"));
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn return_finishes_a_pending_next() {
+        let (items, dropped) = tracked(stream::pending());
+        let got = iterate(items, r#"
+            const pending = iterator.next();
+            const returned = await iterator.return();
+            const { done } = await pending;
+            return [returned.done, done].join();
+        "#);
+        assert_eq!(got.as_deref(), Ok("true,true"));
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn an_abandoned_iterator_drops_its_stream() {
+        let (items, dropped) = tracked(stream::iter((1..).map(Ok)));
+        with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            define_iterator(ctx, global.handle(), items);
+            assert_eq!(eval(ctx, global.handle(), "delete globalThis.iterator").as_deref(), Ok("true"));
+            gc(ctx);
+            assert!(dropped.load(Ordering::SeqCst));
+        });
+    }
+}
@@ -0,0 +1,244 @@
+use std::{
+    ffi::{c_void},
+    ptr::{null_mut},
+};
+use mozjs::{rooted};
+use mozjs::{
+    context::{RawJSContext},
+    gc::{Handle,MutableHandle},
+    jsapi::{
+        Value,
+        HandleObject,MutableHandleObject,
+        JSStructuredCloneReader,TransferableOwnership,
+        CloneDataPolicy,
+        JSStructuredCloneCallbacks,
+        StructuredCloneScope,
+        JS_STRUCTURED_CLONE_VERSION,
+        JS_WriteStructuredClone, JS_ReadStructuredClone,
+    },
+    rust::wrappers::{JS_GetProperty,IsArrayObject},
+    glue::{
+        NewJSAutoStructuredCloneBuffer, DeleteJSAutoStructuredCloneBuffer,
+        GetLengthOfJSStructuredCloneData, CopyJSStructuredCloneData,
+        WriteBytesToJSStructuredCloneData,
+    },
+};
+#[allow(unused_imports)] use tracing::{trace,debug,info,warn,error,instrument};
+
+use crate::message_channel::{can_transfer_port,write_transfer_port,read_transfer_port};
+
+/// Builtin types are understood by the engine, the only host type is `MessagePort`
+static STRUCTURED_CLONE_CALLBACKS: JSStructuredCloneCallbacks = JSStructuredCloneCallbacks {
+    read: None,
+    write: None,
+    reportError: None,
+    readTransfer: Some(read_transfer),
+    writeTransfer: Some(write_transfer),
+    freeTransfer: Some(free_transfer),
+    canTransfer: Some(can_transfer),
+    sabCloned: None,
+};
+
+/// Tag for transferred message ports, the port id is stored in `extraData`
+const SCTAG_MESSAGE_PORT: u32 = 0xFFFF8001;
+
+unsafe extern "C" fn can_transfer(
+    _cx: *mut RawJSContext,
+    obj: HandleObject,
+    same_process_scope_required: *mut bool,
+    closure: *mut c_void,
+) -> bool {
+    unsafe { *same_process_scope_required = false };
+    if !can_transfer_port(obj.get()) {
+        return false;
+    }
+    // port ids only mean something to the thread which made them
+    let scope = unsafe { *(closure as *const CloneScope) };
+    if scope != CloneScope::SameThread {
+        warn!("a MessagePort can't be transferred to another thread");
+        return false;
+    }
+    true
+}
+
+unsafe extern "C" fn write_transfer(
+    _cx: *mut RawJSContext,
+    obj: HandleObject,
+    _closure: *mut c_void,
+    tag: *mut u32,
+    ownership: *mut TransferableOwnership,
+    content: *mut *mut c_void,
+    extra_data: *mut u64,
+) -> bool {
+    let Some(id) = write_transfer_port(obj.get()) else {
+        return false;
+    };
+    unsafe {
+        *tag = SCTAG_MESSAGE_PORT;
+        *ownership = TransferableOwnership::SCTAG_TMO_CUSTOM;
+        *content = null_mut();
+        *extra_data = id;
+    }
+    true
+}
+
+unsafe extern "C" fn read_transfer(
+    cx: *mut RawJSContext,
+    _reader: *mut JSStructuredCloneReader,
+    _policy: *const CloneDataPolicy,
+    tag: u32,
+    _content: *mut c_void,
+    extra_data: u64,
+    _closure: *mut c_void,
+    mut return_object: MutableHandleObject,
+) -> bool {
+    if tag != SCTAG_MESSAGE_PORT {
+        error!("unknown transfer tag: '{:x}'", tag);
+        return false;
+    }
+    match read_transfer_port(cx, extra_data) {
+        Some(port) => {
+            return_object.set(port);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Ports are owned by the registry in `message_channel`, not the buffer
+unsafe extern "C" fn free_transfer(
+    tag: u32,
+    _ownership: TransferableOwnership,
+    _content: *mut c_void,
+    extra_data: u64,
+    _closure: *mut c_void,
+) {
+    trace!("buffer released transferable '{:x}' '{}'", tag, extra_data);
+}
+
+/// Where serialized data is going to be read, which decides what can be transferred
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum CloneScope {
+    /// read back by the writing thread (`structuredClone`, `MessagePort`)
+    SameThread,
+    /// read by another thread (`Worker`)
+    OtherThread,
+}
+
+/// A serialized JS value
+///
+/// Serialized with `DifferentProcess` scope, so transferred
+/// `ArrayBuffer`s are copied into the buffer (and detached at the
+/// source). The bytes own everything they reference, which makes
+/// this safe to send to another thread or runtime. The exception is
+/// `MessagePort`, which can only be transferred within a thread (see
+/// `CloneScope`).
+#[derive(Clone,Debug)]
+pub struct StructuredCloneData {
+    data: Vec<u8>,
+}
+impl StructuredCloneData {
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+fn clone_policy() -> CloneDataPolicy {
+    CloneDataPolicy {
+        allowIntraClusterClonableSharedObjects_: false,
+        allowSharedMemoryObjects_: false,
+    }
+}
+
+/// Serializes `value`, `transfer` is either `undefined` or an array of transferables
+///
+/// `scope` is where the data will be read. On failure an exception is
+/// pending on `cx`.
+#[instrument(skip_all)]
+pub fn write_structured_clone(
+    cx: *mut RawJSContext,
+    value: Handle<'_,Value>,
+    transfer: Handle<'_,Value>,
+    scope: CloneScope,
+) -> Result<StructuredCloneData,()> {
+    unsafe {
+        let buffer = NewJSAutoStructuredCloneBuffer(StructuredCloneScope::DifferentProcess, &STRUCTURED_CLONE_CALLBACKS);
+        let data = &mut (*buffer).data_;
+        let policy = clone_policy();
+        let ok = JS_WriteStructuredClone(
+            cx,
+            value.into(),
+            data,
+            StructuredCloneScope::DifferentProcess,
+            &policy,
+            &STRUCTURED_CLONE_CALLBACKS,
+            &scope as *const CloneScope as *mut c_void,
+            transfer.into(),
+        );
+        if !ok {
+            DeleteJSAutoStructuredCloneBuffer(buffer);
+            return Err(());
+        }
+        let len = GetLengthOfJSStructuredCloneData(data);
+        let mut bytes = Vec::with_capacity(len);
+        CopyJSStructuredCloneData(data, bytes.as_mut_ptr());
+        bytes.set_len(len);
+        DeleteJSAutoStructuredCloneBuffer(buffer);
+        trace!("serialized '{}' bytes", len);
+        Ok(StructuredCloneData { data: bytes })
+    }
+}
+
+/// Deserializes `data` into the current realm
+///
+/// On failure an exception is pending on `cx`.
+#[instrument(skip_all)]
+pub fn read_structured_clone(
+    cx: *mut RawJSContext,
+    data: &StructuredCloneData,
+    rval: MutableHandle<'_,Value>,
+) -> Result<(),()> {
+    unsafe {
+        let buffer = NewJSAutoStructuredCloneBuffer(StructuredCloneScope::DifferentProcess, &STRUCTURED_CLONE_CALLBACKS);
+        let scdata = &mut (*buffer).data_;
+        WriteBytesToJSStructuredCloneData(data.data.as_ptr(), data.data.len(), scdata);
+        let policy = clone_policy();
+        let ok = JS_ReadStructuredClone(
+            cx,
+            scdata,
+            JS_STRUCTURED_CLONE_VERSION,
+            StructuredCloneScope::DifferentProcess,
+            rval.into(),
+            &policy,
+            &STRUCTURED_CLONE_CALLBACKS,
+            null_mut(),
+        );
+        DeleteJSAutoStructuredCloneBuffer(buffer);
+        if ok { Ok(()) } else { Err(()) }
+    }
+}
+
+/// Extracts the transfer list from the options argument of `structuredClone`/`postMessage`
+///
+/// Accepts `{ transfer: [...] }` or a bare array. `out` is left
+/// `undefined` when there is nothing to transfer. Returns `false` if
+/// an exception is pending.
+pub fn transfer_list(cx: *mut RawJSContext, options: Handle<'_,Value>, mut out: MutableHandle<'_,Value>) -> bool {
+    if !options.is_object() {
+        return true;
+    }
+    rooted!(in(cx) let obj = options.to_object());
+    let mut is_array = false;
+    if !unsafe { IsArrayObject(cx, obj.handle(), &mut is_array) } {
+        return false;
+    }
+    if is_array {
+        out.set(options.get());
+        return true;
+    }
+    unsafe { JS_GetProperty(cx, obj.handle(), c"transfer".as_ptr(), out) }
+}
@@ -0,0 +1,275 @@
+use std::{
+    collections::{BTreeMap},
+    fmt,
+    ops::{DerefMut},
+    path::{Path,PathBuf},
+    ptr::{null_mut},
+    sync::{Arc,Mutex,atomic::{AtomicU64,Ordering}},
+    time::{Duration,Instant},
+};
+use mozjs::{rooted};
+use mozjs::{
+    context::{JSContext,RawJSContext},
+    gc::{Handle},
+    jsapi::{JSObject,JS_ClearPendingException,JS_GC,GCReason,PromiseState,GetPromiseState,GetPromiseResult},
+    jsval::{UndefinedValue},
+    realm::{AutoRealm},
+    rust::{JSEngine,JSEngineHandle,Runtime,CompileOptionsWrapper,evaluate_script,wrappers::{JS_GetPendingException}},
+};
+use tracing::{Event,Level,Subscriber,field::{Field,Visit},span::{Attributes,Id,Record}};
+use tracing_subscriber::{
+    layer::{Context,Layer},
+    prelude::*,
+    registry::{LookupSpan},
+};
+
+use super::{
+    callback::{install_job_queue},
+    checkpoint::{run_until,shutdown},
+    exception::{describe_exception,value_to_string},
+    incumbent_stack::{enter_incumbent_stack},
+    roots::{install_root_tracer},
+};
+use crate::{
+    globals::{define_globals},
+    realm::{new_global},
+    worker::{set_engine_handle},
+};
+
+/*
+ * Test support
+ *
+ * Tests which need the engine run within `with_runtime`: a runtime of
+ * their own on the test's thread (so every thread local is theirs too)
+ * with the job queue & roots installed & a tokio runtime entered. The
+ * engine is initialized by the first test & never shut down.
+ *
+ */
+
+/// How long `eval_async` waits for its promise
+const ASYNC_TIMEOUT: Duration = Duration::from_secs(10);
+
+static ENGINE: Mutex<Option<JSEngineHandle>> = Mutex::new(None);
+
+fn engine_handle() -> JSEngineHandle {
+    ENGINE.lock().unwrap().get_or_insert_with(|| {
+        let engine = JSEngine::init().unwrap();
+        let handle = engine.handle();
+        // dropping the engine waits for every handle, tests never give theirs back
+        std::mem::forget(engine);
+        set_engine_handle(handle.clone());
+        handle
+    }).clone()
+}
+
+/// Runs `test` with a fresh runtime on this thread
+pub(crate) fn with_runtime<R>(test: impl FnOnce(&mut JSContext) -> R) -> R {
+    let tokio = tokio::runtime::Runtime::new().unwrap();
+    let _guard = tokio.enter();
+    let mut runtime = Runtime::new(engine_handle());
+    let context = runtime.cx();
+    install_job_queue(context);
+    install_root_tracer(context);
+    let out = test(context);
+    // everything holding a `Heap` must be gone before the runtime is
+    shutdown();
+    out
+}
+
+/// A new realm with the usual globals, null on failure
+pub(crate) fn new_test_global(ctx: &mut JSContext) -> *mut JSObject {
+    rooted!(in(unsafe { ctx.raw_cx() }) let global = new_global(ctx));
+    if !global.get().is_null() {
+        enter_incumbent_stack(ctx, global.handle(), |realm: &mut AutoRealm, global_obj: Handle<'_,*mut JSObject>| {
+            define_globals(realm, global_obj);
+        });
+    }
+    global.get()
+}
+
+/// Runs a full, non incremental, GC
+pub(crate) fn gc(ctx: &mut JSContext) {
+    unsafe { JS_GC(ctx.raw_cx(), GCReason::API) };
+}
+
+/// Evaluates `source` within `global`'s realm
+///
+/// The completion value as a string, or the message of the exception
+/// thrown.
+pub(crate) fn eval(ctx: &mut JSContext, global: Handle<'_,*mut JSObject>, source: &str) -> Result<String,String> {
+    enter_incumbent_stack(ctx, global, |realm: &mut AutoRealm, global_obj: Handle<'_,*mut JSObject>| {
+        rooted!(&in(realm) let mut rval = UndefinedValue());
+        let options = CompileOptionsWrapper::new(realm, "test.js", 1);
+        let ok = evaluate_script(realm, global_obj, source, rval.handle_mut(), options).is_ok();
+        let cx = unsafe { realm.deref_mut().raw_cx() };
+        if !ok {
+            return Err(take_exception(cx));
+        }
+        Ok(value_to_string(cx, rval.handle()).unwrap_or_default())
+    })
+}
+
+/// Runs `body` as an async function within `global`'s realm
+///
+/// The event loop is ran until the function's promise settles, whatever
+/// it leaves behind is dropped with the runtime. The resolved value as a
+/// string, or the message of the rejection.
+pub(crate) fn eval_async(ctx: &mut JSContext, global: Handle<'_,*mut JSObject>, body: &str) -> Result<String,String> {
+    let source = format!("(async () => {{\n{}\n}})()", body);
+    rooted!(in(unsafe { ctx.raw_cx() }) let mut promise = null_mut::<JSObject>());
+    enter_incumbent_stack(ctx, global, |realm: &mut AutoRealm, global_obj: Handle<'_,*mut JSObject>| {
+        rooted!(&in(realm) let mut rval = UndefinedValue());
+        let options = CompileOptionsWrapper::new(realm, "test.js", 1);
+        let ok = evaluate_script(realm, global_obj, &source, rval.handle_mut(), options).is_ok();
+        if !ok {
+            return Err(take_exception(unsafe { realm.deref_mut().raw_cx() }));
+        }
+        promise.set(rval.to_object());
+        Ok(())
+    })?;
+
+    let deadline = Instant::now() + ASYNC_TIMEOUT;
+    run_until(ctx, Some(deadline), || {
+        unsafe { GetPromiseState(promise.handle().into()) } != PromiseState::Pending || Instant::now() >= deadline
+    });
+    let state = unsafe { GetPromiseState(promise.handle().into()) };
+    if state == PromiseState::Pending {
+        return Err("timed out".to_string());
+    }
+    enter_incumbent_stack(ctx, global, |realm: &mut AutoRealm, _: Handle<'_,*mut JSObject>| {
+        let cx = unsafe { realm.deref_mut().raw_cx() };
+        rooted!(in(cx) let result = unsafe { GetPromiseResult(promise.handle().into()) });
+        match state {
+            PromiseState::Fulfilled => Ok(value_to_string(cx, result.handle()).unwrap_or_default()),
+            _ => Err(describe_exception(cx, result.handle()).0),
+        }
+    })
+}
+
+/// Clears & describes the pending exception
+fn take_exception(cx: *mut RawJSContext) -> String {
+    rooted!(in(cx) let mut exn = UndefinedValue());
+    unsafe {
+        if !JS_GetPendingException(cx, exn.handle_mut()) {
+            return "terminated without an exception".to_string();
+        }
+        JS_ClearPendingException(cx);
+    }
+    describe_exception(cx, exn.handle()).0
+}
+
+/// A directory of its own under the system's temp dir, removed when dropped
+pub(crate) struct TempDir {
+    path: PathBuf,
+}
+impl TempDir {
+    pub(crate) fn new(name: &str) -> Self {
+        static NEXT_TEMP_DIR: AtomicU64 = AtomicU64::new(1);
+        let unique = NEXT_TEMP_DIR.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("async-demo-{}-{}-{}", name, std::process::id(), unique));
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir { path }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writes `contents` to `name` within the directory, its path as a string
+    pub(crate) fn write(&self, name: &str, contents: &str) -> String {
+        let path = self.path.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path.display().to_string()
+    }
+}
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// An event seen by `capture`, its fields formatted as strings
+pub(crate) struct CapturedEvent {
+    pub target: String,
+    pub level: Level,
+    pub fields: BTreeMap<&'static str,String>,
+}
+
+/// A span seen by `capture`, with every field recorded until it closed
+pub(crate) struct CapturedSpan {
+    pub name: &'static str,
+    pub parent: Option<&'static str>,
+    pub fields: BTreeMap<&'static str,String>,
+}
+
+/// What `capture` saw, spans in the order they were opened
+#[derive(Default)]
+pub(crate) struct Captured {
+    pub events: Vec<CapturedEvent>,
+    pub spans: Vec<CapturedSpan>,
+    /// open spans by id into `spans`, ids are reused once closed
+    open: BTreeMap<u64,usize>,
+}
+impl Captured {
+    pub(crate) fn spans_named<'a>(&'a self, name: &'a str) -> impl Iterator<Item=&'a CapturedSpan> + 'a {
+        self.spans.iter().filter(move |s| s.name == name)
+    }
+}
+
+/// Runs `f` with every `tracing` span & event on this thread recorded
+pub(crate) fn capture<R>(f: impl FnOnce() -> R) -> (R, Captured) {
+    let captured = Arc::new(Mutex::new(Captured::default()));
+    let subscriber = tracing_subscriber::registry().with(CaptureLayer(captured.clone()));
+    let out = tracing::subscriber::with_default(subscriber, f);
+    let captured = std::mem::take(&mut *captured.lock().unwrap());
+    (out, captured)
+}
+
+struct CaptureLayer(Arc<Mutex<Captured>>);
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for CaptureLayer {
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_,S>) {
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        let parent = ctx.span(id).and_then(|span| span.parent()).map(|parent| parent.name());
+        let mut captured = self.0.lock().unwrap();
+        let index = captured.spans.len();
+        captured.spans.push(CapturedSpan { name: attrs.metadata().name(), parent, fields: fields.0 });
+        captured.open.insert(id.into_u64(), index);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, _ctx: Context<'_,S>) {
+        let mut fields = Fields::default();
+        values.record(&mut fields);
+        let mut captured = self.0.lock().unwrap();
+        if let Some(&index) = captured.open.get(&id.into_u64()) {
+            captured.spans[index].fields.extend(fields.0);
+        }
+    }
+
+    fn on_close(&self, id: Id, _ctx: Context<'_,S>) {
+        self.0.lock().unwrap().open.remove(&id.into_u64());
+    }
+
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_,S>) {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let metadata = event.metadata();
+        self.0.lock().unwrap().events.push(CapturedEvent {
+            target: metadata.target().to_string(),
+            level: *metadata.level(),
+            fields: fields.0,
+        });
+    }
+}
+
+#[derive(Default)]
+struct Fields(BTreeMap<&'static str,String>);
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name(), value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.insert(field.name(), format!("{:?}", value));
+    }
+}
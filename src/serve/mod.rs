@@ -0,0 +1,330 @@
+use std::{
+    cell::{RefCell,LazyCell},
+    collections::{BTreeMap},
+    io,
+    net::{SocketAddr},
+    ops::{DerefMut},
+    ptr::{NonNull},
+};
+use tokio::{
+    io::{DuplexStream,duplex},
+    net::{TcpListener},
+    sync::{mpsc,watch},
+};
+use mozjs::{rooted};
+use mozjs::{
+    context::{JSContext,RawJSContext},
+    gc::{Handle,MutableHandle},
+    jsapi::{JSObject,Value,CallArgs},
+    jsval::{UndefinedValue,ObjectValue,NullValue},
+    realm::{AutoRealm},
+    rust::wrappers2::JS_DefineFunction,
+    panic::wrap_panic,
+};
+#[allow(unused_imports)]
+use tracing::{debug,trace,instrument,warn,error,info};
+use tracing::{Instrument,debug_span};
+
+use crate::{
+    byte_stream::{ByteStreamParts,BoxedReader,new_byte_stream},
+    http::{
+        BODY_CHUNK_SIZE,
+        Headers,
+        server::{IncomingRequest,OutgoingResponse,accept_loop},
+    },
+    realm::{evaluate},
+    runtime::{
+        conversions::{get_option,option_number,option_string,value_to_strings,new_io_error,new_object,define_value,define_property},
+        exception::{describe_exception},
+        host_object::{new_host_object,host_object_data},
+        js_callback::{CallbackArgs,JsCallbackHandle},
+        queue::{Macrotask},
+        resolvable_promise::{ResolutionMarshalling,promise_entry_point,push_task_source},
+    },
+};
+
+/*
+ * `serve(options, handler)`
+ *
+ * An HTTP server whose handler is a JS function. The realm calling
+ * `serve` is the one every request is dispatched into: the prelude's
+ * dispatcher is held in a `JsCallbackHandle`, each request is a call
+ * through it & the response head its promise settles with is written
+ * out by `http::server`.
+ *
+ * A listening server keeps the event loop alive until `close()`.
+ *
+ */
+
+const PRELUDE: &str = include_str!("prelude.js");
+
+thread_local! {
+    /// flipped by `close()`, kept here so collecting a server object doesn't close it
+    static SERVERS: LazyCell<RefCell<BTreeMap<u64,watch::Sender<bool>>>> = LazyCell::new(|| RefCell::new(BTreeMap::new()));
+    static NEXT_SERVER_ID: std::cell::Cell<u64> = std::cell::Cell::new(1);
+}
+
+/// Owned by a server object, the entry outlives the object
+struct ServerId(u64);
+
+/// Defines `serve` on `global_obj`, must come after `define_fetch`
+pub fn define_serve(realm: &mut AutoRealm, global_obj: Handle<'_,*mut JSObject>) {
+    let cx = unsafe { realm.deref_mut().raw_cx() };
+    rooted!(in(cx) let native = new_object(cx));
+    if native.get().is_null() {
+        error!("could not allocate serve natives");
+        return;
+    }
+    unsafe {
+        JS_DefineFunction(realm,native.handle(),c"listen".as_ptr(),Some(native_listen),3,0,);
+    }
+    rooted!(in(cx) let native_val = ObjectValue(native.get()));
+    define_property(cx, global_obj, c"__serveNative", native_val.handle());
+    if evaluate(realm, global_obj, "serve.js", PRELUDE).is_err() {
+        error!("could not evaluate the serve prelude");
+    }
+}
+
+/// `native.listen({ port, hostname }, dispatch)`, resolves to the server object once bound
+unsafe extern "C" fn native_listen(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    promise_entry_point(ctx, argc, vp, "serve", bridge_listen, |ctx, args| {
+        let options = unsafe { Handle::from_raw(args.get(0)) };
+        let port = match option_number(ctx, options, c"port") {
+            None => 0,
+            Some(port) if (0.0..=65535.0).contains(&port) && port.fract() == 0.0 => port as u16,
+            Some(_) => return Err("port must be an integer between 0 and 65535"),
+        };
+        let host = option_string(ctx, options, c"hostname").unwrap_or_else(|| "127.0.0.1".to_string());
+        let dispatch = match argc {
+            0..=1 => None,
+            _ => JsCallbackHandle::new(ctx, unsafe { Handle::from_raw(args.get(1)) }),
+        };
+        let dispatch = dispatch.ok_or("handler must be a function")?;
+        Ok(async move { (dispatch, TcpListener::bind((host.as_str(), port)).await) })
+    })
+}
+
+fn bridge_listen(result: (JsCallbackHandle, io::Result<TcpListener>)) -> ResolutionMarshalling {
+    Box::new(move |realm: &mut AutoRealm, _promise: Handle<'_, *mut JSObject>, _global: Handle<'_, *mut JSObject>, mut ok: MutableHandle<'_,Value>, err: MutableHandle<'_,Value>| {
+        let cx = unsafe { realm.deref_mut().raw_cx() };
+        let (dispatch, listener) = match result {
+            (dispatch, Ok(listener)) => (dispatch, listener),
+            (_, Err(e)) => {
+                new_io_error(cx, &e, err);
+                return;
+            }
+        };
+        let id = NEXT_SERVER_ID.with(|n| {
+            let id = n.get();
+            n.set(id + 1);
+            id
+        });
+        let address = listener.local_addr().ok();
+        let (closed, closed_rx) = watch::channel(false);
+        let (requests, receiver) = mpsc::unbounded_channel();
+        SERVERS.with(|s| s.borrow_mut().insert(id, closed));
+        tokio::spawn(accept_loop(listener, requests, closed_rx));
+        let forwarding = tokio::spawn(forward_requests(dispatch, receiver));
+        push_task_source(async move {
+            let _ = forwarding.await;
+            Macrotask::new("server finished", move |_ctx: &mut JSContext| {
+                debug!("server '{}' finished", id);
+                SERVERS.with(|s| s.borrow_mut().remove(&id));
+            })
+        });
+
+        rooted!(in(cx) let obj = new_host_object(cx, ServerId(id)));
+        if obj.get().is_null() {
+            return;
+        }
+        let mut safe_ctx = unsafe { JSContext::from_ptr(NonNull::new(cx).unwrap()) };
+        unsafe { JS_DefineFunction(&mut safe_ctx, obj.handle(), c"close".as_ptr(), Some(server_close), 0, 0) };
+        if let Some(address) = address {
+            define_value(cx, obj.handle(), c"address", &address.to_string());
+            define_value(cx, obj.handle(), c"port", &address.port());
+        }
+        ok.set(ObjectValue(obj.get()));
+    }) as ResolutionMarshalling
+}
+
+/// `server.close()`, stops accepting, in flight requests still complete
+unsafe extern "C" fn server_close(_ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    wrap_panic(&mut || {
+        let args = unsafe { CallArgs::from_vp(vp, argc) };
+        let this = args.thisv();
+        if this.is_object() {
+            if let Some(ServerId(id)) = unsafe { host_object_data::<ServerId>(this.to_object()) } {
+                SERVERS.with(|s| {
+                    if let Some(closed) = s.borrow().get(id) {
+                        closed.send_replace(true);
+                    }
+                });
+            }
+        }
+        args.rval().set(UndefinedValue());
+    });
+    true
+}
+
+/// Calls the dispatcher for every request, until every sender is gone
+///
+/// The accept loop & each connection hold a sender, so the dispatcher
+/// stays rooted until the server is closed & its last request answered.
+async fn forward_requests(dispatch: JsCallbackHandle, mut requests: mpsc::UnboundedReceiver<IncomingRequest>) {
+    while let Some(request) = requests.recv().await {
+        let IncomingRequest { method, url, headers, body, remote_address, respond } = request;
+        let span = debug_span!("http request", method = %method, url = %url);
+        // the handler writes the response body into one end, `http::server` reads the other
+        let (writer, reader) = duplex(BODY_CHUNK_SIZE);
+        let reader: BoxedReader = Box::new(reader);
+        let response = dispatch.call_with_result(Box::new(move |_cx: *mut RawJSContext, args: &mut CallbackArgs<'_>| {
+            push_request(args, method, url, headers, body, writer, remote_address)
+        }), Box::new(move |cx: *mut RawJSContext, result: Result<Handle<'_,Value>,Handle<'_,Value>>| match result {
+            Ok(head) => response_head(cx, head, Some(reader)),
+            Err(exn) => {
+                let (message, stack) = describe_exception(cx, exn);
+                error!("serve handler: uncaught exception: {}\n{}", message, stack);
+                None
+            }
+        }));
+        tokio::spawn(async move {
+            let response = response.await.flatten();
+            let _ = respond.send(response.unwrap_or_else(|| OutgoingResponse::status(500)));
+        }.instrument(span));
+    }
+}
+
+/// `dispatch(method, url, headers, body, writer, remoteAddress)`
+fn push_request(args: &mut CallbackArgs<'_>, method: String, url: String, headers: Headers, body: Option<BoxedReader>, writer: DuplexStream, remote_address: SocketAddr) -> bool {
+    let cx = args.cx();
+    rooted!(in(cx) let writer = new_byte_stream(cx, ByteStreamParts::new(None, Some(Box::new(writer)))));
+    if writer.get().is_null() {
+        return false;
+    }
+    rooted!(in(cx) let mut body_val = NullValue());
+    if let Some(body) = body {
+        let body = new_byte_stream(cx, ByteStreamParts::new(Some(body), None));
+        if body.is_null() {
+            return false;
+        }
+        body_val.set(ObjectValue(body));
+    }
+    rooted!(in(cx) let writer_val = ObjectValue(writer.get()));
+    let flat: Vec<String> = headers.into_iter().flat_map(|(k, v)| [k, v]).collect();
+    args.push_value(&method)
+        && args.push_value(&url)
+        && args.push_value(&flat)
+        && args.push(body_val.handle())
+        && args.push(writer_val.handle())
+        && args.push_value(&remote_address.to_string())
+}
+
+/// Reads `{ status, statusText, headers, body }` as settled by the dispatcher
+///
+/// `body` is `true` when the handler streams into the writer.
+fn response_head(cx: *mut RawJSContext, head: Handle<'_,Value>, reader: Option<BoxedReader>) -> Option<OutgoingResponse> {
+    if !head.is_object() {
+        error!("serve handler: dispatcher did not settle with a response head");
+        return None;
+    }
+    let status = option_number(cx, head, c"status")
+        .filter(|s| (100.0..=999.0).contains(s))
+        .map(|s| s as u16)?;
+    let status_text = option_string(cx, head, c"statusText")
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| crate::http::status_text(status).to_string());
+    rooted!(in(cx) let mut flat = UndefinedValue());
+    get_option(cx, head, c"headers", flat.handle_mut());
+    let flat = value_to_strings(cx, flat.handle()).unwrap_or_default();
+    let headers = flat.chunks_exact(2).map(|pair| (pair[0].clone(), pair[1].clone())).collect();
+    rooted!(in(cx) let mut streaming = UndefinedValue());
+    get_option(cx, head, c"body", streaming.handle_mut());
+    let body = if streaming.is_boolean() && streaming.to_boolean() { reader } else { None };
+    Some(OutgoingResponse { status, status_text, headers, body })
+}
+
+#[cfg(test)]
+mod tests {
+    use mozjs::{rooted};
+    use crate::runtime::testing::{with_runtime,new_test_global,eval_async};
+
+    /// Runs `body` with `server` serving `handler` & `url` pointing at it, the server is closed after
+    fn with_server(handler: &str, body: &str) -> Result<String,String> {
+        with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            let body = format!(r#"
+                const server = await serve({});
+                const url = `http://127.0.0.1:${{server.port}}`;
+                try {{
+                    {}
+                }} finally {{
+                    server.close();
+                }}
+            "#, handler, body);
+            eval_async(ctx, global.handle(), &body)
+        })
+    }
+
+    #[test]
+    fn fetch_reaches_the_handler() {
+        let got = with_server(r#"
+            (request, { remoteAddress }) => new Response(`${request.method} ${request.url.endsWith('/path')} ${remoteAddress.startsWith('127.0.0.1:')}`, {
+                status: 201,
+                headers: { 'x-seen': request.headers.get('x-sent') },
+            })
+        "#, r#"
+            const response = await fetch(`${url}/path`, { headers: { 'x-sent': 'yes' } });
+            return [response.status, response.headers.get('x-seen'), await response.text()].join();
+        "#);
+        assert_eq!(got.as_deref(), Ok("201,yes,GET true true"));
+    }
+
+    #[test]
+    fn request_bodies_stream_back() {
+        let got = with_server(r#"
+            async (request) => new Response(`got ${await request.text()}`)
+        "#, r#"
+            const response = await fetch(url, { method: 'POST', body: 'ping' });
+            return await response.text();
+        "#);
+        assert_eq!(got.as_deref(), Ok("got ping"));
+    }
+
+    #[test]
+    fn a_throwing_handler_answers_500() {
+        let got = with_server(r#"
+            () => { throw new Error('handler failed'); }
+        "#, r#"
+            const response = await fetch(url);
+            return response.status;
+        "#);
+        assert_eq!(got.as_deref(), Ok("500"));
+    }
+
+    #[test]
+    fn on_error_answers_for_the_handler() {
+        let got = with_server(r#"
+            {
+                onError: (e) => new Response(e.message, { status: 503 }),
+            }, () => { throw new Error('handler failed'); }
+        "#, r#"
+            const response = await fetch(url);
+            return [response.status, await response.text()].join();
+        "#);
+        assert_eq!(got.as_deref(), Ok("503,handler failed"));
+    }
+
+    #[test]
+    fn closing_lets_in_flight_requests_finish() {
+        let got = with_server(r#"
+            () => {
+                server.close();
+                return new Response('still answered');
+            }
+        "#, r#"
+            const response = await fetch(url);
+            return await response.text();
+        "#);
+        assert_eq!(got.as_deref(), Ok("still answered"));
+    }
+}
@@ -0,0 +1,67 @@
+// `serve(options, handler)`
+//
+// Evaluated once per realm by `serve::define_serve`, after the streams
+// & fetch preludes. `__serveNative` is removed from the global before
+// any user script runs.
+(function (native) {
+    'use strict';
+
+    // resolves to `{ port, address, close() }` once listening
+    function serve(options, handler) {
+        if (typeof options === 'function') {
+            [options, handler] = [handler ?? {}, options];
+        }
+        options ??= {};
+        if (typeof handler !== 'function') {
+            throw new TypeError('serve: handler must be a function');
+        }
+        const onError = options.onError;
+
+        // called by rust for every request, settles with the response head
+        async function dispatch(method, url, headerList, body, writer, remoteAddress) {
+            const headers = new Headers();
+            for (let i = 0; i + 1 < headerList.length; i += 2) {
+                headers.append(headerList[i], headerList[i + 1]);
+            }
+            const hasBody = body !== null && method !== 'GET' && method !== 'HEAD';
+            if (body !== null && !hasBody) {
+                body.close();
+            }
+            let response;
+            try {
+                const request = new Request(url, { method, headers, body: hasBody ? body.readable : null });
+                response = await handler(request, { remoteAddress });
+                if (!(response instanceof Response)) {
+                    throw new TypeError('serve: handler must return a Response');
+                }
+            } catch (e) {
+                if (typeof onError !== 'function') {
+                    writer.close();
+                    throw e;
+                }
+                response = await onError(e);
+            }
+
+            const head = {
+                status: response.status,
+                statusText: response.statusText,
+                headers: [...response.headers].flat(),
+                body: response.body !== null,
+            };
+            if (response.body !== null) {
+                // the writer's side closes once the body is done, or aborts on failure
+                response.body.pipeTo(writer.writable).catch((e) => {
+                    queueMicrotask(() => { throw e; });
+                });
+            } else {
+                writer.close();
+            }
+            return head;
+        }
+
+        return native.listen({ port: options.port, hostname: options.hostname }, dispatch);
+    }
+
+    Object.defineProperty(globalThis, 'serve', { value: serve, writable: true, configurable: true, enumerable: false });
+})(globalThis.__serveNative);
+delete globalThis.__serveNative;
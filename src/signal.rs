@@ -0,0 +1,354 @@
+use std::{
+    cell::{RefCell,LazyCell},
+    ptr::{null_mut},
+    collections::{BTreeMap,BTreeSet},
+    ops::{DerefMut},
+    rc::{Rc},
+};
+use tokio::{
+    signal::unix::{Signal,SignalKind},
+    sync::{oneshot},
+};
+use mozjs::{rooted};
+use mozjs::{
+    context::{JSContext,RawJSContext},
+    conversions::{ToJSValConvertible},
+    error::{throw_type_error},
+    gc::{Handle,MutableHandle},
+    jsapi::{JSObject,JSTracer,Value,CallArgs,Heap,CurrentGlobalOrNull},
+    jsval::{UndefinedValue,ObjectValue},
+    realm::{AutoRealm},
+    panic::wrap_panic,
+};
+#[allow(unused_imports)]
+use tracing::{debug,trace,instrument,warn,error,info};
+
+use crate::{
+    process::{signal_number,signal_name},
+    runtime::{
+        call::{call_value,is_callable},
+        checkpoint::{drain},
+        conversions::{arg_string,new_error,throw_io_error},
+        exception::{report_pending_exception},
+        incumbent_stack::{enter_incumbent_stack},
+        queue::{Macrotask},
+        resolvable_promise::{Bridge,ResolutionMarshalling,new_pending_promise,promise_span,push_unref_task_source},
+        profiler::{write_profile},
+        roots::{HeapRoot,trace_object},
+    },
+};
+
+/*
+ * OS signals
+ *
+ *  - `onSignal(name, handler)` / `offSignal(name, handler)`
+ *  - `signal(name)`, a promise for the next delivery of `name`
+ *
+ * A signal is delivered as a single macrotask: waiters are resolved
+ * & every handler is called within the realm which registered it.
+ * Listening doesn't keep the event loop alive, waiting does. Handlers
+ * are rooted until they're removed.
+ *
+ * Signals are only taken over from the OS once a script listens.
+ * From then on, nobody handling a signal means the default policy
+ * applies: SIGTERM drains the event loop (see `checkpoint::drain`) &
+ * gives SIGTERM back to the OS, so a second one terminates a script
+ * that never yields. Anything else exits, after the hook main set
+ * (see `set_exit_hook`) has written what the run was asked for.
+ *
+ */
+
+/// Signals scripts may listen for
+const LISTENABLE: &[&str] = &["SIGINT", "SIGTERM", "SIGHUP", "SIGQUIT", "SIGUSR1", "SIGUSR2", "SIGWINCH"];
+
+thread_local! {
+    static HANDLERS: LazyCell<RefCell<BTreeMap<libc::c_int,Vec<SignalHandler>>>> = LazyCell::new(|| RefCell::new(BTreeMap::new()));
+    /// `signal(name)` calls waiting on the next delivery
+    static WAITERS: LazyCell<RefCell<BTreeMap<libc::c_int,Vec<oneshot::Sender<()>>>>> = LazyCell::new(|| RefCell::new(BTreeMap::new()));
+    /// signals with a listener armed, they stay armed until suspended
+    static LISTENING: LazyCell<RefCell<BTreeSet<libc::c_int>>> = LazyCell::new(|| RefCell::new(BTreeSet::new()));
+    /// tokio's actions for signals given back to the OS, `listen` puts them back
+    static SUSPENDED: LazyCell<RefCell<BTreeMap<libc::c_int,libc::sigaction>>> = LazyCell::new(|| RefCell::new(BTreeMap::new()));
+    /// ran before the default policy exits, see `set_exit_hook`
+    static EXIT_HOOK: RefCell<Option<Box<dyn FnOnce(&mut JSContext)>>> = RefCell::new(None);
+}
+
+struct SignalHandler {
+    callback: Box<Heap<*mut JSObject>>,
+    global: Rc<Box<Heap<*mut JSObject>>>,
+}
+
+/// Reports every handler & its realm to the GC, see `roots`
+pub(crate) unsafe fn trace_signal_handlers(trc: *mut JSTracer) {
+    HANDLERS.with(|h| {
+        for handler in h.borrow().values().flatten() {
+            unsafe {
+                trace_object(trc, &handler.callback, c"signal handler");
+                trace_object(trc, &handler.global, c"signal handler global");
+            }
+        }
+    });
+}
+
+/// Sets what runs before the default policy exits the process
+///
+/// Without one only the profile is written.
+pub fn set_exit_hook(hook: impl FnOnce(&mut JSContext) + 'static) {
+    EXIT_HOOK.with(|h| *h.borrow_mut() = Some(Box::new(hook)));
+}
+
+/// Arms the listener for `signal`, if it isn't already
+fn listen(signal: libc::c_int) -> std::io::Result<()> {
+    if LISTENING.with(|l| l.borrow().contains(&signal)) {
+        return Ok(());
+    }
+    // tokio only installs its action once per process, after a suspend it's ours to restore
+    if let Some(action) = SUSPENDED.with(|s| s.borrow_mut().remove(&signal)) {
+        if unsafe { libc::sigaction(signal, &action, null_mut()) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    let stream = tokio::signal::unix::signal(SignalKind::from_raw(signal))?;
+    LISTENING.with(|l| l.borrow_mut().insert(signal));
+    rearm(signal, stream);
+    Ok(())
+}
+
+fn rearm(signal: libc::c_int, mut stream: Signal) {
+    push_unref_task_source(async move {
+        stream.recv().await;
+        Macrotask::new("signal", move |ctx: &mut JSContext| {
+            if deliver(ctx, signal) {
+                rearm(signal, stream);
+            }
+        })
+    });
+}
+
+/// Gives `signal` back to the OS default, keeping tokio's action for `listen`
+fn suspend(signal: libc::c_int) {
+    LISTENING.with(|l| l.borrow_mut().remove(&signal));
+    let mut default: libc::sigaction = unsafe { std::mem::zeroed() };
+    default.sa_sigaction = libc::SIG_DFL;
+    let mut previous: libc::sigaction = unsafe { std::mem::zeroed() };
+    if unsafe { libc::sigaction(signal, &default, &mut previous) } != 0 {
+        warn!("could not restore the default action: {}", std::io::Error::last_os_error());
+        return;
+    }
+    SUSPENDED.with(|s| s.borrow_mut().insert(signal, previous));
+}
+
+/// Resolves waiters & calls handlers, or applies the default policy
+///
+/// `false` once the signal was given back to the OS, it isn't rearmed.
+#[instrument(skip(ctx))]
+fn deliver(ctx: &mut JSContext, signal: libc::c_int) -> bool {
+    let name = signal_name(signal).unwrap_or("unknown");
+    let waiters = WAITERS.with(|w| w.borrow_mut().remove(&signal).unwrap_or_default());
+    // rooted, a handler may remove the others
+    let handlers: Vec<(HeapRoot, HeapRoot)> = HANDLERS.with(|h| {
+        h.borrow().get(&signal)
+            .map(|list| list.iter().map(|h| (HeapRoot::new(h.callback.get()), HeapRoot::new(h.global.get()))).collect())
+            .unwrap_or_default()
+    });
+    debug!("'{}' waiters, '{}' handlers", waiters.len(), handlers.len());
+    if waiters.is_empty() && handlers.is_empty() {
+        default_policy(ctx, signal);
+        return false;
+    }
+    for waiter in waiters {
+        let _ = waiter.send(());
+    }
+    for (callback, global) in handlers {
+        rooted!(in(unsafe { ctx.raw_cx() }) let global = global.get());
+        rooted!(in(unsafe { ctx.raw_cx() }) let callback = ObjectValue(callback.get()));
+        enter_incumbent_stack(ctx, global.handle(), |realm, global| {
+            let cx = unsafe { realm.deref_mut().raw_cx() };
+            rooted!(in(cx) let mut name_val = UndefinedValue());
+            unsafe { name.to_jsval(cx, name_val.handle_mut()) };
+            rooted!(in(cx) let mut rval = UndefinedValue());
+            if !call_value(cx, global, callback.handle(), [name_val.get()], rval.handle_mut()) {
+                report_pending_exception(cx, "onSignal");
+            }
+        });
+    }
+    true
+}
+
+fn default_policy(ctx: &mut JSContext, signal: libc::c_int) {
+    if signal == libc::SIGTERM {
+        info!("SIGTERM with no handlers, draining");
+        // drained futures include the listener, the OS default takes over
+        suspend(signal);
+        drain();
+    } else {
+        info!("'{}' with no handlers, exiting", signal_name(signal).unwrap_or("unknown"));
+        // e.g. ctrl-c still leaves a profile & coverage behind
+        match EXIT_HOOK.with(|h| h.borrow_mut().take()) {
+            Some(hook) => hook(ctx),
+            None => write_profile(),
+        }
+        std::process::exit(128 + signal);
+    }
+}
+
+/// The signal named by `args[0]`, throws a `TypeError` if it isn't one we listen for
+fn signal_arg(ctx: *mut RawJSContext, args: &CallArgs, caller: &str) -> Option<libc::c_int> {
+    let name = arg_string(ctx, args, 0).unwrap_or_default();
+    match signal_number(&name).filter(|_| LISTENABLE.contains(&name.as_str())) {
+        Some(signal) => Some(signal),
+        None => {
+            unsafe { throw_type_error(ctx, &format!("{}: unsupported signal '{}'", caller, name)) };
+            None
+        }
+    }
+}
+
+/// `onSignal(name, handler)`, `handler(name)` is called on every delivery
+pub(crate) unsafe extern "C" fn on_signal(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    let mut is_okay = true;
+    wrap_panic(&mut || {
+        let args = unsafe { CallArgs::from_vp(vp, argc) };
+        let Some(signal) = signal_arg(ctx, &args, "onSignal") else {
+            is_okay = false;
+            return;
+        };
+        let handler = unsafe { Handle::from_raw(args.get(1)) };
+        if argc < 2 || !is_callable(handler) {
+            unsafe { throw_type_error(ctx, "onSignal: handler must be a function") };
+            is_okay = false;
+            return;
+        }
+        if let Err(e) = listen(signal) {
+            is_okay = throw_io_error(ctx, &e);
+            return;
+        }
+        let global = unsafe { CurrentGlobalOrNull(ctx) };
+        HANDLERS.with(|h| h.borrow_mut().entry(signal).or_default().push(SignalHandler {
+            callback: Heap::boxed(handler.to_object()),
+            global: Rc::new(Heap::boxed(global)),
+        }));
+        args.rval().set(UndefinedValue());
+    });
+    is_okay
+}
+
+/// `offSignal(name, handler)`, removes a handler added by `onSignal`
+pub(crate) unsafe extern "C" fn off_signal(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    let mut is_okay = true;
+    wrap_panic(&mut || {
+        let args = unsafe { CallArgs::from_vp(vp, argc) };
+        let Some(signal) = signal_arg(ctx, &args, "offSignal") else {
+            is_okay = false;
+            return;
+        };
+        let handler = args.get(1);
+        if argc >= 2 && handler.is_object() {
+            let handler = handler.to_object();
+            HANDLERS.with(|h| {
+                if let Some(list) = h.borrow_mut().get_mut(&signal) {
+                    if let Some(index) = list.iter().position(|h| h.callback.get() == handler) {
+                        list.remove(index);
+                    }
+                }
+            });
+        }
+        args.rval().set(UndefinedValue());
+    });
+    is_okay
+}
+
+/// `signal(name)`, resolves to `name` the next time it is delivered
+pub(crate) unsafe extern "C" fn wait_for_signal(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    let mut is_okay = true;
+    wrap_panic(&mut || {
+        let args = unsafe { CallArgs::from_vp(vp, argc) };
+        let Some(signal) = signal_arg(ctx, &args, "signal") else {
+            is_okay = false;
+            return;
+        };
+        if let Err(e) = listen(signal) {
+            is_okay = throw_io_error(ctx, &e);
+            return;
+        }
+        let Some((promise_id, promise)) = new_pending_promise(ctx, "signal") else {
+            is_okay = false;
+            return;
+        };
+        let (sender, receiver) = oneshot::channel();
+        WAITERS.with(|w| w.borrow_mut().entry(signal).or_default().push(sender));
+        args.rval().set(ObjectValue(promise));
+        // the listener is unref'd, this is what keeps the loop alive
+        let name = signal_name(signal).unwrap_or("unknown");
+        promise_span(promise_id).in_scope(|| {
+            Bridge::new(async move { (promise_id, receiver.await.map(|_| name).map_err(|_| ())) }, bridge_signal);
+        });
+    });
+    is_okay
+}
+
+fn bridge_signal(result: Result<&'static str,()>) -> ResolutionMarshalling {
+    Box::new(move |realm: &mut AutoRealm, _promise: Handle<'_, *mut JSObject>, _global: Handle<'_, *mut JSObject>, ok: MutableHandle<'_,Value>, err: MutableHandle<'_,Value>| {
+        let cx = unsafe { realm.deref_mut().raw_cx() };
+        match result {
+            Ok(name) => unsafe { name.to_jsval(cx, ok) },
+            Err(()) => { new_error(cx, "signal listener was dropped", None, err); },
+        };
+    }) as ResolutionMarshalling
+}
+
+#[cfg(test)]
+mod tests {
+    use mozjs::{rooted};
+    use crate::runtime::testing::{with_runtime,new_test_global,eval,eval_async};
+
+    /// Runs `setup`, raises `signal` & runs `body`
+    ///
+    /// Each test raises its own signal, waiters are in place before it's
+    /// raised so a delivery never falls to the default policy.
+    fn raise_between(signal: libc::c_int, setup: &str, body: &str) -> Result<String,String> {
+        with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            eval(ctx, global.handle(), setup)?;
+            assert_eq!(unsafe { libc::raise(signal) }, 0);
+            eval_async(ctx, global.handle(), body)
+        })
+    }
+
+    #[test]
+    fn handlers_and_waiters_see_a_delivery() {
+        let got = raise_between(libc::SIGUSR1, r#"
+            globalThis.seen = [];
+            onSignal('SIGUSR1', (name) => seen.push(name));
+            globalThis.next = signal('SIGUSR1');
+        "#, r#"
+            return [await next, ...seen].join();
+        "#);
+        assert_eq!(got.as_deref(), Ok("SIGUSR1,SIGUSR1"));
+    }
+
+    #[test]
+    fn removed_handlers_are_not_called() {
+        let got = raise_between(libc::SIGUSR2, r#"
+            globalThis.seen = [];
+            const first = () => seen.push('first');
+            onSignal('SIGUSR2', first);
+            onSignal('SIGUSR2', () => seen.push('second'));
+            offSignal('SIGUSR2', first);
+            globalThis.next = signal('SIGUSR2');
+        "#, r#"
+            await next;
+            return seen.join();
+        "#);
+        assert_eq!(got.as_deref(), Ok("second"));
+    }
+
+    #[test]
+    fn unsupported_signals_throw() {
+        let got = with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            eval(ctx, global.handle(), "try { signal('SIGKILL'); 'waiting' } catch (e) { e.message }")
+        });
+        assert_eq!(got.as_deref(), Ok("signal: unsupported signal 'SIGKILL'"));
+    }
+}
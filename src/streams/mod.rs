@@ -0,0 +1,303 @@
+use std::{
+    cell::{RefCell,LazyCell},
+    collections::{BTreeMap},
+    ops::{DerefMut},
+};
+use mozjs::{rooted};
+use mozjs::{
+    context::{RawJSContext},
+    error::{throw_type_error},
+    gc::{Handle},
+    jsapi::{JSObject,JSTracer,Value,CallArgs,Heap,CurrentGlobalOrNull},
+    jsval::{UndefinedValue,ObjectValue},
+    realm::{AutoRealm},
+    rust::wrappers2::JS_DefineFunction,
+    panic::wrap_panic,
+};
+#[allow(unused_imports)]
+use tracing::{debug,trace,instrument,warn,error,info};
+
+use crate::{
+    realm::{evaluate,realm_id},
+    runtime::{
+        call::{call_value,is_callable},
+        conversions::{new_object,define_property},
+        exception::{report_pending_exception},
+        roots::{trace_object},
+    },
+};
+
+/*
+ * WHATWG streams
+ *
+ * `ReadableStream`, `WritableStream`, `TransformStream` & the queuing
+ * strategies are plain JS (`prelude.js`).
+ *
+ * Every byte stream (see `byte_stream`) made in a realm with the
+ * prelude gets `readable` and/or `writable` properties through the
+ * hook the prelude registers with `setByteStreamHook`. The readable
+ * side has a high water mark of zero & only calls `read()` when
+ * pulled, so the underlying `AsyncRead` isn't polled while the
+ * consumer is behind; the writable side waits for each `write()` to
+ * finish before the next.
+ *
+ * Hooks are kept by realm id & rooted until `forget_byte_stream_hook`,
+ * which a realm's owner calls once it's done with it.
+ *
+ */
+
+const PRELUDE: &str = include_str!("prelude.js");
+
+thread_local! {
+    /// the prelude's `attachViews(stream)`, by realm id
+    static BYTE_STREAM_HOOKS: LazyCell<RefCell<BTreeMap<u64,Box<Heap<*mut JSObject>>>>> = LazyCell::new(|| RefCell::new(BTreeMap::new()));
+}
+
+/// Reports every hook to the GC, see `roots`
+pub(crate) unsafe fn trace_byte_stream_hooks(trc: *mut JSTracer) {
+    BYTE_STREAM_HOOKS.with(|h| {
+        for hook in h.borrow().values() {
+            unsafe { trace_object(trc, hook, c"byte stream hook") };
+        }
+    });
+}
+
+/// Drops realm `id`'s hook, its byte streams get no views from then on
+pub(crate) fn forget_byte_stream_hook(id: u64) {
+    BYTE_STREAM_HOOKS.with(|h| h.borrow_mut().remove(&id));
+}
+
+/// Defines the stream classes on `global_obj`, must come before `define_fetch`
+pub fn define_streams(realm: &mut AutoRealm, global_obj: Handle<'_,*mut JSObject>) {
+    let cx = unsafe { realm.deref_mut().raw_cx() };
+    rooted!(in(cx) let native = new_object(cx));
+    if native.get().is_null() {
+        error!("could not allocate streams natives");
+        return;
+    }
+    unsafe {
+        JS_DefineFunction(realm,native.handle(),c"setByteStreamHook".as_ptr(),Some(set_byte_stream_hook),1,0,);
+    }
+    rooted!(in(cx) let native_val = ObjectValue(native.get()));
+    define_property(cx, global_obj, c"__streamsNative", native_val.handle());
+    if evaluate(realm, global_obj, "streams.js", PRELUDE).is_err() {
+        error!("could not evaluate the streams prelude");
+    }
+}
+
+/// `native.setByteStreamHook(fn)`, replaces the current realm's hook
+unsafe extern "C" fn set_byte_stream_hook(ctx: *mut RawJSContext, argc: u32, vp: *mut Value) -> bool {
+    let mut is_okay = true;
+    wrap_panic(&mut || {
+        let args = unsafe { CallArgs::from_vp(vp, argc) };
+        let hook = unsafe { Handle::from_raw(args.get(0)) };
+        if argc < 1 || !is_callable(hook) {
+            unsafe { throw_type_error(ctx, "setByteStreamHook: hook must be a function") };
+            is_okay = false;
+            return;
+        }
+        let Some(id) = realm_id(ctx) else {
+            unsafe { throw_type_error(ctx, "setByteStreamHook: not within a realm") };
+            is_okay = false;
+            return;
+        };
+        BYTE_STREAM_HOOKS.with(|h| h.borrow_mut().insert(id, Heap::boxed(hook.to_object())));
+        args.rval().set(UndefinedValue());
+    });
+    is_okay
+}
+
+/// Gives a new byte stream its `readable`/`writable` views
+///
+/// Does nothing in a realm without the prelude. A throwing hook is
+/// reported & the stream is left without views.
+pub(crate) fn attach_stream_views(cx: *mut RawJSContext, obj: Handle<'_,*mut JSObject>) {
+    let Some(hook) = realm_id(cx).and_then(|id| BYTE_STREAM_HOOKS.with(|h| {
+        h.borrow().get(&id).map(|hook| hook.get())
+    })) else {
+        return;
+    };
+    rooted!(in(cx) let global = unsafe { CurrentGlobalOrNull(cx) });
+    rooted!(in(cx) let hook = ObjectValue(hook));
+    rooted!(in(cx) let mut rval = UndefinedValue());
+    if !call_value(cx, global.handle(), hook.handle(), [ObjectValue(obj.get())], rval.handle_mut()) {
+        report_pending_exception(cx, "streams.js");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{VecDeque},
+        io,
+        ops::{DerefMut},
+        pin::{Pin},
+        sync::{Arc,Mutex,atomic::{AtomicBool,AtomicUsize,Ordering}},
+        task::{Context,Poll},
+    };
+    use tokio::io::{AsyncRead,AsyncWrite,ReadBuf};
+    use mozjs::{rooted};
+    use mozjs::{
+        context::{JSContext},
+        gc::{Handle},
+        jsapi::{JSObject},
+        jsval::{ObjectValue},
+    };
+    use crate::{
+        byte_stream::{ByteStreamParts,new_byte_stream},
+        runtime::{
+            conversions::{define_property},
+            incumbent_stack::{enter_incumbent_stack},
+            testing::{with_runtime,new_test_global,eval_async},
+        },
+    };
+
+    /// Hands out one chunk per read, counting reads & noting its drop
+    struct Source {
+        chunks: VecDeque<io::Result<Vec<u8>>>,
+        reads: Arc<AtomicUsize>,
+        dropped: Arc<AtomicBool>,
+    }
+    impl AsyncRead for Source {
+        fn poll_read(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            match self.chunks.pop_front() {
+                Some(Ok(chunk)) => buf.put_slice(&chunk),
+                Some(Err(e)) => return Poll::Ready(Err(e)),
+                None => {}
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+    impl Drop for Source {
+        fn drop(&mut self) {
+            self.dropped.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Keeps what's written, or fails every write
+    struct Sink {
+        written: Arc<Mutex<Vec<u8>>>,
+        fail: bool,
+    }
+    impl AsyncWrite for Sink {
+        fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            if self.fail {
+                return Poll::Ready(Err(io::Error::from_raw_os_error(libc::EPIPE)));
+            }
+            self.written.lock().unwrap().extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn source(chunks: Vec<io::Result<Vec<u8>>>) -> (Source, Arc<AtomicUsize>, Arc<AtomicBool>) {
+        let reads = Arc::new(AtomicUsize::new(0));
+        let dropped = Arc::new(AtomicBool::new(false));
+        (Source { chunks: chunks.into(), reads: reads.clone(), dropped: dropped.clone() }, reads, dropped)
+    }
+
+    /// Makes `globalThis.stream` from `parts`, views attached by the prelude's hook
+    fn define_stream(ctx: &mut JSContext, global: Handle<'_,*mut JSObject>, parts: ByteStreamParts) {
+        enter_incumbent_stack(ctx, global, |realm, global_obj| {
+            let cx = unsafe { realm.deref_mut().raw_cx() };
+            rooted!(in(cx) let stream = ObjectValue(new_byte_stream(cx, parts)));
+            define_property(cx, global_obj, c"stream", stream.handle());
+        });
+    }
+
+    #[test]
+    fn reads_wait_for_the_consumer() {
+        let chunks = (0..100).map(|i| Ok(vec![i])).collect();
+        let (source, reads, _) = source(chunks);
+        let got = with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            define_stream(ctx, global.handle(), ByteStreamParts::new(Some(Box::new(source)), None));
+            eval_async(ctx, global.handle(), r#"
+                const reader = stream.readable.getReader();
+                const { value } = await reader.read();
+                // time enough for any read ahead to happen
+                await sleep_ms(50);
+                return value[0];
+            "#)
+        });
+        assert_eq!(got.as_deref(), Ok("0"));
+        assert_eq!(reads.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn the_stream_closes_once_both_sides_are_done() {
+        let (source, _, dropped) = source(vec![Ok(b"ping".to_vec())]);
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let sink = Sink { written: written.clone(), fail: false };
+        with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            define_stream(ctx, global.handle(), ByteStreamParts::new(Some(Box::new(source)), Some(Box::new(sink))));
+            let read = eval_async(ctx, global.handle(), r#"
+                const reader = stream.readable.getReader();
+                let text = '';
+                for (let chunk = await reader.read(); !chunk.done; chunk = await reader.read()) {
+                    text += String.fromCharCode(...chunk.value);
+                }
+                return text;
+            "#);
+            assert_eq!(read.as_deref(), Ok("ping"));
+            assert!(!dropped.load(Ordering::SeqCst), "closed while still writable");
+
+            let wrote = eval_async(ctx, global.handle(), r#"
+                const writer = stream.writable.getWriter();
+                await writer.write('pong');
+                await writer.close();
+                return 'closed';
+            "#);
+            assert_eq!(wrote.as_deref(), Ok("closed"));
+            assert!(dropped.load(Ordering::SeqCst), "still open with both sides done");
+        });
+        assert_eq!(&*written.lock().unwrap(), b"pong");
+    }
+
+    #[test]
+    fn read_errors_reject_and_close() {
+        let (source, _, dropped) = source(vec![Ok(b"ping".to_vec()), Err(io::Error::from_raw_os_error(libc::ECONNRESET))]);
+        let got = with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            define_stream(ctx, global.handle(), ByteStreamParts::new(Some(Box::new(source)), None));
+            eval_async(ctx, global.handle(), r#"
+                const reader = stream.readable.getReader();
+                await reader.read();
+                try {
+                    await reader.read();
+                    return 'read';
+                } catch (e) {
+                    return e.code;
+                }
+            "#)
+        });
+        assert_eq!(got.as_deref(), Ok("ECONNRESET"));
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn write_errors_reject() {
+        let sink = Sink { written: Arc::new(Mutex::new(Vec::new())), fail: true };
+        let got = with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            define_stream(ctx, global.handle(), ByteStreamParts::new(None, Some(Box::new(sink))));
+            eval_async(ctx, global.handle(), r#"
+                const writer = stream.writable.getWriter();
+                try {
+                    await writer.write('ping');
+                    return 'written';
+                } catch (e) {
+                    return e.code;
+                }
+            "#)
+        });
+        assert_eq!(got.as_deref(), Ok("EPIPE"));
+    }
+}
@@ -0,0 +1,1374 @@
+// `ReadableStream`, `WritableStream`, `TransformStream` & the queuing
+// strategies
+//
+// Evaluated once per realm by `streams::define_streams`, before the
+// fetch prelude. `__streamsNative` is removed from the global before
+// any user script runs; `__streamsInternal` is picked up & removed by
+// the fetch prelude.
+(function (native) {
+    'use strict';
+
+    const kState = Symbol('state');
+    const kCreate = Symbol('create');
+    const kClose = Symbol('close');
+    const noop = () => {};
+
+    // --- helpers ----------------------------------------------------------
+
+    function withResolvers() {
+        let resolve, reject;
+        const promise = new Promise((res, rej) => { resolve = res; reject = rej; });
+        return { promise, resolve, reject };
+    }
+
+    // a promise which remembers if it settled, rejections are marked handled
+    function deferred() {
+        const d = withResolvers();
+        const { resolve, reject } = d;
+        d.settled = false;
+        d.resolve = (value) => { d.settled = true; resolve(value); };
+        d.reject = (e) => { d.settled = true; d.promise.catch(noop); reject(e); };
+        return d;
+    }
+
+    // calls `fn`, turning a synchronous throw into a rejection
+    function invoke(fn, ...args) {
+        try {
+            return Promise.resolve(fn(...args));
+        } catch (e) {
+            return Promise.reject(e);
+        }
+    }
+
+    // `source[name]` bound to `source`, or a no-op
+    function method(source, name) {
+        const fn = source[name];
+        if (fn === undefined) {
+            return noop;
+        }
+        if (typeof fn !== 'function') {
+            throw new TypeError(`${name} must be a function`);
+        }
+        return (...args) => fn.apply(source, args);
+    }
+
+    function extractStrategy(strategy, defaultHwm) {
+        strategy ??= {};
+        const hwm = strategy.highWaterMark === undefined ? defaultHwm : Number(strategy.highWaterMark);
+        if (Number.isNaN(hwm) || hwm < 0) {
+            throw new RangeError('Invalid highWaterMark');
+        }
+        const size = strategy.size;
+        if (size !== undefined && typeof size !== 'function') {
+            throw new TypeError('size must be a function');
+        }
+        return { hwm, size: size === undefined ? () => 1 : (chunk) => size.call(undefined, chunk) };
+    }
+
+    // chunks with their sizes, as measured by the strategy
+    class Queue {
+        items = [];
+        total = 0;
+
+        get length() { return this.items.length; }
+
+        push(value, size) {
+            if (!Number.isFinite(size) || size < 0) {
+                throw new RangeError('Invalid chunk size');
+            }
+            this.items.push({ value, size });
+            this.total += size;
+        }
+
+        peek() {
+            return this.items[0].value;
+        }
+
+        shift() {
+            const { value, size } = this.items.shift();
+            this.total = Math.max(0, this.total - size);
+            return value;
+        }
+
+        reset() {
+            this.items = [];
+            this.total = 0;
+        }
+    }
+
+    // --- ReadableStream -----------------------------------------------------
+
+    function createReadable(start, pull, cancel, hwm, size) {
+        const stream = Object.create(ReadableStream.prototype);
+        initReadable(stream);
+        setUpReadable(stream, start, pull, cancel, hwm, size);
+        return stream;
+    }
+
+    function initReadable(stream) {
+        stream[kState] = { state: 'readable', reader: null, error: undefined, disturbed: false, controller: null };
+    }
+
+    function setUpReadable(stream, start, pull, cancel, hwm, size) {
+        const controller = new ReadableStreamDefaultController(kCreate);
+        const c = {
+            controller, stream, queue: new Queue(), hwm, size, pull, cancel,
+            started: false, pulling: false, pullAgain: false, closeRequested: false,
+        };
+        controller[kState] = c;
+        stream[kState].controller = c;
+        Promise.resolve(start(controller)).then(() => {
+            c.started = true;
+            pullIfNeeded(c);
+        }, (e) => controllerError(c, e));
+    }
+
+    function readableClose(stream) {
+        const st = stream[kState];
+        st.state = 'closed';
+        if (st.reader !== null) {
+            const r = st.reader[kState];
+            for (const request of r.requests.splice(0)) {
+                request.close();
+            }
+            r.closed.resolve();
+        }
+    }
+
+    function readableError(stream, e) {
+        const st = stream[kState];
+        st.state = 'errored';
+        st.error = e;
+        if (st.reader !== null) {
+            const r = st.reader[kState];
+            r.closed.reject(e);
+            for (const request of r.requests.splice(0)) {
+                request.error(e);
+            }
+        }
+    }
+
+    function readableCancel(stream, reason) {
+        const st = stream[kState];
+        st.disturbed = true;
+        if (st.state === 'closed') {
+            return Promise.resolve();
+        }
+        if (st.state === 'errored') {
+            return Promise.reject(st.error);
+        }
+        readableClose(stream);
+        const c = st.controller;
+        c.queue.reset();
+        const cancel = c.cancel;
+        clearReadableAlgorithms(c);
+        return invoke(cancel, reason).then(noop);
+    }
+
+    // `request` is `{ chunk(value), close(), error(e) }`
+    function readableRead(stream, request) {
+        const st = stream[kState];
+        st.disturbed = true;
+        if (st.state === 'closed') {
+            request.close();
+        } else if (st.state === 'errored') {
+            request.error(st.error);
+        } else {
+            const c = st.controller;
+            if (c.queue.length > 0) {
+                const chunk = c.queue.shift();
+                if (c.closeRequested && c.queue.length === 0) {
+                    clearReadableAlgorithms(c);
+                    readableClose(stream);
+                } else {
+                    pullIfNeeded(c);
+                }
+                request.chunk(chunk);
+            } else {
+                st.reader[kState].requests.push(request);
+                pullIfNeeded(c);
+            }
+        }
+    }
+
+    function clearReadableAlgorithms(c) {
+        c.pull = noop;
+        c.cancel = noop;
+        c.size = () => 1;
+    }
+
+    function canCloseOrEnqueue(c) {
+        return !c.closeRequested && c.stream[kState].state === 'readable';
+    }
+
+    function readableDesiredSize(c) {
+        switch (c.stream[kState].state) {
+            case 'errored': return null;
+            case 'closed': return 0;
+            default: return c.hwm - c.queue.total;
+        }
+    }
+
+    function shouldCallPull(c) {
+        if (!canCloseOrEnqueue(c) || !c.started) {
+            return false;
+        }
+        const reader = c.stream[kState].reader;
+        if (reader !== null && reader[kState].requests.length > 0) {
+            return true;
+        }
+        return readableDesiredSize(c) > 0;
+    }
+
+    // the only place a source is asked for more, so a source which
+    // reads on `pull` stops reading while the consumer is behind
+    function pullIfNeeded(c) {
+        if (!shouldCallPull(c)) {
+            return;
+        }
+        if (c.pulling) {
+            c.pullAgain = true;
+            return;
+        }
+        c.pulling = true;
+        invoke(c.pull, c.controller).then(() => {
+            c.pulling = false;
+            if (c.pullAgain) {
+                c.pullAgain = false;
+                pullIfNeeded(c);
+            }
+        }, (e) => controllerError(c, e));
+    }
+
+    function controllerEnqueue(c, chunk) {
+        const reader = c.stream[kState].reader;
+        if (reader !== null && reader[kState].requests.length > 0) {
+            reader[kState].requests.shift().chunk(chunk);
+        } else {
+            try {
+                c.queue.push(chunk, c.size(chunk));
+            } catch (e) {
+                controllerError(c, e);
+                throw e;
+            }
+        }
+        pullIfNeeded(c);
+    }
+
+    function controllerClose(c) {
+        c.closeRequested = true;
+        if (c.queue.length === 0) {
+            clearReadableAlgorithms(c);
+            readableClose(c.stream);
+        }
+    }
+
+    function controllerError(c, e) {
+        if (c.stream[kState].state !== 'readable') {
+            return;
+        }
+        c.queue.reset();
+        clearReadableAlgorithms(c);
+        readableError(c.stream, e);
+    }
+
+    class ReadableStreamDefaultController {
+        constructor(token) {
+            if (token !== kCreate) {
+                throw new TypeError('Illegal constructor');
+            }
+        }
+
+        get desiredSize() {
+            return readableDesiredSize(this[kState]);
+        }
+
+        close() {
+            if (!canCloseOrEnqueue(this[kState])) {
+                throw new TypeError('The stream is not in a state that permits close');
+            }
+            controllerClose(this[kState]);
+        }
+
+        enqueue(chunk) {
+            if (!canCloseOrEnqueue(this[kState])) {
+                throw new TypeError('The stream is not in a state that permits enqueue');
+            }
+            controllerEnqueue(this[kState], chunk);
+        }
+
+        error(e) {
+            controllerError(this[kState], e);
+        }
+    }
+
+    class ReadableStreamDefaultReader {
+        constructor(stream) {
+            if (!(stream instanceof ReadableStream)) {
+                throw new TypeError('ReadableStreamDefaultReader needs a ReadableStream');
+            }
+            if (stream.locked) {
+                throw new TypeError('ReadableStream is locked');
+            }
+            const st = stream[kState];
+            const r = { stream, requests: [], closed: deferred() };
+            this[kState] = r;
+            st.reader = this;
+            if (st.state === 'closed') {
+                r.closed.resolve();
+            } else if (st.state === 'errored') {
+                r.closed.reject(st.error);
+            }
+        }
+
+        get closed() {
+            return this[kState].closed.promise;
+        }
+
+        read() {
+            const stream = this[kState].stream;
+            if (stream === null) {
+                return Promise.reject(new TypeError('Reader has been released'));
+            }
+            const { promise, resolve, reject } = withResolvers();
+            readableRead(stream, {
+                chunk: (value) => resolve({ value, done: false }),
+                close: () => resolve({ value: undefined, done: true }),
+                error: reject,
+            });
+            return promise;
+        }
+
+        cancel(reason) {
+            const stream = this[kState].stream;
+            if (stream === null) {
+                return Promise.reject(new TypeError('Reader has been released'));
+            }
+            return readableCancel(stream, reason);
+        }
+
+        releaseLock() {
+            const r = this[kState];
+            if (r.stream === null) {
+                return;
+            }
+            const e = new TypeError('Reader has been released');
+            if (r.closed.settled) {
+                r.closed = deferred();
+            }
+            r.closed.reject(e);
+            for (const request of r.requests.splice(0)) {
+                request.error(e);
+            }
+            r.stream[kState].reader = null;
+            r.stream = null;
+        }
+    }
+
+    function readableTee(stream) {
+        const reader = new ReadableStreamDefaultReader(stream);
+        const canceled = [false, false];
+        const reasons = [undefined, undefined];
+        const cancelled = withResolvers();
+        let reading = false;
+        let readAgain = false;
+        let branches = null;
+
+        const controllers = () => branches.map((b) => b[kState].controller);
+
+        function pull() {
+            if (reading) {
+                readAgain = true;
+                return Promise.resolve();
+            }
+            reading = true;
+            readableRead(stream, {
+                chunk(value) {
+                    queueMicrotask(() => {
+                        readAgain = false;
+                        controllers().forEach((c, i) => {
+                            if (!canceled[i]) {
+                                controllerEnqueue(c, value);
+                            }
+                        });
+                        reading = false;
+                        if (readAgain) {
+                            pull();
+                        }
+                    });
+                },
+                close() {
+                    reading = false;
+                    controllers().forEach((c, i) => {
+                        if (!canceled[i]) {
+                            controllerClose(c);
+                        }
+                    });
+                    cancelled.resolve();
+                },
+                error() {
+                    reading = false;
+                },
+            });
+            return Promise.resolve();
+        }
+
+        const cancel = (i) => (reason) => {
+            canceled[i] = true;
+            reasons[i] = reason;
+            if (canceled[1 - i]) {
+                cancelled.resolve(readableCancel(stream, reasons));
+            }
+            return cancelled.promise;
+        };
+
+        branches = [0, 1].map((i) => createReadable(noop, pull, cancel(i), 1, () => 1));
+        reader[kState].closed.promise.catch((e) => {
+            controllers().forEach((c) => controllerError(c, e));
+            cancelled.resolve();
+        });
+        return branches;
+    }
+
+    function readablePipeTo(source, dest, preventClose, preventAbort, preventCancel, signal) {
+        const reader = new ReadableStreamDefaultReader(source);
+        const writer = new WritableStreamDefaultWriter(dest);
+        source[kState].disturbed = true;
+        const { promise, resolve, reject } = withResolvers();
+        let shuttingDown = false;
+        let currentWrite = Promise.resolve();
+
+        function waitForWrites() {
+            const write = currentWrite;
+            return write.then(() => (write !== currentWrite ? waitForWrites() : undefined));
+        }
+
+        function finalize(isError, error) {
+            writer.releaseLock();
+            reader.releaseLock();
+            signal?.removeEventListener('abort', onAbort);
+            if (isError) {
+                reject(error);
+            } else {
+                resolve();
+            }
+        }
+
+        // waits for writes already started, runs `action` & finalizes
+        function shutdown(action, isError, error) {
+            if (shuttingDown) {
+                return;
+            }
+            shuttingDown = true;
+            const finish = () => {
+                if (action === null) {
+                    finalize(isError, error);
+                } else {
+                    action().then(() => finalize(isError, error), (e) => finalize(true, e));
+                }
+            };
+            const st = dest[kState];
+            if (st.state === 'writable' && !closeQueuedOrInFlight(st)) {
+                waitForWrites().then(finish);
+            } else {
+                finish();
+            }
+        }
+
+        function onAbort() {
+            const error = signal.reason;
+            const actions = [];
+            if (!preventAbort) {
+                actions.push(() => (dest[kState].state === 'writable' ? writableAbort(dest, error) : Promise.resolve()));
+            }
+            if (!preventCancel) {
+                actions.push(() => (source[kState].state === 'readable' ? readableCancel(source, error) : Promise.resolve()));
+            }
+            shutdown(() => Promise.all(actions.map((action) => action())), true, error);
+        }
+
+        function onDestClosed() {
+            const error = new TypeError('The destination stream closed');
+            shutdown(preventCancel ? null : () => readableCancel(source, error), true, error);
+        }
+
+        if (signal !== undefined) {
+            if (signal.aborted) {
+                onAbort();
+                return promise;
+            }
+            signal.addEventListener('abort', onAbort);
+        }
+
+        reader.closed.then(() => {
+            shutdown(preventClose ? null : () => writerCloseWithErrorPropagation(writer), false);
+        }, (e) => {
+            shutdown(preventAbort ? null : () => writableAbort(dest, e), true, e);
+        });
+        writer.closed.then(noop, (e) => {
+            shutdown(preventCancel ? null : () => readableCancel(source, e), true, e);
+        });
+        if (closeQueuedOrInFlight(dest[kState]) || dest[kState].state === 'closed') {
+            onDestClosed();
+        }
+
+        (async () => {
+            while (!shuttingDown) {
+                // backpressure, nothing is read while the destination is full
+                await writer.ready;
+                if (shuttingDown) {
+                    return;
+                }
+                const { value, done } = await reader.read();
+                if (done) {
+                    return;
+                }
+                currentWrite = writer.write(value).catch(noop);
+            }
+        })().catch(noop);
+        return promise;
+    }
+
+    class ReadableStream {
+        constructor(source = undefined, strategy = undefined) {
+            source ??= {};
+            if (source.type !== undefined) {
+                throw new RangeError(String(source.type) === 'bytes'
+                    ? 'Byte sources are not supported'
+                    : `Invalid type '${source.type}'`);
+            }
+            const { hwm, size } = extractStrategy(strategy, 1);
+            initReadable(this);
+            setUpReadable(this, method(source, 'start'), method(source, 'pull'), method(source, 'cancel'), hwm, size);
+        }
+
+        get locked() {
+            return this[kState].reader !== null;
+        }
+
+        cancel(reason) {
+            if (this.locked) {
+                return Promise.reject(new TypeError('ReadableStream is locked'));
+            }
+            return readableCancel(this, reason);
+        }
+
+        getReader(options = undefined) {
+            if (options?.mode !== undefined) {
+                throw new RangeError(String(options.mode) === 'byob'
+                    ? 'BYOB readers are not supported'
+                    : `Invalid mode '${options.mode}'`);
+            }
+            return new ReadableStreamDefaultReader(this);
+        }
+
+        pipeThrough(transform, options = undefined) {
+            if (this.locked) {
+                throw new TypeError('ReadableStream is locked');
+            }
+            if (transform.writable.locked) {
+                throw new TypeError('WritableStream is locked');
+            }
+            this.pipeTo(transform.writable, options).catch(noop);
+            return transform.readable;
+        }
+
+        pipeTo(dest, options = undefined) {
+            if (!(dest instanceof WritableStream)) {
+                return Promise.reject(new TypeError('pipeTo needs a WritableStream'));
+            }
+            if (this.locked) {
+                return Promise.reject(new TypeError('ReadableStream is locked'));
+            }
+            if (dest.locked) {
+                return Promise.reject(new TypeError('WritableStream is locked'));
+            }
+            options ??= {};
+            return readablePipeTo(this, dest, !!options.preventClose, !!options.preventAbort, !!options.preventCancel, options.signal);
+        }
+
+        tee() {
+            return readableTee(this);
+        }
+
+        values(options = undefined) {
+            const preventCancel = !!options?.preventCancel;
+            const reader = this.getReader();
+            let done = false;
+            return {
+                async next() {
+                    if (done) {
+                        return { value: undefined, done: true };
+                    }
+                    let result;
+                    try {
+                        result = await reader.read();
+                    } catch (e) {
+                        done = true;
+                        reader.releaseLock();
+                        throw e;
+                    }
+                    if (result.done) {
+                        done = true;
+                        reader.releaseLock();
+                    }
+                    return result;
+                },
+                async return(value) {
+                    if (!done) {
+                        done = true;
+                        const cancelled = preventCancel ? undefined : reader.cancel(value);
+                        reader.releaseLock();
+                        await cancelled;
+                    }
+                    return { value, done: true };
+                },
+                [Symbol.asyncIterator]() {
+                    return this;
+                },
+            };
+        }
+
+        [Symbol.asyncIterator](options = undefined) {
+            return this.values(options);
+        }
+
+        static from(iterable) {
+            let iterator;
+            if (typeof iterable?.[Symbol.asyncIterator] === 'function') {
+                iterator = iterable[Symbol.asyncIterator]();
+            } else if (typeof iterable?.[Symbol.iterator] === 'function') {
+                iterator = iterable[Symbol.iterator]();
+            } else {
+                throw new TypeError('ReadableStream.from needs an iterable');
+            }
+            return createReadable(noop, async (controller) => {
+                const { value, done } = await iterator.next();
+                if (done) {
+                    controller.close();
+                } else {
+                    controller.enqueue(await value);
+                }
+            }, async (reason) => {
+                await iterator.return?.(reason);
+            }, 0, () => 1);
+        }
+    }
+
+    // --- WritableStream -----------------------------------------------------
+
+    function createWritable(start, write, close, abort, hwm, size) {
+        const stream = Object.create(WritableStream.prototype);
+        initWritable(stream);
+        setUpWritable(stream, start, write, close, abort, hwm, size);
+        return stream;
+    }
+
+    function initWritable(stream) {
+        stream[kState] = {
+            state: 'writable', error: undefined, writer: null, controller: null, backpressure: false,
+            writeRequests: [], inFlightWrite: null, closeRequest: null, inFlightClose: null, pendingAbort: null,
+        };
+    }
+
+    function setUpWritable(stream, start, write, close, abort, hwm, size) {
+        const controller = new WritableStreamDefaultController(kCreate);
+        const c = {
+            controller, stream, queue: new Queue(), hwm, size, write, close, abort,
+            started: false, aborted: false, abortReason: undefined, abortController: null,
+        };
+        controller[kState] = c;
+        stream[kState].controller = c;
+        updateBackpressure(stream, writableDesiredSize(c) <= 0);
+        Promise.resolve(start(controller)).then(() => {
+            c.started = true;
+            advanceQueueIfNeeded(c);
+        }, (e) => {
+            c.started = true;
+            dealWithRejection(stream, e);
+        });
+    }
+
+    function closeQueuedOrInFlight(st) {
+        return st.closeRequest !== null || st.inFlightClose !== null;
+    }
+
+    function writableAbort(stream, reason) {
+        const st = stream[kState];
+        if (st.state === 'closed' || st.state === 'errored') {
+            return Promise.resolve();
+        }
+        const c = st.controller;
+        if (!c.aborted) {
+            c.aborted = true;
+            c.abortReason = reason;
+            c.abortController?.abort(reason);
+        }
+        if (st.state === 'closed' || st.state === 'errored') {
+            return Promise.resolve();
+        }
+        if (st.pendingAbort !== null) {
+            return st.pendingAbort.promise;
+        }
+        const wasAlreadyErroring = st.state === 'erroring';
+        st.pendingAbort = withResolvers();
+        st.pendingAbort.reason = wasAlreadyErroring ? undefined : reason;
+        st.pendingAbort.wasAlreadyErroring = wasAlreadyErroring;
+        const promise = st.pendingAbort.promise;
+        if (!wasAlreadyErroring) {
+            startErroring(stream, reason);
+        }
+        return promise;
+    }
+
+    function writableClose(stream) {
+        const st = stream[kState];
+        if (st.state === 'closed' || st.state === 'errored') {
+            return Promise.reject(new TypeError('The stream is closed or errored'));
+        }
+        st.closeRequest = withResolvers();
+        const promise = st.closeRequest.promise;
+        if (st.writer !== null && st.backpressure && st.state === 'writable') {
+            st.writer[kState].ready.resolve();
+        }
+        st.controller.queue.push(kClose, 0);
+        advanceQueueIfNeeded(st.controller);
+        return promise;
+    }
+
+    function dealWithRejection(stream, e) {
+        if (stream[kState].state === 'writable') {
+            startErroring(stream, e);
+        } else {
+            finishErroring(stream);
+        }
+    }
+
+    function startErroring(stream, reason) {
+        const st = stream[kState];
+        st.state = 'erroring';
+        st.error = reason;
+        if (st.writer !== null) {
+            writerEnsureReadyRejected(st.writer, reason);
+        }
+        if (st.inFlightWrite === null && st.inFlightClose === null && st.controller.started) {
+            finishErroring(stream);
+        }
+    }
+
+    function finishErroring(stream) {
+        const st = stream[kState];
+        const c = st.controller;
+        st.state = 'errored';
+        c.queue.reset();
+        for (const request of st.writeRequests.splice(0)) {
+            request.reject(st.error);
+        }
+        const abort = st.pendingAbort;
+        if (abort === null) {
+            rejectCloseAndClosed(stream);
+            return;
+        }
+        st.pendingAbort = null;
+        if (abort.wasAlreadyErroring) {
+            abort.reject(st.error);
+            rejectCloseAndClosed(stream);
+            return;
+        }
+        const abortSink = c.abort;
+        clearWritableAlgorithms(c);
+        invoke(abortSink, abort.reason).then(() => {
+            abort.resolve();
+            rejectCloseAndClosed(stream);
+        }, (e) => {
+            abort.reject(e);
+            rejectCloseAndClosed(stream);
+        });
+    }
+
+    function rejectCloseAndClosed(stream) {
+        const st = stream[kState];
+        if (st.closeRequest !== null) {
+            st.closeRequest.reject(st.error);
+            st.closeRequest = null;
+        }
+        if (st.writer !== null) {
+            st.writer[kState].closed.reject(st.error);
+        }
+    }
+
+    function updateBackpressure(stream, backpressure) {
+        const st = stream[kState];
+        if (st.writer !== null && backpressure !== st.backpressure) {
+            const w = st.writer[kState];
+            if (backpressure) {
+                w.ready = deferred();
+            } else {
+                w.ready.resolve();
+            }
+        }
+        st.backpressure = backpressure;
+    }
+
+    function clearWritableAlgorithms(c) {
+        c.write = noop;
+        c.close = noop;
+        c.abort = noop;
+        c.size = () => 1;
+    }
+
+    function writableDesiredSize(c) {
+        return c.hwm - c.queue.total;
+    }
+
+    function writableControllerError(c, e) {
+        if (c.stream[kState].state !== 'writable') {
+            return;
+        }
+        clearWritableAlgorithms(c);
+        startErroring(c.stream, e);
+    }
+
+    function controllerWrite(c, chunk, size) {
+        try {
+            c.queue.push(chunk, size);
+        } catch (e) {
+            writableControllerError(c, e);
+            return;
+        }
+        const st = c.stream[kState];
+        if (!closeQueuedOrInFlight(st) && st.state === 'writable') {
+            updateBackpressure(c.stream, writableDesiredSize(c) <= 0);
+        }
+        advanceQueueIfNeeded(c);
+    }
+
+    // hands the oldest queued chunk to the sink, one at a time
+    function advanceQueueIfNeeded(c) {
+        const stream = c.stream;
+        const st = stream[kState];
+        if (!c.started || st.inFlightWrite !== null) {
+            return;
+        }
+        if (st.state === 'erroring') {
+            finishErroring(stream);
+            return;
+        }
+        if (c.queue.length === 0) {
+            return;
+        }
+        if (c.queue.peek() === kClose) {
+            st.inFlightClose = st.closeRequest;
+            st.closeRequest = null;
+            c.queue.shift();
+            const close = c.close;
+            clearWritableAlgorithms(c);
+            invoke(close).then(() => finishInFlightClose(stream), (e) => finishInFlightCloseWithError(stream, e));
+            return;
+        }
+        st.inFlightWrite = st.writeRequests.shift();
+        invoke(c.write, c.queue.peek(), c.controller).then(() => {
+            st.inFlightWrite.resolve();
+            st.inFlightWrite = null;
+            c.queue.shift();
+            if (!closeQueuedOrInFlight(st) && st.state === 'writable') {
+                updateBackpressure(stream, writableDesiredSize(c) <= 0);
+            }
+            advanceQueueIfNeeded(c);
+        }, (e) => {
+            if (st.state === 'writable') {
+                clearWritableAlgorithms(c);
+            }
+            st.inFlightWrite.reject(e);
+            st.inFlightWrite = null;
+            dealWithRejection(stream, e);
+        });
+    }
+
+    function finishInFlightClose(stream) {
+        const st = stream[kState];
+        st.inFlightClose.resolve();
+        st.inFlightClose = null;
+        if (st.state === 'erroring') {
+            st.error = undefined;
+            if (st.pendingAbort !== null) {
+                st.pendingAbort.resolve();
+                st.pendingAbort = null;
+            }
+        }
+        st.state = 'closed';
+        if (st.writer !== null) {
+            st.writer[kState].closed.resolve();
+        }
+    }
+
+    function finishInFlightCloseWithError(stream, e) {
+        const st = stream[kState];
+        st.inFlightClose.reject(e);
+        st.inFlightClose = null;
+        if (st.pendingAbort !== null) {
+            st.pendingAbort.reject(e);
+            st.pendingAbort = null;
+        }
+        dealWithRejection(stream, e);
+    }
+
+    function writerEnsureReadyRejected(writer, e) {
+        const w = writer[kState];
+        if (w.ready.settled) {
+            w.ready = deferred();
+        }
+        w.ready.reject(e);
+    }
+
+    function writerCloseWithErrorPropagation(writer) {
+        const st = writer[kState].stream[kState];
+        if (closeQueuedOrInFlight(st) || st.state === 'closed') {
+            return Promise.resolve();
+        }
+        if (st.state === 'errored') {
+            return Promise.reject(st.error);
+        }
+        return writableClose(writer[kState].stream);
+    }
+
+    class WritableStreamDefaultController {
+        constructor(token) {
+            if (token !== kCreate) {
+                throw new TypeError('Illegal constructor');
+            }
+        }
+
+        // aborted when the stream is, for sinks with long running writes
+        get signal() {
+            const c = this[kState];
+            if (c.abortController === null) {
+                c.abortController = new globalThis.AbortController();
+                if (c.aborted) {
+                    c.abortController.abort(c.abortReason);
+                }
+            }
+            return c.abortController.signal;
+        }
+
+        error(e) {
+            writableControllerError(this[kState], e);
+        }
+    }
+
+    class WritableStreamDefaultWriter {
+        constructor(stream) {
+            if (!(stream instanceof WritableStream)) {
+                throw new TypeError('WritableStreamDefaultWriter needs a WritableStream');
+            }
+            if (stream.locked) {
+                throw new TypeError('WritableStream is locked');
+            }
+            const st = stream[kState];
+            const w = { stream, ready: deferred(), closed: deferred() };
+            this[kState] = w;
+            st.writer = this;
+            switch (st.state) {
+                case 'writable':
+                    if (closeQueuedOrInFlight(st) || !st.backpressure) {
+                        w.ready.resolve();
+                    }
+                    break;
+                case 'erroring':
+                    w.ready.reject(st.error);
+                    break;
+                case 'closed':
+                    w.ready.resolve();
+                    w.closed.resolve();
+                    break;
+                default:
+                    w.ready.reject(st.error);
+                    w.closed.reject(st.error);
+            }
+        }
+
+        get closed() {
+            return this[kState].closed.promise;
+        }
+
+        get ready() {
+            return this[kState].ready.promise;
+        }
+
+        get desiredSize() {
+            const stream = this[kState].stream;
+            if (stream === null) {
+                throw new TypeError('Writer has been released');
+            }
+            const st = stream[kState];
+            switch (st.state) {
+                case 'errored':
+                case 'erroring': return null;
+                case 'closed': return 0;
+                default: return writableDesiredSize(st.controller);
+            }
+        }
+
+        abort(reason) {
+            const stream = this[kState].stream;
+            if (stream === null) {
+                return Promise.reject(new TypeError('Writer has been released'));
+            }
+            return writableAbort(stream, reason);
+        }
+
+        close() {
+            const stream = this[kState].stream;
+            if (stream === null) {
+                return Promise.reject(new TypeError('Writer has been released'));
+            }
+            if (closeQueuedOrInFlight(stream[kState])) {
+                return Promise.reject(new TypeError('The stream is already closing'));
+            }
+            return writableClose(stream);
+        }
+
+        write(chunk) {
+            const stream = this[kState].stream;
+            if (stream === null) {
+                return Promise.reject(new TypeError('Writer has been released'));
+            }
+            const st = stream[kState];
+            const c = st.controller;
+            let size = 1;
+            try {
+                size = c.size(chunk);
+            } catch (e) {
+                writableControllerError(c, e);
+            }
+            if (st.state === 'errored' || st.state === 'erroring') {
+                return Promise.reject(st.error);
+            }
+            if (closeQueuedOrInFlight(st) || st.state === 'closed') {
+                return Promise.reject(new TypeError('The stream is closing or closed'));
+            }
+            const request = withResolvers();
+            st.writeRequests.push(request);
+            controllerWrite(c, chunk, size);
+            return request.promise;
+        }
+
+        releaseLock() {
+            const w = this[kState];
+            if (w.stream === null) {
+                return;
+            }
+            const e = new TypeError('Writer has been released');
+            writerEnsureReadyRejected(this, e);
+            if (w.closed.settled) {
+                w.closed = deferred();
+            }
+            w.closed.reject(e);
+            w.stream[kState].writer = null;
+            w.stream = null;
+        }
+    }
+
+    class WritableStream {
+        constructor(sink = undefined, strategy = undefined) {
+            sink ??= {};
+            if (sink.type !== undefined) {
+                throw new RangeError(`Invalid type '${sink.type}'`);
+            }
+            const { hwm, size } = extractStrategy(strategy, 1);
+            initWritable(this);
+            setUpWritable(this, method(sink, 'start'), method(sink, 'write'), method(sink, 'close'), method(sink, 'abort'), hwm, size);
+        }
+
+        get locked() {
+            return this[kState].writer !== null;
+        }
+
+        abort(reason) {
+            if (this.locked) {
+                return Promise.reject(new TypeError('WritableStream is locked'));
+            }
+            return writableAbort(this, reason);
+        }
+
+        close() {
+            if (this.locked) {
+                return Promise.reject(new TypeError('WritableStream is locked'));
+            }
+            if (closeQueuedOrInFlight(this[kState])) {
+                return Promise.reject(new TypeError('The stream is already closing'));
+            }
+            return writableClose(this);
+        }
+
+        getWriter() {
+            return new WritableStreamDefaultWriter(this);
+        }
+    }
+
+    // --- TransformStream ----------------------------------------------------
+
+    // `backpressure` is set while the readable side is full, writes
+    // wait on `backpressureChange` until it is pulled from
+    function setBackpressure(t, backpressure) {
+        t.backpressureChange?.resolve();
+        t.backpressureChange = withResolvers();
+        t.backpressure = backpressure;
+    }
+
+    function errorWritableAndUnblock(t, e) {
+        writableControllerError(t.writable[kState].controller, e);
+        if (t.backpressure) {
+            setBackpressure(t, false);
+        }
+    }
+
+    function transformError(t, e) {
+        controllerError(t.readable[kState].controller, e);
+        errorWritableAndUnblock(t, e);
+    }
+
+    function performTransform(t, chunk) {
+        return invoke(t.transform, chunk, t.controller).catch((e) => {
+            transformError(t, e);
+            throw e;
+        });
+    }
+
+    function transformSinkWrite(t, chunk) {
+        if (!t.backpressure) {
+            return performTransform(t, chunk);
+        }
+        return t.backpressureChange.promise.then(() => {
+            const st = t.writable[kState];
+            if (st.state === 'erroring') {
+                throw st.error;
+            }
+            return performTransform(t, chunk);
+        });
+    }
+
+    // shared by close, abort & cancel: whichever comes first wins
+    function transformFinish(t, action, onDone) {
+        t.finish ??= action().then(() => {
+            const st = t.readable[kState];
+            if (st.state === 'errored') {
+                throw st.error;
+            }
+            onDone();
+        }, (e) => {
+            transformError(t, e);
+            throw e;
+        });
+        return t.finish;
+    }
+
+    class TransformStreamDefaultController {
+        constructor(token) {
+            if (token !== kCreate) {
+                throw new TypeError('Illegal constructor');
+            }
+        }
+
+        get desiredSize() {
+            return readableDesiredSize(this[kState].readable[kState].controller);
+        }
+
+        enqueue(chunk) {
+            const t = this[kState];
+            const c = t.readable[kState].controller;
+            if (!canCloseOrEnqueue(c)) {
+                throw new TypeError('The readable side is not in a state that permits enqueue');
+            }
+            try {
+                controllerEnqueue(c, chunk);
+            } catch (e) {
+                errorWritableAndUnblock(t, e);
+                throw t.readable[kState].error;
+            }
+            const backpressure = !shouldCallPull(c);
+            if (backpressure !== t.backpressure) {
+                setBackpressure(t, true);
+            }
+        }
+
+        error(e) {
+            transformError(this[kState], e);
+        }
+
+        terminate() {
+            const t = this[kState];
+            const c = t.readable[kState].controller;
+            if (canCloseOrEnqueue(c)) {
+                controllerClose(c);
+            }
+            errorWritableAndUnblock(t, new TypeError('TransformStream terminated'));
+        }
+    }
+
+    class TransformStream {
+        constructor(transformer = undefined, writableStrategy = undefined, readableStrategy = undefined) {
+            transformer ??= {};
+            if (transformer.readableType !== undefined || transformer.writableType !== undefined) {
+                throw new RangeError('Invalid transformer type');
+            }
+            const writable = extractStrategy(writableStrategy, 1);
+            const readable = extractStrategy(readableStrategy, 0);
+            const controller = new TransformStreamDefaultController(kCreate);
+            const started = withResolvers();
+            const t = {
+                controller, readable: null, writable: null, backpressure: false, backpressureChange: null, finish: null,
+                transform: transformer.transform === undefined
+                    ? (chunk) => controller.enqueue(chunk)
+                    : method(transformer, 'transform'),
+                flush: method(transformer, 'flush'),
+                cancel: method(transformer, 'cancel'),
+            };
+            controller[kState] = t;
+            this[kState] = t;
+
+            t.writable = createWritable(() => started.promise, (chunk) => transformSinkWrite(t, chunk), () => {
+                return transformFinish(t, () => invoke(t.flush, controller), () => {
+                    const c = t.readable[kState].controller;
+                    if (canCloseOrEnqueue(c)) {
+                        controllerClose(c);
+                    }
+                });
+            }, (reason) => {
+                return transformFinish(t, () => invoke(t.cancel, reason), () => {
+                    controllerError(t.readable[kState].controller, reason);
+                });
+            }, writable.hwm, writable.size);
+            t.readable = createReadable(() => started.promise, () => {
+                setBackpressure(t, false);
+                return t.backpressureChange.promise;
+            }, (reason) => {
+                return transformFinish(t, () => invoke(t.cancel, reason), () => {
+                    errorWritableAndUnblock(t, reason);
+                });
+            }, readable.hwm, readable.size);
+            setBackpressure(t, true);
+            started.resolve(method(transformer, 'start')(controller));
+        }
+
+        get readable() {
+            return this[kState].readable;
+        }
+
+        get writable() {
+            return this[kState].writable;
+        }
+    }
+
+    // --- queuing strategies ---------------------------------------------------
+
+    const countSize = () => 1;
+    const byteLengthSize = (chunk) => chunk.byteLength;
+
+    class CountQueuingStrategy {
+        #highWaterMark;
+
+        constructor(init) {
+            this.#highWaterMark = Number(init.highWaterMark);
+        }
+
+        get highWaterMark() { return this.#highWaterMark; }
+        get size() { return countSize; }
+    }
+
+    class ByteLengthQueuingStrategy {
+        #highWaterMark;
+
+        constructor(init) {
+            this.#highWaterMark = Number(init.highWaterMark);
+        }
+
+        get highWaterMark() { return this.#highWaterMark; }
+        get size() { return byteLengthSize; }
+    }
+
+    // --- native byte streams ----------------------------------------------------
+
+    // Gives a rust byte stream `readable` and/or `writable` views. The
+    // readable side only reads when pulled, so rust stops polling the
+    // source while the consumer is behind. The native stream is closed
+    // once every side it has is done with.
+    function attachViews(stream) {
+        const hasRead = typeof stream.read === 'function';
+        const hasWrite = typeof stream.write === 'function';
+        let open = hasRead + hasWrite;
+        const release = () => {
+            if (--open === 0) {
+                stream.close();
+            }
+        };
+        if (hasRead) {
+            let done = false;
+            const finish = () => {
+                if (!done) {
+                    done = true;
+                    release();
+                }
+            };
+            const readable = createReadable(noop, async (controller) => {
+                let chunk;
+                try {
+                    chunk = await stream.read();
+                } catch (e) {
+                    finish();
+                    throw e;
+                }
+                if (chunk === null) {
+                    finish();
+                    controller.close();
+                } else {
+                    controller.enqueue(chunk);
+                }
+            }, finish, 0, () => 1);
+            Object.defineProperty(stream, 'readable', { value: readable, enumerable: true });
+        }
+        if (hasWrite) {
+            let done = false;
+            const finish = () => {
+                if (!done) {
+                    done = true;
+                    release();
+                }
+            };
+            const writable = createWritable(noop, (chunk) => stream.write(chunk).catch((e) => {
+                finish();
+                throw e;
+            }), async () => {
+                try {
+                    await stream.shutdown();
+                } finally {
+                    finish();
+                }
+            }, finish, 1, () => 1);
+            Object.defineProperty(stream, 'writable', { value: writable, enumerable: true });
+        }
+    }
+
+    native.setByteStreamHook(attachViews);
+
+    const classes = {
+        ReadableStream,
+        ReadableStreamDefaultReader,
+        ReadableStreamDefaultController,
+        WritableStream,
+        WritableStreamDefaultWriter,
+        WritableStreamDefaultController,
+        TransformStream,
+        TransformStreamDefaultController,
+        CountQueuingStrategy,
+        ByteLengthQueuingStrategy,
+    };
+    for (const [name, value] of Object.entries(classes)) {
+        Object.defineProperty(globalThis, name, { value, writable: true, configurable: true, enumerable: false });
+    }
+    Object.defineProperty(globalThis, '__streamsInternal', {
+        value: { isDisturbed: (stream) => stream[kState].disturbed },
+        configurable: true,
+    });
+})(globalThis.__streamsNative);
+delete globalThis.__streamsNative;
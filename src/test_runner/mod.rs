@@ -0,0 +1,277 @@
+use std::{
+    io::{self,Write as _},
+    ops::{DerefMut},
+    path::{Path,PathBuf},
+    ptr::{null_mut},
+    time::{Duration,Instant},
+};
+use mozjs::{rooted};
+use mozjs::{
+    context::{JSContext,RawJSContext},
+    gc::{Handle},
+    jsapi::{JSObject,PromiseState,GetPromiseState,GetPromiseResult,JS_IsExceptionPending,JS_ClearPendingException},
+    jsval::{UndefinedValue,ObjectValue,Int32Value},
+    realm::{AutoRealm},
+    rust::{CompileOptionsWrapper,evaluate_script,wrappers::{JS_GetPendingException}},
+};
+#[allow(unused_imports)]
+use tracing::{debug,trace,instrument,warn,error,info};
+
+use crate::{
+    globals::{define_globals},
+    realm::{new_global,realm_id_of,forget_realm},
+    runtime::{
+        call::{call_method},
+        checkpoint::{run_until},
+        conversions::{new_object,define_property,get_option,value_to_strings},
+        coverage::{collect_realm_coverage},
+        exception::{describe_exception},
+        incumbent_stack::{enter_incumbent_stack},
+        source_map::{register_source},
+    },
+    streams::{forget_byte_stream_hook},
+};
+
+mod report;
+
+use report::{Failure,TestResult};
+
+/*
+ * Test runner
+ *
+ * `async-demo test [paths]` runs every `*.test.js` under `paths` (the
+ * working directory by default). Each file gets a fresh realm with the
+ * usual globals plus `test(name, fn)`, `describe(name, fn)`,
+ * `beforeEach(fn)` & `assert`, all plain JS (`prelude.js`).
+ *
+ * Once a file has been evaluated its tests run one after another: the
+ * prelude starts a test (its `beforeEach` hooks, then the test itself)
+ * & returns a promise, the event loop is then driven until it settles
+ * or the test's time is up. Work a test leaves behind (e.g. a timer)
+ * keeps running alongside later tests but never holds up the run.
+ *
+ */
+
+const PRELUDE: &str = include_str!("prelude.js");
+/// Only `*.test.js` files are ran
+const SUFFIX: &str = ".test.js";
+
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum ReportFormat {
+    Tap,
+    Junit,
+}
+
+#[derive(Clone,Debug)]
+pub struct TestOptions {
+    pub format: ReportFormat,
+    /// for each test, its `beforeEach` hooks included
+    pub timeout: Duration,
+}
+impl Default for TestOptions {
+    fn default() -> Self {
+        TestOptions {
+            format: ReportFormat::Tap,
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Runs every test file under `paths` & prints the report, `true` if every test passed
+#[instrument(skip(ctx))]
+pub fn run_tests(ctx: &mut JSContext, paths: &[PathBuf], options: &TestOptions) -> bool {
+    let mut files = Vec::new();
+    let roots = if paths.is_empty() { vec![PathBuf::from(".")] } else { paths.to_vec() };
+    for root in &roots {
+        if let Err(e) = find_test_files(root, &mut files) {
+            error!("could not search '{}' for tests: {}", root.display(), e);
+        }
+    }
+    files.sort();
+    files.dedup();
+    info!("found '{}' test files", files.len());
+
+    let mut stdout = io::stdout();
+    if options.format == ReportFormat::Tap {
+        let _ = stdout.write_all(report::tap_header().as_bytes());
+    }
+    let mut results: Vec<TestResult> = Vec::new();
+    for file in &files {
+        run_file(ctx, file, options, &mut |result| {
+            results.push(result);
+            if options.format == ReportFormat::Tap {
+                let _ = stdout.write_all(report::tap_result(results.len(), results.last().unwrap()).as_bytes());
+                let _ = stdout.flush();
+            }
+        });
+    }
+    let report = match options.format {
+        ReportFormat::Tap => report::tap_footer(&results),
+        ReportFormat::Junit => report::junit(&results),
+    };
+    let _ = stdout.write_all(report.as_bytes());
+    let _ = stdout.flush();
+    results.iter().all(|r| r.failure.is_none())
+}
+
+/// `path` itself if it's a file, otherwise every `*.test.js` beneath it
+///
+/// Hidden directories & `node_modules` are skipped.
+fn find_test_files(path: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    if !path.is_dir() {
+        std::fs::metadata(path)?;
+        files.push(path.to_path_buf());
+        return Ok(());
+    }
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if entry.file_type()?.is_dir() {
+            if !name.starts_with('.') && name != "node_modules" {
+                find_test_files(&path, files)?;
+            }
+        } else if name.ends_with(SUFFIX) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Evaluates `path` in a new realm & runs its tests, handing each result to `report`
+#[instrument(skip(ctx, options, report), fields(file = %path.display()))]
+fn run_file(ctx: &mut JSContext, path: &Path, options: &TestOptions, report: &mut dyn FnMut(TestResult)) {
+    let file = path.display().to_string();
+    let started = Instant::now();
+    // a file that can't be ran is reported as one failed test
+    let file_failure = |message: String, stack: String| TestResult {
+        file: file.clone(),
+        name: "(file)".to_string(),
+        duration: started.elapsed(),
+        failure: Some(Failure { message, stack }),
+    };
+    let source = match std::fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) => return report(file_failure(format!("could not read the file: {}", e), String::new())),
+    };
+
+    rooted!(in(unsafe { ctx.raw_cx() }) let global = new_global(ctx));
+    if global.get().is_null() {
+        return report(file_failure("could not create a realm".to_string(), String::new()));
+    }
+    rooted!(in(unsafe { ctx.raw_cx() }) let mut harness = null_mut::<JSObject>());
+    let loaded = enter_incumbent_stack(ctx, global.handle(), |realm: &mut AutoRealm, global_obj: Handle<'_,*mut JSObject>| -> Result<Vec<String>,Failure> {
+        define_globals(realm, global_obj);
+        let cx = unsafe { realm.deref_mut().raw_cx() };
+        harness.set(new_object(cx));
+        if harness.get().is_null() {
+            return Err(Failure { message: "could not allocate the test harness".to_string(), stack: String::new() });
+        }
+        rooted!(in(cx) let harness_val = ObjectValue(harness.get()));
+        define_property(cx, global_obj, c"__testHarness", harness_val.handle());
+        evaluate_captured(realm, global_obj, "test.js", PRELUDE)?;
+        evaluate_captured(realm, global_obj, &file, &source)?;
+
+        rooted!(in(cx) let mut names = UndefinedValue());
+        if call_method(cx, harness.handle(), c"names", [], names.handle_mut()) != Ok(true) {
+            return Err(take_exception(cx));
+        }
+        value_to_strings(cx, names.handle())
+            .ok_or_else(|| Failure { message: "the harness returned no tests".to_string(), stack: String::new() })
+    });
+    match loaded {
+        Ok(names) => {
+            debug!("'{}' tests", names.len());
+            for (index, name) in names.into_iter().enumerate() {
+                let started = Instant::now();
+                let failure = run_test(ctx, global.handle(), harness.handle(), index, options.timeout);
+                report(TestResult { file: file.clone(), name, duration: started.elapsed(), failure });
+            }
+        }
+        Err(failure) => report(file_failure(failure.message, failure.stack)),
+    }
+    // work the file left behind keeps running, but new byte streams go
+    // without views & it's no longer covered
+    if let Some(id) = realm_id_of(global.get()) {
+        collect_realm_coverage(ctx, id);
+        forget_byte_stream_hook(id);
+        forget_realm(id);
+    }
+}
+
+/// Runs the `index`th test of the file, `None` if it passed
+fn run_test(
+    ctx: &mut JSContext,
+    global: Handle<'_,*mut JSObject>,
+    harness: Handle<'_,*mut JSObject>,
+    index: usize,
+    timeout: Duration,
+) -> Option<Failure> {
+    let deadline = Instant::now() + timeout;
+    rooted!(in(unsafe { ctx.raw_cx() }) let mut promise = null_mut::<JSObject>());
+    let started = enter_incumbent_stack(ctx, global, |realm: &mut AutoRealm, _: Handle<'_,*mut JSObject>| -> Result<(),Failure> {
+        let cx = unsafe { realm.deref_mut().raw_cx() };
+        rooted!(in(cx) let mut rval = UndefinedValue());
+        if call_method(cx, harness, c"run", [Int32Value(index as i32)], rval.handle_mut()) != Ok(true) || !rval.is_object() {
+            return Err(take_exception(cx));
+        }
+        promise.set(rval.to_object());
+        Ok(())
+    });
+    if let Err(failure) = started {
+        return Some(failure);
+    }
+
+    let settled = run_until(ctx, Some(deadline), || {
+        unsafe { GetPromiseState(promise.handle().into()) } != PromiseState::Pending || Instant::now() >= deadline
+    });
+    if unsafe { GetPromiseState(promise.handle().into()) } == PromiseState::Pending {
+        let message = match settled {
+            true => format!("timed out after {} ms", timeout.as_millis()),
+            false => "never finished, the event loop ran out of work".to_string(),
+        };
+        return Some(Failure { message, stack: String::new() });
+    }
+
+    // the harness never rejects, a failure is `{ error }`
+    enter_incumbent_stack(ctx, global, |realm: &mut AutoRealm, _: Handle<'_,*mut JSObject>| -> Option<Failure> {
+        let cx = unsafe { realm.deref_mut().raw_cx() };
+        rooted!(in(cx) let outcome = unsafe { GetPromiseResult(promise.handle().into()) });
+        if outcome.is_undefined() {
+            return None;
+        }
+        rooted!(in(cx) let mut error = UndefinedValue());
+        if !get_option(cx, outcome.handle(), c"error", error.handle_mut()) {
+            return Some(take_exception(cx));
+        }
+        let (message, stack) = describe_exception(cx, error.handle());
+        Some(Failure { message, stack })
+    })
+}
+
+/// Like `realm::evaluate`, but an uncaught exception is returned rather than logged
+fn evaluate_captured(realm: &mut AutoRealm, global_obj: Handle<'_,*mut JSObject>, filename: &str, source: &str) -> Result<(),Failure> {
+    let cx = unsafe { realm.deref_mut().raw_cx() };
+    register_source(cx, filename, source);
+    rooted!(&in(realm) let mut rval = UndefinedValue());
+    let options = CompileOptionsWrapper::new(realm, filename, 1);
+    evaluate_script(realm, global_obj, source, rval.handle_mut(), options)
+        .map_err(|()| take_exception(unsafe { realm.deref_mut().raw_cx() }))
+}
+
+/// Clears & describes the pending exception
+fn take_exception(cx: *mut RawJSContext) -> Failure {
+    unsafe {
+        if !JS_IsExceptionPending(cx) {
+            return Failure { message: "terminated without an exception".to_string(), stack: String::new() };
+        }
+        rooted!(in(cx) let mut exn = UndefinedValue());
+        let got = JS_GetPendingException(cx, exn.handle_mut());
+        JS_ClearPendingException(cx);
+        if !got {
+            return Failure { message: "an exception was thrown but could not be retrieved".to_string(), stack: String::new() };
+        }
+        let (message, stack) = describe_exception(cx, exn.handle());
+        Failure { message, stack }
+    }
+}
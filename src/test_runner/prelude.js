@@ -0,0 +1,262 @@
+(function (harness) {
+    'use strict';
+
+    // every `describe` being evaluated, outermost first; the root is the file
+    const root = { name: null, beforeEach: [] };
+    let scopes = [root];
+    const tests = [];
+    let started = false;
+
+    function show(value) {
+        switch (typeof value) {
+            case 'string':
+                return JSON.stringify(value);
+            case 'bigint':
+                return `${value}n`;
+            case 'symbol':
+                return value.toString();
+            case 'function':
+                return `[Function: ${value.name || '(anonymous)'}]`;
+            case 'object':
+                if (value === null) {
+                    return 'null';
+                }
+                if (value instanceof Error) {
+                    return `${value.name}: ${value.message}`;
+                }
+                try {
+                    return JSON.stringify(value) ?? String(value);
+                } catch {
+                    return Object.prototype.toString.call(value);
+                }
+            default:
+                return String(value);
+        }
+    }
+
+    function fullName(name) {
+        return scopes.slice(1).map((scope) => scope.name).concat(String(name)).join(' > ');
+    }
+
+    function callback(caller, fn) {
+        if (typeof fn !== 'function') {
+            throw new TypeError(`${caller}: expected a function, got ${show(fn)}`);
+        }
+        if (started) {
+            throw new Error(`${caller}: must be called while the file is evaluated`);
+        }
+        return fn;
+    }
+
+    function test(name, fn) {
+        tests.push({ name: fullName(name), fn: callback('test', fn), scopes: scopes.slice() });
+    }
+
+    // the body is ran straight away, so tests must be declared synchronously
+    function describe(name, fn) {
+        callback('describe', fn);
+        scopes.push({ name: String(name), beforeEach: [] });
+        try {
+            fn();
+        } finally {
+            scopes.pop();
+        }
+    }
+
+    // ran before every test of the enclosing `describe` (or file), outermost first
+    function beforeEach(fn) {
+        scopes[scopes.length - 1].beforeEach.push(callback('beforeEach', fn));
+    }
+
+    class AssertionError extends Error {
+        constructor({ message, actual, expected, operator }) {
+            super(message);
+            this.name = 'AssertionError';
+            this.actual = actual;
+            this.expected = expected;
+            this.operator = operator;
+        }
+    }
+
+    function fail(message, actual, expected, operator, fallback) {
+        if (message instanceof Error) {
+            throw message;
+        }
+        throw new AssertionError({ message: message ?? fallback, actual, expected, operator });
+    }
+
+    function isDeepEqual(a, b, seen = new Map()) {
+        if (Object.is(a, b)) {
+            return true;
+        }
+        if (typeof a !== 'object' || typeof b !== 'object' || a === null || b === null) {
+            return false;
+        }
+        if (Object.getPrototypeOf(a) !== Object.getPrototypeOf(b)) {
+            return false;
+        }
+        // cycles compare equal if they line up
+        if (seen.get(a) === b) {
+            return true;
+        }
+        seen.set(a, b);
+        if (a instanceof Date) {
+            return Object.is(a.getTime(), b.getTime());
+        }
+        if (a instanceof RegExp) {
+            return a.source === b.source && a.flags === b.flags;
+        }
+        if (a instanceof Map) {
+            if (a.size !== b.size) {
+                return false;
+            }
+            for (const [key, value] of a) {
+                if (!b.has(key) || !isDeepEqual(value, b.get(key), seen)) {
+                    return false;
+                }
+            }
+            return true;
+        }
+        if (a instanceof Set) {
+            if (a.size !== b.size) {
+                return false;
+            }
+            const unmatched = [...b];
+            for (const value of a) {
+                const index = unmatched.findIndex((other) => isDeepEqual(value, other, seen));
+                if (index < 0) {
+                    return false;
+                }
+                unmatched.splice(index, 1);
+            }
+            return true;
+        }
+        if (ArrayBuffer.isView(a)) {
+            return a.length === b.length && Array.prototype.every.call(a, (value, i) => Object.is(value, b[i]));
+        }
+        const keys = Reflect.ownKeys(a).filter((key) => Object.prototype.propertyIsEnumerable.call(a, key));
+        const otherKeys = Reflect.ownKeys(b).filter((key) => Object.prototype.propertyIsEnumerable.call(b, key));
+        if (keys.length !== otherKeys.length) {
+            return false;
+        }
+        return keys.every((key) => Object.prototype.propertyIsEnumerable.call(b, key) && isDeepEqual(a[key], b[key], seen));
+    }
+
+    // `expected` is a class, a `RegExp` for the message or a predicate
+    function matches(error, expected) {
+        if (expected === undefined) {
+            return true;
+        }
+        if (expected instanceof RegExp) {
+            return expected.test(error instanceof Error ? error.message : String(error));
+        }
+        if (typeof expected === 'function') {
+            if (expected.prototype !== undefined && error instanceof expected) {
+                return true;
+            }
+            if (Error.isPrototypeOf(expected) || expected === Error) {
+                return false;
+            }
+            return expected(error) === true;
+        }
+        throw new TypeError(`expected must be a class, RegExp or function, got ${show(expected)}`);
+    }
+
+    function assert(value, message) {
+        if (!value) {
+            fail(message, value, true, '==', `${show(value)} is not truthy`);
+        }
+    }
+
+    // deep comparisons are always strict, like node.js' `assert/strict`
+    Object.assign(assert, {
+        AssertionError,
+        ok: assert,
+        equal(actual, expected, message) {
+            if (!(actual == expected)) {
+                fail(message, actual, expected, '==', `${show(actual)} == ${show(expected)}`);
+            }
+        },
+        notEqual(actual, expected, message) {
+            if (actual == expected) {
+                fail(message, actual, expected, '!=', `${show(actual)} != ${show(expected)}`);
+            }
+        },
+        strictEqual(actual, expected, message) {
+            if (!Object.is(actual, expected)) {
+                fail(message, actual, expected, 'strictEqual', `expected ${show(expected)}, got ${show(actual)}`);
+            }
+        },
+        notStrictEqual(actual, expected, message) {
+            if (Object.is(actual, expected)) {
+                fail(message, actual, expected, 'notStrictEqual', `expected anything but ${show(expected)}`);
+            }
+        },
+        deepEqual(actual, expected, message) {
+            assert.deepStrictEqual(actual, expected, message);
+        },
+        deepStrictEqual(actual, expected, message) {
+            if (!isDeepEqual(actual, expected)) {
+                fail(message, actual, expected, 'deepStrictEqual', `expected ${show(expected)}, got ${show(actual)}`);
+            }
+        },
+        notDeepStrictEqual(actual, expected, message) {
+            if (isDeepEqual(actual, expected)) {
+                fail(message, actual, expected, 'notDeepStrictEqual', `expected anything but ${show(expected)}`);
+            }
+        },
+        match(string, regexp, message) {
+            if (typeof string !== 'string' || !regexp.test(string)) {
+                fail(message, string, regexp, 'match', `${show(string)} does not match ${regexp}`);
+            }
+        },
+        throws(fn, expected, message) {
+            try {
+                fn();
+            } catch (error) {
+                if (!matches(error, expected)) {
+                    fail(message, error, expected, 'throws', `unexpected ${show(error)}`);
+                }
+                return;
+            }
+            fail(message, undefined, expected, 'throws', 'missing expected exception');
+        },
+        async rejects(promiseOrFn, expected, message) {
+            try {
+                await (typeof promiseOrFn === 'function' ? promiseOrFn() : promiseOrFn);
+            } catch (error) {
+                if (!matches(error, expected)) {
+                    fail(message, error, expected, 'rejects', `unexpected ${show(error)}`);
+                }
+                return;
+            }
+            fail(message, undefined, expected, 'rejects', 'missing expected rejection');
+        },
+        fail(message) {
+            fail(message, undefined, undefined, 'fail', 'failed');
+        },
+    });
+
+    harness.names = () => {
+        started = true;
+        return tests.map((t) => t.name);
+    };
+    // settles with nothing when the test passes, `{ error }` when it doesn't
+    harness.run = (index) => {
+        const { fn, scopes } = tests[index];
+        const run = async () => {
+            for (const scope of scopes) {
+                for (const hook of scope.beforeEach) {
+                    await hook();
+                }
+            }
+            await fn();
+        };
+        return run().then(() => undefined, (error) => ({ error }));
+    };
+
+    for (const [name, value] of Object.entries({ test, describe, beforeEach, assert })) {
+        Object.defineProperty(globalThis, name, { value, writable: true, configurable: true, enumerable: false });
+    }
+})(globalThis.__testHarness);
+delete globalThis.__testHarness;
@@ -0,0 +1,213 @@
+use std::{
+    fmt::{Write as _},
+    time::{Duration},
+};
+
+/*
+ * Test reports
+ *
+ * TAP (version 13) is written as each test finishes, a failure's
+ * message & stack go in its YAML block. JUnit XML is written once
+ * every file has ran, one `<testsuite>` per file.
+ *
+ */
+
+/// Why a test failed
+#[derive(Clone,Debug)]
+pub(crate) struct Failure {
+    pub message: String,
+    pub stack: String,
+}
+
+#[derive(Clone,Debug)]
+pub(crate) struct TestResult {
+    pub file: String,
+    pub name: String,
+    pub duration: Duration,
+    pub failure: Option<Failure>,
+}
+
+pub(crate) fn tap_header() -> String {
+    "TAP version 13\n".to_string()
+}
+
+/// `ok`/`not ok` for the `number`th (one based) test
+pub(crate) fn tap_result(number: usize, result: &TestResult) -> String {
+    let mut out = String::new();
+    let status = if result.failure.is_some() { "not ok" } else { "ok" };
+    // `#` starts a directive, so it can't appear in the description
+    let name = format!("{} > {}", result.file, result.name).replace('#', "\\#");
+    let _ = writeln!(out, "{} {} - {}", status, number, name);
+    let _ = writeln!(out, "  ---");
+    let _ = writeln!(out, "  duration_ms: {:.3}", result.duration.as_secs_f64() * 1000.0);
+    if let Some(failure) = &result.failure {
+        let _ = writeln!(out, "  message: {}", yaml_string(&failure.message));
+        if !failure.stack.is_empty() {
+            let _ = writeln!(out, "  stack: |-");
+            for line in failure.stack.lines() {
+                let _ = writeln!(out, "    {}", line);
+            }
+        }
+    }
+    let _ = writeln!(out, "  ...");
+    out
+}
+
+/// The plan & totals, once every test has ran
+pub(crate) fn tap_footer(results: &[TestResult]) -> String {
+    let failed = results.iter().filter(|r| r.failure.is_some()).count();
+    format!(
+        "1..{}\n# tests {}\n# pass {}\n# fail {}\n",
+        results.len(), results.len(), results.len() - failed, failed,
+    )
+}
+
+pub(crate) fn junit(results: &[TestResult]) -> String {
+    let failed = |results: &[&TestResult]| results.iter().filter(|r| r.failure.is_some()).count();
+    let seconds = |results: &[&TestResult]| results.iter().map(|r| r.duration.as_secs_f64()).sum::<f64>();
+    // files in the order they ran
+    let mut files: Vec<(&str, Vec<&TestResult>)> = Vec::new();
+    for result in results {
+        match files.iter_mut().find(|(file, _)| *file == result.file) {
+            Some((_, tests)) => tests.push(result),
+            None => files.push((&result.file, vec![result])),
+        }
+    }
+    let all: Vec<&TestResult> = results.iter().collect();
+
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    let _ = writeln!(out, "<testsuites tests=\"{}\" failures=\"{}\" time=\"{:.3}\">", all.len(), failed(&all), seconds(&all));
+    for (file, tests) in &files {
+        let _ = writeln!(
+            out, "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">",
+            xml_escape(file), tests.len(), failed(tests), seconds(tests),
+        );
+        for test in tests {
+            let _ = write!(
+                out, "    <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\"",
+                xml_escape(&test.name), xml_escape(file), test.duration.as_secs_f64(),
+            );
+            match &test.failure {
+                Some(failure) => {
+                    let _ = writeln!(out, ">");
+                    let _ = writeln!(
+                        out, "      <failure message=\"{}\">{}</failure>",
+                        xml_escape(&failure.message), xml_escape(&failure.stack),
+                    );
+                    let _ = writeln!(out, "    </testcase>");
+                }
+                None => {
+                    let _ = writeln!(out, "/>");
+                }
+            }
+        }
+        let _ = writeln!(out, "  </testsuite>");
+    }
+    out.push_str("</testsuites>\n");
+    out
+}
+
+/// A double quoted YAML scalar
+fn yaml_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\x{:02x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn xml_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            // the only control characters XML 1.0 allows
+            '\n' | '\r' | '\t' => out.push(c),
+            c if c.is_control() => { },
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn passed(file: &str, name: &str) -> TestResult {
+        TestResult { file: file.to_string(), name: name.to_string(), duration: Duration::from_millis(2), failure: None }
+    }
+
+    fn failed(file: &str, name: &str, message: &str, stack: &str) -> TestResult {
+        TestResult {
+            failure: Some(Failure { message: message.to_string(), stack: stack.to_string() }),
+            ..passed(file, name)
+        }
+    }
+
+    #[test]
+    fn tap_results_carry_their_failure() {
+        assert_eq!(tap_result(1, &passed("a.test.js", "adds")), "ok 1 - a.test.js > adds\n  ---\n  duration_ms: 2.000\n  ...\n");
+        let result = failed("a.test.js", "issue #1", "expected \"1\"\nto be 2", "f@a.test.js:1:1\ng@a.test.js:2:1");
+        assert_eq!(tap_result(2, &result), concat!(
+            "not ok 2 - a.test.js > issue \\#1\n",
+            "  ---\n",
+            "  duration_ms: 2.000\n",
+            "  message: \"expected \\\"1\\\"\\nto be 2\"\n",
+            "  stack: |-\n",
+            "    f@a.test.js:1:1\n",
+            "    g@a.test.js:2:1\n",
+            "  ...\n",
+        ));
+    }
+
+    #[test]
+    fn tap_footers_count_the_results() {
+        let results = [passed("a.test.js", "x"), failed("a.test.js", "y", "no", ""), passed("b.test.js", "z")];
+        assert_eq!(tap_header(), "TAP version 13\n");
+        assert_eq!(tap_footer(&results), "1..3\n# tests 3\n# pass 2\n# fail 1\n");
+    }
+
+    #[test]
+    fn junit_has_a_suite_per_file_in_the_order_they_ran() {
+        let results = [
+            passed("b.test.js", "x"),
+            failed("a.test.js", "<y>", "a & b", "f@a.test.js:1:1\u{1}"),
+            passed("b.test.js", "z"),
+        ];
+        assert_eq!(junit(&results), concat!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+            "<testsuites tests=\"3\" failures=\"1\" time=\"0.006\">\n",
+            "  <testsuite name=\"b.test.js\" tests=\"2\" failures=\"0\" time=\"0.004\">\n",
+            "    <testcase name=\"x\" classname=\"b.test.js\" time=\"0.002\"/>\n",
+            "    <testcase name=\"z\" classname=\"b.test.js\" time=\"0.002\"/>\n",
+            "  </testsuite>\n",
+            "  <testsuite name=\"a.test.js\" tests=\"1\" failures=\"1\" time=\"0.002\">\n",
+            "    <testcase name=\"&lt;y&gt;\" classname=\"a.test.js\" time=\"0.002\">\n",
+            "      <failure message=\"a &amp; b\">f@a.test.js:1:1</failure>\n",
+            "    </testcase>\n",
+            "  </testsuite>\n",
+            "</testsuites>\n",
+        ));
+    }
+
+    #[test]
+    fn yaml_strings_escape_control_characters() {
+        assert_eq!(yaml_string("a\tb\\c\u{7}"), "\"a\\tb\\\\c\\x07\"");
+    }
+}
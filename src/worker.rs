@@ -0,0 +1,545 @@
+use std::{
+    cell::{RefCell},
+    ops::{DerefMut},
+    ptr::{NonNull},
+    rc::{Rc},
+    sync::{Arc,Mutex,atomic::{AtomicBool,Ordering}},
+    thread::{JoinHandle},
+};
+use tokio::{
+    runtime::{Handle as TokioHandle},
+    sync::{
+        oneshot,
+        mpsc::{UnboundedSender,UnboundedReceiver,unbounded_channel},
+    },
+};
+use mozjs::{rooted};
+use mozjs::{
+    context::{JSContext,RawJSContext},
+    gc::{Handle},
+    jsapi::{
+        JSObject,Value,CallArgs,
+        CurrentGlobalOrNull,JS_AddInterruptCallback,JS_RequestInterruptCallback,
+    },
+    jsval::{UndefinedValue,ObjectValue},
+    error::{throw_type_error,throw_internal_error},
+    realm::{AutoRealm},
+    rust::{JSEngineHandle,Runtime,wrappers2::JS_DefineFunction},
+    panic::wrap_panic,
+};
+#[allow(unused_imports)]
+use tracing::{debug,trace,instrument,warn,error,info};
+
+use crate::{
+    globals::{define_globals},
+    realm::{new_global,evaluate,realm_id_of,forget_realm},
+    streams::{forget_byte_stream_hook},
+    runtime::{
+        callback::{install_job_queue},
+        roots::{install_root_tracer,HeapRoot},
+        metrics::{install_gc_metrics},
+        introspection::{install_dump_on_signal},
+        checkpoint::{runtime_checkpoint,shutdown},
+        conversions::{new_object,define_property},
+        coverage::{collect_coverage},
+        exception::{value_to_string},
+        host_object::{new_host_object,host_object_data},
+        incumbent_stack::{enter_incumbent_stack},
+        message::{MessageListener},
+        queue::{Macrotask},
+        resolvable_promise::{push_task_source},
+        structured_clone::{StructuredCloneData,write_structured_clone,CloneScope,transfer_list},
+    },
+};
+
+/*
+ * Workers
+ *
+ * Every worker is an OS thread with its own `Runtime`, job queue
+ * and event loop. The two sides only share channels of structured
+ * clone data & a termination flag.
+ *
+ * Like node.js, a worker runs until its event loop runs out of work,
+ * `terminate()` or `close()`. Its `onmessage` handler counts as work:
+ * while one is set the worker waits for messages. Until the worker
+ * exits the parent's event loop is kept alive & the `Worker` object
+ * is rooted, so its `onmessage` gets every message. Workers still
+ * running when the parent's runtime goes away are terminated.
+ */
+
+/// Makes a worker's `onmessage` an accessor, see `scope_set_listening`
+const SCOPE_PRELUDE: &str = r#"
+(function (native) {
+    'use strict';
+    let handler = null;
+    Object.defineProperty(globalThis, 'onmessage', {
+        get() {
+            return handler;
+        },
+        set(value) {
+            handler = typeof value === 'function' ? value : null;
+            native.setListening(handler !== null);
+        },
+        enumerable: true,
+        configurable: true,
+    });
+})(globalThis.__workerNative);
+delete globalThis.__workerNative;
+"#;
+
+static ENGINE: Mutex<Option<JSEngineHandle>> = Mutex::new(None);
+
+/// Must be called once before any worker is spawned
+pub fn set_engine_handle(handle: JSEngineHandle) {
+    *ENGINE.lock().unwrap() = Some(handle);
+}
+
+/// Gives back the handle from `set_engine_handle`, no worker can be spawned after
+///
+/// Must be called before the engine is dropped, which waits for every
+/// handle to be gone.
+pub fn clear_engine_handle() {
+    ENGINE.lock().unwrap().take();
+}
+
+fn engine_handle() -> Option<JSEngineHandle> {
+    ENGINE.lock().unwrap().clone()
+}
+
+thread_local! {
+    /// Only set on worker threads
+    static WORKER_SCOPE: RefCell<Option<WorkerScope>> = RefCell::new(None);
+}
+
+/// The worker side of the connection
+struct WorkerScope {
+    outbox: UnboundedSender<StructuredCloneData>,
+    shared: Arc<WorkerShared>,
+    /// while `onmessage` is set, dropped to let the event loop exit
+    listening: Option<oneshot::Sender<()>>,
+}
+
+/// State both threads can see
+#[derive(Default)]
+struct WorkerShared {
+    terminated: AtomicBool,
+    /// the worker's context while it is alive, used to interrupt running script
+    context: Mutex<Option<ContextPtr>>,
+}
+impl WorkerShared {
+    fn terminate(&self) {
+        self.terminated.store(true, Ordering::SeqCst);
+        if let Some(ptr) = self.context.lock().unwrap().as_ref() {
+            unsafe { JS_RequestInterruptCallback(ptr.0) };
+        }
+    }
+}
+
+struct ContextPtr(*mut RawJSContext);
+// only ever used for `JS_RequestInterruptCallback` which is thread safe
+unsafe impl Send for ContextPtr {}
+
+/// Owned by the `Worker` object in the parent
+struct WorkerHandle {
+    id: u64,
+    inbox: Option<UnboundedSender<StructuredCloneData>>,
+    shared: Arc<WorkerShared>,
+    _thread: JoinHandle<()>,
+}
+impl WorkerHandle {
+    fn terminate(&mut self) {
+        if self.inbox.take().is_some() {
+            debug!("terminating worker '{}'", self.id);
+        }
+        self.shared.terminate();
+    }
+}
+impl Drop for WorkerHandle {
+    fn drop(&mut self) {
+        // never join, this can run within a GC finalizer
+        self.terminate();
+    }
+}
+
+/// `new Worker(path)`
+#[instrument(skip_all,name="worker_constructor_entry_point")]
+pub unsafe extern "C" fn worker_constructor(
+    ctx: *mut RawJSContext,
+    argc: u32,
+    vp: *mut Value,
+) -> bool {
+    static NEXT_WORKER_ID: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(1);
+
+    let mut is_okay = true;
+    wrap_panic(&mut || {
+        let args = unsafe { CallArgs::from_vp(vp, argc) };
+        let path = match value_to_string(ctx, unsafe { Handle::from_raw(args.get(0)) }) {
+            Some(path) if args.argc_ > 0 => path,
+            _ => {
+                unsafe { throw_type_error(ctx, "Worker: argument 1 must be a script path") };
+                is_okay = false;
+                return;
+            }
+        };
+        let Some(engine) = engine_handle() else {
+            unsafe { throw_internal_error(ctx, "Worker: engine handle was never set") };
+            is_okay = false;
+            return;
+        };
+        rooted!(in(ctx) let current_global = unsafe { CurrentGlobalOrNull(ctx) });
+        if current_global.get().is_null() {
+            error!("global is null");
+            is_okay = false;
+            return;
+        }
+
+        let id = NEXT_WORKER_ID.fetch_add(1, Ordering::Relaxed);
+        let (inbox_tx, inbox_rx) = unbounded_channel();
+        let (outbox_tx, outbox_rx) = unbounded_channel();
+        let shared = Arc::new(WorkerShared::default());
+        let tokio = TokioHandle::current();
+        let thread_shared = shared.clone();
+        let thread = std::thread::Builder::new()
+            .name(format!("worker-{}", id))
+            .spawn(move || run_worker(engine, tokio, path, inbox_rx, outbox_tx, thread_shared));
+        let thread = match thread {
+            Ok(thread) => thread,
+            Err(e) => {
+                unsafe { throw_internal_error(ctx, &format!("Worker: could not spawn thread: {}", e)) };
+                is_okay = false;
+                return;
+            }
+        };
+
+        let handle = WorkerHandle { id, inbox: Some(inbox_tx), shared, _thread: thread };
+        rooted!(in(ctx) let worker = new_host_object(ctx, handle));
+        if worker.get().is_null() {
+            is_okay = false;
+            return;
+        }
+        let mut safe_ctx = unsafe { JSContext::from_ptr(NonNull::new(ctx).unwrap()) };
+        unsafe {
+            JS_DefineFunction(&mut safe_ctx, worker.handle(), c"postMessage".as_ptr(), Some(worker_post_message), 2, 0);
+            JS_DefineFunction(&mut safe_ctx, worker.handle(), c"terminate".as_ptr(), Some(worker_terminate), 0, 0);
+        }
+
+        MessageListener {
+            receiver: outbox_rx,
+            target: HeapRoot::new(worker.get()),
+            global: Rc::new(HeapRoot::new(current_global.get())),
+            source: "worker message",
+            keep_alive: true,
+            on_close: Some(Box::new(move |_: &mut JSContext| debug!("worker '{}' exited", id))),
+        }.listen();
+
+        args.rval().set(ObjectValue(worker.get()));
+    });
+    is_okay
+}
+
+/// `worker.postMessage(value, transfer)`
+unsafe extern "C" fn worker_post_message(
+    ctx: *mut RawJSContext,
+    argc: u32,
+    vp: *mut Value,
+) -> bool {
+    let mut is_okay = true;
+    wrap_panic(&mut || {
+        let args = unsafe { CallArgs::from_vp(vp, argc) };
+        let Some(handle) = this_worker(&args) else {
+            unsafe { throw_type_error(ctx, "postMessage: this is not a Worker") };
+            is_okay = false;
+            return;
+        };
+        let Some(data) = serialize_message(ctx, &args) else {
+            is_okay = false;
+            return;
+        };
+        match handle.inbox.as_ref() {
+            Some(inbox) if inbox.send(data).is_ok() => { },
+            // posting to a terminated worker is silently ignored
+            _ => trace!("worker '{}' is gone, dropping message", handle.id),
+        };
+        args.rval().set(UndefinedValue());
+    });
+    is_okay
+}
+
+/// `worker.terminate()`
+unsafe extern "C" fn worker_terminate(
+    ctx: *mut RawJSContext,
+    argc: u32,
+    vp: *mut Value,
+) -> bool {
+    let mut is_okay = true;
+    wrap_panic(&mut || {
+        let args = unsafe { CallArgs::from_vp(vp, argc) };
+        let Some(handle) = this_worker(&args) else {
+            unsafe { throw_type_error(ctx, "terminate: this is not a Worker") };
+            is_okay = false;
+            return;
+        };
+        handle.terminate();
+        args.rval().set(UndefinedValue());
+    });
+    is_okay
+}
+
+fn this_worker<'a>(args: &CallArgs) -> Option<&'a mut WorkerHandle> {
+    let this = args.thisv();
+    if !this.is_object() {
+        return None;
+    }
+    unsafe { host_object_data::<WorkerHandle>(this.to_object()) }
+}
+
+/// Serializes `args[0]` with `args[1]` as the transfer list
+fn serialize_message(ctx: *mut RawJSContext, args: &CallArgs) -> Option<StructuredCloneData> {
+    rooted!(in(ctx) let value = args.get(0).get());
+    rooted!(in(ctx) let mut transfer = UndefinedValue());
+    if !transfer_list(ctx, unsafe { Handle::from_raw(args.get(1)) }, transfer.handle_mut()) {
+        return None;
+    }
+    write_structured_clone(ctx, value.handle(), transfer.handle(), CloneScope::OtherThread).ok()
+}
+
+/*
+ * Worker thread
+ *
+ */
+
+#[instrument(skip_all, fields(path = %path))]
+fn run_worker(
+    engine: JSEngineHandle,
+    tokio: TokioHandle,
+    path: String,
+    inbox: UnboundedReceiver<StructuredCloneData>,
+    outbox: UnboundedSender<StructuredCloneData>,
+    shared: Arc<WorkerShared>,
+) {
+    let _guard = tokio.enter();
+    let source = match std::fs::read_to_string(&path) {
+        Ok(source) => source,
+        Err(e) => {
+            error!("could not read worker script '{}': {}", path, e);
+            return;
+        }
+    };
+
+    let mut runtime = Runtime::new(engine);
+    let context = runtime.cx();
+    install_job_queue(context);
+    install_root_tracer(context);
+    install_gc_metrics(unsafe { context.raw_cx() });
+    install_dump_on_signal();
+    unsafe { JS_AddInterruptCallback(context.raw_cx(), Some(worker_interrupt)) };
+    *shared.context.lock().unwrap() = Some(ContextPtr(unsafe { context.raw_cx() }));
+    WORKER_SCOPE.with(|w| *w.borrow_mut() = Some(WorkerScope { outbox, shared: shared.clone(), listening: None }));
+
+    // terminate may have raced us before the context was published
+    if !shared.terminated.load(Ordering::SeqCst) {
+        rooted!(in(unsafe { context.raw_cx() }) let global = new_global(context));
+        enter_incumbent_stack(context, global.handle(), |realm, global_obj| {
+            define_globals(realm, global_obj);
+            define_worker_globals(realm, global_obj);
+            let _ = evaluate(realm, global_obj, &path, &source);
+        });
+
+        MessageListener {
+            receiver: inbox,
+            target: HeapRoot::new(global.get()),
+            global: Rc::new(HeapRoot::new(global.get())),
+            source: "worker inbox",
+            // `onmessage` keeps the worker alive, see `scope_set_listening`
+            keep_alive: false,
+            on_close: Some(Box::new(|_: &mut JSContext| shutdown())),
+        }.listen();
+
+        runtime_checkpoint(context);
+        // before the parent hears we've exited
+        collect_coverage(context);
+        // the hook & global are rooted until forgotten, they can't outlive the runtime
+        if let Some(id) = realm_id_of(global.get()) {
+            forget_byte_stream_hook(id);
+            forget_realm(id);
+        }
+    }
+
+    // everything holding a `Heap` must be gone before the runtime is
+    shutdown();
+    *shared.context.lock().unwrap() = None;
+    // dropping the outbox tells the parent we've exited
+    WORKER_SCOPE.with(|w| w.borrow_mut().take());
+    info!("worker exiting");
+}
+
+fn define_worker_globals(realm: &mut AutoRealm, global_obj: Handle<'_,*mut JSObject>) {
+    unsafe {
+        JS_DefineFunction(realm,global_obj,c"postMessage".as_ptr(),Some(scope_post_message),2,0,);
+        JS_DefineFunction(realm,global_obj,c"close".as_ptr(),Some(scope_close),0,0,);
+    }
+    let cx = unsafe { realm.deref_mut().raw_cx() };
+    rooted!(in(cx) let native = new_object(cx));
+    if native.get().is_null() {
+        error!("could not allocate worker natives");
+        return;
+    }
+    unsafe {
+        JS_DefineFunction(realm,native.handle(),c"setListening".as_ptr(),Some(scope_set_listening),1,0,);
+    }
+    rooted!(in(cx) let native_val = ObjectValue(native.get()));
+    define_property(cx, global_obj, c"__workerNative", native_val.handle());
+    if evaluate(realm, global_obj, "worker.js", SCOPE_PRELUDE).is_err() {
+        error!("could not evaluate the worker prelude");
+    }
+}
+
+/// `native.setListening(listening)`, keeps the event loop alive while `onmessage` is set
+unsafe extern "C" fn scope_set_listening(
+    _ctx: *mut RawJSContext,
+    argc: u32,
+    vp: *mut Value,
+) -> bool {
+    wrap_panic(&mut || {
+        let args = unsafe { CallArgs::from_vp(vp, argc) };
+        let listening = args.get(0).is_boolean() && args.get(0).to_boolean();
+        WORKER_SCOPE.with(|w| {
+            let mut scope = w.borrow_mut();
+            let Some(scope) = scope.as_mut() else {
+                return;
+            };
+            if !listening {
+                scope.listening.take();
+                return;
+            }
+            if scope.listening.is_none() {
+                let (sender, receiver) = oneshot::channel::<()>();
+                scope.listening = Some(sender);
+                push_task_source(async move {
+                    let _ = receiver.await;
+                    Macrotask::new("worker listening", |_: &mut JSContext| trace!("onmessage unset"))
+                });
+            }
+        });
+        args.rval().set(UndefinedValue());
+    });
+    true
+}
+
+/// Aborts running script once the parent has terminated us
+unsafe extern "C" fn worker_interrupt(_cx: *mut RawJSContext) -> bool {
+    let terminated = WORKER_SCOPE.with(|w| {
+        w.borrow().as_ref().map(|scope| scope.shared.terminated.load(Ordering::SeqCst)).unwrap_or(false)
+    });
+    if terminated {
+        info!("worker terminated while running script");
+        shutdown();
+    }
+    // returning false is an uncatchable exception
+    !terminated
+}
+
+/// `postMessage(value, transfer)` within a worker
+unsafe extern "C" fn scope_post_message(
+    ctx: *mut RawJSContext,
+    argc: u32,
+    vp: *mut Value,
+) -> bool {
+    let mut is_okay = true;
+    wrap_panic(&mut || {
+        let args = unsafe { CallArgs::from_vp(vp, argc) };
+        let Some(data) = serialize_message(ctx, &args) else {
+            is_okay = false;
+            return;
+        };
+        WORKER_SCOPE.with(|w| {
+            if let Some(scope) = w.borrow().as_ref() {
+                if scope.outbox.send(data).is_err() {
+                    trace!("parent is gone, dropping message");
+                }
+            }
+        });
+        args.rval().set(UndefinedValue());
+    });
+    is_okay
+}
+
+/// `close()` within a worker, stops the event loop after the current task
+unsafe extern "C" fn scope_close(
+    _ctx: *mut RawJSContext,
+    argc: u32,
+    vp: *mut Value,
+) -> bool {
+    wrap_panic(&mut || {
+        let args = unsafe { CallArgs::from_vp(vp, argc) };
+        shutdown();
+        args.rval().set(UndefinedValue());
+    });
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration,Instant};
+    use mozjs::{rooted};
+    use crate::runtime::{
+        checkpoint::{run_until},
+        testing::{with_runtime,new_test_global,eval,eval_async,gc,TempDir},
+    };
+
+    /// Runs the event loop, `true` if it ran out of work within a few seconds
+    fn runs_out_of_work(ctx: &mut mozjs::context::JSContext) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        !run_until(ctx, Some(deadline), || Instant::now() >= deadline)
+    }
+
+    #[test]
+    fn an_unreferenced_worker_exits() {
+        let dir = TempDir::new("worker");
+        let path = dir.write("worker.js", "postMessage('done');");
+        with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            eval(ctx, global.handle(), &format!("new Worker({:?}); undefined", path)).unwrap();
+            gc(ctx);
+            assert!(runs_out_of_work(ctx));
+        });
+    }
+
+    #[test]
+    fn a_listening_worker_answers_until_terminated() {
+        let dir = TempDir::new("worker");
+        let path = dir.write("echo.js", "onmessage = (event) => postMessage(event.data * 2);");
+        with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            let answer = eval_async(ctx, global.handle(), &format!(r#"
+                globalThis.worker = new Worker({:?});
+                const answer = await new Promise((resolve) => {{
+                    worker.onmessage = (event) => resolve(event.data);
+                    worker.postMessage(21);
+                }});
+                worker.terminate();
+                return answer;
+            "#, path));
+            assert_eq!(answer.unwrap(), "42");
+            assert!(runs_out_of_work(ctx));
+        });
+    }
+
+    #[test]
+    fn unsetting_onmessage_lets_the_worker_exit() {
+        let dir = TempDir::new("worker");
+        let path = dir.write("once.js", "onmessage = (event) => { postMessage(event.data); onmessage = null; };");
+        with_runtime(|ctx| {
+            rooted!(in(unsafe { ctx.raw_cx() }) let global = new_test_global(ctx));
+            let answer = eval_async(ctx, global.handle(), &format!(r#"
+                const worker = new Worker({:?});
+                return await new Promise((resolve) => {{
+                    worker.onmessage = (event) => resolve(event.data);
+                    worker.postMessage('once');
+                }});
+            "#, path));
+            assert_eq!(answer.unwrap(), "once");
+            assert!(runs_out_of_work(ctx));
+        });
+    }
+}